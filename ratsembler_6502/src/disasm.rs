@@ -0,0 +1,1037 @@
+pub mod hints;
+pub mod trace;
+
+use crate::elf::reader::ElfFile;
+use crate::elf::relocatable::base_section_name;
+use crate::elf::relocatable::section_index;
+use crate::elf::relocatable::Object;
+use crate::elf::relocatable::Relocation;
+use crate::elf::relocatable::Rom;
+use crate::elf::relocatable::Symbol;
+use crate::elf::writer::*;
+use crate::lang::ast::INVERT_BRANCH;
+use crate::lang::instruction::AddressModeIndexer;
+use crate::lang::instruction::InstructionCode;
+use crate::lang::instruction::INSTRUCTION_MAP;
+use crate::lang::instruction::INSTRUCTION_STR_MAP;
+use crate::lang::instruction::LONG_BRANCHES;
+use crate::lang::instruction::OPCODE_MAP;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+const JMP: u8 = 0x4C;
+// How many of each go on a line
+const BYTES_PER_LINE: usize = 8;
+const WORDS_PER_LINE: usize = 4;
+const TEXT_PER_LINE: usize = 32;
+
+// Whether a byte is meant to be run or read, and how. Code that doesn't
+// decode, or that the assembler couldn't write back the same way, comes out
+// as data anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Code,
+    Data,
+    Word,
+    // Words that are addresses of code, like a jump table
+    Pointer,
+    Text,
+}
+
+// A run of bytes to disassemble, which is a section of an object or
+// executable, or the whole of a binary. Blocks that haven't been linked yet
+// have no address, and refer to everything else through relocations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub section: Option<String>,
+    pub bank: Option<(Rom, usize)>,
+    pub address: Option<u16>,
+    pub bytes: Vec<u8>,
+    pub kinds: Vec<Kind>,
+    // Everything from here on is by offset into the block
+    pub labels: BTreeMap<usize, Vec<String>>,
+    pub relocations: BTreeMap<usize, Relocation>,
+    // Space that's only reserved, like the bss, which comes out as `.res`
+    pub reserved: bool,
+}
+
+impl Block {
+    fn new(
+        section: Option<String>,
+        bank: Option<(Rom, usize)>,
+        address: Option<u16>,
+        bytes: Vec<u8>,
+        kind: Kind,
+    ) -> Block {
+        Block {
+            section,
+            bank,
+            address,
+            kinds: vec![kind; bytes.len()],
+            bytes,
+            labels: BTreeMap::new(),
+            relocations: BTreeMap::new(),
+            reserved: false,
+        }
+    }
+
+    fn add_label(&mut self, offset: usize, name: &str) {
+        let labels = self.labels.entry(offset).or_default();
+        labels.push(name.to_string());
+        labels.sort();
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Disassembly {
+    pub blocks: Vec<Block>,
+    // Symbols with a value rather than a location
+    pub constants: BTreeMap<String, u16>,
+}
+
+impl Disassembly {
+    // A flat binary loaded at `origin`, taken to be all code until it's
+    // told otherwise.
+    pub fn from_binary(bytes: &[u8], origin: u16) -> Disassembly {
+        Disassembly {
+            blocks: vec![Block::new(
+                None,
+                None,
+                Some(origin),
+                bytes.to_vec(),
+                Kind::Code,
+            )],
+            constants: BTreeMap::new(),
+        }
+    }
+
+    // Each section of an object, with its symbols as labels. Sections the
+    // program writes to are taken to be data.
+    pub fn from_object(object: &Object) -> Disassembly {
+        let mut disassembly = Disassembly::default();
+        for section in &object.sections {
+            let kind = match section_flags(&section.name, section.bank) & SHF_WRITE {
+                0 => Kind::Code,
+                _ => Kind::Data,
+            };
+            let bytes = object.raw_section[section.start..section.end()].to_vec();
+            let mut block = Block::new(Some(section.name.clone()), section.bank, None, bytes, kind);
+            block.reserved = matches!(base_section_name(&section.name), "bss" | "zp" | "zeropage")
+                && block.bytes.iter().all(|byte| *byte == 0);
+            disassembly.blocks.push(block);
+        }
+
+        for (name, symbol) in &object.symbols {
+            let location = match symbol {
+                Symbol::Location(location) | Symbol::Procedure(location, _) => *location,
+                Symbol::ShortValue(value) => {
+                    disassembly.constants.insert(name.clone(), *value as u16);
+                    continue;
+                }
+                Symbol::LongValue(value) => {
+                    disassembly.constants.insert(name.clone(), *value);
+                    continue;
+                }
+            };
+            if let Some(index) = section_index(&object.sections, location) {
+                let start = object.sections[index].start;
+                disassembly.blocks[index].add_label(location - start, name);
+            }
+        }
+
+        for relocation in &object.relocations {
+            let offset = relocation.offset() as usize;
+            if let Some(index) = section_index(&object.sections, offset) {
+                let offset = offset - object.sections[index].start;
+                disassembly.blocks[index]
+                    .relocations
+                    .insert(offset, relocation.moved_to(offset as u32));
+            }
+        }
+        disassembly
+    }
+
+    // Objects go through `from_object`. Executables have their sections at
+    // their final addresses, and symbols that are addresses in them.
+    pub fn from_elf(file: &ElfFile) -> Result<Disassembly, String> {
+        if file.header.e_type != ET_EXEC {
+            return Ok(Disassembly::from_object(&file.to_object()?));
+        }
+
+        let mut disassembly = Disassembly::default();
+        // Which block each ELF section became
+        let mut blocks = HashMap::new();
+        for (index, section) in file.sections.iter().enumerate() {
+            let header = &section.header;
+            if header.sh_flags & SHF_ALLOC == 0
+                || !matches!(header.sh_type, SHT_PROGBITS | SHT_NOBITS)
+            {
+                continue;
+            }
+            let bank = match header.sh_flags & (SHF_6502_PRG | SHF_6502_CHR) {
+                SHF_6502_PRG => Some((Rom::Prg, header.sh_info as usize)),
+                SHF_6502_CHR => Some((Rom::Chr, header.sh_info as usize)),
+                _ => None,
+            };
+            let kind = match header.sh_flags & SHF_EXECINSTR {
+                0 => Kind::Data,
+                _ => Kind::Code,
+            };
+            let bytes = match header.sh_type {
+                SHT_NOBITS => vec![0; header.sh_size as usize],
+                _ => file.contents(header)?.to_vec(),
+            };
+            let mut block = Block::new(
+                Some(section.name.trim_start_matches('.').to_string()),
+                bank,
+                Some(header.sh_addr as u16),
+                bytes,
+                kind,
+            );
+            block.reserved = header.sh_type == SHT_NOBITS;
+            blocks.insert(index, disassembly.blocks.len());
+            disassembly.blocks.push(block);
+        }
+
+        for symbol in file.symbols()? {
+            let sym = &symbol.symbol;
+            if symbol.name.is_empty() || sym.st_shndx == SHN_UNDEF || symbol.kind() == STT_SECTION {
+                continue;
+            }
+            if sym.st_shndx == SHN_ABS {
+                disassembly
+                    .constants
+                    .insert(symbol.name.clone(), sym.st_value as u16);
+                continue;
+            }
+            let Some(&index) = blocks.get(&(sym.st_shndx as usize)) else {
+                continue;
+            };
+            let block = &mut disassembly.blocks[index];
+            let offset = (sym.st_value as usize).checked_sub(block.address.unwrap() as usize);
+            if let Some(offset) = offset.filter(|offset| *offset <= block.bytes.len()) {
+                block.add_label(offset, &symbol.name);
+            }
+        }
+        Ok(disassembly)
+    }
+
+    // Marks the addresses in `range` as being `kind`. Blocks without an
+    // address go by offset into the block instead.
+    pub fn mark(&mut self, range: &RangeInclusive<u16>, kind: Kind) {
+        let range = *range.start() as usize..=*range.end() as usize;
+        for block in &mut self.blocks {
+            let start = block.address.unwrap_or(0) as usize;
+            for (offset, block_kind) in block.kinds.iter_mut().enumerate() {
+                if range.contains(&(start + offset)) {
+                    *block_kind = kind;
+                }
+            }
+        }
+    }
+}
+
+// What each run of a block comes out as.
+#[derive(Debug)]
+enum Item<'a> {
+    // An instruction and the relocation on its operand, if it has one
+    Instruction(InstructionCode, AddressModeIndexer, Option<&'a Relocation>),
+    // `JEQ` and friends in their long form, with the relocation on the JMP
+    LongBranch(InstructionCode, &'a Relocation),
+    Bytes(usize),
+    Words(usize),
+    Pointers(usize),
+    Text(usize),
+    // A `.word` or `.byte` of a symbol
+    Reference(&'a Relocation),
+    // Bytes with a relocation the assembler has no way of writing
+    Unexpressible(&'a Relocation, usize),
+    Reserved(usize),
+}
+
+impl Item<'_> {
+    fn size(&self) -> usize {
+        match self {
+            Item::Instruction(_, mode, _) => 1 + operand_size(*mode),
+            Item::LongBranch(..) => 5,
+            Item::Bytes(size)
+            | Item::Text(size)
+            | Item::Unexpressible(_, size)
+            | Item::Reserved(size) => *size,
+            Item::Words(count) | Item::Pointers(count) => 2 * count,
+            Item::Reference(relocation) => relocation.size(),
+        }
+    }
+}
+
+pub fn operand_size(mode: AddressModeIndexer) -> usize {
+    match mode {
+        AddressModeIndexer::IMPLIED | AddressModeIndexer::ACCUMULATOR => 0,
+        AddressModeIndexer::ABSOLUTE
+        | AddressModeIndexer::ABS_X
+        | AddressModeIndexer::ABS_Y
+        | AddressModeIndexer::ABSOLUTE_INDIRECT => 2,
+        _ => 1,
+    }
+}
+
+// Whether the assembler would put this relocation on an operand in this
+// mode. Labels are never zero page, so the only relocations a zero page
+// operand can have are a bank number or a byte of an address.
+fn expressible(mode: AddressModeIndexer, relocation: &Relocation) -> bool {
+    use AddressModeIndexer::*;
+    matches!(
+        (mode, relocation),
+        (IMMEDIATE | INDEX_IND | IND_INDEX, Relocation::Short(..))
+            | (
+                IMMEDIATE | ZERO_PAGE | ZP_X | ZP_Y,
+                Relocation::Bank(_, _, 0) | Relocation::Low(..) | Relocation::High(..)
+            )
+            | (ABSOLUTE | ABS_X | ABS_Y, Relocation::Long(..))
+            | (ABSOLUTE, Relocation::Jump(..))
+            | (ABSOLUTE_INDIRECT, Relocation::Absolute(..))
+            | (RELATIVE, Relocation::Relative(..))
+    )
+}
+
+// Whether every byte in `range` is code that nothing but the relocation at
+// `operand` refers into.
+fn plain_code(block: &Block, start: usize, size: usize, operand: Option<usize>) -> bool {
+    let end = start + size;
+    end <= block.bytes.len()
+        && block.kinds[start..end]
+            .iter()
+            .all(|kind| *kind == Kind::Code)
+        && block.labels.range(start + 1..end).next().is_none()
+        && block
+            .relocations
+            .range(start..end)
+            .all(|(offset, relocation)| {
+                Some(*offset) == operand
+                    || (*offset == start && matches!(relocation, Relocation::Relax(..)))
+            })
+}
+
+// The long form of a long branch is the opposite branch over a JMP, with a
+// Relax relocation at the start of it for the linker.
+fn decode_long_branch(block: &Block, offset: usize) -> Option<Item<'_>> {
+    let Some(Relocation::Relax(symbol, _, addend)) = block.relocations.get(&offset) else {
+        return None;
+    };
+    let jump = block.relocations.get(&(offset + 3))?;
+    match jump {
+        Relocation::Jump(target, _, jump_addend) if target == symbol && jump_addend == addend => {}
+        _ => return None,
+    }
+    if !plain_code(block, offset, 5, Some(offset + 3))
+        || block.bytes[offset + 1..offset + 3] != [3, JMP]
+    {
+        return None;
+    }
+    let opcode = block.bytes[offset] ^ INVERT_BRANCH;
+    LONG_BRANCHES
+        .iter()
+        .find(|(_, branch)| {
+            INSTRUCTION_MAP.get(&(*branch, AddressModeIndexer::RELATIVE)) == Some(&opcode)
+        })
+        .map(|(long, _)| Item::LongBranch(*long, jump))
+}
+
+fn decode_instruction(block: &Block, offset: usize) -> Option<Item<'_>> {
+    if let Some(item) = decode_long_branch(block, offset) {
+        return Some(item);
+    }
+    let &(code, mode) = OPCODE_MAP.get(&block.bytes[offset])?;
+    let relocation = block.relocations.get(&(offset + 1));
+    if relocation.is_some_and(|relocation| !expressible(mode, relocation)) {
+        return None;
+    }
+    plain_code(block, offset, 1 + operand_size(mode), Some(offset + 1))
+        .then_some(Item::Instruction(code, mode, relocation))
+}
+
+// What's at `offset` when it isn't an instruction, returning how many bytes
+// it took up. Plain bytes are run together up to a line's worth.
+fn decode_data<'a>(block: &'a Block, offset: usize, items: &mut Vec<(usize, Item<'a>)>) -> usize {
+    let fits = |size: usize| {
+        offset + size <= block.bytes.len()
+            && block
+                .labels
+                .range(offset + 1..offset + size)
+                .next()
+                .is_none()
+            && block
+                .relocations
+                .range(offset + 1..offset + size)
+                .next()
+                .is_none()
+    };
+    let item = match block.relocations.get(&offset) {
+        Some(relocation @ Relocation::Long(..)) if fits(2) => Item::Reference(relocation),
+        Some(
+            relocation @ (Relocation::Short(..)
+            | Relocation::Bank(_, _, 0)
+            | Relocation::Low(..)
+            | Relocation::High(..)),
+        ) => Item::Reference(relocation),
+        // Only the JMP of a long branch is any use without it
+        Some(Relocation::Relax(..)) | None => {
+            let kind = block.kinds[offset];
+            let word = fits(2) && block.kinds[offset + 1] == kind;
+            let (item, unit, limit) = match kind {
+                Kind::Word if word => (Item::Words(1), 2, WORDS_PER_LINE),
+                Kind::Pointer if word => (Item::Pointers(1), 2, WORDS_PER_LINE),
+                Kind::Text => (Item::Text(1), 1, TEXT_PER_LINE),
+                _ => (Item::Bytes(1), 1, BYTES_PER_LINE),
+            };
+            if let Some((start, last)) = items.last_mut() {
+                let follows = *start + last.size() == offset
+                    && !block.labels.contains_key(&offset)
+                    && !block.relocations.contains_key(&offset);
+                match (last, &item) {
+                    (Item::Bytes(count), Item::Bytes(_))
+                    | (Item::Words(count), Item::Words(_))
+                    | (Item::Pointers(count), Item::Pointers(_))
+                    | (Item::Text(count), Item::Text(_))
+                        if follows && *count < limit =>
+                    {
+                        *count += 1;
+                        return unit;
+                    }
+                    _ => {}
+                }
+            }
+            item
+        }
+        Some(relocation) => {
+            let size = match fits(relocation.size()) {
+                true => relocation.size(),
+                false => 1,
+            };
+            Item::Unexpressible(relocation, size)
+        }
+    };
+    let size = item.size();
+    items.push((offset, item));
+    size
+}
+
+fn decode(block: &Block) -> Vec<(usize, Item<'_>)> {
+    let mut items = Vec::new();
+    if block.reserved {
+        // Split up so the labels can go in between
+        let mut starts: Vec<usize> = block.labels.keys().copied().collect();
+        starts.push(0);
+        starts.push(block.bytes.len());
+        starts.sort();
+        starts.dedup();
+        for pair in starts.windows(2) {
+            if pair[0] < pair[1] && pair[1] <= block.bytes.len() {
+                items.push((pair[0], Item::Reserved(pair[1] - pair[0])));
+            }
+        }
+        return items;
+    }
+
+    let mut offset = 0;
+    while offset < block.bytes.len() {
+        match decode_instruction(block, offset) {
+            Some(item) => {
+                let size = item.size();
+                items.push((offset, item));
+                offset += size;
+            }
+            None => offset += decode_data(block, offset, &mut items),
+        }
+    }
+    items
+}
+
+// Whether the assembler can read a symbol's name back as it is, which it
+// can for scoped names like `main::loop` as long as every part of them
+// would do for a label.
+fn valid_name(name: &str) -> bool {
+    name.split("::").all(|part| {
+        let upper = part.to_uppercase();
+        part.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !INSTRUCTION_STR_MAP.contains_key(upper.as_str())
+            && !matches!(upper.as_str(), "A" | "X" | "Y")
+    })
+}
+
+// Symbol names that the assembler can read back. Names it can't read, like
+// the ones given to anonymous scopes, have anything it doesn't allow in a
+// label turned into `_`, and get a `_` on the end if they'd be taken for a
+// mnemonic or register.
+fn printable_name(name: &str) -> String {
+    if valid_name(name) {
+        return name.to_string();
+    }
+    let mut printed: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    if !printed.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        printed.insert(0, 'L');
+    }
+    let upper = printed.to_uppercase();
+    if INSTRUCTION_STR_MAP.contains_key(upper.as_str()) || matches!(upper.as_str(), "A" | "X" | "Y")
+    {
+        printed.push('_');
+    }
+    printed
+}
+
+// Gives every symbol a printable name that's different from all the others.
+fn unique_name(name: &str, taken: &mut HashSet<String>) -> String {
+    let printed = printable_name(name);
+    let mut unique = printed.clone();
+    let mut count = 1;
+    while !taken.insert(unique.clone()) {
+        unique = format!("{}_{}", printed, count);
+        count += 1;
+    }
+    unique
+}
+
+// Defines a symbol, opening up its scopes around it if it's scoped. `rest`
+// is what goes after the name, like `:` for a label.
+fn write_definition(out: &mut dyn Write, name: &str, rest: &str) -> io::Result<()> {
+    let parts: Vec<&str> = name.split("::").collect();
+    let (last, scopes) = parts.split_last().unwrap();
+    for scope in scopes {
+        writeln!(out, ".scope {}", scope)?;
+    }
+    writeln!(out, "{}{}", last, rest)?;
+    for _ in scopes {
+        writeln!(out, ".endscope")?;
+    }
+    Ok(())
+}
+
+// Everything the output needs to know about names: what each symbol is
+// printed as, and the label at each place something refers to.
+struct Names {
+    symbols: HashMap<String, String>,
+    // By block and offset
+    labels: HashMap<(usize, usize), String>,
+}
+
+impl Names {
+    fn symbol(&self, name: &str) -> &str {
+        &self.symbols[name]
+    }
+
+    // The label at whatever `address` is from code in `block`, if there is one
+    fn label(&self, disassembly: &Disassembly, block: &Block, address: u16) -> Option<&str> {
+        let at = locate(&disassembly.blocks, address, block.bank)?;
+        self.labels.get(&at).map(String::as_str)
+    }
+
+    fn reference(&self, name: &str, addend: i32) -> String {
+        match addend {
+            0 => self.symbol(name).to_string(),
+            addend if addend > 0 => format!("{}+{}", self.symbol(name), addend),
+            addend => format!("{}-{}", self.symbol(name), -addend),
+        }
+    }
+
+    fn relocation(&self, relocation: &Relocation) -> String {
+        let reference = self.reference(relocation.symbol(), relocation.addend());
+        // `>table+2` would be two more than the high byte
+        let byte_of = |operator: &str| match relocation.addend() {
+            0 => format!("{}{}", operator, reference),
+            _ => format!("{}({})", operator, reference),
+        };
+        match relocation {
+            Relocation::Bank(..) => format!("^{}", reference),
+            Relocation::Low(..) => byte_of("<"),
+            Relocation::High(..) => byte_of(">"),
+            _ => reference,
+        }
+    }
+}
+
+// The addresses an item refers to: an instruction's absolute address or a
+// branch that hasn't been relocated, or the addresses in a pointer table.
+fn targets(block: &Block, offset: usize, item: &Item) -> Vec<u16> {
+    let Some(start) = block.address else {
+        return Vec::new();
+    };
+    let address = start.wrapping_add(offset as u16);
+    let operand = &block.bytes[offset + 1..];
+    match item {
+        Item::Instruction(_, AddressModeIndexer::RELATIVE, None) => vec![address
+            .wrapping_add(2)
+            .wrapping_add(operand[0] as i8 as u16)],
+        Item::Instruction(
+            _,
+            AddressModeIndexer::ABSOLUTE
+            | AddressModeIndexer::ABS_X
+            | AddressModeIndexer::ABS_Y
+            | AddressModeIndexer::ABSOLUTE_INDIRECT,
+            None,
+        ) => vec![u16::from_le_bytes([operand[0], operand[1]])],
+        Item::Pointers(count) => block.bytes[offset..offset + 2 * count]
+            .chunks(2)
+            .map(|word| u16::from_le_bytes([word[0], word[1]]))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Which block `address` is in, and where in it, as seen from code in
+// `bank`. That can only see its own bank and whatever isn't banked.
+pub fn locate(
+    blocks: &[Block],
+    address: u16,
+    bank: Option<(Rom, usize)>,
+) -> Option<(usize, usize)> {
+    blocks
+        .iter()
+        .enumerate()
+        .filter(|(_, block)| block.bank == bank || block.bank.is_none())
+        .find_map(|(index, block)| {
+            let offset = (address as usize).checked_sub(block.address? as usize)?;
+            (offset < block.bytes.len()).then_some((index, offset))
+        })
+}
+
+// Names every symbol, then finds a label for each address an instruction
+// refers to. That's a symbol if there's one there, and `Lxxxx` if there
+// isn't, as long as it's at the start of something.
+fn name_everything(disassembly: &Disassembly, items: &[Vec<(usize, Item)>]) -> Names {
+    let mut taken = HashSet::new();
+    let mut symbols = HashMap::new();
+    let mut all: Vec<&str> = disassembly.constants.keys().map(String::as_str).collect();
+    for block in &disassembly.blocks {
+        all.extend(block.labels.values().flatten().map(String::as_str));
+        all.extend(block.relocations.values().map(Relocation::symbol));
+    }
+    // Names that can be kept go first, so nothing renamed takes them
+    all.sort_by_key(|name| !valid_name(name));
+    for name in all {
+        if !symbols.contains_key(name) {
+            let printed = unique_name(name, &mut taken);
+            symbols.insert(name.to_string(), printed);
+        }
+    }
+
+    let mut labels = HashMap::new();
+    for (index, block) in disassembly.blocks.iter().enumerate() {
+        for (offset, item) in &items[index] {
+            for address in targets(block, *offset, item) {
+                let Some((other, offset)) = locate(&disassembly.blocks, address, block.bank) else {
+                    continue;
+                };
+                let at_item = items[other]
+                    .binary_search_by_key(&offset, |(start, _)| *start)
+                    .is_ok();
+                let symbols_here = disassembly.blocks[other].labels.get(&offset);
+                if labels.contains_key(&(other, offset)) || !(at_item || symbols_here.is_some()) {
+                    continue;
+                }
+                let label = match symbols_here {
+                    Some(names) => symbols[&names[0]].clone(),
+                    None => unique_name(&format!("L{:04X}", address), &mut taken),
+                };
+                labels.insert((other, offset), label);
+            }
+        }
+    }
+    Names { symbols, labels }
+}
+
+fn operand_text(
+    names: &Names,
+    disassembly: &Disassembly,
+    index: usize,
+    offset: usize,
+    item: &Item,
+) -> String {
+    let Item::Instruction(_, mode, relocation) = item else {
+        unreachable!()
+    };
+    match mode {
+        AddressModeIndexer::IMPLIED => return String::new(),
+        AddressModeIndexer::ACCUMULATOR => return " A".to_string(),
+        _ => {}
+    }
+    let block = &disassembly.blocks[index];
+    let bytes = &block.bytes[offset + 1..offset + 1 + operand_size(*mode)];
+    let label = targets(block, offset, item)
+        .first()
+        .and_then(|address| names.label(disassembly, block, *address));
+    let value = match (relocation, label) {
+        (Some(relocation), _) => names.relocation(relocation),
+        (None, Some(label)) => label.to_string(),
+        (None, None) if bytes.len() == 2 => format!("${:02X}{:02X}", bytes[1], bytes[0]),
+        (None, None) => format!("${:02X}", bytes[0]),
+    };
+    match mode {
+        AddressModeIndexer::IMMEDIATE => format!(" #{}", value),
+        AddressModeIndexer::ABS_X | AddressModeIndexer::ZP_X => format!(" {},X", value),
+        AddressModeIndexer::ABS_Y | AddressModeIndexer::ZP_Y => format!(" {},Y", value),
+        AddressModeIndexer::INDEX_IND => format!(" ({},X)", value),
+        AddressModeIndexer::IND_INDEX => format!(" ({}),Y", value),
+        AddressModeIndexer::ABSOLUTE_INDIRECT => format!(" ({})", value),
+        _ => format!(" {}", value),
+    }
+}
+
+fn byte_list(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("${:02X}", byte)).collect();
+    bytes.join(", ")
+}
+
+// Runs of printable characters go in strings, and everything else is a
+// number. Quotes, backslashes and semicolons are left out of the strings to
+// save escaping them.
+fn text_list(bytes: &[u8]) -> String {
+    let printable = |byte: &u8| (0x20..0x7F).contains(byte) && !b"\"\\;".contains(byte);
+    let mut parts = Vec::new();
+    let mut rest = bytes;
+    while let Some(first) = rest.first() {
+        let length = match printable(first) {
+            true => rest.iter().take_while(|byte| printable(byte)).count(),
+            false => 1,
+        };
+        let (part, after) = rest.split_at(length);
+        parts.push(match printable(first) {
+            true => format!("\"{}\"", String::from_utf8_lossy(part)),
+            false => format!("${:02X}", first),
+        });
+        rest = after;
+    }
+    parts.join(", ")
+}
+
+// Writes out source that assembles back into the same bytes, and the same
+// relocations where the assembler has a way of writing them. Each block
+// starts with its section and `.org`, if it has them.
+pub fn write_disassembly(out: &mut impl Write, disassembly: &Disassembly) -> io::Result<()> {
+    let items: Vec<Vec<(usize, Item)>> = disassembly.blocks.iter().map(decode).collect();
+    let names = name_everything(disassembly, &items);
+
+    for (name, value) in &disassembly.constants {
+        match value {
+            0..=0xFF => write_definition(out, names.symbol(name), &format!(" = ${:02X}", value))?,
+            _ => write_definition(out, names.symbol(name), &format!(" = ${:04X}", value))?,
+        }
+    }
+    for (index, block) in disassembly.blocks.iter().enumerate() {
+        if index > 0 || !disassembly.constants.is_empty() {
+            writeln!(out)?;
+        }
+        if let Some(section) = &block.section {
+            match block.bank {
+                Some((rom, bank)) => {
+                    let rom = match rom {
+                        Rom::Prg => "prg",
+                        Rom::Chr => "chr",
+                    };
+                    writeln!(out, ".section \"{}\", {}, {}", section, rom, bank)?
+                }
+                None => writeln!(out, ".section \"{}\"", section)?,
+            }
+        }
+        if let Some(address) = block.address {
+            writeln!(out, ".org ${:04X}", address)?;
+        }
+
+        let write_labels = |out: &mut dyn Write, offset: usize| -> io::Result<()> {
+            let mut printed = Vec::new();
+            for label in block.labels.get(&offset).into_iter().flatten() {
+                printed.push(names.symbol(label).to_string());
+            }
+            if let Some(label) = names.labels.get(&(index, offset)) {
+                if !printed.contains(label) {
+                    printed.push(label.clone());
+                }
+            }
+            for label in printed {
+                write_definition(out, &label, ":")?;
+            }
+            Ok(())
+        };
+
+        for (offset, item) in &items[index] {
+            write_labels(out, *offset)?;
+            let bytes = &block.bytes[*offset..*offset + item.size()];
+            match item {
+                Item::Instruction(code, ..) => writeln!(
+                    out,
+                    "  {:?}{}",
+                    code,
+                    operand_text(&names, disassembly, index, *offset, item)
+                )?,
+                Item::LongBranch(code, relocation) => {
+                    writeln!(out, "  {:?} {}", code, names.relocation(relocation))?
+                }
+                Item::Bytes(_) => writeln!(out, "  .byte {}", byte_list(bytes))?,
+                Item::Text(_) => writeln!(out, "  .byte {}", text_list(bytes))?,
+                Item::Words(_) | Item::Pointers(_) => {
+                    let words: Vec<String> = bytes
+                        .chunks(2)
+                        .map(|word| {
+                            let address = u16::from_le_bytes([word[0], word[1]]);
+                            let label = match item {
+                                Item::Pointers(_) => names.label(disassembly, block, address),
+                                _ => None,
+                            };
+                            match label {
+                                Some(label) => label.to_string(),
+                                None => format!("${:04X}", address),
+                            }
+                        })
+                        .collect();
+                    writeln!(out, "  .word {}", words.join(", "))?
+                }
+                Item::Reference(relocation @ Relocation::Long(..)) => {
+                    writeln!(out, "  .word {}", names.relocation(relocation))?
+                }
+                Item::Reference(relocation) => {
+                    writeln!(out, "  .byte {}", names.relocation(relocation))?
+                }
+                Item::Unexpressible(relocation, _) => writeln!(
+                    out,
+                    "  .byte {} ; relocated by {}",
+                    byte_list(bytes),
+                    names.relocation(relocation)
+                )?,
+                Item::Reserved(size) => writeln!(out, "  .res {}", size)?,
+            }
+        }
+        write_labels(out, block.bytes.len())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::elf::reader::read_relocatable;
+    use crate::elf::relocatable::Relocatable;
+    use crate::elf::relocatable::Section;
+    use crate::elf::writer::write_relocatable;
+    use crate::lang::assembler::Assembler;
+    use crate::lang::ast::Program;
+    use crate::lang::instruction::INSTRUCTION_SET;
+    use crate::lang::source::SourceLine;
+    use crate::linker::link;
+    use crate::linker::read_input;
+    use crate::linker::test_util::input;
+    use crate::linker::Layout;
+    use crate::output::image::Image;
+
+    fn assemble(source: &str) -> Program {
+        let mut assembler = Assembler::new();
+        let program = assembler.assemble(&SourceLine::from_source("test.s", source));
+        assert!(
+            assembler.diagnostics().is_empty(),
+            "{:?}\n{}",
+            assembler.diagnostics(),
+            source
+        );
+        program.unwrap()
+    }
+
+    fn disassemble(disassembly: &Disassembly) -> String {
+        let mut source = Vec::new();
+        write_disassembly(&mut source, disassembly).unwrap();
+        String::from_utf8(source).unwrap()
+    }
+
+    // Assembled back at `origin`
+    fn reassembled_bytes(source: &str, origin: u16) -> Vec<u8> {
+        let image = Image::from_program(&assemble(source), origin).unwrap();
+        image
+            .segments
+            .into_iter()
+            .flat_map(|segment| segment.bytes)
+            .collect()
+    }
+
+    #[test]
+    fn test_every_opcode() {
+        let mut bytes = Vec::new();
+        for (_, mode, opcode) in INSTRUCTION_SET.iter() {
+            bytes.push(*opcode);
+            bytes.extend([0x12, 0x34].iter().take(operand_size(*mode)));
+        }
+        let source = disassemble(&Disassembly::from_binary(&bytes, 0x1000));
+        assert!(!source.contains(".byte"), "{}", source);
+        assert_eq!(reassembled_bytes(&source, 0x1000), bytes);
+    }
+
+    #[test]
+    fn test_binary() {
+        let bytes = [
+            0xA2, 0x00, // LDX #$00
+            0xBD, 0x0C, 0x10, // LDA $100C,X
+            0xF0, 0x04, // BEQ $100B
+            0xE8, // INX
+            0xD0, 0xF8, // BNE $1002
+            0x02, // not an instruction
+            0x60, // RTS
+            0x01, 0x02, 0x03, 0x20, // data that looks like a JSR
+            0x4C, 0x34, 0x12, // JMP $1234
+        ];
+        let mut disassembly = Disassembly::from_binary(&bytes, 0x1000);
+        disassembly.mark(&(0x100C..=0x100F), Kind::Data);
+        let source = disassemble(&disassembly);
+        assert_eq!(
+            source,
+            r#".org $1000
+  LDX #$00
+L1002:
+  LDA L100C,X
+  BEQ L100B
+  INX
+  BNE L1002
+  .byte $02
+L100B:
+  RTS
+L100C:
+  .byte $01, $02, $03, $20
+  JMP $1234
+"#
+        );
+        assert_eq!(reassembled_bytes(&source, 0), bytes);
+    }
+
+    #[test]
+    fn test_object_round_trip() {
+        let source = r#"
+pointer = $10
+start:
+  LDX #0
+loop:
+  LDA (pointer),Y
+  LDA (table),Y
+  LDA table+1,X
+  STA $0200,X
+  STA $20
+  INX
+  BNE loop
+  JEQ far
+  JNE loop
+  JSR external
+  LDA #^far
+  LDA #table-1
+  LDA #<table
+  LDX #>(external+2)
+  JMP (vector)
+  ASL A
+  RTS
+.section data
+table: .byte 1, 2, 3, $FF
+vector: .word start, external+2
+  .byte table, ^far, <external, >vector
+.section bss
+buffer: .res 4
+end:
+"#;
+        let program = assemble(source);
+        let mut elf = Vec::new();
+        write_relocatable(&mut elf, &program).unwrap();
+        let disassembly = Disassembly::from_elf(&ElfFile::parse(&elf).unwrap()).unwrap();
+        let output = disassemble(&disassembly);
+        assert!(output.contains("  JEQ far\n"), "{}", output);
+        assert!(output.contains("  LDA (table),Y\n"), "{}", output);
+        assert!(output.contains("  LDX #>(external+2)\n"), "{}", output);
+        assert!(output.contains("buffer:\n  .res 4\nend:\n"), "{}", output);
+
+        let object = read_relocatable(&elf).unwrap();
+        let mut again = Vec::new();
+        write_relocatable(&mut again, &assemble(&output)).unwrap();
+        let again = read_relocatable(&again).unwrap();
+        assert_eq!(again.get_raw_section(), object.get_raw_section());
+        assert_eq!(again.get_relocations(), object.get_relocations());
+        assert_eq!(again.get_sections(), object.get_sections());
+    }
+
+    #[test]
+    fn test_names_round_trip() {
+        let source = r#"
+.scope sc
+val = 5
+.endscope
+.proc _start
+loop:
+  LDA #sc::val
+  JSR _print
+  BNE loop
+.endproc
+.scope
+inner: RTS
+.endscope
+"#;
+        let mut elf = Vec::new();
+        write_relocatable(&mut elf, &assemble(source)).unwrap();
+        let disassembly = Disassembly::from_elf(&ElfFile::parse(&elf).unwrap()).unwrap();
+        let output = disassemble(&disassembly);
+        // Only the label from the anonymous scope can't be kept
+        assert!(
+            output.contains(".scope sc\nval = $05\n.endscope\n"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("_start:\n.scope _start\nloop:\n.endscope\n"),
+            "{}",
+            output
+        );
+        assert!(output.contains("  JSR _print\n"), "{}", output);
+        assert!(output.contains("L_1__inner:\n"), "{}", output);
+
+        // What's left still links against the things it uses and that use it
+        let mut again = Vec::new();
+        write_relocatable(&mut again, &assemble(&output)).unwrap();
+        let again = read_input("again.o", &again).unwrap();
+        let lib = input("lib.o", "_print:\n  LDA #sc::val\n  JMP _start::loop\n");
+        let linked = link(&[again, lib], &Layout::Origin(0x0800)).unwrap();
+        assert_eq!(linked.address_of("_start::loop"), Some(0x0800));
+        assert_eq!(
+            linked.sections[0].bytes[7..],
+            [0x60, 0xA9, 0x05, 0x4C, 0x00, 0x08]
+        );
+    }
+
+    #[test]
+    fn test_unexpressible_relocations() {
+        let object = Object {
+            raw_section: vec![0xA9, 0xFF, 0xA5, 0xFF, 0x60],
+            relocations: vec![
+                Relocation::Bank("table".to_string(), 1, 2),
+                Relocation::Short("table".to_string(), 3, 0),
+            ],
+            symbols: HashMap::from([("lda".to_string(), Symbol::Location(4))]),
+            sections: vec![Section {
+                name: "text".to_string(),
+                start: 0,
+                size: 5,
+                bank: None,
+            }],
+        };
+        let source = disassemble(&Disassembly::from_object(&object));
+        assert_eq!(
+            source,
+            r#".section "text"
+  .byte $A9
+  .byte $FF ; relocated by ^table+2
+  .byte $A5
+  .byte table
+lda_:
+  RTS
+"#
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use super::Kind;
+
+use crate::lang::number::parse_address;
+
+use std::ops::RangeInclusive;
+
+// What a line of a hint file says about the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hint {
+    Range(RangeInclusive<u16>, Kind),
+    // Somewhere to trace code from
+    Entry(u16),
+}
+
+// A hint file has an address or range and what's there on each line,
+//
+//     $C000-$C0FF byte
+//     $C100-$C11F word
+//     $C120-$C12F pointer     ; a jump table
+//     $C130-$C17F text
+//     $C200 code
+//
+// where `pointer` is a table of the addresses of code, and `code` is a
+// place to trace from. A single address is one byte, or one word.
+pub fn parse_hints(text: &str) -> Result<Vec<Hint>, String> {
+    let mut hints = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: String| Err(format!("line {}: {}", index + 1, message));
+        let line = line.split(';').next().unwrap_or("");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let [range, kind] = words.as_slice() else {
+            if words.is_empty() {
+                continue;
+            }
+            return error("expected an address or range and what's there".to_string());
+        };
+        let kind = match kind.to_lowercase().as_str() {
+            "byte" => Some(Kind::Data),
+            "word" => Some(Kind::Word),
+            "pointer" => Some(Kind::Pointer),
+            "text" => Some(Kind::Text),
+            "code" => None,
+            _ => {
+                return error(format!(
+                    "'{}' isn't one of byte, word, pointer, text or code",
+                    kind
+                ))
+            }
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse_address(start), Some(parse_address(end))),
+            None => (parse_address(range), None),
+        };
+        let (Some(start), Some(end)) = (start, end.unwrap_or(start)) else {
+            return error(format!("'{}' isn't an address or a range of them", range));
+        };
+        let hint = match (kind, end) {
+            (None, _) if range.contains('-') => {
+                return error("code is a single address to trace from".to_string())
+            }
+            (None, _) => Hint::Entry(start),
+            (Some(kind @ (Kind::Word | Kind::Pointer)), _) if !range.contains('-') => {
+                Hint::Range(start..=start.saturating_add(1), kind)
+            }
+            (Some(_), end) if end < start => {
+                return error(format!("range '{}' ends before it starts", range))
+            }
+            (Some(kind), end) => Hint::Range(start..=end, kind),
+        };
+        hints.push(hint);
+    }
+    Ok(hints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hints() {
+        let hints = parse_hints(
+            r#"
+; Tables
+$C000-$C0FF byte
+$C100-$C11F WORD
+49440 pointer   ; $C120
+$C130-$C17F text
+$C200 code
+"#,
+        );
+        assert_eq!(
+            hints,
+            Ok(vec![
+                Hint::Range(0xC000..=0xC0FF, Kind::Data),
+                Hint::Range(0xC100..=0xC11F, Kind::Word),
+                Hint::Range(0xC120..=0xC121, Kind::Pointer),
+                Hint::Range(0xC130..=0xC17F, Kind::Text),
+                Hint::Entry(0xC200),
+            ])
+        );
+
+        let error = |text: &str| parse_hints(text).unwrap_err();
+        assert_eq!(
+            error("$C000 byte\n$C000\n"),
+            "line 2: expected an address or range and what's there"
+        );
+        assert_eq!(
+            error("$C000 bytes"),
+            "line 1: 'bytes' isn't one of byte, word, pointer, text or code"
+        );
+        assert_eq!(
+            error("$C000-$1000 text"),
+            "line 1: range '$C000-$1000' ends before it starts"
+        );
+        assert_eq!(
+            error("$10000 text"),
+            "line 1: '$10000' isn't an address or a range of them"
+        );
+        assert_eq!(
+            error("$C000-$C010 code"),
+            "line 1: code is a single address to trace from"
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use super::locate;
+use super::operand_size;
+use super::Disassembly;
+use super::Kind;
+
+use crate::lang::instruction::AddressModeIndexer;
+use crate::lang::instruction::InstructionCode;
+use crate::lang::instruction::OPCODE_MAP;
+
+// Where the CPU finds the addresses of its handlers
+const VECTORS: [(u16, &str); 3] = [(0xFFFA, "nmi"), (0xFFFC, "reset"), (0xFFFE, "irq")];
+
+// The words in each run of pointers, as (block, offset) of the word.
+fn pointers(disassembly: &Disassembly) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    for (index, block) in disassembly.blocks.iter().enumerate() {
+        let mut offset = 0;
+        while offset + 1 < block.bytes.len() {
+            if block.kinds[offset] == Kind::Pointer && block.kinds[offset + 1] == Kind::Pointer {
+                words.push((index, offset));
+                offset += 2;
+            } else {
+                offset += 1;
+            }
+        }
+    }
+    words
+}
+
+// Follows the code from everywhere the CPU can get into it: the NMI, reset
+// and IRQ vectors of any block that has them, the pointer tables and
+// `entries`. If there's none of those, the start of the first block is
+// taken to be code. Everything that's reached is code, and anything else
+// that wasn't hinted as something else is data. The vectors become a
+// pointer table, and their handlers are labelled after them if they don't
+// have a label already.
+//
+// Only blocks with an address can be traced, the others are left as
+// they are.
+pub fn trace(disassembly: &mut Disassembly, entries: &[u16]) {
+    let blocks = &mut disassembly.blocks;
+    let mut starts = Vec::new();
+    for index in 0..blocks.len() {
+        let block = &mut blocks[index];
+        let Some(vectors) = block
+            .address
+            .and_then(|address| (VECTORS[0].0 as usize).checked_sub(address as usize))
+            .filter(|vectors| vectors + 6 <= block.bytes.len())
+        else {
+            continue;
+        };
+        if block.kinds[vectors..vectors + 6]
+            .iter()
+            .any(|kind| *kind != Kind::Code)
+        {
+            continue;
+        }
+        block.kinds[vectors..vectors + 6].fill(Kind::Pointer);
+        let bank = block.bank;
+        let handlers: Vec<u16> = block.bytes[vectors..vectors + 6]
+            .chunks(2)
+            .map(|word| u16::from_le_bytes([word[0], word[1]]))
+            .collect();
+        for (handler, (_, name)) in handlers.into_iter().zip(VECTORS) {
+            if let Some((other, offset)) = locate(blocks, handler, bank) {
+                if !blocks[other].labels.contains_key(&offset) {
+                    blocks[other].add_label(offset, name);
+                }
+            }
+        }
+    }
+    for (index, offset) in pointers(disassembly) {
+        let block = &disassembly.blocks[index];
+        let address = u16::from_le_bytes([block.bytes[offset], block.bytes[offset + 1]]);
+        starts.extend(locate(&disassembly.blocks, address, block.bank));
+    }
+    for entry in entries {
+        // Whichever bank it's in
+        let found = disassembly
+            .blocks
+            .iter()
+            .enumerate()
+            .find_map(|(index, block)| {
+                let offset = (*entry as usize).checked_sub(block.address? as usize)?;
+                (offset < block.bytes.len()).then_some((index, offset))
+            });
+        starts.extend(found);
+    }
+    if starts.is_empty() {
+        starts.extend(
+            disassembly
+                .blocks
+                .iter()
+                .position(|block| block.address.is_some())
+                .map(|index| (index, 0)),
+        );
+    }
+
+    let blocks = &disassembly.blocks;
+    let mut reached: Vec<Vec<bool>> = blocks
+        .iter()
+        .map(|block| vec![false; block.bytes.len()])
+        .collect();
+    while let Some((index, mut offset)) = starts.pop() {
+        let block = &blocks[index];
+        let address = block.address.unwrap();
+        while offset < block.bytes.len() && !reached[index][offset] {
+            let Some(&(code, mode)) = OPCODE_MAP.get(&block.bytes[offset]) else {
+                break;
+            };
+            let end = offset + 1 + operand_size(mode);
+            if end > block.bytes.len()
+                || block.kinds[offset..end]
+                    .iter()
+                    .any(|kind| *kind != Kind::Code)
+            {
+                break;
+            }
+            reached[index][offset..end].fill(true);
+
+            let operand = &block.bytes[offset + 1..end];
+            let here = address.wrapping_add(offset as u16);
+            let target = match (code, mode) {
+                (_, AddressModeIndexer::RELATIVE) => {
+                    Some(here.wrapping_add(2).wrapping_add(operand[0] as i8 as u16))
+                }
+                (InstructionCode::JMP | InstructionCode::JSR, AddressModeIndexer::ABSOLUTE) => {
+                    Some(u16::from_le_bytes([operand[0], operand[1]]))
+                }
+                _ => None,
+            };
+            starts.extend(target.and_then(|target| locate(blocks, target, block.bank)));
+            if matches!(
+                code,
+                InstructionCode::JMP
+                    | InstructionCode::RTS
+                    | InstructionCode::RTI
+                    | InstructionCode::BRK
+            ) {
+                break;
+            }
+            offset = end;
+        }
+    }
+
+    for (index, block) in disassembly.blocks.iter_mut().enumerate() {
+        if block.address.is_none() {
+            continue;
+        }
+        for (kind, reached) in block.kinds.iter_mut().zip(&reached[index]) {
+            if *kind == Kind::Code && !reached {
+                *kind = Kind::Data;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::disasm::write_disassembly;
+    use crate::lang::assembler::Assembler;
+    use crate::lang::source::SourceLine;
+    use crate::output::image::Image;
+
+    fn disassemble(disassembly: &Disassembly) -> String {
+        let mut source = Vec::new();
+        write_disassembly(&mut source, disassembly).unwrap();
+        String::from_utf8(source).unwrap()
+    }
+
+    fn reassembled_bytes(source: &str) -> Vec<u8> {
+        let program = Assembler::new()
+            .assemble(&SourceLine::from_source("test.s", source))
+            .unwrap();
+        Image::from_program(&program, 0)
+            .unwrap()
+            .segments
+            .into_iter()
+            .flat_map(|segment| segment.bytes)
+            .collect()
+    }
+
+    #[test]
+    fn test_trace_from_vectors() {
+        let bytes = [
+            0xA2, 0x00, // LDX #$00
+            0x20, 0xF8, 0xFF, // JSR $FFF8
+            0x4C, 0xF5, 0xFF, // JMP $FFF5
+            0x60, // RTS
+            0xA9, // never reached
+            0xF8, 0xFF, 0xF0, 0xFF, 0xF8, 0xFF, // NMI, reset and IRQ
+        ];
+        let mut disassembly = Disassembly::from_binary(&bytes, 0xFFF0);
+        trace(&mut disassembly, &[]);
+        let source = disassemble(&disassembly);
+        assert_eq!(
+            source,
+            r#".org $FFF0
+reset:
+  LDX #$00
+  JSR nmi
+LFFF5:
+  JMP LFFF5
+nmi:
+  RTS
+  .byte $A9
+  .word nmi, reset, nmi
+"#
+        );
+        assert_eq!(reassembled_bytes(&source), bytes);
+    }
+
+    #[test]
+    fn test_trace_with_hints() {
+        let bytes = [
+            0xA9, 0x00, // LDA #$00
+            0x6C, 0x08, 0x10, // JMP ($1008)
+            0x60, // RTS, only reached from the table
+            0x48, 0x69, // "Hi"
+            0x05, 0x10, // pointer to the RTS
+            0x00, 0x01, // a word
+        ];
+        let mut disassembly = Disassembly::from_binary(&bytes, 0x1000);
+        disassembly.mark(&(0x1006..=0x1007), Kind::Text);
+        disassembly.mark(&(0x1008..=0x1009), Kind::Pointer);
+        disassembly.mark(&(0x100A..=0x100B), Kind::Word);
+        trace(&mut disassembly, &[0x1000]);
+        let source = disassemble(&disassembly);
+        assert_eq!(
+            source,
+            r#".org $1000
+  LDA #$00
+  JMP (L1008)
+L1005:
+  RTS
+  .byte "Hi"
+L1008:
+  .word L1005
+  .word $0100
+"#
+        );
+        assert_eq!(reassembled_bytes(&source), bytes);
+
+        // Without anything to go on it starts at the start
+        let mut disassembly = Disassembly::from_binary(&bytes, 0x1000);
+        trace(&mut disassembly, &[]);
+        assert_eq!(
+            &disassembly.blocks[0].kinds[..7],
+            [[Kind::Code; 5].as_slice(), &[Kind::Data; 2]].concat()
+        );
+    }
+}
@@ -1,2 +1,4 @@
+pub mod dump;
+pub mod reader;
 pub mod relocatable;
 pub mod writer;
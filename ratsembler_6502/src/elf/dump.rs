@@ -0,0 +1,221 @@
+use super::reader::ElfFile;
+use super::writer::*;
+
+use std::io::{self, Write};
+
+fn type_name(e_type: u16) -> &'static str {
+    match e_type {
+        ET_REL => "REL (Relocatable file)",
+        ET_EXEC => "EXEC (Executable file)",
+        _ => "unknown",
+    }
+}
+
+fn section_type_name(sh_type: u32) -> &'static str {
+    match sh_type {
+        SHT_NULL => "NULL",
+        SHT_PROGBITS => "PROGBITS",
+        SHT_SYMTAB => "SYMTAB",
+        SHT_STRTAB => "STRTAB",
+        SHT_RELA => "RELA",
+        SHT_NOBITS => "NOBITS",
+        _ => "unknown",
+    }
+}
+
+fn section_flags(sh_flags: u32) -> String {
+    [
+        (SHF_WRITE, 'W'),
+        (SHF_ALLOC, 'A'),
+        (SHF_EXECINSTR, 'X'),
+        (SHF_6502_PRG, 'P'),
+        (SHF_6502_CHR, 'C'),
+    ]
+    .iter()
+    .filter(|(flag, _)| sh_flags & flag != 0)
+    .map(|(_, letter)| *letter)
+    .collect()
+}
+
+fn symbol_type_name(kind: u8) -> &'static str {
+    match kind {
+        STT_NOTYPE => "NOTYPE",
+        STT_FUNC => "FUNC",
+        STT_SECTION => "SECTION",
+        _ => "unknown",
+    }
+}
+
+fn symbol_bind_name(bind: u8) -> &'static str {
+    match bind {
+        STB_LOCAL => "LOCAL",
+        STB_GLOBAL => "GLOBAL",
+        _ => "unknown",
+    }
+}
+
+fn section_index_name(st_shndx: u16) -> String {
+    match st_shndx {
+        SHN_UNDEF => "UND".to_string(),
+        SHN_ABS => "ABS".to_string(),
+        index => index.to_string(),
+    }
+}
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+// Prints the headers, sections, symbols and relocations of an object, along
+// the lines of `readelf -h -S -s -r` but knowing our relocation types.
+pub fn write_dump(out: &mut impl Write, file: &ElfFile) -> io::Result<()> {
+    writeln!(out, "ELF header:")?;
+    writeln!(out, "  Type:     {}", type_name(file.header.e_type))?;
+    let machine = match file.machine {
+        EM_6502 => "6502".to_string(),
+        other => format!("unknown (0x{:X})", other),
+    };
+    writeln!(out, "  Machine:  {}", machine)?;
+    writeln!(out, "  Entry:    0x{:04X}", file.header.e_entry)?;
+    writeln!(out, "  Sections: {}", file.sections.len())?;
+
+    writeln!(out)?;
+    writeln!(out, "Section headers:")?;
+    writeln!(
+        out,
+        "  [Nr] Name               Type      Offset   Size     Flg  Lk Inf"
+    )?;
+    for (index, section) in file.sections.iter().enumerate() {
+        let header = &section.header;
+        writeln!(
+            out,
+            "  [{:2}] {:<18} {:<9} {:08X} {:08X} {:<4} {:2} {:3}",
+            index,
+            section.name,
+            section_type_name(header.sh_type),
+            header.sh_offset,
+            header.sh_size,
+            section_flags(header.sh_flags),
+            header.sh_link,
+            header.sh_info,
+        )?;
+    }
+    writeln!(
+        out,
+        "Key to flags: W (write), A (alloc), X (execute), P (PRG bank), C (CHR bank)"
+    )?;
+
+    if !file.program_headers.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "Program headers:")?;
+        writeln!(
+            out,
+            "  Type   Offset   VirtAddr PhysAddr FileSiz  MemSiz   Flg"
+        )?;
+        for header in &file.program_headers {
+            let kind = match header.p_type {
+                PT_LOAD => "LOAD",
+                _ => "?",
+            };
+            let flags: String = [(PF_R, 'R'), (PF_W, 'W'), (PF_X, 'E')]
+                .iter()
+                .map(|(flag, letter)| match header.p_flags & flag {
+                    0 => ' ',
+                    _ => *letter,
+                })
+                .collect();
+            writeln!(
+                out,
+                "  {:<6} {:08X} {:08X} {:08X} {:08X} {:08X} {}",
+                kind,
+                header.p_offset,
+                header.p_vaddr,
+                header.p_paddr,
+                header.p_filesz,
+                header.p_memsz,
+                flags,
+            )?;
+        }
+    }
+
+    let symbols = file.symbols().map_err(invalid)?;
+    writeln!(out)?;
+    writeln!(out, "Symbol table contains {} entries:", symbols.len())?;
+    writeln!(out, "   Num: Value  Size Type    Bind   Ndx Name")?;
+    for (index, symbol) in symbols.iter().enumerate() {
+        writeln!(
+            out,
+            "  {:4}: {:04X} {:5} {:<7} {:<6} {:>3} {}",
+            index,
+            symbol.symbol.st_value,
+            symbol.symbol.st_size,
+            symbol_type_name(symbol.kind()),
+            symbol_bind_name(symbol.bind()),
+            section_index_name(symbol.symbol.st_shndx),
+            symbol.name,
+        )?;
+    }
+
+    for section in &file.sections {
+        if section.header.sh_type != SHT_RELA {
+            continue;
+        }
+        let relocations = file.relocations(section).map_err(invalid)?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "Relocation section '{}' contains {} entries:",
+            section.name,
+            relocations.len()
+        )?;
+        writeln!(out, "  Offset Type               Symbol + Addend")?;
+        for relocation in relocations {
+            let name = match relocation_name(relocation.r_type()) {
+                Some(name) => name.to_string(),
+                None => format!("unknown ({})", relocation.r_type()),
+            };
+            let symbol = symbols
+                .get(relocation.symbol() as usize)
+                .map_or("?", |symbol| symbol.name.as_str());
+            writeln!(
+                out,
+                "  {:04X}   {:<18} {} {} {}",
+                relocation.r_offset,
+                name,
+                symbol,
+                if relocation.r_addend < 0 { '-' } else { '+' },
+                relocation.r_addend.unsigned_abs(),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lang::assembler::Assembler;
+    use crate::lang::source::SourceLine;
+
+    #[test]
+    fn test_dump() {
+        let source = "main:\n  JSR print\n  BNE main-2\n";
+        let program = Assembler::new()
+            .assemble(&SourceLine::from_source("test.s", source))
+            .unwrap();
+        let mut object = Vec::new();
+        write_relocatable(&mut object, &program).unwrap();
+        let mut dump = Vec::new();
+        write_dump(&mut dump, &ElfFile::parse(&object).unwrap()).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+
+        assert!(dump.contains("  Machine:  6502\n"));
+        assert!(dump.contains("  [ 1] .text              PROGBITS  00000034 00000005 AX "));
+        assert!(dump.contains("     2: 0000     0 NOTYPE  GLOBAL   1 main\n"));
+        assert!(dump.contains("     3: 0000     0 NOTYPE  GLOBAL UND print\n"));
+        assert!(dump.contains("  0001   R_6502_JUMP16      print + 0\n"));
+        assert!(dump.contains("  0004   R_6502_PCREL8      main - 2\n"));
+    }
+}
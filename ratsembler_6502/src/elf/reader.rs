@@ -0,0 +1,450 @@
+use super::relocatable::Object;
+use super::relocatable::Relocation;
+use super::relocatable::Rom;
+use super::relocatable::Section;
+use super::relocatable::Symbol;
+use super::writer::*;
+
+use crate::output::image::Image;
+use crate::output::image::Segment;
+
+use std::collections::HashMap;
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+// The string starting at `offset` in a string table.
+fn string_at(table: &[u8], offset: u32) -> Result<String, String> {
+    let rest = table
+        .get(offset as usize..)
+        .ok_or_else(|| format!("string table has no offset {}", offset))?;
+    let length = rest
+        .iter()
+        .position(|byte| *byte == 0)
+        .ok_or("string table isn't terminated")?;
+    Ok(String::from_utf8_lossy(&rest[..length]).into_owned())
+}
+
+pub struct ElfSection {
+    pub name: String,
+    pub header: Elf32Shdr,
+}
+
+pub struct ElfSymbol {
+    pub name: String,
+    pub symbol: Elf32Sym,
+}
+
+impl ElfSymbol {
+    pub fn bind(&self) -> u8 {
+        self.symbol.st_info >> 4
+    }
+
+    pub fn kind(&self) -> u8 {
+        self.symbol.st_info & 0xF
+    }
+}
+
+// An ELF file split up into its headers, without making any sense of what's
+// in the sections yet.
+pub struct ElfFile<'a> {
+    bytes: &'a [u8],
+    pub header: Elf32Ehdr,
+    pub machine: u16,
+    pub program_headers: Vec<Elf32Phdr>,
+    pub sections: Vec<ElfSection>,
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<ElfFile<'a>, String> {
+        if bytes.len() < ELF32_EHDR_SIZE || !bytes.starts_with(b"\x7FELF") {
+            return Err("not an ELF file".to_string());
+        }
+        if bytes[4] != 1 || bytes[5] != 1 {
+            return Err("only 32 bit little endian ELF files are supported".to_string());
+        }
+        let header = Elf32Ehdr {
+            e_type: u16_at(bytes, 16),
+            e_entry: u32_at(bytes, 24),
+            e_phoff: u32_at(bytes, 28),
+            e_shoff: u32_at(bytes, 32),
+            e_phentsize: u16_at(bytes, 42),
+            e_phnum: u16_at(bytes, 44),
+            e_shnum: u16_at(bytes, 48),
+            e_shstrndx: u16_at(bytes, 50),
+        };
+        let machine = u16_at(bytes, 18);
+
+        let table = header.e_shoff as usize;
+        let headers: Vec<Elf32Shdr> = (0..header.e_shnum as usize)
+            .map(|index| {
+                let offset = table + index * ELF32_SHDR_SIZE;
+                let entry = bytes.get(offset..offset + ELF32_SHDR_SIZE).ok_or_else(|| {
+                    format!("section header {} is past the end of the file", index)
+                })?;
+                let field = |number: usize| u32_at(entry, number * 4);
+                Ok(Elf32Shdr {
+                    sh_name: field(0),
+                    sh_type: field(1),
+                    sh_flags: field(2),
+                    sh_addr: field(3),
+                    sh_offset: field(4),
+                    sh_size: field(5),
+                    sh_link: field(6),
+                    sh_info: field(7),
+                    sh_addralign: field(8),
+                    sh_entsize: field(9),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        let program_headers = (0..header.e_phnum as usize)
+            .map(|index| {
+                let offset = header.e_phoff as usize + index * ELF32_PHDR_SIZE;
+                let entry = bytes.get(offset..offset + ELF32_PHDR_SIZE).ok_or_else(|| {
+                    format!("program header {} is past the end of the file", index)
+                })?;
+                let field = |number: usize| u32_at(entry, number * 4);
+                Ok(Elf32Phdr {
+                    p_type: field(0),
+                    p_offset: field(1),
+                    p_vaddr: field(2),
+                    p_paddr: field(3),
+                    p_filesz: field(4),
+                    p_memsz: field(5),
+                    p_flags: field(6),
+                    p_align: field(7),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        let mut file = ElfFile {
+            bytes,
+            header,
+            machine,
+            program_headers,
+            sections: Vec::new(),
+        };
+        let names = match headers.get(file.header.e_shstrndx as usize) {
+            Some(shstrtab) => file.contents(shstrtab)?,
+            None => &[],
+        };
+        for header in headers {
+            let name = string_at(names, header.sh_name)?;
+            file.sections.push(ElfSection { name, header });
+        }
+        Ok(file)
+    }
+
+    // What's in a section. NOBITS sections take up no room in the file.
+    pub fn contents(&self, header: &Elf32Shdr) -> Result<&'a [u8], String> {
+        if header.sh_type == SHT_NOBITS {
+            return Ok(&[]);
+        }
+        let start = header.sh_offset as usize;
+        self.bytes
+            .get(start..start + header.sh_size as usize)
+            .ok_or_else(|| "section runs past the end of the file".to_string())
+    }
+
+    fn section(&self, index: u32) -> Result<&ElfSection, String> {
+        self.sections
+            .get(index as usize)
+            .ok_or_else(|| format!("there's no section {}", index))
+    }
+
+    // The symbol table, with names looked up in the string table it links to.
+    pub fn symbols(&self) -> Result<Vec<ElfSymbol>, String> {
+        let Some(symtab) = self
+            .sections
+            .iter()
+            .find(|section| section.header.sh_type == SHT_SYMTAB)
+        else {
+            return Ok(Vec::new());
+        };
+        let strtab = self.contents(&self.section(symtab.header.sh_link)?.header)?;
+        self.contents(&symtab.header)?
+            .chunks_exact(ELF32_SYM_SIZE)
+            .map(|entry| {
+                let symbol = Elf32Sym {
+                    st_name: u32_at(entry, 0),
+                    st_value: u32_at(entry, 4),
+                    st_size: u32_at(entry, 8),
+                    st_info: entry[12],
+                    st_shndx: u16_at(entry, 14),
+                };
+                Ok(ElfSymbol {
+                    name: string_at(strtab, symbol.st_name)?,
+                    symbol,
+                })
+            })
+            .collect()
+    }
+
+    pub fn relocations(&self, rela: &ElfSection) -> Result<Vec<Elf32Rela>, String> {
+        Ok(self
+            .contents(&rela.header)?
+            .chunks_exact(ELF32_RELA_SIZE)
+            .map(|entry| Elf32Rela {
+                r_offset: u32_at(entry, 0),
+                r_info: u32_at(entry, 4),
+                r_addend: u32_at(entry, 8) as i32,
+            })
+            .collect())
+    }
+
+    // Puts the file back together into the shape the assembler produces,
+    // with the allocated sections one after another in the raw section.
+    pub fn to_object(&self) -> Result<Object, String> {
+        if self.machine != EM_6502 || self.header.e_type != ET_REL {
+            return Err("not a 6502 relocatable object".to_string());
+        }
+
+        let mut object = Object::default();
+        // Where each ELF section starts in the raw section
+        let mut starts = HashMap::new();
+        for (index, section) in self.sections.iter().enumerate() {
+            let header = &section.header;
+            if header.sh_flags & SHF_ALLOC == 0
+                || !matches!(header.sh_type, SHT_PROGBITS | SHT_NOBITS)
+            {
+                continue;
+            }
+            let start = object.raw_section.len();
+            match header.sh_type {
+                SHT_NOBITS => object
+                    .raw_section
+                    .resize(start + header.sh_size as usize, 0),
+                _ => object.raw_section.extend(self.contents(header)?),
+            }
+            let bank = match header.sh_flags & (SHF_6502_PRG | SHF_6502_CHR) {
+                SHF_6502_PRG => Some((Rom::Prg, header.sh_info as usize)),
+                SHF_6502_CHR => Some((Rom::Chr, header.sh_info as usize)),
+                0 => None,
+                _ => return Err(format!("section '{}' is in two ROMs", section.name)),
+            };
+            object.sections.push(Section {
+                name: section.name.trim_start_matches('.').to_string(),
+                start,
+                size: header.sh_size as usize,
+                bank,
+            });
+            starts.insert(index, start);
+        }
+        let start_of = |index: u16, name: &str| {
+            starts
+                .get(&(index as usize))
+                .copied()
+                .ok_or_else(|| format!("'{}' is in section {}, which isn't loaded", name, index))
+        };
+
+        let symbols = self.symbols()?;
+        for symbol in &symbols {
+            let ElfSymbol { name, symbol: sym } = symbol;
+            if name.is_empty() || sym.st_shndx == SHN_UNDEF || symbol.kind() == STT_SECTION {
+                continue;
+            }
+            let defined = match (sym.st_shndx, symbol.kind()) {
+                (SHN_ABS, _) if sym.st_size == 1 => Symbol::ShortValue(sym.st_value as u8),
+                (SHN_ABS, _) => Symbol::LongValue(sym.st_value as u16),
+                (index, STT_FUNC) => Symbol::Procedure(
+                    start_of(index, name)? + sym.st_value as usize,
+                    sym.st_size as usize,
+                ),
+                (index, _) => Symbol::Location(start_of(index, name)? + sym.st_value as usize),
+            };
+            object.symbols.insert(name.clone(), defined);
+        }
+
+        for rela in &self.sections {
+            if rela.header.sh_type != SHT_RELA {
+                continue;
+            }
+            let start = start_of(rela.header.sh_info as u16, &rela.name)?;
+            for entry in self.relocations(rela)? {
+                let symbol = symbols
+                    .get(entry.symbol() as usize)
+                    .ok_or_else(|| format!("relocation refers to symbol {}", entry.symbol()))?;
+                if symbol.name.is_empty() {
+                    return Err(format!(
+                        "relocation at offset {} in '{}' isn't against a named symbol",
+                        entry.r_offset, rela.name
+                    ));
+                }
+                let name = symbol.name.clone();
+                let offset = start as u32 + entry.r_offset;
+                let addend = entry.r_addend;
+                object.relocations.push(match entry.r_type() {
+                    R_6502_8 => Relocation::Short(name, offset, addend),
+                    R_6502_16 => Relocation::Long(name, offset, addend),
+                    R_6502_PCREL8 => Relocation::Relative(name, offset, addend),
+                    R_6502_INDIRECT16 => Relocation::Absolute(name, offset, addend),
+                    R_6502_LO8 => Relocation::Low(name, offset, addend),
+                    R_6502_HI8 => Relocation::High(name, offset, addend),
+                    R_6502_BANK8 => Relocation::Bank(name, offset, addend),
+                    R_6502_RELAX => Relocation::Relax(name, offset, addend),
+                    R_6502_JUMP16 => Relocation::Jump(name, offset, addend),
+                    other => return Err(format!("unknown relocation type {}", other)),
+                });
+            }
+        }
+
+        Ok(object)
+    }
+
+    // The loadable segments of an executable at the addresses they're
+    // loaded at, for running it. That's `p_paddr`, which is where data that
+    // gets copied somewhere else to run is stored, the same as in a flat
+    // image. The section starting at the same place says which bank it's in.
+    pub fn to_image(&self) -> Result<Image, String> {
+        if self.machine != EM_6502 || self.header.e_type != ET_EXEC {
+            return Err("not a 6502 executable".to_string());
+        }
+        let mut image = Image::default();
+        for segment in &self.program_headers {
+            if segment.p_type != PT_LOAD || segment.p_filesz == 0 {
+                continue;
+            }
+            let start = segment.p_offset as usize;
+            let bytes = self
+                .bytes
+                .get(start..start + segment.p_filesz as usize)
+                .ok_or("segment runs past the end of the file")?;
+            let flags = self
+                .sections
+                .iter()
+                .find(|section| {
+                    section.header.sh_flags & SHF_ALLOC != 0
+                        && section.header.sh_offset == segment.p_offset
+                })
+                .map_or((0, 0), |section| {
+                    (section.header.sh_flags, section.header.sh_info)
+                });
+            let bank = match flags.0 & (SHF_6502_PRG | SHF_6502_CHR) {
+                SHF_6502_PRG => Some((Rom::Prg, flags.1 as usize)),
+                SHF_6502_CHR => Some((Rom::Chr, flags.1 as usize)),
+                _ => None,
+            };
+            image.segments.push(Segment {
+                address: segment.p_paddr as u16,
+                bytes: bytes.to_vec(),
+                bank,
+            });
+        }
+        image.entry = match self.header.e_entry {
+            0 => None,
+            entry => Some(entry as u16),
+        };
+        Ok(image)
+    }
+}
+
+pub fn read_relocatable(bytes: &[u8]) -> Result<Object, String> {
+    ElfFile::parse(bytes)?.to_object()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::elf::relocatable::Relocatable;
+    use crate::lang::assembler::Assembler;
+    use crate::lang::source::SourceLine;
+    use crate::o65::reader::read_o65;
+    use crate::o65::writer::write_o65;
+
+    fn round_trip(object: &dyn Relocatable) -> Object {
+        let mut file = Vec::new();
+        write_relocatable(&mut file, object).unwrap();
+        read_relocatable(&file).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = r#"
+.proc main
+loop:
+  JSR print
+  LDA table+1,X
+  BNE loop
+  JMP (vector)
+.endproc
+.scope
+hidden: RTS
+.endscope
+.section data
+table: .byte 1, 2, hidden
+vector: .word main
+.section chr, chr, 3
+.byte 0
+.section empty
+"#;
+        let program = Assembler::new()
+            .assemble(&SourceLine::from_source("test.s", source))
+            .unwrap();
+        let object = round_trip(&program);
+
+        assert_eq!(object.raw_section, program.get_raw_section());
+        assert_eq!(object.relocations, program.get_relocations());
+        assert_eq!(object.symbols, program.get_symbols());
+        assert_eq!(object.sections, program.get_sections());
+        assert_eq!(object.sections[2].bank, Some((Rom::Chr, 3)));
+    }
+
+    #[test]
+    fn test_values_and_byte_relocations() {
+        let object = Object {
+            raw_section: vec![0xA9, 0x00, 0xA2, 0x00],
+            relocations: vec![
+                Relocation::Low("handler".to_string(), 1, 0),
+                Relocation::High("handler".to_string(), 3, -2),
+            ],
+            symbols: HashMap::from([
+                ("width".to_string(), Symbol::ShortValue(40)),
+                ("screen".to_string(), Symbol::LongValue(0x0400)),
+            ]),
+            sections: vec![Section {
+                name: "text".to_string(),
+                start: 0,
+                size: 4,
+                bank: None,
+            }],
+        };
+        assert_eq!(round_trip(&object), object);
+
+        // Anything read in from o65 can go back out as ELF.
+        let mut o65 = Vec::new();
+        write_o65(&mut o65, &object).unwrap();
+        let object = read_o65(&o65).unwrap();
+        assert_eq!(round_trip(&object), object);
+    }
+
+    #[test]
+    fn test_bad_objects() {
+        assert_eq!(
+            read_relocatable(b"not an object").unwrap_err(),
+            "not an ELF file"
+        );
+        let mut file = Vec::new();
+        write_relocatable(&mut file, &Object::default()).unwrap();
+        file[18] = 0x3E;
+        assert_eq!(
+            read_relocatable(&file).unwrap_err(),
+            "not a 6502 relocatable object"
+        );
+        file.truncate(60);
+        assert!(read_relocatable(&file)
+            .unwrap_err()
+            .contains("past the end"));
+    }
+}
@@ -1,23 +1,240 @@
 use std::collections::HashMap;
 use std::vec::Vec;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Symbol {
     Location(usize),
+    // The start of a `.proc` and its size in bytes
+    Procedure(usize, usize),
     ShortValue(u8),
     LongValue(u16),
 }
 
-#[derive(Debug)]
+// Which of a cartridge's ROMs a banked section goes in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rom {
+    Prg,
+    Chr,
+}
+
+// A named run of the raw section, from `start` up to `start + size`.
+// Sections are laid out one after another, and symbols and relocations are
+// all offsets into the raw section as a whole.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub start: usize,
+    pub size: usize,
+    pub bank: Option<(Rom, usize)>,
+}
+
+impl Section {
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+}
+
+// The section something at `offset` is in. An offset right at the end of a
+// section, like a label after its last byte, is taken to be in it as long
+// as there isn't another section starting there.
+pub fn section_index(sections: &[Section], offset: usize) -> Option<usize> {
+    sections
+        .iter()
+        .position(|section| (section.start..section.end()).contains(&offset))
+        .or_else(|| sections.iter().rposition(|section| section.end() == offset))
+}
+
+// Function sections are named after their section and the routine in
+// them, like `text.main`, and get merged back into `text` when linking.
+pub fn base_section_name(name: &str) -> &str {
+    name.split('.').next().unwrap_or(name)
+}
+
+// Each relocation is the symbol it refers to, the offset into the section
+// that needs patching, and a constant to add to the symbol's value. Short
+// values have to fit in a byte, where Low and High take the low and high
+// byte of a word. Bank is the number of the bank the symbol ends up in.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Relocation {
-    Absolute(String, u16),
-    Relative(String, u16),
-    Short(String, u16),
-    Long(String, u16),
+    Absolute(String, u32, i32),
+    Relative(String, u32, i32),
+    Short(String, u32, i32),
+    Long(String, u32, i32),
+    Low(String, u32, i32),
+    High(String, u32, i32),
+    Bank(String, u32, i32),
+    // The operand of an absolute JSR or JMP, which is patched like Long. The
+    // linker needs to know which words are jumps to catch ones into another
+    // bank.
+    Jump(String, u32, i32),
+    // Doesn't patch anything, it marks the start of a long branch to the
+    // symbol, a branch over a JMP, so the linker can turn it back into a
+    // plain branch if the symbol turns out to be close enough.
+    Relax(String, u32, i32),
+}
+
+impl Relocation {
+    pub fn symbol(&self) -> &str {
+        match self {
+            Relocation::Absolute(symbol, _, _)
+            | Relocation::Relative(symbol, _, _)
+            | Relocation::Short(symbol, _, _)
+            | Relocation::Long(symbol, _, _)
+            | Relocation::Low(symbol, _, _)
+            | Relocation::High(symbol, _, _)
+            | Relocation::Bank(symbol, _, _)
+            | Relocation::Jump(symbol, _, _)
+            | Relocation::Relax(symbol, _, _) => symbol,
+        }
+    }
+
+    pub fn offset(&self) -> u32 {
+        match self {
+            Relocation::Absolute(_, offset, _)
+            | Relocation::Relative(_, offset, _)
+            | Relocation::Short(_, offset, _)
+            | Relocation::Long(_, offset, _)
+            | Relocation::Low(_, offset, _)
+            | Relocation::High(_, offset, _)
+            | Relocation::Bank(_, offset, _)
+            | Relocation::Jump(_, offset, _)
+            | Relocation::Relax(_, offset, _) => *offset,
+        }
+    }
+
+    pub fn addend(&self) -> i32 {
+        match self {
+            Relocation::Absolute(_, _, addend)
+            | Relocation::Relative(_, _, addend)
+            | Relocation::Short(_, _, addend)
+            | Relocation::Long(_, _, addend)
+            | Relocation::Low(_, _, addend)
+            | Relocation::High(_, _, addend)
+            | Relocation::Bank(_, _, addend)
+            | Relocation::Jump(_, _, addend)
+            | Relocation::Relax(_, _, addend) => *addend,
+        }
+    }
+
+    // The same relocation at a different offset, for when the bytes it
+    // patches get moved.
+    pub fn moved_to(&self, offset: u32) -> Relocation {
+        let symbol = self.symbol().to_string();
+        let addend = self.addend();
+        match self {
+            Relocation::Absolute(..) => Relocation::Absolute(symbol, offset, addend),
+            Relocation::Relative(..) => Relocation::Relative(symbol, offset, addend),
+            Relocation::Short(..) => Relocation::Short(symbol, offset, addend),
+            Relocation::Long(..) => Relocation::Long(symbol, offset, addend),
+            Relocation::Low(..) => Relocation::Low(symbol, offset, addend),
+            Relocation::High(..) => Relocation::High(symbol, offset, addend),
+            Relocation::Bank(..) => Relocation::Bank(symbol, offset, addend),
+            Relocation::Jump(..) => Relocation::Jump(symbol, offset, addend),
+            Relocation::Relax(..) => Relocation::Relax(symbol, offset, addend),
+        }
+    }
+}
+
+impl Relocation {
+    // How many bytes get patched.
+    pub fn size(&self) -> usize {
+        match self {
+            Relocation::Relative(..)
+            | Relocation::Short(..)
+            | Relocation::Low(..)
+            | Relocation::High(..)
+            | Relocation::Bank(..) => 1,
+            Relocation::Absolute(..) | Relocation::Long(..) | Relocation::Jump(..) => 2,
+            Relocation::Relax(..) => 0,
+        }
+    }
+
+    // The bytes to patch in, given the final address of the symbol and the
+    // address the patched bytes end up at. For Bank the value is the
+    // symbol's bank rather than its address.
+    pub fn resolve(&self, value: i64, place: i64) -> Result<Vec<u8>, String> {
+        let target = value + self.addend() as i64;
+        match self {
+            Relocation::Short(symbol, _, _) if !(-0x80..=0xFF).contains(&target) => Err(format!(
+                "value ${:X} of '{}' does not fit in a byte",
+                target, symbol
+            )),
+            Relocation::Short(..) => Ok(vec![target as u8]),
+            Relocation::Absolute(symbol, _, _)
+            | Relocation::Long(symbol, _, _)
+            | Relocation::Jump(symbol, _, _)
+                if !(0..=0xFFFF).contains(&target) =>
+            {
+                Err(format!(
+                    "value ${:X} of '{}' does not fit in a word",
+                    target, symbol
+                ))
+            }
+            Relocation::Absolute(..) | Relocation::Long(..) | Relocation::Jump(..) => {
+                Ok((target as u16).to_le_bytes().to_vec())
+            }
+            Relocation::Low(..) => Ok(vec![target as u8]),
+            Relocation::High(..) => Ok(vec![(target >> 8) as u8]),
+            Relocation::Bank(symbol, _, _) if !(0..=0xFF).contains(&target) => Err(format!(
+                "bank {} of '{}' does not fit in a byte",
+                target, symbol
+            )),
+            Relocation::Bank(..) => Ok(vec![target as u8]),
+            Relocation::Relax(..) => Ok(Vec::new()),
+            // Branches are relative to the end of the instruction, which is
+            // just after the patched byte.
+            Relocation::Relative(symbol, _, _) => {
+                let offset = target - (place + 1);
+                if (-0x80..=0x7F).contains(&offset) {
+                    Ok(vec![offset as u8])
+                } else {
+                    Err(format!(
+                        "branch to '{}' is out of range ({} bytes)",
+                        symbol, offset
+                    ))
+                }
+            }
+        }
+    }
+}
+
+// Symbols declared in anonymous scopes have an `@` in their name, and aren't
+// visible outside the object they're defined in.
+pub fn is_local_symbol(name: &str) -> bool {
+    name.contains('@')
 }
 
 pub trait Relocatable {
     fn get_raw_section(&self) -> Vec<u8>;
     fn get_relocations(&self) -> Vec<Relocation>;
     fn get_symbols(&self) -> HashMap<String, Symbol>;
+    fn get_sections(&self) -> Vec<Section>;
+}
+
+// An object read back in from a file, in the same shape the assembler
+// produces.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub raw_section: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+    pub symbols: HashMap<String, Symbol>,
+    pub sections: Vec<Section>,
+}
+
+impl Relocatable for Object {
+    fn get_raw_section(&self) -> Vec<u8> {
+        self.raw_section.clone()
+    }
+
+    fn get_relocations(&self) -> Vec<Relocation> {
+        self.relocations.clone()
+    }
+
+    fn get_symbols(&self) -> HashMap<String, Symbol> {
+        self.symbols.clone()
+    }
+
+    fn get_sections(&self) -> Vec<Section> {
+        self.sections.clone()
+    }
 }
@@ -1,30 +1,127 @@
-use std::fs::File;
+use super::relocatable::base_section_name;
+use super::relocatable::is_local_symbol;
+use super::relocatable::section_index;
+use super::relocatable::Relocatable;
+use super::relocatable::Relocation;
+use super::relocatable::Rom;
+use super::relocatable::Symbol;
+use crate::linker::Linked;
+
 use std::io::{self, Write};
-use std::mem;
-
-const EI_NIDENT: usize = 16;
-const EM_6502: u16 = 0x6502; // Hypothetical value for 6502 architecture
-
-#[repr(C)]
-struct Elf32Ehdr {
-    e_ident: [u8; EI_NIDENT],
-    e_type: u16,
-    e_machine: u16,
-    e_version: u32,
-    e_entry: u32,
-    e_phoff: u32,
-    e_shoff: u32,
-    e_flags: u32,
-    e_ehsize: u16,
-    e_phentsize: u16,
-    e_phnum: u16,
-    e_shentsize: u16,
-    e_shnum: u16,
-    e_shstrndx: u16,
+
+pub const EI_NIDENT: usize = 16;
+pub const EM_6502: u16 = 0x6502; // Hypothetical value for 6502 architecture
+
+pub const ET_REL: u16 = 1;
+pub const ET_EXEC: u16 = 2;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+pub const SHT_NULL: u32 = 0;
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_RELA: u32 = 4;
+pub const SHT_NOBITS: u32 = 8;
+
+pub const SHF_WRITE: u32 = 1;
+pub const SHF_ALLOC: u32 = 2;
+pub const SHF_EXECINSTR: u32 = 4;
+// Processor specific flags for banked sections, which keep the bank number
+// in sh_info.
+pub const SHF_6502_PRG: u32 = 0x1000_0000;
+pub const SHF_6502_CHR: u32 = 0x2000_0000;
+
+pub const SHN_UNDEF: u16 = 0;
+pub const SHN_ABS: u16 = 0xFFF1;
+
+pub const STB_LOCAL: u8 = 0;
+pub const STB_GLOBAL: u8 = 1;
+
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+
+// There's no standard set of relocation types for the 6502, so these are
+// our own. One for each kind of `Relocation`.
+pub const R_6502_NONE: u8 = 0;
+pub const R_6502_8: u8 = 1; // Relocation::Short
+pub const R_6502_16: u8 = 2; // Relocation::Long
+pub const R_6502_PCREL8: u8 = 3; // Relocation::Relative
+pub const R_6502_INDIRECT16: u8 = 4; // Relocation::Absolute
+pub const R_6502_LO8: u8 = 5; // Relocation::Low
+pub const R_6502_HI8: u8 = 6; // Relocation::High
+pub const R_6502_BANK8: u8 = 7; // Relocation::Bank
+pub const R_6502_RELAX: u8 = 8; // Relocation::Relax
+pub const R_6502_JUMP16: u8 = 9; // Relocation::Jump
+
+pub fn relocation_name(r_type: u8) -> Option<&'static str> {
+    match r_type {
+        R_6502_NONE => Some("R_6502_NONE"),
+        R_6502_8 => Some("R_6502_8"),
+        R_6502_16 => Some("R_6502_16"),
+        R_6502_PCREL8 => Some("R_6502_PCREL8"),
+        R_6502_INDIRECT16 => Some("R_6502_INDIRECT16"),
+        R_6502_LO8 => Some("R_6502_LO8"),
+        R_6502_HI8 => Some("R_6502_HI8"),
+        R_6502_BANK8 => Some("R_6502_BANK8"),
+        R_6502_RELAX => Some("R_6502_RELAX"),
+        R_6502_JUMP16 => Some("R_6502_JUMP16"),
+        _ => None,
+    }
+}
+
+pub const ELF32_EHDR_SIZE: usize = 52;
+pub const ELF32_SHDR_SIZE: usize = 40;
+pub const ELF32_SYM_SIZE: usize = 16;
+pub const ELF32_RELA_SIZE: usize = 12;
+pub const ELF32_PHDR_SIZE: usize = 32;
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn align(out: &mut Vec<u8>, alignment: usize) {
+    while !out.len().is_multiple_of(alignment) {
+        out.push(0);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf32Ehdr {
+    pub e_type: u16,
+    pub e_entry: u32,
+    pub e_phoff: u32,
+    pub e_shoff: u32,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
 }
 
 impl Elf32Ehdr {
-    fn new() -> Self {
+    pub fn new(e_type: u16) -> Self {
+        Elf32Ehdr {
+            e_type,
+            e_entry: 0,
+            e_phoff: 0,
+            e_shoff: 0,
+            e_phentsize: 0,
+            e_phnum: 0,
+            e_shnum: 0,
+            e_shstrndx: 0,
+        }
+    }
+
+    pub fn write_to(&self, out: &mut Vec<u8>) {
         let mut e_ident = [0u8; EI_NIDENT];
         e_ident[0] = 0x7F;
         e_ident[1] = b'E';
@@ -33,34 +130,657 @@ impl Elf32Ehdr {
         e_ident[4] = 1; // ELFCLASS32
         e_ident[5] = 1; // ELFDATA2LSB
         e_ident[6] = 1; // EV_CURRENT
+        out.extend_from_slice(&e_ident);
+        push_u16(out, self.e_type);
+        push_u16(out, EM_6502);
+        push_u32(out, 1); // EV_CURRENT
+        push_u32(out, self.e_entry);
+        push_u32(out, self.e_phoff);
+        push_u32(out, self.e_shoff);
+        push_u32(out, 0); // e_flags
+        push_u16(out, ELF32_EHDR_SIZE as u16);
+        push_u16(out, self.e_phentsize);
+        push_u16(out, self.e_phnum);
+        push_u16(out, ELF32_SHDR_SIZE as u16);
+        push_u16(out, self.e_shnum);
+        push_u16(out, self.e_shstrndx);
+    }
+}
 
-        Elf32Ehdr {
-            e_ident,
-            e_type: 2, // ET_EXEC
-            e_machine: EM_6502,
-            e_version: 1, // EV_CURRENT
-            e_entry: 0x08048000,
-            e_phoff: mem::size_of::<Elf32Ehdr>() as u32,
-            e_shoff: 0,
-            e_flags: 0,
-            e_ehsize: mem::size_of::<Elf32Ehdr>() as u16,
-            e_phentsize: 0,
-            e_phnum: 0,
-            e_shentsize: 0,
-            e_shnum: 0,
-            e_shstrndx: 0,
+#[derive(Debug, Clone, Default)]
+pub struct Elf32Shdr {
+    pub sh_name: u32,
+    pub sh_type: u32,
+    pub sh_flags: u32,
+    pub sh_addr: u32,
+    pub sh_offset: u32,
+    pub sh_size: u32,
+    pub sh_link: u32,
+    pub sh_info: u32,
+    pub sh_addralign: u32,
+    pub sh_entsize: u32,
+}
+
+impl Elf32Shdr {
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        for field in [
+            self.sh_name,
+            self.sh_type,
+            self.sh_flags,
+            self.sh_addr,
+            self.sh_offset,
+            self.sh_size,
+            self.sh_link,
+            self.sh_info,
+            self.sh_addralign,
+            self.sh_entsize,
+        ] {
+            push_u32(out, field);
         }
     }
 }
 
-pub fn write_elf_header(file: &mut File) -> io::Result<()> {
-    let header = Elf32Ehdr::new();
-    let header_bytes = unsafe {
-        std::slice::from_raw_parts(
-            &header as *const Elf32Ehdr as *const u8,
-            mem::size_of::<Elf32Ehdr>(),
-        )
+#[derive(Debug, Clone, Default)]
+pub struct Elf32Phdr {
+    pub p_type: u32,
+    pub p_offset: u32,
+    pub p_vaddr: u32,
+    pub p_paddr: u32,
+    pub p_filesz: u32,
+    pub p_memsz: u32,
+    pub p_flags: u32,
+    pub p_align: u32,
+}
+
+impl Elf32Phdr {
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        for field in [
+            self.p_type,
+            self.p_offset,
+            self.p_vaddr,
+            self.p_paddr,
+            self.p_filesz,
+            self.p_memsz,
+            self.p_flags,
+            self.p_align,
+        ] {
+            push_u32(out, field);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf32Sym {
+    pub st_name: u32,
+    pub st_value: u32,
+    pub st_size: u32,
+    pub st_info: u8,
+    pub st_shndx: u16,
+}
+
+impl Elf32Sym {
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        push_u32(out, self.st_name);
+        push_u32(out, self.st_value);
+        push_u32(out, self.st_size);
+        out.push(self.st_info);
+        out.push(0); // st_other
+        push_u16(out, self.st_shndx);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf32Rela {
+    pub r_offset: u32,
+    pub r_info: u32,
+    pub r_addend: i32,
+}
+
+impl Elf32Rela {
+    pub fn new(r_offset: u32, symbol: u32, r_type: u8, r_addend: i32) -> Self {
+        Elf32Rela {
+            r_offset,
+            r_info: (symbol << 8) | r_type as u32,
+            r_addend,
+        }
+    }
+
+    pub fn symbol(&self) -> u32 {
+        self.r_info >> 8
+    }
+
+    pub fn r_type(&self) -> u8 {
+        self.r_info as u8
+    }
+
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        push_u32(out, self.r_offset);
+        push_u32(out, self.r_info);
+        push_u32(out, self.r_addend as u32);
+    }
+}
+
+// Builds up a string table. Offset 0 is always the empty string.
+pub struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        StringTable { bytes: vec![0] }
+    }
+}
+
+impl StringTable {
+    pub fn add(&mut self, string: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(string.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+pub fn relocation_type(relocation: &Relocation) -> u8 {
+    match relocation {
+        Relocation::Absolute(..) => R_6502_INDIRECT16,
+        Relocation::Relative(..) => R_6502_PCREL8,
+        Relocation::Short(..) => R_6502_8,
+        Relocation::Long(..) => R_6502_16,
+        Relocation::Low(..) => R_6502_LO8,
+        Relocation::High(..) => R_6502_HI8,
+        Relocation::Bank(..) => R_6502_BANK8,
+        Relocation::Relax(..) => R_6502_RELAX,
+        Relocation::Jump(..) => R_6502_JUMP16,
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// Function sections like `text.main` get the flags of the section they're
+// split off from.
+pub fn section_flags(name: &str, bank: Option<(Rom, usize)>) -> u32 {
+    let flags = match base_section_name(name) {
+        "text" | "code" => SHF_ALLOC | SHF_EXECINSTR,
+        "data" | "bss" | "zp" | "zeropage" => SHF_ALLOC | SHF_WRITE,
+        _ => SHF_ALLOC,
+    };
+    match bank {
+        Some((Rom::Prg, _)) => flags | SHF_6502_PRG,
+        Some((Rom::Chr, _)) => flags | SHF_6502_CHR,
+        None => flags,
+    }
+}
+
+// Writes an ELF relocatable object with each section of the program in an
+// ELF section of its own, followed by their relocations and the symbols.
+// Symbols that are referenced but not defined are written as undefined
+// globals for the linker to find.
+pub fn write_relocatable(out: &mut impl Write, object: &dyn Relocatable) -> io::Result<()> {
+    let raw_section = object.get_raw_section();
+    let sections = object.get_sections();
+    let relocations = object.get_relocations();
+    let symbols = object.get_symbols();
+
+    // Everything in the raw section is written relative to the start of the
+    // section it's in, and each section's symbol is just after the null one.
+    let locate = |offset: usize, what: &str| {
+        section_index(&sections, offset)
+            .map(|index| (index + 1, (offset - sections[index].start) as u32))
+            .ok_or_else(|| invalid(format!("{} at offset {} isn't in a section", what, offset)))
+    };
+    let mut relocated: Vec<Vec<&Relocation>> = vec![Vec::new(); sections.len()];
+    for relocation in &relocations {
+        let offset = relocation.offset() as usize;
+        let index = sections
+            .iter()
+            .position(|section| (section.start..section.end()).contains(&offset))
+            .ok_or_else(|| {
+                invalid(format!(
+                    "relocation for '{}' at offset {} isn't in a section",
+                    relocation.symbol(),
+                    offset
+                ))
+            })?;
+        relocated[index].push(relocation);
+    }
+    let rela_count = relocated.iter().filter(|list| !list.is_empty()).count();
+    let symtab_index = 1 + sections.len() + rela_count;
+    let strtab_index = symtab_index + 1;
+    let shstrtab_index = strtab_index + 1;
+
+    // Locals have to come before globals in the symbol table.
+    let mut defined: Vec<_> = symbols.iter().collect();
+    defined.sort_by(|(a, _), (b, _)| {
+        (!is_local_symbol(a), a.as_str()).cmp(&(!is_local_symbol(b), b.as_str()))
+    });
+    let mut undefined: Vec<&str> = relocations
+        .iter()
+        .map(|relocation| relocation.symbol())
+        .filter(|name| !symbols.contains_key(*name))
+        .collect();
+    undefined.sort();
+    undefined.dedup();
+
+    let mut strtab = StringTable::default();
+    let mut symtab = Vec::new();
+    let mut names = Vec::new();
+    Elf32Sym {
+        st_name: 0,
+        st_value: 0,
+        st_size: 0,
+        st_info: 0,
+        st_shndx: SHN_UNDEF,
+    }
+    .write_to(&mut symtab);
+    for index in 0..sections.len() {
+        Elf32Sym {
+            st_name: 0,
+            st_value: 0,
+            st_size: 0,
+            st_info: (STB_LOCAL << 4) | STT_SECTION,
+            st_shndx: index as u16 + 1,
+        }
+        .write_to(&mut symtab);
+    }
+    let first_symbol = 1 + sections.len();
+    let first_global = first_symbol
+        + defined
+            .iter()
+            .filter(|(name, _)| is_local_symbol(name))
+            .count();
+    for (name, symbol) in defined {
+        let bind = if is_local_symbol(name) {
+            STB_LOCAL
+        } else {
+            STB_GLOBAL
+        };
+        // Absolute values keep their width in the symbol's size.
+        let (st_value, st_size, kind, st_shndx) = match symbol {
+            Symbol::Location(location) => {
+                let (index, value) = locate(*location, name)?;
+                (value, 0, STT_NOTYPE, index as u16)
+            }
+            Symbol::Procedure(location, size) => {
+                let (index, value) = locate(*location, name)?;
+                (value, *size as u32, STT_FUNC, index as u16)
+            }
+            Symbol::ShortValue(value) => (*value as u32, 1, STT_NOTYPE, SHN_ABS),
+            Symbol::LongValue(value) => (*value as u32, 2, STT_NOTYPE, SHN_ABS),
+        };
+        Elf32Sym {
+            st_name: strtab.add(name),
+            st_value,
+            st_size,
+            st_info: (bind << 4) | kind,
+            st_shndx,
+        }
+        .write_to(&mut symtab);
+        names.push(name.as_str());
+    }
+    for name in &undefined {
+        Elf32Sym {
+            st_name: strtab.add(name),
+            st_value: 0,
+            st_size: 0,
+            st_info: (STB_GLOBAL << 4) | STT_NOTYPE,
+            st_shndx: SHN_UNDEF,
+        }
+        .write_to(&mut symtab);
+        names.push(name);
+    }
+
+    let mut shstrtab = StringTable::default();
+    let mut headers = vec![Elf32Shdr::default()];
+    let mut file = Vec::new();
+    Elf32Ehdr::new(ET_REL).write_to(&mut file);
+
+    let mut add_section = |file: &mut Vec<u8>, header: Elf32Shdr, contents: &[u8]| {
+        align(file, header.sh_addralign.max(1) as usize);
+        headers.push(Elf32Shdr {
+            sh_offset: file.len() as u32,
+            sh_size: contents.len() as u32,
+            ..header
+        });
+        file.extend_from_slice(contents);
+    };
+    for section in &sections {
+        add_section(
+            &mut file,
+            Elf32Shdr {
+                sh_name: shstrtab.add(&format!(".{}", section.name)),
+                sh_type: SHT_PROGBITS,
+                sh_flags: section_flags(&section.name, section.bank),
+                sh_info: section.bank.map_or(0, |(_, bank)| bank as u32),
+                sh_addralign: 1,
+                ..Default::default()
+            },
+            &raw_section[section.start..section.end()],
+        );
+    }
+    for (index, relocations) in relocated.iter().enumerate() {
+        if relocations.is_empty() {
+            continue;
+        }
+        let section = &sections[index];
+        let mut rela = Vec::new();
+        for relocation in relocations {
+            let symbol = first_symbol
+                + names
+                    .iter()
+                    .position(|name| *name == relocation.symbol())
+                    .unwrap();
+            Elf32Rela::new(
+                relocation.offset() - section.start as u32,
+                symbol as u32,
+                relocation_type(relocation),
+                relocation.addend(),
+            )
+            .write_to(&mut rela);
+        }
+        add_section(
+            &mut file,
+            Elf32Shdr {
+                sh_name: shstrtab.add(&format!(".rela.{}", section.name)),
+                sh_type: SHT_RELA,
+                sh_link: symtab_index as u32,
+                sh_info: index as u32 + 1,
+                sh_addralign: 4,
+                sh_entsize: ELF32_RELA_SIZE as u32,
+                ..Default::default()
+            },
+            &rela,
+        );
+    }
+    add_section(
+        &mut file,
+        Elf32Shdr {
+            sh_name: shstrtab.add(".symtab"),
+            sh_type: SHT_SYMTAB,
+            sh_link: strtab_index as u32,
+            sh_info: first_global as u32,
+            sh_addralign: 4,
+            sh_entsize: ELF32_SYM_SIZE as u32,
+            ..Default::default()
+        },
+        &symtab,
+    );
+    add_section(
+        &mut file,
+        Elf32Shdr {
+            sh_name: shstrtab.add(".strtab"),
+            sh_type: SHT_STRTAB,
+            sh_addralign: 1,
+            ..Default::default()
+        },
+        strtab.as_bytes(),
+    );
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    add_section(
+        &mut file,
+        Elf32Shdr {
+            sh_name: shstrtab_name,
+            sh_type: SHT_STRTAB,
+            sh_addralign: 1,
+            ..Default::default()
+        },
+        shstrtab.as_bytes(),
+    );
+    align(&mut file, 4);
+    let mut header = Elf32Ehdr::new(ET_REL);
+    header.e_shoff = file.len() as u32;
+    header.e_shnum = headers.len() as u16;
+    header.e_shstrndx = shstrtab_index as u16;
+    for section in &headers {
+        section.write_to(&mut file);
+    }
+    let mut header_bytes = Vec::new();
+    header.write_to(&mut header_bytes);
+    file[..ELF32_EHDR_SIZE].copy_from_slice(&header_bytes);
+
+    out.write_all(&file)
+}
+
+// Writes a linked program as an ELF executable, with a loadable segment
+// for each output section and the global symbols at their final addresses.
+pub fn write_executable(
+    out: &mut impl Write,
+    linked: &Linked,
+    entry: Option<u16>,
+) -> io::Result<()> {
+    let loaded: Vec<_> = linked
+        .sections
+        .iter()
+        .filter(|section| !section.bytes.is_empty())
+        .collect();
+    let symtab_index = 1 + linked.sections.len();
+    let strtab_index = symtab_index + 1;
+    let shstrtab_index = strtab_index + 1;
+
+    let mut file = Vec::new();
+    Elf32Ehdr::new(ET_EXEC).write_to(&mut file);
+    let phoff = file.len();
+    file.resize(phoff + loaded.len() * ELF32_PHDR_SIZE, 0);
+
+    let mut shstrtab = StringTable::default();
+    let mut headers = vec![Elf32Shdr::default()];
+    let mut program_headers = Vec::new();
+    for section in &linked.sections {
+        let flags = section_flags(&section.name, section.bank);
+        headers.push(Elf32Shdr {
+            sh_name: shstrtab.add(&format!(".{}", section.name)),
+            sh_type: SHT_PROGBITS,
+            sh_flags: flags,
+            sh_addr: section.address as u32,
+            sh_offset: file.len() as u32,
+            sh_size: section.bytes.len() as u32,
+            sh_info: section.bank.map_or(0, |(_, bank)| bank as u32),
+            sh_addralign: 1,
+            ..Default::default()
+        });
+        if !section.bytes.is_empty() {
+            let mut p_flags = PF_R;
+            if flags & SHF_WRITE != 0 {
+                p_flags |= PF_W;
+            }
+            if flags & SHF_EXECINSTR != 0 {
+                p_flags |= PF_X;
+            }
+            Elf32Phdr {
+                p_type: PT_LOAD,
+                p_offset: file.len() as u32,
+                p_vaddr: section.address as u32,
+                p_paddr: section.load_address as u32,
+                p_filesz: section.bytes.len() as u32,
+                p_memsz: section.bytes.len() as u32,
+                p_flags,
+                p_align: 1,
+            }
+            .write_to(&mut program_headers);
+        }
+        file.extend_from_slice(&section.bytes);
+    }
+    file[phoff..phoff + program_headers.len()].copy_from_slice(&program_headers);
+
+    let mut strtab = StringTable::default();
+    let mut symtab = Vec::new();
+    Elf32Sym {
+        st_name: 0,
+        st_value: 0,
+        st_size: 0,
+        st_info: 0,
+        st_shndx: SHN_UNDEF,
+    }
+    .write_to(&mut symtab);
+    for (name, symbol) in &linked.symbols {
+        let kind = match symbol.size {
+            0 => STT_NOTYPE,
+            _ => STT_FUNC,
+        };
+        Elf32Sym {
+            st_name: strtab.add(name),
+            st_value: symbol.value as u32,
+            st_size: symbol.size as u32,
+            st_info: (STB_GLOBAL << 4) | kind,
+            st_shndx: symbol.section.map_or(SHN_ABS, |section| section as u16 + 1),
+        }
+        .write_to(&mut symtab);
+    }
+
+    let mut add_section = |file: &mut Vec<u8>, header: Elf32Shdr, contents: &[u8]| {
+        align(file, header.sh_addralign.max(1) as usize);
+        headers.push(Elf32Shdr {
+            sh_offset: file.len() as u32,
+            sh_size: contents.len() as u32,
+            ..header
+        });
+        file.extend_from_slice(contents);
     };
-    file.write_all(header_bytes)?;
-    Ok(())
+    add_section(
+        &mut file,
+        Elf32Shdr {
+            sh_name: shstrtab.add(".symtab"),
+            sh_type: SHT_SYMTAB,
+            sh_link: strtab_index as u32,
+            sh_info: 1,
+            sh_addralign: 4,
+            sh_entsize: ELF32_SYM_SIZE as u32,
+            ..Default::default()
+        },
+        &symtab,
+    );
+    add_section(
+        &mut file,
+        Elf32Shdr {
+            sh_name: shstrtab.add(".strtab"),
+            sh_type: SHT_STRTAB,
+            sh_addralign: 1,
+            ..Default::default()
+        },
+        strtab.as_bytes(),
+    );
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    add_section(
+        &mut file,
+        Elf32Shdr {
+            sh_name: shstrtab_name,
+            sh_type: SHT_STRTAB,
+            sh_addralign: 1,
+            ..Default::default()
+        },
+        shstrtab.as_bytes(),
+    );
+    align(&mut file, 4);
+    let mut header = Elf32Ehdr::new(ET_EXEC);
+    header.e_entry = entry.unwrap_or(0) as u32;
+    header.e_phoff = phoff as u32;
+    header.e_phentsize = ELF32_PHDR_SIZE as u16;
+    header.e_phnum = loaded.len() as u16;
+    header.e_shoff = file.len() as u32;
+    header.e_shnum = headers.len() as u16;
+    header.e_shstrndx = shstrtab_index as u16;
+    for section in &headers {
+        section.write_to(&mut file);
+    }
+    let mut header_bytes = Vec::new();
+    header.write_to(&mut header_bytes);
+    file[..ELF32_EHDR_SIZE].copy_from_slice(&header_bytes);
+
+    out.write_all(&file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lang::assembler::Assembler;
+    use crate::lang::source::SourceLine;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn c_string(bytes: &[u8], offset: usize) -> &str {
+        let end = bytes[offset..].iter().position(|byte| *byte == 0).unwrap();
+        std::str::from_utf8(&bytes[offset..offset + end]).unwrap()
+    }
+
+    #[test]
+    fn test_relocatable_object() {
+        let source = r#"
+.proc main
+loop:
+  JSR print
+  JMP loop
+.endproc
+.scope
+hidden: RTS
+.endscope
+"#;
+        let program = Assembler::new()
+            .assemble(&SourceLine::from_source("test.s", source))
+            .unwrap();
+        let mut object = Vec::new();
+        write_relocatable(&mut object, &program).unwrap();
+
+        assert_eq!(&object[..4], b"\x7FELF");
+        assert_eq!(u16_at(&object, 16), ET_REL);
+        assert_eq!(u16_at(&object, 18), EM_6502);
+        let shoff = u32_at(&object, 32) as usize;
+        assert_eq!(u16_at(&object, 48), 6);
+
+        let section = |index: usize| &object[shoff + index * ELF32_SHDR_SIZE..];
+        let contents = |index: usize| {
+            let offset = u32_at(section(index), 16) as usize;
+            &object[offset..offset + u32_at(section(index), 20) as usize]
+        };
+        assert_eq!(contents(1), &[0x20, 0xFF, 0xFF, 0x4C, 0xFF, 0xFF, 0x60]);
+
+        let symtab = contents(3);
+        let strtab = contents(4);
+        let symbols: Vec<(&str, u32, u32, u8, u16)> = symtab
+            .chunks(ELF32_SYM_SIZE)
+            .map(|symbol| {
+                (
+                    c_string(strtab, u32_at(symbol, 0) as usize),
+                    u32_at(symbol, 4),
+                    u32_at(symbol, 8),
+                    symbol[12],
+                    u16_at(symbol, 14),
+                )
+            })
+            .collect();
+        assert_eq!(
+            symbols[2..],
+            [
+                ("@1::hidden", 6, 0, STT_NOTYPE, 1),
+                ("main", 0, 6, (STB_GLOBAL << 4) | STT_FUNC, 1),
+                ("main::loop", 0, 0, STB_GLOBAL << 4, 1),
+                ("print", 0, 0, STB_GLOBAL << 4, SHN_UNDEF),
+            ]
+        );
+        // Locals come first, so the first global is `main`.
+        assert_eq!(u32_at(section(3), 28), 3);
+
+        let rela: Vec<(u32, u32, u32)> = contents(2)
+            .chunks(ELF32_RELA_SIZE)
+            .map(|entry| (u32_at(entry, 0), u32_at(entry, 4), u32_at(entry, 8)))
+            .collect();
+        assert_eq!(
+            rela,
+            [
+                (1, (5 << 8) | R_6502_JUMP16 as u32, 0),
+                (4, (4 << 8) | R_6502_JUMP16 as u32, 0),
+            ]
+        );
+    }
 }
@@ -0,0 +1,1096 @@
+pub mod machine;
+
+use crate::lang::instruction::AddressModeIndexer;
+use crate::lang::instruction::InstructionCode;
+use crate::lang::instruction::OPCODE_MAP;
+
+pub const NMI_VECTOR: u16 = 0xFFFA;
+pub const RESET_VECTOR: u16 = 0xFFFC;
+pub const IRQ_VECTOR: u16 = 0xFFFE;
+
+// The bits of the status register
+pub const CARRY: u8 = 0x01;
+pub const ZERO: u8 = 0x02;
+pub const INTERRUPT: u8 = 0x04;
+pub const DECIMAL: u8 = 0x08;
+pub const BREAK: u8 = 0x10;
+pub const UNUSED: u8 = 0x20;
+pub const OVERFLOW: u8 = 0x40;
+pub const NEGATIVE: u8 = 0x80;
+
+// Whatever the CPU is wired up to. Reads take `&mut self` as well, since
+// reading an I/O register often does something.
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+}
+
+// 64K of RAM and nothing else.
+pub struct Ram {
+    pub bytes: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new() -> Ram {
+        Ram {
+            bytes: vec![0; 0x10000],
+        }
+    }
+
+    // Copies `bytes` in at `address`, wrapping round at the top of memory.
+    pub fn load(&mut self, address: u16, bytes: &[u8]) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.bytes[(address as usize + offset) & 0xFFFF] = *byte;
+        }
+    }
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Ram::new()
+    }
+}
+
+impl Bus for Ram {
+    fn read(&mut self, address: u16) -> u8 {
+        self.bytes[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.bytes[address as usize] = value;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    // The original 6502, where the undocumented opcodes are errors
+    Nmos,
+    // The 65C02, without the Rockwell and WDC bit instructions
+    Cmos,
+}
+
+// The assembler's addressing modes and the two the 65C02 adds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Nmos(AddressModeIndexer),
+    // (zp)
+    ZeroPageIndirect,
+    // (abs,X), only used by JMP
+    AbsoluteIndexedIndirect,
+}
+
+// The 65C02 has instructions the assembler doesn't know about, so they're
+// listed here rather than in lang::instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Instruction(InstructionCode),
+    Bra,
+    Phx,
+    Phy,
+    Plx,
+    Ply,
+    Stz,
+    Trb,
+    Tsb,
+    // The 65C02's unused opcodes, which take this many cycles
+    Nop(u32),
+}
+
+// Opcodes in lang::instruction that only the 65C02 has.
+const CMOS_ONLY: [u8; 1] = [
+    0x89, // BIT #imm
+];
+
+fn cmos_operation(opcode: u8) -> Option<(Operation, Mode)> {
+    use AddressModeIndexer::*;
+    use InstructionCode::*;
+    use Operation::*;
+    let (operation, mode) = match opcode {
+        0x80 => (Bra, Mode::Nmos(RELATIVE)),
+        0xDA => (Phx, Mode::Nmos(IMPLIED)),
+        0x5A => (Phy, Mode::Nmos(IMPLIED)),
+        0xFA => (Plx, Mode::Nmos(IMPLIED)),
+        0x7A => (Ply, Mode::Nmos(IMPLIED)),
+        0x64 => (Stz, Mode::Nmos(ZERO_PAGE)),
+        0x74 => (Stz, Mode::Nmos(ZP_X)),
+        0x9C => (Stz, Mode::Nmos(ABSOLUTE)),
+        0x9E => (Stz, Mode::Nmos(ABS_X)),
+        0x14 => (Trb, Mode::Nmos(ZERO_PAGE)),
+        0x1C => (Trb, Mode::Nmos(ABSOLUTE)),
+        0x04 => (Tsb, Mode::Nmos(ZERO_PAGE)),
+        0x0C => (Tsb, Mode::Nmos(ABSOLUTE)),
+        0x1A => (Instruction(INC), Mode::Nmos(ACCUMULATOR)),
+        0x3A => (Instruction(DEC), Mode::Nmos(ACCUMULATOR)),
+        0x34 => (Instruction(BIT), Mode::Nmos(ZP_X)),
+        0x3C => (Instruction(BIT), Mode::Nmos(ABS_X)),
+        0x12 => (Instruction(ORA), Mode::ZeroPageIndirect),
+        0x32 => (Instruction(AND), Mode::ZeroPageIndirect),
+        0x52 => (Instruction(EOR), Mode::ZeroPageIndirect),
+        0x72 => (Instruction(ADC), Mode::ZeroPageIndirect),
+        0x92 => (Instruction(STA), Mode::ZeroPageIndirect),
+        0xB2 => (Instruction(LDA), Mode::ZeroPageIndirect),
+        0xD2 => (Instruction(CMP), Mode::ZeroPageIndirect),
+        0xF2 => (Instruction(SBC), Mode::ZeroPageIndirect),
+        0x7C => (Instruction(JMP), Mode::AbsoluteIndexedIndirect),
+        0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xC2 | 0xE2 => (Nop(2), Mode::Nmos(IMMEDIATE)),
+        0x44 => (Nop(3), Mode::Nmos(ZERO_PAGE)),
+        0x54 | 0xD4 | 0xF4 => (Nop(4), Mode::Nmos(ZP_X)),
+        0xDC | 0xFC => (Nop(4), Mode::Nmos(ABSOLUTE)),
+        0x5C => (Nop(8), Mode::Nmos(ABSOLUTE)),
+        _ if opcode & 0x03 == 0x03 => (Nop(1), Mode::Nmos(IMPLIED)),
+        _ => return None,
+    };
+    Some((operation, mode))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+    Modify,
+}
+
+fn access(operation: Operation) -> Access {
+    use InstructionCode::*;
+    match operation {
+        Operation::Instruction(STA | STX | STY) | Operation::Stz => Access::Write,
+        Operation::Instruction(ASL | LSR | ROL | ROR | INC | DEC)
+        | Operation::Trb
+        | Operation::Tsb => Access::Modify,
+        _ => Access::Read,
+    }
+}
+
+fn is_shift(operation: Operation) -> bool {
+    use InstructionCode::*;
+    matches!(operation, Operation::Instruction(ASL | LSR | ROL | ROR))
+}
+
+// The cycles an instruction takes before any penalties for crossing a page,
+// taking a branch or, on the 65C02, decimal arithmetic.
+fn base_cycles(operation: Operation, mode: Mode, variant: Variant) -> u32 {
+    use AddressModeIndexer::*;
+    use InstructionCode::*;
+    use Operation::*;
+    match (operation, mode) {
+        (Nop(cycles), _) => cycles,
+        (Instruction(BRK), _) => 7,
+        (Instruction(RTI | RTS | JSR), _) => 6,
+        (Instruction(PHA | PHP) | Phx | Phy, _) => 3,
+        (Instruction(PLA | PLP) | Plx | Ply, _) => 4,
+        (Instruction(JMP), Mode::Nmos(ABSOLUTE)) => 3,
+        (Instruction(JMP), Mode::Nmos(ABSOLUTE_INDIRECT)) => match variant {
+            Variant::Nmos => 5,
+            Variant::Cmos => 6,
+        },
+        (_, Mode::AbsoluteIndexedIndirect) => 6,
+        (_, Mode::ZeroPageIndirect) => 5,
+        (_, Mode::Nmos(IMPLIED | ACCUMULATOR | IMMEDIATE | RELATIVE)) => 2,
+        (_, Mode::Nmos(mode)) => {
+            let read = match mode {
+                ZERO_PAGE => 3,
+                ZP_X | ZP_Y | ABSOLUTE | ABS_X | ABS_Y => 4,
+                INDEX_IND => 6,
+                _ => 5,
+            };
+            match (access(operation), mode) {
+                (Access::Read, _) => read,
+                (Access::Write, ABS_X | ABS_Y | IND_INDEX) => read + 1,
+                (Access::Write, _) => read,
+                // The 65C02 only takes the extra cycle if it crosses a page
+                (Access::Modify, ABS_X) if variant == Variant::Cmos && is_shift(operation) => 6,
+                (Access::Modify, ABS_X) => 7,
+                (Access::Modify, _) => read + 2,
+            }
+        }
+    }
+}
+
+// Whether crossing a page while indexing costs a cycle.
+fn page_penalty(operation: Operation, mode: Mode, variant: Variant) -> bool {
+    use AddressModeIndexer::*;
+    match mode {
+        Mode::Nmos(ABS_X | ABS_Y | IND_INDEX) => {
+            access(operation) == Access::Read
+                || (variant == Variant::Cmos && is_shift(operation) && mode == Mode::Nmos(ABS_X))
+        }
+        _ => false,
+    }
+}
+
+fn indexed(base: u16, index: u8) -> (u16, bool) {
+    let address = base.wrapping_add(index as u16);
+    (address, address & 0xFF00 != base & 0xFF00)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cpu {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub pc: u16,
+    pub p: u8,
+    // Every cycle since the CPU was made, including the reset
+    pub cycles: u64,
+    pub variant: Variant,
+    nmi: bool,
+    irq: bool,
+}
+
+impl Cpu {
+    // Call reset() before running it, like the real thing.
+    pub fn new(variant: Variant) -> Cpu {
+        Cpu {
+            a: 0,
+            x: 0,
+            y: 0,
+            s: 0,
+            pc: 0,
+            p: UNUSED | INTERRUPT,
+            cycles: 0,
+            variant,
+            nmi: false,
+            irq: false,
+        }
+    }
+
+    pub fn reset(&mut self, bus: &mut impl Bus) {
+        // It goes through the motions of an interrupt without writing anything
+        self.s = self.s.wrapping_sub(3);
+        self.p |= INTERRUPT | UNUSED;
+        if self.variant == Variant::Cmos {
+            self.p &= !DECIMAL;
+        }
+        self.pc = read_word(bus, RESET_VECTOR);
+        self.nmi = false;
+        self.cycles += 7;
+    }
+
+    // NMI is edge triggered, so it's taken once before the next instruction.
+    pub fn nmi(&mut self) {
+        self.nmi = true;
+    }
+
+    // IRQ is level triggered, and taken before every instruction while it's
+    // asserted and interrupts are enabled.
+    pub fn set_irq(&mut self, asserted: bool) {
+        self.irq = asserted;
+    }
+
+    // Runs one instruction, or takes a pending interrupt, and returns how
+    // many cycles it took.
+    pub fn step(&mut self, bus: &mut impl Bus) -> Result<u32, String> {
+        if self.nmi {
+            self.nmi = false;
+            return Ok(self.interrupt(bus, NMI_VECTOR));
+        }
+        if self.irq && self.p & INTERRUPT == 0 {
+            return Ok(self.interrupt(bus, IRQ_VECTOR));
+        }
+
+        let start = self.pc;
+        let opcode = self.fetch(bus);
+        let decoded = match (OPCODE_MAP.get(&opcode), self.variant) {
+            (Some(_), Variant::Nmos) if CMOS_ONLY.contains(&opcode) => None,
+            (Some(&(code, mode)), _) => Some((Operation::Instruction(code), Mode::Nmos(mode))),
+            (None, Variant::Cmos) => cmos_operation(opcode),
+            (None, Variant::Nmos) => None,
+        };
+        let Some((operation, mode)) = decoded else {
+            self.pc = start;
+            return Err(format!("illegal opcode ${:02X} at ${:04X}", opcode, start));
+        };
+
+        let mut cycles = base_cycles(operation, mode, self.variant);
+        let (address, crossed) = self.operand(bus, mode);
+        if crossed && page_penalty(operation, mode, self.variant) {
+            cycles += 1;
+        }
+        cycles += self.execute(bus, operation, mode, address, crossed);
+        self.cycles += cycles as u64;
+        Ok(cycles)
+    }
+
+    fn interrupt(&mut self, bus: &mut impl Bus, vector: u16) -> u32 {
+        self.push_word(bus, self.pc);
+        self.push(bus, (self.p & !BREAK) | UNUSED);
+        self.enter_handler(bus, vector);
+        self.cycles += 7;
+        7
+    }
+
+    fn enter_handler(&mut self, bus: &mut impl Bus, vector: u16) {
+        self.p |= INTERRUPT;
+        if self.variant == Variant::Cmos {
+            self.p &= !DECIMAL;
+        }
+        self.pc = read_word(bus, vector);
+    }
+
+    fn fetch(&mut self, bus: &mut impl Bus) -> u8 {
+        let byte = bus.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        byte
+    }
+
+    fn fetch_word(&mut self, bus: &mut impl Bus) -> u16 {
+        let low = self.fetch(bus);
+        let high = self.fetch(bus);
+        u16::from_le_bytes([low, high])
+    }
+
+    fn push(&mut self, bus: &mut impl Bus, value: u8) {
+        bus.write(0x0100 | self.s as u16, value);
+        self.s = self.s.wrapping_sub(1);
+    }
+
+    fn push_word(&mut self, bus: &mut impl Bus, value: u16) {
+        self.push(bus, (value >> 8) as u8);
+        self.push(bus, value as u8);
+    }
+
+    fn pull(&mut self, bus: &mut impl Bus) -> u8 {
+        self.s = self.s.wrapping_add(1);
+        bus.read(0x0100 | self.s as u16)
+    }
+
+    fn pull_word(&mut self, bus: &mut impl Bus) -> u16 {
+        let low = self.pull(bus);
+        let high = self.pull(bus);
+        u16::from_le_bytes([low, high])
+    }
+
+    // B only exists on the stack
+    fn pull_status(&mut self, bus: &mut impl Bus) {
+        self.p = (self.pull(bus) & !BREAK) | UNUSED;
+    }
+
+    fn set_flag(&mut self, flag: u8, set: bool) {
+        if set {
+            self.p |= flag;
+        } else {
+            self.p &= !flag;
+        }
+    }
+
+    fn set_nz(&mut self, value: u8) {
+        self.set_flag(ZERO, value == 0);
+        self.set_flag(NEGATIVE, value & 0x80 != 0);
+    }
+
+    // Works out the effective address, and whether indexing crossed a page.
+    // Branches give their target, and implied and accumulator modes nothing.
+    fn operand(&mut self, bus: &mut impl Bus, mode: Mode) -> (Option<u16>, bool) {
+        use AddressModeIndexer::*;
+        let (address, crossed) = match mode {
+            Mode::Nmos(IMPLIED | ACCUMULATOR) => return (None, false),
+            Mode::Nmos(IMMEDIATE) => {
+                let address = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                (address, false)
+            }
+            Mode::Nmos(ZERO_PAGE) => (self.fetch(bus) as u16, false),
+            Mode::Nmos(ZP_X) => (self.fetch(bus).wrapping_add(self.x) as u16, false),
+            Mode::Nmos(ZP_Y) => (self.fetch(bus).wrapping_add(self.y) as u16, false),
+            Mode::Nmos(ABSOLUTE) => (self.fetch_word(bus), false),
+            Mode::Nmos(ABS_X) => indexed(self.fetch_word(bus), self.x),
+            Mode::Nmos(ABS_Y) => indexed(self.fetch_word(bus), self.y),
+            Mode::Nmos(INDEX_IND) => {
+                let pointer = self.fetch(bus).wrapping_add(self.x);
+                (read_zero_page_word(bus, pointer), false)
+            }
+            Mode::Nmos(IND_INDEX) => {
+                let pointer = self.fetch(bus);
+                indexed(read_zero_page_word(bus, pointer), self.y)
+            }
+            Mode::ZeroPageIndirect => {
+                let pointer = self.fetch(bus);
+                (read_zero_page_word(bus, pointer), false)
+            }
+            Mode::Nmos(ABSOLUTE_INDIRECT) => {
+                let pointer = self.fetch_word(bus);
+                // The NMOS part doesn't carry into the high byte of the
+                // pointer, so JMP ($10FF) reads $10FF and $1000
+                let high = match self.variant {
+                    Variant::Nmos => (pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF),
+                    Variant::Cmos => pointer.wrapping_add(1),
+                };
+                let address = u16::from_le_bytes([bus.read(pointer), bus.read(high)]);
+                (address, false)
+            }
+            Mode::AbsoluteIndexedIndirect => {
+                let pointer = self.fetch_word(bus).wrapping_add(self.x as u16);
+                (read_word(bus, pointer), false)
+            }
+            Mode::Nmos(RELATIVE) => {
+                let offset = self.fetch(bus) as i8;
+                let target = self.pc.wrapping_add(offset as u16);
+                (target, target & 0xFF00 != self.pc & 0xFF00)
+            }
+        };
+        (Some(address), crossed)
+    }
+
+    // Reads, changes and writes back memory, or A if there's no address.
+    fn modify(
+        &mut self,
+        bus: &mut impl Bus,
+        address: Option<u16>,
+        change: impl FnOnce(&mut Cpu, u8) -> u8,
+    ) {
+        let Some(address) = address else {
+            self.a = change(self, self.a);
+            return;
+        };
+        let value = bus.read(address);
+        // The NMOS part writes the old value back while it works out the new
+        // one, and the 65C02 reads it again. It matters for I/O registers.
+        match self.variant {
+            Variant::Nmos => bus.write(address, value),
+            Variant::Cmos => {
+                bus.read(address);
+            }
+        }
+        let value = change(self, value);
+        bus.write(address, value);
+    }
+
+    // Returns the cycles a branch takes beyond the base two.
+    fn branch(&mut self, taken: bool, target: Option<u16>, crossed: bool) -> u32 {
+        if !taken {
+            return 0;
+        }
+        self.pc = target.unwrap();
+        1 + crossed as u32
+    }
+
+    // Returns any cycles beyond the ones worked out from the addressing mode.
+    fn execute(
+        &mut self,
+        bus: &mut impl Bus,
+        operation: Operation,
+        mode: Mode,
+        address: Option<u16>,
+        crossed: bool,
+    ) -> u32 {
+        use InstructionCode::*;
+        let code = match operation {
+            Operation::Instruction(code) => code,
+            Operation::Bra => return self.branch(true, address, crossed),
+            Operation::Phx => {
+                self.push(bus, self.x);
+                return 0;
+            }
+            Operation::Phy => {
+                self.push(bus, self.y);
+                return 0;
+            }
+            Operation::Plx => {
+                self.x = self.pull(bus);
+                self.set_nz(self.x);
+                return 0;
+            }
+            Operation::Ply => {
+                self.y = self.pull(bus);
+                self.set_nz(self.y);
+                return 0;
+            }
+            Operation::Stz => {
+                bus.write(address.unwrap(), 0);
+                return 0;
+            }
+            Operation::Trb => {
+                self.modify(bus, address, |cpu, value| {
+                    cpu.set_flag(ZERO, value & cpu.a == 0);
+                    value & !cpu.a
+                });
+                return 0;
+            }
+            Operation::Tsb => {
+                self.modify(bus, address, |cpu, value| {
+                    cpu.set_flag(ZERO, value & cpu.a == 0);
+                    value | cpu.a
+                });
+                return 0;
+            }
+            Operation::Nop(_) => {
+                // Memory still gets read
+                if let Some(address) = address {
+                    bus.read(address);
+                }
+                return 0;
+            }
+        };
+
+        match code {
+            LDA => {
+                self.a = bus.read(address.unwrap());
+                self.set_nz(self.a);
+            }
+            LDX => {
+                self.x = bus.read(address.unwrap());
+                self.set_nz(self.x);
+            }
+            LDY => {
+                self.y = bus.read(address.unwrap());
+                self.set_nz(self.y);
+            }
+            STA => bus.write(address.unwrap(), self.a),
+            STX => bus.write(address.unwrap(), self.x),
+            STY => bus.write(address.unwrap(), self.y),
+            ADC => {
+                let value = bus.read(address.unwrap());
+                return self.adc(value);
+            }
+            SBC => {
+                let value = bus.read(address.unwrap());
+                return self.sbc(value);
+            }
+            AND => {
+                self.a &= bus.read(address.unwrap());
+                self.set_nz(self.a);
+            }
+            ORA => {
+                self.a |= bus.read(address.unwrap());
+                self.set_nz(self.a);
+            }
+            EOR => {
+                self.a ^= bus.read(address.unwrap());
+                self.set_nz(self.a);
+            }
+            CMP => self.compare(bus, self.a, address),
+            CPX => self.compare(bus, self.x, address),
+            CPY => self.compare(bus, self.y, address),
+            BIT => {
+                let value = bus.read(address.unwrap());
+                self.set_flag(ZERO, self.a & value == 0);
+                // BIT #imm only sets Z
+                if mode != Mode::Nmos(AddressModeIndexer::IMMEDIATE) {
+                    self.set_flag(NEGATIVE, value & NEGATIVE != 0);
+                    self.set_flag(OVERFLOW, value & OVERFLOW != 0);
+                }
+            }
+            INC => self.modify(bus, address, |cpu, value| {
+                let value = value.wrapping_add(1);
+                cpu.set_nz(value);
+                value
+            }),
+            DEC => self.modify(bus, address, |cpu, value| {
+                let value = value.wrapping_sub(1);
+                cpu.set_nz(value);
+                value
+            }),
+            ASL => self.modify(bus, address, |cpu, value| {
+                cpu.set_flag(CARRY, value & 0x80 != 0);
+                let value = value << 1;
+                cpu.set_nz(value);
+                value
+            }),
+            LSR => self.modify(bus, address, |cpu, value| {
+                cpu.set_flag(CARRY, value & 0x01 != 0);
+                let value = value >> 1;
+                cpu.set_nz(value);
+                value
+            }),
+            ROL => self.modify(bus, address, |cpu, value| {
+                let carry = cpu.p & CARRY;
+                cpu.set_flag(CARRY, value & 0x80 != 0);
+                let value = (value << 1) | carry;
+                cpu.set_nz(value);
+                value
+            }),
+            ROR => self.modify(bus, address, |cpu, value| {
+                let carry = (cpu.p & CARRY) << 7;
+                cpu.set_flag(CARRY, value & 0x01 != 0);
+                let value = (value >> 1) | carry;
+                cpu.set_nz(value);
+                value
+            }),
+            INX => {
+                self.x = self.x.wrapping_add(1);
+                self.set_nz(self.x);
+            }
+            INY => {
+                self.y = self.y.wrapping_add(1);
+                self.set_nz(self.y);
+            }
+            DEX => {
+                self.x = self.x.wrapping_sub(1);
+                self.set_nz(self.x);
+            }
+            DEY => {
+                self.y = self.y.wrapping_sub(1);
+                self.set_nz(self.y);
+            }
+            TAX => {
+                self.x = self.a;
+                self.set_nz(self.x);
+            }
+            TAY => {
+                self.y = self.a;
+                self.set_nz(self.y);
+            }
+            TXA => {
+                self.a = self.x;
+                self.set_nz(self.a);
+            }
+            TYA => {
+                self.a = self.y;
+                self.set_nz(self.a);
+            }
+            TSX => {
+                self.x = self.s;
+                self.set_nz(self.x);
+            }
+            TXS => self.s = self.x,
+            BCC => return self.branch(self.p & CARRY == 0, address, crossed),
+            BCS => return self.branch(self.p & CARRY != 0, address, crossed),
+            BNE => return self.branch(self.p & ZERO == 0, address, crossed),
+            BEQ => return self.branch(self.p & ZERO != 0, address, crossed),
+            BPL => return self.branch(self.p & NEGATIVE == 0, address, crossed),
+            BMI => return self.branch(self.p & NEGATIVE != 0, address, crossed),
+            BVC => return self.branch(self.p & OVERFLOW == 0, address, crossed),
+            BVS => return self.branch(self.p & OVERFLOW != 0, address, crossed),
+            JMP => self.pc = address.unwrap(),
+            JSR => {
+                // The return address is the last byte of the JSR
+                self.push_word(bus, self.pc.wrapping_sub(1));
+                self.pc = address.unwrap();
+            }
+            RTS => self.pc = self.pull_word(bus).wrapping_add(1),
+            RTI => {
+                self.pull_status(bus);
+                self.pc = self.pull_word(bus);
+            }
+            BRK => {
+                // It skips a signature byte after the opcode
+                self.push_word(bus, self.pc.wrapping_add(1));
+                self.push(bus, self.p | BREAK | UNUSED);
+                self.enter_handler(bus, IRQ_VECTOR);
+            }
+            PHA => self.push(bus, self.a),
+            PHP => self.push(bus, self.p | BREAK | UNUSED),
+            PLA => {
+                self.a = self.pull(bus);
+                self.set_nz(self.a);
+            }
+            PLP => self.pull_status(bus),
+            CLC => self.p &= !CARRY,
+            CLD => self.p &= !DECIMAL,
+            CLI => self.p &= !INTERRUPT,
+            CLV => self.p &= !OVERFLOW,
+            SEC => self.p |= CARRY,
+            SED => self.p |= DECIMAL,
+            SEI => self.p |= INTERRUPT,
+            NOP => {}
+            JCC | JCS | JEQ | JNE | JMI | JPL | JVS | JVC => {
+                unreachable!("{:?} doesn't have an opcode", code)
+            }
+        }
+        0
+    }
+
+    fn compare(&mut self, bus: &mut impl Bus, register: u8, address: Option<u16>) {
+        let value = bus.read(address.unwrap());
+        self.set_flag(CARRY, register >= value);
+        self.set_nz(register.wrapping_sub(value));
+    }
+
+    fn add_binary(&mut self, value: u8) {
+        let sum = self.a as u16 + value as u16 + (self.p & CARRY) as u16;
+        let result = sum as u8;
+        self.set_flag(CARRY, sum > 0xFF);
+        self.set_flag(OVERFLOW, !(self.a ^ value) & (self.a ^ result) & 0x80 != 0);
+        self.a = result;
+        self.set_nz(result);
+    }
+
+    // Decimal mode follows Bruce Clark's "Decimal Mode" tutorial on 6502.org,
+    // down to the flags the NMOS part leaves in a mess. Returns the 65C02's
+    // extra cycle.
+    fn adc(&mut self, value: u8) -> u32 {
+        if self.p & DECIMAL == 0 {
+            self.add_binary(value);
+            return 0;
+        }
+        let a = self.a;
+        let carry = (self.p & CARRY) as i16;
+        let mut low = (a & 0x0F) as i16 + (value & 0x0F) as i16 + carry;
+        if low >= 0x0A {
+            low = ((low + 0x06) & 0x0F) + 0x10;
+        }
+        let mut sum = (a & 0xF0) as i16 + (value & 0xF0) as i16 + low;
+        // N and V come from before the high digit's adjusted
+        let signed = (a & 0xF0) as i8 as i16 + (value & 0xF0) as i8 as i16 + low;
+        if sum >= 0xA0 {
+            sum += 0x60;
+        }
+        self.set_flag(CARRY, sum >= 0x100);
+        self.set_flag(OVERFLOW, !(-128..=127).contains(&signed));
+        self.a = sum as u8;
+        match self.variant {
+            Variant::Nmos => {
+                self.set_flag(NEGATIVE, signed & 0x80 != 0);
+                // Z is whatever it would have been in binary
+                self.set_flag(ZERO, a.wrapping_add(value).wrapping_add(carry as u8) == 0);
+                0
+            }
+            Variant::Cmos => {
+                self.set_nz(self.a);
+                1
+            }
+        }
+    }
+
+    fn sbc(&mut self, value: u8) -> u32 {
+        let a = self.a;
+        let borrow = 1 - (self.p & CARRY) as i16;
+        // C and V are the binary ones either way, and N and Z too on NMOS
+        self.add_binary(!value);
+        if self.p & DECIMAL == 0 {
+            return 0;
+        }
+        let low = (a & 0x0F) as i16 - (value & 0x0F) as i16 - borrow;
+        let result = match self.variant {
+            Variant::Nmos => {
+                let low = if low < 0 {
+                    ((low - 0x06) & 0x0F) - 0x10
+                } else {
+                    low
+                };
+                let result = (a & 0xF0) as i16 - (value & 0xF0) as i16 + low;
+                if result < 0 {
+                    result - 0x60
+                } else {
+                    result
+                }
+            }
+            Variant::Cmos => {
+                let mut result = a as i16 - value as i16 - borrow;
+                if result < 0 {
+                    result -= 0x60;
+                }
+                if low < 0 {
+                    result -= 0x06;
+                }
+                result
+            }
+        };
+        self.a = result as u8;
+        match self.variant {
+            Variant::Nmos => 0,
+            Variant::Cmos => {
+                self.set_nz(self.a);
+                1
+            }
+        }
+    }
+}
+
+fn read_word(bus: &mut impl Bus, address: u16) -> u16 {
+    u16::from_le_bytes([bus.read(address), bus.read(address.wrapping_add(1))])
+}
+
+// Pointers in zero page wrap round within it
+fn read_zero_page_word(bus: &mut impl Bus, pointer: u8) -> u16 {
+    u16::from_le_bytes([
+        bus.read(pointer as u16),
+        bus.read(pointer.wrapping_add(1) as u16),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lang::assembler::Assembler;
+    use crate::lang::source::SourceLine;
+    use crate::output::image::Image;
+
+    // Assembles `source` at $0200 with the vectors pointing at it, resets,
+    // and runs it until it gets stuck on a `JMP *` or runs out of steps.
+    fn run(variant: Variant, source: &str) -> (Cpu, Ram) {
+        let program = Assembler::new()
+            .assemble(&SourceLine::from_source(
+                "test.s",
+                &format!(".org $0200\n{}", source),
+            ))
+            .unwrap();
+        let mut ram = Ram::new();
+        for segment in Image::from_program(&program, 0).unwrap().segments {
+            ram.load(segment.address, &segment.bytes);
+        }
+        ram.load(NMI_VECTOR, &[0x00, 0x02, 0x00, 0x02, 0x00, 0x02]);
+        let mut cpu = Cpu::new(variant);
+        cpu.reset(&mut ram);
+        for _ in 0..1000 {
+            let pc = cpu.pc;
+            cpu.step(&mut ram).unwrap();
+            if cpu.pc == pc {
+                break;
+            }
+        }
+        (cpu, ram)
+    }
+
+    fn step_cycles(cpu: &mut Cpu, ram: &mut Ram, count: usize) -> Vec<u32> {
+        (0..count).map(|_| cpu.step(ram).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_flags() {
+        let (cpu, ram) = run(
+            Variant::Nmos,
+            r#"
+  CLC
+  LDA #$50
+  ADC #$50
+  PHP
+  SEC
+  LDA #$50
+  SBC #$B0
+  PHP
+  LDA #$01
+  CMP #$02
+  PHP
+  LDA #$F0
+  STA $10
+  LDA #$0F
+  BIT $10
+  PHP
+  LDA #$81
+  ROR A
+  ROR A
+  PHP
+done:
+  JMP done
+"#,
+        );
+        let flags: Vec<u8> = (0x01F9..=0x01FD)
+            .rev()
+            .map(|address| ram.bytes[address] & !(BREAK | UNUSED | INTERRUPT))
+            .collect();
+        assert_eq!(
+            flags,
+            [
+                NEGATIVE | OVERFLOW,
+                NEGATIVE | OVERFLOW,
+                // Only ADC, SBC, BIT and CLV touch V
+                NEGATIVE | OVERFLOW,
+                NEGATIVE | OVERFLOW | ZERO,
+                NEGATIVE | OVERFLOW,
+            ]
+        );
+        assert_eq!(cpu.a, 0xA0);
+        assert_eq!(cpu.s, 0xF8);
+    }
+
+    #[test]
+    fn test_decimal_mode() {
+        let source = r#"
+  SED
+  CLC
+  LDA #$99
+  ADC #$01
+  STA $10
+  PHP
+  SEC
+  LDA #$00
+  SBC #$01
+  STA $11
+  PHP
+  CLC
+  LDA #$15
+  ADC #$27
+  STA $12
+done:
+  JMP done
+"#;
+        let (_, nmos) = run(Variant::Nmos, source);
+        let (_, cmos) = run(Variant::Cmos, source);
+        for ram in [&nmos, &cmos] {
+            assert_eq!(ram.bytes[0x10..0x13], [0x00, 0x99, 0x42]);
+        }
+        let flags = |ram: &Ram| {
+            [
+                ram.bytes[0x01FD] & (NEGATIVE | ZERO | CARRY),
+                ram.bytes[0x01FC] & (NEGATIVE | ZERO | CARRY),
+            ]
+        };
+        // The NMOS part sets N and Z from the binary sum
+        assert_eq!(flags(&nmos), [NEGATIVE | CARRY, NEGATIVE]);
+        assert_eq!(flags(&cmos), [ZERO | CARRY, NEGATIVE]);
+
+        // And the 65C02 takes another cycle for it
+        let mut ram = Ram::new();
+        ram.load(0x0200, &[0xF8, 0x69, 0x01, 0xE9, 0x01, 0xD8, 0x69, 0x01]);
+        for (variant, cycles) in [
+            (Variant::Nmos, [2, 2, 2, 2, 2]),
+            (Variant::Cmos, [2, 3, 3, 2, 2]),
+        ] {
+            let mut cpu = Cpu::new(variant);
+            cpu.pc = 0x0200;
+            assert_eq!(step_cycles(&mut cpu, &mut ram, 5), cycles);
+        }
+    }
+
+    #[test]
+    fn test_cycles() {
+        let mut ram = Ram::new();
+        ram.load(
+            0x02E0,
+            &[
+                0xBD, 0x00, 0x10, // LDA $1000,X
+                0xBD, 0xFF, 0x10, // LDA $10FF,X, which crosses a page
+                0x9D, 0x00, 0x10, // STA $1000,X
+                0xB1, 0x80, // LDA ($80),Y, which crosses a page
+                0x1E, 0x00, 0x10, // ASL $1000,X
+                0xD0, 0x00, // BNE, not taken
+                0xF0, 0x10, // BEQ, taken to the next page
+            ],
+        );
+        ram.load(0x0080, &[0xFF, 0x20]);
+        for (variant, cycles) in [
+            (Variant::Nmos, [4, 5, 5, 6, 7, 2, 4]),
+            (Variant::Cmos, [4, 5, 5, 6, 6, 2, 4]),
+        ] {
+            let mut cpu = Cpu::new(variant);
+            cpu.pc = 0x02E0;
+            cpu.x = 1;
+            cpu.y = 1;
+            cpu.a = 0;
+            ram.bytes[0x1001] = 0;
+            assert_eq!(step_cycles(&mut cpu, &mut ram, 7), cycles);
+            assert_eq!(cpu.pc, 0x0302);
+            assert_eq!(cpu.cycles, cycles.iter().sum::<u32>() as u64);
+        }
+    }
+
+    #[test]
+    fn test_zero_page_indirect_cycles() {
+        let mut ram = Ram::new();
+        ram.load(
+            0x0200,
+            &[
+                0xB2, 0x80, // LDA ($80)
+                0x92, 0x80, // STA ($80)
+            ],
+        );
+        ram.load(0x0080, &[0xFF, 0x10]);
+        let mut cpu = Cpu::new(Variant::Cmos);
+        cpu.pc = 0x0200;
+        assert_eq!(step_cycles(&mut cpu, &mut ram, 2), [5, 5]);
+        assert_eq!(cpu.pc, 0x0204);
+    }
+
+    #[test]
+    fn test_indirect_jump_bug() {
+        let mut ram = Ram::new();
+        ram.load(0x0200, &[0x6C, 0xFF, 0x10]);
+        ram.load(0x10FF, &[0x34, 0x12]);
+        ram.load(0x1000, &[0x56]);
+        for (variant, pc, cycles) in [(Variant::Nmos, 0x5634, 5), (Variant::Cmos, 0x1234, 6)] {
+            let mut cpu = Cpu::new(variant);
+            cpu.pc = 0x0200;
+            assert_eq!(cpu.step(&mut ram), Ok(cycles));
+            assert_eq!(cpu.pc, pc);
+        }
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let mut ram = Ram::new();
+        // Reset at $0200, NMI at $0300 and IRQ at $0400
+        ram.load(NMI_VECTOR, &[0x00, 0x03, 0x00, 0x02, 0x00, 0x04]);
+        ram.load(0x0200, &[0x58, 0xF8, 0x00, 0xEA, 0xEA]); // CLI, SED, BRK
+        ram.load(0x0300, &[0x40]); // RTI
+        ram.load(0x0400, &[0x40]); // RTI
+
+        let mut cpu = Cpu::new(Variant::Cmos);
+        cpu.reset(&mut ram);
+        assert_eq!((cpu.pc, cpu.s, cpu.cycles), (0x0200, 0xFD, 7));
+        assert_eq!(cpu.p & INTERRUPT, INTERRUPT);
+
+        // IRQ waits for CLI
+        cpu.set_irq(true);
+        assert_eq!(cpu.step(&mut ram), Ok(2));
+        cpu.set_irq(false);
+        assert_eq!(step_cycles(&mut cpu, &mut ram, 2), [2, 7]);
+        // BRK skips a byte, sets B on the stack and the 65C02 clears D
+        assert_eq!(cpu.pc, 0x0400);
+        assert_eq!(
+            ram.bytes[0x01FB..0x01FE],
+            [BREAK | DECIMAL | UNUSED, 0x04, 0x02]
+        );
+        assert_eq!(cpu.p & (DECIMAL | INTERRUPT), INTERRUPT);
+        assert_eq!(cpu.step(&mut ram), Ok(6));
+        assert_eq!(cpu.pc, 0x0204);
+        assert_eq!(cpu.p & (DECIMAL | INTERRUPT | BREAK), DECIMAL);
+
+        // Then an NMI and an IRQ, with B clear on the stack
+        cpu.nmi();
+        cpu.set_irq(true);
+        assert_eq!(cpu.step(&mut ram), Ok(7));
+        assert_eq!(cpu.pc, 0x0300);
+        assert_eq!(ram.bytes[0x01FB], UNUSED | DECIMAL);
+        assert_eq!(cpu.step(&mut ram), Ok(6));
+        assert_eq!(cpu.pc, 0x0204);
+        assert_eq!(cpu.step(&mut ram), Ok(7));
+        assert_eq!(cpu.pc, 0x0400);
+    }
+
+    #[test]
+    fn test_cmos_instructions() {
+        let source = r#"
+  LDX #$12
+  LDY #$34
+  .byte $DA       ; PHX
+  .byte $5A       ; PHY
+  .byte $FA       ; PLX
+  .byte $7A       ; PLY
+  STX $20
+  STY $21
+  .byte $9C, $21, $00 ; STZ $0021
+  LDA #$0F
+  STA $30
+  LDA #$3C
+  .byte $14, $30  ; TRB $30
+  .byte $1A       ; INC A
+  LDA #$20
+  STA $40
+  LDA #$00
+  STA $41
+  .byte $B2, $40  ; LDA ($40)
+  STA $31
+  LDX #$02
+  .byte $7C, $00, $03 ; JMP ($0300,X)
+  .byte $80, $FE  ; BRA *
+target:
+  LDA #$55
+  STA $32
+  .byte $80, $FE  ; BRA *
+  .org $0302
+  .word target
+"#;
+        let mut ram = Ram::new();
+        let (cpu, cmos) = run(Variant::Cmos, source);
+        assert_eq!(cmos.bytes[0x20..0x22], [0x34, 0x00]);
+        assert_eq!(cmos.bytes[0x30..0x33], [0x03, 0x34, 0x55]);
+        assert_eq!(cmos.bytes[cpu.pc as usize], 0x80);
+        assert_eq!(cpu.a, 0x55);
+
+        // The NMOS part doesn't know any of them, including the BIT #imm
+        // the assembler has
+        for opcode in [0xDA, 0x89] {
+            ram.load(0x0200, &[opcode, 0x00]);
+            let mut cpu = Cpu::new(Variant::Nmos);
+            cpu.pc = 0x0200;
+            assert_eq!(
+                cpu.step(&mut ram),
+                Err(format!("illegal opcode ${:02X} at $0200", opcode))
+            );
+            assert_eq!(cpu.pc, 0x0200);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use super::Bus;
+use super::Cpu;
+use super::Ram;
+use super::RESET_VECTOR;
+
+use crate::output::image::Image;
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+// Where a program talks to the host. Writing to PUTCHAR prints the byte,
+// writing to EXIT stops with the byte as the exit code, and reading GETCHAR
+// gets the next byte of input, or $00 once there isn't any. They're where
+// py65 has them, apart from EXIT.
+pub const PUTCHAR: u16 = 0xF001;
+pub const EXIT: u16 = 0xF002;
+pub const GETCHAR: u16 = 0xF004;
+
+// Why a run stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    Exit(u8),
+    // In a loop that jumps to itself, which nothing can interrupt
+    Stuck(u16),
+    // It had as many cycles as it was allowed
+    OutOfCycles,
+    Error(String),
+}
+
+// 64K of RAM with the host's I/O mapped over it.
+pub struct Machine<R: Read, W: Write> {
+    pub ram: Ram,
+    input: R,
+    output: W,
+    exit: Option<u8>,
+    // The first thing that went wrong writing the output
+    error: Option<io::Error>,
+}
+
+impl<R: Read, W: Write> Machine<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Machine {
+            ram: Ram::new(),
+            input,
+            output,
+            exit: None,
+            error: None,
+        }
+    }
+
+    pub fn output(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    // There's no bank switching, so banked segments can't be loaded.
+    pub fn load(&mut self, image: &Image) -> Result<(), String> {
+        for segment in &image.segments {
+            if segment.bank.is_some() {
+                return Err(format!(
+                    "segment at ${:04X} is banked, which can't be run",
+                    segment.address
+                ));
+            }
+            self.ram.load(segment.address, &segment.bytes);
+        }
+        Ok(())
+    }
+
+    pub fn set_reset_vector(&mut self, address: u16) {
+        self.ram.load(RESET_VECTOR, &address.to_le_bytes());
+    }
+
+    // Runs until the program exits, gets stuck or fails, or has had
+    // `cycles` cycles in total if there's a limit.
+    pub fn run(&mut self, cpu: &mut Cpu, cycles: Option<u64>) -> Stop {
+        let stop = loop {
+            if let Some(code) = self.exit {
+                break Stop::Exit(code);
+            }
+            if let Some(error) = self.error.take() {
+                break Stop::Error(error.to_string());
+            }
+            if cycles.is_some_and(|limit| cpu.cycles >= limit) {
+                break Stop::OutOfCycles;
+            }
+            let pc = cpu.pc;
+            if let Err(error) = cpu.step(self) {
+                break Stop::Error(error);
+            }
+            if cpu.pc == pc {
+                break Stop::Stuck(pc);
+            }
+        };
+        match self.output.flush() {
+            Err(error) if !matches!(stop, Stop::Error(_)) => Stop::Error(error.to_string()),
+            _ => stop,
+        }
+    }
+}
+
+impl<R: Read, W: Write> Bus for Machine<R, W> {
+    fn read(&mut self, address: u16) -> u8 {
+        if address != GETCHAR {
+            return self.ram.read(address);
+        }
+        let mut byte = [0];
+        match self.input.read(&mut byte) {
+            Ok(1) => byte[0],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            PUTCHAR => {
+                if let Err(error) = self.output.write_all(&[value]) {
+                    self.error.get_or_insert(error);
+                }
+            }
+            EXIT => self.exit = Some(value),
+            _ => self.ram.write(address, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::emu::Variant;
+    use crate::lang::assembler::Assembler;
+    use crate::lang::source::SourceLine;
+
+    fn run(source: &str, input: &[u8], cycles: Option<u64>) -> (Stop, String) {
+        let program = Assembler::new()
+            .assemble(&SourceLine::from_source("test.s", source))
+            .unwrap();
+        let image = Image::from_program(&program, 0x0200).unwrap();
+        let mut machine = Machine::new(input, Vec::new());
+        machine.load(&image).unwrap();
+        machine.set_reset_vector(0x0200);
+        let mut cpu = Cpu::new(Variant::Nmos);
+        cpu.reset(&mut machine);
+        let stop = machine.run(&mut cpu, cycles);
+        (stop, String::from_utf8(machine.output().clone()).unwrap())
+    }
+
+    #[test]
+    fn test_io() {
+        // Upper cases its input
+        let source = r#"
+PUTCHAR = $F001
+EXIT = $F002
+GETCHAR = $F004
+loop:
+  LDA GETCHAR
+  BEQ done
+  CMP #$61
+  BCC put
+  AND #$DF
+put:
+  STA PUTCHAR
+  JMP loop
+done:
+  LDA #3
+  STA EXIT
+"#;
+        assert_eq!(
+            run(source, b"hello, world\n", None),
+            (Stop::Exit(3), "HELLO, WORLD\n".to_string())
+        );
+        assert_eq!(run(source, b"", None), (Stop::Exit(3), String::new()));
+    }
+
+    #[test]
+    fn test_stops() {
+        assert_eq!(
+            run("  LDA #1\nforever:\n  JMP forever\n", b"", None),
+            (Stop::Stuck(0x0202), String::new())
+        );
+        assert_eq!(
+            run("loop:\n  INX\n  JMP loop\n", b"", Some(1000)),
+            (Stop::OutOfCycles, String::new())
+        );
+        assert_eq!(
+            run("  .byte $02\n", b"", None),
+            (
+                Stop::Error("illegal opcode $02 at $0200".to_string()),
+                String::new()
+            )
+        );
+    }
+}
@@ -1,4 +1,11 @@
+pub mod arithmetic;
+pub mod assembler;
 pub mod ast;
-mod expression;
+pub mod diagnostic;
+pub mod encoding;
+pub mod expression;
 pub mod instruction;
+pub mod listing;
+pub mod number;
 pub mod parser;
+pub mod source;
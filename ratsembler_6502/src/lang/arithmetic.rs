@@ -0,0 +1,427 @@
+use super::encoding::unescape;
+use super::encoding::Encoding;
+use super::parser::Rule;
+
+use pest::iterators::Pair;
+use pest::pratt_parser::{Assoc, Op, PrattParser};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Negate,
+    BitwiseNot,
+    LogicalNot,
+    LowByte,
+    HighByte,
+    // Only known once the linker has put the label in a bank
+    BankByte,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    LogicalOr,
+    LogicalAnd,
+    BitwiseOr,
+    BitwiseXor,
+    BitwiseAnd,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArithmeticExpression {
+    Number(i64),
+    // A character literal, whose value depends on the current encoding
+    Character(char),
+    Symbol(String),
+    Defined(String),
+    SizeOf(String),
+    Unary(UnaryOperator, Box<ArithmeticExpression>),
+    Binary(
+        BinaryOperator,
+        Box<ArithmeticExpression>,
+        Box<ArithmeticExpression>,
+    ),
+}
+
+// Anything that can hand out values for symbols while an expression is being
+// evaluated. `lookup` should return an error message for symbols that exist
+// but can't be used as a number yet (labels, for instance).
+pub trait SymbolLookup {
+    fn lookup(&self, name: &str) -> Result<i64, String>;
+    fn is_defined(&self, name: &str) -> bool;
+
+    // Loop variables stand in for a whole expression rather than a value.
+    // Aliased expressions are expected to be closed, they're evaluated
+    // without looking up any further aliases.
+    fn alias(&self, _name: &str) -> Option<&ArithmeticExpression> {
+        None
+    }
+
+    // The size in bytes of a struct, union or struct member.
+    fn size_of(&self, name: &str) -> Result<i64, String> {
+        Err(format!("unknown type '{}'", name))
+    }
+
+    fn encode(&self, character: char) -> Result<i64, String> {
+        Encoding::Ascii
+            .encode(character)
+            .map(i64::from)
+            .ok_or_else(|| format!("character {:?} can't be encoded in ascii", character))
+    }
+}
+
+struct WithoutAliases<'a>(&'a dyn SymbolLookup);
+
+impl SymbolLookup for WithoutAliases<'_> {
+    fn lookup(&self, name: &str) -> Result<i64, String> {
+        self.0.lookup(name)
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.0.is_defined(name)
+    }
+
+    fn size_of(&self, name: &str) -> Result<i64, String> {
+        self.0.size_of(name)
+    }
+
+    fn encode(&self, character: char) -> Result<i64, String> {
+        self.0.encode(character)
+    }
+}
+
+lazy_static! {
+    static ref PRATT_PARSER: PrattParser<Rule> = PrattParser::new()
+        .op(Op::infix(Rule::logical_or, Assoc::Left))
+        .op(Op::infix(Rule::logical_and, Assoc::Left))
+        .op(Op::infix(Rule::bitwise_or, Assoc::Left))
+        .op(Op::infix(Rule::bitwise_xor, Assoc::Left))
+        .op(Op::infix(Rule::bitwise_and, Assoc::Left))
+        .op(Op::infix(Rule::equal, Assoc::Left) | Op::infix(Rule::not_equal, Assoc::Left))
+        .op(Op::infix(Rule::less, Assoc::Left)
+            | Op::infix(Rule::less_equal, Assoc::Left)
+            | Op::infix(Rule::greater, Assoc::Left)
+            | Op::infix(Rule::greater_equal, Assoc::Left))
+        .op(Op::infix(Rule::shift_left, Assoc::Left) | Op::infix(Rule::shift_right, Assoc::Left))
+        .op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::subtract, Assoc::Left))
+        .op(Op::infix(Rule::multiply, Assoc::Left)
+            | Op::infix(Rule::divide, Assoc::Left)
+            | Op::infix(Rule::modulo, Assoc::Left))
+        .op(Op::prefix(Rule::negate)
+            | Op::prefix(Rule::bitwise_not)
+            | Op::prefix(Rule::logical_not)
+            | Op::prefix(Rule::low_byte)
+            | Op::prefix(Rule::high_byte)
+            | Op::prefix(Rule::bank_byte));
+}
+
+impl ArithmeticExpression {
+    pub fn from_arithmetic_pair(arithmetic: Pair<Rule>) -> ArithmeticExpression {
+        assert_eq!(arithmetic.as_rule(), Rule::arithmetic);
+        PRATT_PARSER
+            .map_primary(|primary| match primary.as_rule() {
+                Rule::hex_number => ArithmeticExpression::Number(
+                    i64::from_str_radix(&primary.as_str()[1..], 16).unwrap(),
+                ),
+                Rule::binary_number => ArithmeticExpression::Number(
+                    i64::from_str_radix(&primary.as_str()[1..], 2).unwrap(),
+                ),
+                Rule::decimal_number => {
+                    ArithmeticExpression::Number(primary.as_str().parse().unwrap())
+                }
+                Rule::character => {
+                    let text = primary.as_str();
+                    let character = unescape(&text[1..text.len() - 1]).chars().next().unwrap();
+                    ArithmeticExpression::Character(character)
+                }
+                Rule::symbol => ArithmeticExpression::Symbol(primary.as_str().into()),
+                Rule::defined => ArithmeticExpression::Defined(
+                    primary.into_inner().next().unwrap().as_str().into(),
+                ),
+                Rule::sizeof => ArithmeticExpression::SizeOf(
+                    primary.into_inner().next().unwrap().as_str().into(),
+                ),
+                Rule::arithmetic => ArithmeticExpression::from_arithmetic_pair(primary),
+                _ => unreachable!(),
+            })
+            .map_prefix(|operator, operand| {
+                let operator = match operator.as_rule() {
+                    Rule::negate => UnaryOperator::Negate,
+                    Rule::bitwise_not => UnaryOperator::BitwiseNot,
+                    Rule::logical_not => UnaryOperator::LogicalNot,
+                    Rule::low_byte => UnaryOperator::LowByte,
+                    Rule::high_byte => UnaryOperator::HighByte,
+                    Rule::bank_byte => UnaryOperator::BankByte,
+                    _ => unreachable!(),
+                };
+                ArithmeticExpression::Unary(operator, Box::new(operand))
+            })
+            .map_infix(|left, operator, right| {
+                let operator = match operator.as_rule() {
+                    Rule::logical_or => BinaryOperator::LogicalOr,
+                    Rule::logical_and => BinaryOperator::LogicalAnd,
+                    Rule::bitwise_or => BinaryOperator::BitwiseOr,
+                    Rule::bitwise_xor => BinaryOperator::BitwiseXor,
+                    Rule::bitwise_and => BinaryOperator::BitwiseAnd,
+                    Rule::equal => BinaryOperator::Equal,
+                    Rule::not_equal => BinaryOperator::NotEqual,
+                    Rule::less => BinaryOperator::Less,
+                    Rule::less_equal => BinaryOperator::LessEqual,
+                    Rule::greater => BinaryOperator::Greater,
+                    Rule::greater_equal => BinaryOperator::GreaterEqual,
+                    Rule::shift_left => BinaryOperator::ShiftLeft,
+                    Rule::shift_right => BinaryOperator::ShiftRight,
+                    Rule::add => BinaryOperator::Add,
+                    Rule::subtract => BinaryOperator::Subtract,
+                    Rule::multiply => BinaryOperator::Multiply,
+                    Rule::divide => BinaryOperator::Divide,
+                    Rule::modulo => BinaryOperator::Modulo,
+                    _ => unreachable!(),
+                };
+                ArithmeticExpression::Binary(operator, Box::new(left), Box::new(right))
+            })
+            .parse(arithmetic.into_inner())
+    }
+
+    pub fn evaluate(&self, symbols: &dyn SymbolLookup) -> Result<i64, String> {
+        match self {
+            ArithmeticExpression::Number(value) => Ok(*value),
+            ArithmeticExpression::Character(character) => symbols.encode(*character),
+            ArithmeticExpression::Symbol(name) => match symbols.alias(name) {
+                Some(alias) => alias.evaluate(&WithoutAliases(symbols)),
+                None => symbols.lookup(name),
+            },
+            ArithmeticExpression::Defined(name) => {
+                Ok((symbols.alias(name).is_some() || symbols.is_defined(name)) as i64)
+            }
+            ArithmeticExpression::SizeOf(name) => symbols.size_of(name),
+            ArithmeticExpression::Unary(UnaryOperator::BankByte, _) => {
+                Err("'^' only works on a label".into())
+            }
+            ArithmeticExpression::Unary(operator, operand) => {
+                let value = operand.evaluate(symbols)?;
+                Ok(match operator {
+                    UnaryOperator::Negate => value.wrapping_neg(),
+                    UnaryOperator::BitwiseNot => !value,
+                    UnaryOperator::LogicalNot => (value == 0) as i64,
+                    UnaryOperator::LowByte => value & 0xFF,
+                    UnaryOperator::HighByte => (value >> 8) & 0xFF,
+                    UnaryOperator::BankByte => unreachable!(),
+                })
+            }
+            ArithmeticExpression::Binary(operator, left, right) => {
+                let left = left.evaluate(symbols)?;
+                let right = right.evaluate(symbols)?;
+                Ok(match operator {
+                    BinaryOperator::LogicalOr => (left != 0 || right != 0) as i64,
+                    BinaryOperator::LogicalAnd => (left != 0 && right != 0) as i64,
+                    BinaryOperator::BitwiseOr => left | right,
+                    BinaryOperator::BitwiseXor => left ^ right,
+                    BinaryOperator::BitwiseAnd => left & right,
+                    BinaryOperator::Equal => (left == right) as i64,
+                    BinaryOperator::NotEqual => (left != right) as i64,
+                    BinaryOperator::Less => (left < right) as i64,
+                    BinaryOperator::LessEqual => (left <= right) as i64,
+                    BinaryOperator::Greater => (left > right) as i64,
+                    BinaryOperator::GreaterEqual => (left >= right) as i64,
+                    BinaryOperator::ShiftLeft => left.wrapping_shl(right as u32),
+                    BinaryOperator::ShiftRight => left.wrapping_shr(right as u32),
+                    BinaryOperator::Add => left.wrapping_add(right),
+                    BinaryOperator::Subtract => left.wrapping_sub(right),
+                    BinaryOperator::Multiply => left.wrapping_mul(right),
+                    BinaryOperator::Divide => {
+                        if right == 0 {
+                            return Err("division by zero".into());
+                        }
+                        left.wrapping_div(right)
+                    }
+                    BinaryOperator::Modulo => {
+                        if right == 0 {
+                            return Err("division by zero".into());
+                        }
+                        left.wrapping_rem(right)
+                    }
+                })
+            }
+        }
+    }
+
+    // Operands can refer to labels whose addresses aren't known until link
+    // time. Returns the label and constant offset for expressions of the form
+    // `label`, `label + n`, `n + label` or `label - n`.
+    pub fn as_label_offset(&self, symbols: &dyn SymbolLookup) -> Option<(String, i64)> {
+        match self {
+            ArithmeticExpression::Symbol(name) => match symbols.alias(name) {
+                Some(alias) => alias.as_label_offset(&WithoutAliases(symbols)),
+                None if symbols.lookup(name).is_err() => Some((name.clone(), 0)),
+                None => None,
+            },
+            ArithmeticExpression::Binary(BinaryOperator::Add, left, right) => {
+                if let Some((label, offset)) = left.as_label_offset(symbols) {
+                    Some((label, offset + right.evaluate(symbols).ok()?))
+                } else {
+                    let (label, offset) = right.as_label_offset(symbols)?;
+                    Some((label, offset + left.evaluate(symbols).ok()?))
+                }
+            }
+            ArithmeticExpression::Binary(BinaryOperator::Subtract, left, right) => {
+                let (label, offset) = left.as_label_offset(symbols)?;
+                Some((label, offset - right.evaluate(symbols).ok()?))
+            }
+            _ => None,
+        }
+    }
+
+    // The label in `^label`, whose bank number is filled in at link time.
+    pub fn as_bank_label(&self, symbols: &dyn SymbolLookup) -> Option<String> {
+        match self {
+            ArithmeticExpression::Unary(UnaryOperator::BankByte, operand) => {
+                match operand.as_label_offset(symbols)? {
+                    (label, 0) => Some(label),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // `<label` or `>label`, and `<(label + n)` and so on, whose bytes are
+    // filled in at link time. Returns the operator along with the label and
+    // offset.
+    pub fn as_byte_label(
+        &self,
+        symbols: &dyn SymbolLookup,
+    ) -> Option<(UnaryOperator, String, i64)> {
+        match self {
+            ArithmeticExpression::Unary(
+                operator @ (UnaryOperator::LowByte | UnaryOperator::HighByte),
+                operand,
+            ) => {
+                let (label, offset) = operand.as_label_offset(symbols)?;
+                Some((*operator, label, offset))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::Assembler6502Parser;
+    use super::*;
+
+    use pest::Parser;
+    use std::collections::HashMap;
+
+    impl SymbolLookup for HashMap<String, i64> {
+        fn lookup(&self, name: &str) -> Result<i64, String> {
+            self.get(name)
+                .copied()
+                .ok_or(format!("undefined symbol '{}'", name))
+        }
+
+        fn is_defined(&self, name: &str) -> bool {
+            self.contains_key(name)
+        }
+    }
+
+    fn evaluate(input: &str, symbols: &HashMap<String, i64>) -> Result<i64, String> {
+        let pair = Assembler6502Parser::parse(Rule::arithmetic, input)
+            .unwrap()
+            .next()
+            .unwrap();
+        ArithmeticExpression::from_arithmetic_pair(pair).evaluate(symbols)
+    }
+
+    #[test]
+    fn test_literals() {
+        let symbols = HashMap::new();
+        assert_eq!(evaluate("$ff", &symbols), Ok(0xFF));
+        assert_eq!(evaluate("%1010", &symbols), Ok(10));
+        assert_eq!(evaluate("1234", &symbols), Ok(1234));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let symbols = HashMap::new();
+        assert_eq!(evaluate("2 + 3 * 4", &symbols), Ok(14));
+        assert_eq!(evaluate("(2 + 3) * 4", &symbols), Ok(20));
+        assert_eq!(evaluate("1 << 4 | 1", &symbols), Ok(17));
+        assert_eq!(evaluate("10 - 4 - 3", &symbols), Ok(3));
+        assert_eq!(evaluate("1 + 1 == 2 && 3 > 2", &symbols), Ok(1));
+        assert_eq!(evaluate("7 % 4", &symbols), Ok(3));
+    }
+
+    #[test]
+    fn test_unary_operators() {
+        let symbols = HashMap::new();
+        assert_eq!(evaluate("<$1234", &symbols), Ok(0x34));
+        assert_eq!(evaluate(">$1234", &symbols), Ok(0x12));
+        assert_eq!(evaluate("-5 + 6", &symbols), Ok(1));
+        assert_eq!(evaluate("!0", &symbols), Ok(1));
+        assert_eq!(evaluate("~0 & $ff", &symbols), Ok(0xFF));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut symbols = HashMap::new();
+        symbols.insert("MACHINE".to_string(), 2);
+        assert_eq!(evaluate("MACHINE == 2", &symbols), Ok(1));
+        assert_eq!(evaluate("defined(MACHINE)", &symbols), Ok(1));
+        assert_eq!(evaluate("defined(OTHER)", &symbols), Ok(0));
+        assert!(evaluate("OTHER + 1", &symbols).is_err());
+        assert!(evaluate("1 / (MACHINE - 2)", &symbols).is_err());
+    }
+
+    #[test]
+    fn test_label_offsets() {
+        let mut symbols = HashMap::new();
+        symbols.insert("ROW".to_string(), 3);
+        let label_offset = |input: &str| {
+            let pair = Assembler6502Parser::parse(Rule::arithmetic, input)
+                .unwrap()
+                .next()
+                .unwrap();
+            ArithmeticExpression::from_arithmetic_pair(pair).as_label_offset(&symbols)
+        };
+        assert_eq!(label_offset("table"), Some(("table".to_string(), 0)));
+        assert_eq!(
+            label_offset("table + ROW * 2"),
+            Some(("table".to_string(), 6))
+        );
+        assert_eq!(
+            label_offset("1 + table - 2"),
+            Some(("table".to_string(), -1))
+        );
+        assert_eq!(label_offset("ROW + 1"), None);
+        assert_eq!(label_offset("table * 2"), None);
+    }
+
+    #[test]
+    fn test_bank_labels() {
+        let symbols = HashMap::new();
+        let bank_label = |input: &str| {
+            let pair = Assembler6502Parser::parse(Rule::arithmetic, input)
+                .unwrap()
+                .next()
+                .unwrap();
+            ArithmeticExpression::from_arithmetic_pair(pair).as_bank_label(&symbols)
+        };
+        assert_eq!(bank_label("^far"), Some("far".to_string()));
+        assert_eq!(bank_label("^far + 1"), None);
+        assert_eq!(bank_label("a ^ b"), None);
+        assert!(evaluate("^$1234", &symbols).is_err());
+        assert_eq!(evaluate("6 ^ 3", &symbols), Ok(5));
+    }
+}
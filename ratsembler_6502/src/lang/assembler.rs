@@ -0,0 +1,2726 @@
+use super::arithmetic::ArithmeticExpression;
+use super::arithmetic::BinaryOperator;
+use super::arithmetic::SymbolLookup;
+use super::ast::moved_cursor;
+use super::ast::LongBranch;
+use super::ast::Program;
+use super::ast::Statement;
+use super::diagnostic::Diagnostic;
+use super::encoding::unescape;
+use super::encoding::CharacterMap;
+use super::encoding::Encoding;
+use super::encoding::ENCODING_NAMES;
+use super::expression::Expression;
+use super::expression::LongOperand;
+use super::expression::ShortOperand;
+use super::listing::Listing;
+use super::listing::ListingLine;
+use super::parser::Assembler6502Parser;
+use super::parser::Rule;
+use super::source::SourceLine;
+use super::source::SourceSpan;
+
+use crate::elf::relocatable::base_section_name;
+use crate::elf::relocatable::Rom;
+use crate::output::ines::InesHeader;
+use crate::output::ines::Mirroring;
+use crate::output::ines::{MAX_BANKS, MAX_MAPPER, MAX_SUBMAPPER};
+
+use pest::iterators::Pair;
+use pest::Parser;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+const CONDITIONAL_DIRECTIVES: &[&str] = &[".if", ".ifdef", ".ifndef", ".elseif", ".else", ".endif"];
+const REPETITION_DIRECTIVES: &[&str] = &[".rept", ".irp", ".for"];
+const MAX_REPETITIONS: usize = 0x10000;
+const DEFINITION_ENDS: &[&str] = &[".endstruct", ".endunion", ".endenum"];
+
+struct Conditional {
+    span: SourceSpan,
+    // Whether the region the block opened in was being assembled. If it
+    // wasn't, none of the branches can be taken.
+    parent_active: bool,
+    taken: bool,
+    active: bool,
+    seen_else: bool,
+}
+
+// A `.rept`, `.irp` or `.for` block whose body is still being read. Nested
+// blocks are collected as part of the body and expanded along with it.
+struct Repetition {
+    span: SourceSpan,
+    variable: Option<String>,
+    values: Vec<ArithmeticExpression>,
+    depth: usize,
+    body: Vec<SourceLine>,
+}
+
+// One level of a .struct or .union being laid out. Struct fields follow one
+// another, union fields all start at the same offset.
+struct Layout {
+    union: bool,
+    start: i64,
+    size: i64,
+}
+
+impl Layout {
+    fn directive(&self) -> &'static str {
+        if self.union {
+            ".union"
+        } else {
+            ".struct"
+        }
+    }
+
+    fn next_offset(&self) -> i64 {
+        if self.union {
+            self.start
+        } else {
+            self.start + self.size
+        }
+    }
+
+    fn add(&mut self, size: i64) {
+        if self.union {
+            self.size = self.size.max(size);
+        } else {
+            self.size += size;
+        }
+    }
+}
+
+// A `.struct`, `.union` or `.enum` whose members are still being read.
+// Members are defined as `prefix` followed by their name, so the prefix is
+// `Name::`, empty for an anonymous enum, or None if the opening directive
+// was bad and nothing should be defined.
+enum Definition {
+    // Anonymous structs and unions nested inside a definition are further
+    // levels of the same layout.
+    Layout {
+        span: SourceSpan,
+        prefix: Option<String>,
+        levels: Vec<Layout>,
+    },
+    Enumeration {
+        span: SourceSpan,
+        prefix: Option<String>,
+        next: i64,
+    },
+}
+
+// A `.proc` or `.scope` that hasn't been closed yet. Symbols declared inside
+// it are qualified with its prefix, e.g. `main::loop`.
+struct Scope {
+    span: SourceSpan,
+    directive: &'static str,
+    prefix: String,
+    procedure: Option<String>,
+}
+
+// A section that isn't being assembled into at the moment. While one is,
+// its program and character map are swapped out into the assembler.
+struct SectionState {
+    name: String,
+    bank: Option<(Rom, usize)>,
+    program: Program,
+    charmap: CharacterMap,
+}
+
+impl SectionState {
+    fn new(name: &str, bank: Option<(Rom, usize)>) -> SectionState {
+        SectionState {
+            name: name.to_string(),
+            bank,
+            program: Program::new(),
+            charmap: CharacterMap::default(),
+        }
+    }
+}
+
+// Code before the first `.section` goes here.
+const DEFAULT_SECTION: &str = "text";
+
+// Drives assembly one source line at a time. Lines are only handed to the
+// parser when they're in an active conditional region, so skipped regions
+// can contain syntax for other targets.
+pub struct Assembler {
+    constants: HashMap<String, i64>,
+    labels: HashSet<String>,
+    // Sizes of structs, unions and their fields, for `sizeof`.
+    sizes: HashMap<String, i64>,
+    // How strings and character literals are turned into bytes, in the
+    // current section. Each section has its own.
+    charmap: CharacterMap,
+    loop_variables: Vec<(String, ArithmeticExpression)>,
+    conditionals: Vec<Conditional>,
+    repetition: Option<Repetition>,
+    definition: Option<Definition>,
+    scopes: Vec<Scope>,
+    anonymous_scopes: usize,
+    // Each statement's section and index, and the prefix of the scope it
+    // was in.
+    scoped_statements: Vec<(usize, usize, String)>,
+    // Each long branch's section and index, and the line it's on, to say
+    // which ones needed a JMP.
+    long_branches: Vec<(usize, usize, SourceSpan)>,
+    include_paths: Vec<PathBuf>,
+    // Canonical paths of the files currently being read, outermost first.
+    include_stack: Vec<PathBuf>,
+    dependencies: Vec<PathBuf>,
+    diagnostics: Vec<Diagnostic>,
+    listing_lines: Vec<ListingLine>,
+    // Constants looked up, and the index of the listing line that used them.
+    // Lookups only get a shared reference, hence the RefCell.
+    references: RefCell<Vec<(usize, String)>>,
+    definitions: HashMap<String, SourceSpan>,
+    sections: Vec<SectionState>,
+    current_section: usize,
+    ines: InesHeader,
+    // Start a new section at each top level label, see
+    // `start_function_section`
+    function_sections: bool,
+    // The current section's program
+    program: Program,
+}
+
+impl SymbolLookup for Assembler {
+    fn lookup(&self, name: &str) -> Result<i64, String> {
+        match self.resolve(name) {
+            Some(scoped) => match self.constants.get(&scoped) {
+                Some(value) => {
+                    if let Some(line) = self.listing_lines.len().checked_sub(1) {
+                        self.references.borrow_mut().push((line, scoped));
+                    }
+                    Ok(*value)
+                }
+                None => Err(format!("'{}' is a label, not a constant", name)),
+            },
+            None => Err(format!("undefined symbol '{}'", name)),
+        }
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.resolve(name).is_some()
+    }
+
+    fn alias(&self, name: &str) -> Option<&ArithmeticExpression> {
+        self.loop_variables
+            .iter()
+            .rev()
+            .find(|(variable, _)| variable == name)
+            .map(|(_, value)| value)
+    }
+
+    fn size_of(&self, name: &str) -> Result<i64, String> {
+        scoped_names(self.scope_prefix(), name)
+            .find_map(|scoped| self.sizes.get(&scoped))
+            .copied()
+            .ok_or_else(|| format!("unknown type '{}'", name))
+    }
+
+    fn encode(&self, character: char) -> Result<i64, String> {
+        self.charmap.encode(character).map(i64::from)
+    }
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Assembler::new()
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler {
+            constants: HashMap::new(),
+            labels: HashSet::new(),
+            sizes: HashMap::new(),
+            charmap: CharacterMap::default(),
+            loop_variables: Vec::new(),
+            conditionals: Vec::new(),
+            repetition: None,
+            definition: None,
+            scopes: Vec::new(),
+            anonymous_scopes: 0,
+            scoped_statements: Vec::new(),
+            long_branches: Vec::new(),
+            include_paths: Vec::new(),
+            include_stack: Vec::new(),
+            dependencies: Vec::new(),
+            diagnostics: Vec::new(),
+            listing_lines: Vec::new(),
+            references: RefCell::new(Vec::new()),
+            definitions: HashMap::new(),
+            sections: vec![SectionState::new(DEFAULT_SECTION, None)],
+            current_section: 0,
+            ines: InesHeader::default(),
+            function_sections: false,
+            program: Program::new(),
+        }
+    }
+
+    // Predefines a constant, as if `name = value` appeared before the source.
+    pub fn define(&mut self, name: &str, value: i64) {
+        self.constants.insert(name.to_string(), value);
+    }
+
+    // Puts each routine in a section of its own, so the linker can throw
+    // away the ones nothing uses.
+    pub fn set_function_sections(&mut self, function_sections: bool) {
+        self.function_sections = function_sections;
+    }
+
+    // Directories searched by `.include` and `.incbin`, after the directory
+    // of the file doing the including.
+    pub fn add_include_path(&mut self, path: impl Into<PathBuf>) {
+        self.include_paths.push(path.into());
+    }
+
+    // Every file read during assembly, in the order they were first read.
+    pub fn dependencies(&self) -> &[PathBuf] {
+        &self.dependencies
+    }
+
+    // Every line read so far, with what it assembled to. Meant to be taken
+    // once assembly has finished.
+    pub fn listing(&mut self) -> Listing {
+        let mut lines = mem::take(&mut self.listing_lines);
+        for (line, name) in self.references.take() {
+            if !lines[line].references.contains(&name) {
+                lines[line].references.push(name);
+            }
+        }
+        Listing {
+            lines,
+            definitions: mem::take(&mut self.definitions),
+            constants: self.constants.clone(),
+        }
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.is_error())
+    }
+
+    pub fn assemble_file(&mut self, path: &Path) -> io::Result<Option<Program>> {
+        let source = fs::read_to_string(path)?;
+        self.dependencies.push(path.to_path_buf());
+        self.include_stack.push(path.canonicalize()?);
+        let program = self.assemble(&SourceLine::from_source(&path.to_string_lossy(), &source));
+        self.include_stack.pop();
+        Ok(program)
+    }
+
+    // Assembles the given lines. Returns None if any errors were reported,
+    // in which case they can be found in `diagnostics`.
+    pub fn assemble(&mut self, lines: &[SourceLine]) -> Option<Program> {
+        for line in lines {
+            self.assemble_line(line);
+        }
+
+        for conditional in mem::take(&mut self.conditionals) {
+            self.diagnostics.push(Diagnostic::error(
+                &conditional.span,
+                "unterminated .if block",
+            ));
+        }
+        if let Some(repetition) = self.repetition.take() {
+            self.diagnostics.push(Diagnostic::error(
+                &repetition.span,
+                "unterminated repetition block",
+            ));
+        }
+        for scope in mem::take(&mut self.scopes) {
+            self.diagnostics.push(Diagnostic::error(
+                &scope.span,
+                format!("unterminated {} block", scope.directive),
+            ));
+        }
+        // Put the current section back with the others, so they can all be
+        // laid out.
+        self.swap_section(self.current_section);
+        self.resolve_scoped_references();
+        self.resolve_forward_constants();
+        self.relax_branches();
+        match self.definition.take() {
+            Some(Definition::Layout { span, levels, .. }) => {
+                self.diagnostics.push(Diagnostic::error(
+                    &span,
+                    format!("unterminated {} block", levels[0].directive()),
+                ));
+            }
+            Some(Definition::Enumeration { span, .. }) => {
+                self.diagnostics
+                    .push(Diagnostic::error(&span, "unterminated .enum block"));
+            }
+            None => {}
+        }
+
+        let mut program = Program::new();
+        let sections = mem::replace(
+            &mut self.sections,
+            vec![SectionState::new(DEFAULT_SECTION, None)],
+        );
+        for section in sections {
+            program.append_section(&section.name, section.bank, section.program);
+        }
+        program.ines = mem::take(&mut self.ines);
+        // Everything assigned in the source, including scoped ones and struct
+        // and enum members, but not the ones from the command line
+        program.constants = self
+            .constants
+            .iter()
+            .filter(|(name, _)| self.definitions.contains_key(*name))
+            .map(|(name, value)| (name.clone(), *value))
+            .collect();
+        self.current_section = 0;
+        for line in &mut self.listing_lines {
+            line.address += program.sections[line.section].start;
+        }
+
+        if self.has_errors() {
+            None
+        } else {
+            Some(program)
+        }
+    }
+
+    // Long branches can only be sized once every label in their section is
+    // known. Lines after one that's made long move along with it.
+    fn relax_branches(&mut self) {
+        for (index, section) in self.sections.iter_mut().enumerate() {
+            let grown = section.program.relax_branches();
+            for line in &mut self.listing_lines {
+                if line.section == index {
+                    let end = moved_cursor(&grown, line.address + line.size);
+                    line.address = moved_cursor(&grown, line.address);
+                    line.size = end - line.address;
+                }
+            }
+        }
+        for (section, index, span) in mem::take(&mut self.long_branches) {
+            let program = &self.sections[section].program;
+            let Statement::Branch(branch) = &program.statements[index] else {
+                continue;
+            };
+            match &branch.target {
+                LongOperand::Label(label) | LongOperand::Offset(label, _)
+                    if branch.long && program.labels.contains_key(label) =>
+                {
+                    self.diagnostics.push(Diagnostic::note(
+                        &span,
+                        format!(
+                            "'{}' is out of range of a branch, so this uses a JMP",
+                            label
+                        ),
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn scope_prefix(&self) -> &str {
+        self.scopes.last().map_or("", |scope| &scope.prefix)
+    }
+
+    // Names are looked up in the current scope first, then each enclosing
+    // scope out to the global one. Returns the fully qualified name.
+    fn resolve(&self, name: &str) -> Option<String> {
+        scoped_names(self.scope_prefix(), name)
+            .find(|scoped| self.constants.contains_key(scoped) || self.labels.contains(scoped))
+    }
+
+    // Whether a fully qualified name has been used for anything, ignoring
+    // enclosing scopes since declarations are allowed to shadow them.
+    fn is_taken(&self, scoped: &str) -> bool {
+        self.constants.contains_key(scoped)
+            || self.labels.contains(scoped)
+            || self.sizes.contains_key(scoped)
+    }
+
+    // Labels and constants used before they were declared can only be
+    // matched up with the scope they belong to once everything has been
+    // read.
+    fn resolve_scoped_references(&mut self) {
+        for (section, index, prefix) in mem::take(&mut self.scoped_statements) {
+            for label in self.sections[section].program.statements[index].labels_mut() {
+                let scoped = scoped_names(&prefix, label).find(|scoped| {
+                    self.labels.contains(scoped) || self.constants.contains_key(scoped)
+                });
+                if let Some(scoped) = scoped {
+                    *label = scoped;
+                } else if let Some(global) = label.strip_prefix("::") {
+                    *label = global.to_string();
+                }
+            }
+        }
+    }
+
+    // Constants used before they were assigned were taken for labels, so
+    // they're filled in now that their values are known.
+    fn resolve_forward_constants(&mut self) {
+        let constant = |name: &str| self.constants.get(name).copied();
+        for section in &mut self.sections {
+            for statement in &mut section.program.statements {
+                statement.resolve_constants(constant);
+            }
+        }
+    }
+
+    // Statements always belong to the last line read. Lines that expand into
+    // other lines, like `.endr` or `.include`, don't produce any themselves.
+    fn push_statement(&mut self, statement: Statement) {
+        if let Some(line) = self.listing_lines.last_mut() {
+            line.size += statement.get_size();
+        }
+        let prefix = self.scope_prefix().to_string();
+        self.scoped_statements
+            .push((self.current_section, self.program.statements.len(), prefix));
+        self.program.push_statement(statement);
+    }
+
+    // Swaps the program and character map being assembled into with the
+    // ones stowed away for a section.
+    fn swap_section(&mut self, index: usize) {
+        let section = &mut self.sections[index];
+        mem::swap(&mut self.program, &mut section.program);
+        mem::swap(&mut self.charmap, &mut section.charmap);
+    }
+
+    // Carries on assembling into the named section, starting it if it's
+    // new. Coming back to a section doesn't need its bank repeated.
+    fn switch_section(&mut self, name: &str, bank: Option<(Rom, usize)>, span: &SourceSpan) {
+        let index = match self
+            .sections
+            .iter()
+            .position(|section| section.name == name)
+        {
+            Some(index) => {
+                let existing = self.sections[index].bank;
+                if bank.is_some() && bank != existing {
+                    self.diagnostics.push(Diagnostic::error(
+                        span,
+                        format!("section '{}' was already started in another bank", name),
+                    ));
+                    return;
+                }
+                index
+            }
+            None => {
+                self.sections.push(SectionState::new(name, bank));
+                self.sections.len() - 1
+            }
+        };
+        self.swap_section(self.current_section);
+        self.current_section = index;
+        self.swap_section(index);
+    }
+
+    // Moves on to a section named after a top level label, like `text.main`
+    // for `main` in `text`. Labels that the code before them can run on
+    // into stay where they are though, since the two have to stay together,
+    // as do several labels in a row.
+    fn start_function_section(&mut self, label: &str, span: &SourceSpan) {
+        let follows_on = match self.program.statements.last() {
+            Some(Statement::Instruction(expression)) => expression.falls_through(),
+            Some(Statement::Branch(_)) => true,
+            Some(_) => false,
+            None => !self.program.labels.is_empty(),
+        };
+        if follows_on {
+            return;
+        }
+        let section = &mut self.sections[self.current_section];
+        let name = format!("{}.{}", base_section_name(&section.name), label);
+        // Nothing's gone in the section yet, so it can just be renamed.
+        if self.program.statements.is_empty() {
+            section.name = name;
+            return;
+        }
+        let bank = section.bank;
+        self.switch_section(&name, bank, span);
+    }
+
+    fn is_active(&self) -> bool {
+        self.conditionals
+            .last()
+            .is_none_or(|conditional| conditional.active)
+    }
+
+    fn assemble_line(&mut self, line: &SourceLine) {
+        let name = directive_name(&line.text);
+        let listing_index = self.listing_lines.len();
+        self.listing_lines.push(ListingLine {
+            span: line.span.clone(),
+            text: line.text.clone(),
+            assembled: false,
+            address: self.program.get_cursor(),
+            size: 0,
+            section: self.current_section,
+            references: Vec::new(),
+        });
+
+        if let Some(repetition) = &mut self.repetition {
+            match name.as_deref() {
+                Some(name) if REPETITION_DIRECTIVES.contains(&name) => repetition.depth += 1,
+                Some(".endr") if repetition.depth == 0 => {
+                    let repetition = self.repetition.take().unwrap();
+                    self.expand(repetition);
+                    return;
+                }
+                Some(".endr") => repetition.depth -= 1,
+                _ => {}
+            }
+            repetition.body.push(line.clone());
+            return;
+        }
+
+        if let Some(name) = &name {
+            if CONDITIONAL_DIRECTIVES.contains(&name.as_str()) {
+                self.conditional(line, name);
+                return;
+            }
+        }
+
+        if !self.is_active() {
+            return;
+        }
+        self.listing_lines[listing_index].assembled = true;
+
+        match &self.definition {
+            Some(Definition::Layout { .. }) => return self.field_line(line),
+            Some(Definition::Enumeration { .. }) => return self.enum_line(line),
+            None => {}
+        }
+
+        let parsed = match Assembler6502Parser::parse(Rule::line, &line.text) {
+            Ok(mut pairs) => pairs.next().unwrap().into_inner().next().unwrap(),
+            Err(e) => {
+                self.diagnostics
+                    .push(Diagnostic::error(&line.span, e.variant.message()));
+                return;
+            }
+        };
+
+        match parsed.as_rule() {
+            Rule::expression => {
+                let (labels, expression) =
+                    match Expression::from_expression_pair_with_symbols(parsed, self) {
+                        Ok(parsed) => parsed,
+                        Err(message) => {
+                            self.diagnostics
+                                .push(Diagnostic::error(&line.span, message));
+                            return;
+                        }
+                    };
+                for label in labels {
+                    self.declare_label(label, &line.span);
+                }
+                if let Some((branch, target)) = expression.long_branch() {
+                    self.long_branches.push((
+                        self.current_section,
+                        self.program.statements.len(),
+                        line.span.clone(),
+                    ));
+                    self.push_statement(Statement::Branch(LongBranch::new(branch, target)));
+                    return;
+                }
+                if !expression.has_code() {
+                    self.diagnostics.push(Diagnostic::error(
+                        &line.span,
+                        "addressing mode is not supported by this instruction",
+                    ));
+                    return;
+                }
+                self.push_statement(Statement::Instruction(expression));
+            }
+            Rule::statement => {
+                for pair in parsed.into_inner() {
+                    match pair.as_rule() {
+                        Rule::label_dec => {
+                            let mut label = pair.as_str().to_string();
+                            label.pop();
+                            self.declare_label(label, &line.span);
+                        }
+                        Rule::directive => self.directive(pair, &line.span),
+                        Rule::constant_assignment => {
+                            let mut inner_pairs = pair.into_inner();
+                            let name = inner_pairs.next().unwrap().as_str().to_string();
+                            let value = ArithmeticExpression::from_arithmetic_pair(
+                                inner_pairs.next().unwrap(),
+                            );
+                            self.assign_constant(name, value, &line.span);
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn declare_label(&mut self, label: String, span: &SourceSpan) {
+        if self.function_sections && self.scopes.is_empty() {
+            self.start_function_section(&label, span);
+        }
+        let label = format!("{}{}", self.scope_prefix(), label);
+        if self.is_taken(&label) {
+            self.diagnostics.push(Diagnostic::error(
+                span,
+                format!("symbol '{}' is already defined", label),
+            ));
+            return;
+        }
+        self.labels.insert(label.clone());
+        self.definitions.insert(label.clone(), span.clone());
+        self.program.define_label(label);
+    }
+
+    fn assign_constant(&mut self, name: String, value: ArithmeticExpression, span: &SourceSpan) {
+        let name = format!("{}{}", self.scope_prefix(), name);
+        if self.is_taken(&name) {
+            self.diagnostics.push(Diagnostic::error(
+                span,
+                format!("symbol '{}' is already defined", name),
+            ));
+            return;
+        }
+        match value.evaluate(self) {
+            Ok(value) => {
+                self.definitions.insert(name.clone(), span.clone());
+                self.constants.insert(name, value);
+            }
+            Err(message) => self.diagnostics.push(Diagnostic::error(span, message)),
+        }
+    }
+
+    fn directive(&mut self, directive: Pair<Rule>, span: &SourceSpan) {
+        let mut inner_pairs = directive.into_inner();
+        let name = inner_pairs.next().unwrap().as_str().to_lowercase();
+        let arguments: Vec<Pair<Rule>> = inner_pairs.collect();
+
+        match name.as_str() {
+            ".error" | ".warning" => {
+                let Some(message) = self.string_argument(&name, &arguments, span) else {
+                    return;
+                };
+                if name == ".error" {
+                    self.diagnostics.push(Diagnostic::error(span, message));
+                } else {
+                    self.diagnostics.push(Diagnostic::warning(span, message));
+                }
+            }
+            ".assert" => {
+                if arguments.len() != 2
+                    || arguments[0].as_rule() != Rule::arithmetic
+                    || arguments[1].as_rule() != Rule::string_literal
+                {
+                    self.diagnostics.push(Diagnostic::error(
+                        span,
+                        ".assert expects an expression and a message",
+                    ));
+                    return;
+                }
+                let condition = ArithmeticExpression::from_arithmetic_pair(arguments[0].clone());
+                match condition.evaluate(self) {
+                    Ok(0) => {
+                        let message = string_from_literal(&arguments[1]);
+                        self.diagnostics.push(Diagnostic::error(span, message));
+                    }
+                    Ok(_) => {}
+                    Err(message) => self.diagnostics.push(Diagnostic::error(span, message)),
+                }
+            }
+            // `.bankbyte far` is the same as `.byte ^far`
+            ".bankbyte" => {
+                let mut bytes = Vec::new();
+                for argument in arguments {
+                    let label = (argument.as_rule() == Rule::arithmetic)
+                        .then(|| ArithmeticExpression::from_arithmetic_pair(argument))
+                        .and_then(|expression| expression.as_label_offset(self));
+                    match label {
+                        Some((label, 0)) => bytes.push(ShortOperand::Bank(label)),
+                        _ => {
+                            self.diagnostics.push(Diagnostic::error(
+                                span,
+                                ".bankbyte expects a list of labels",
+                            ));
+                            return;
+                        }
+                    }
+                }
+                self.push_statement(Statement::Bytes(bytes));
+            }
+            // Strings are only allowed in byte data, and are translated with
+            // the current character map. `.asciiz` adds a zero terminator.
+            ".byte" | ".ascii" | ".asciiz" | ".word" => {
+                let mut statement = match name.as_str() {
+                    ".word" => Statement::Words(Vec::new()),
+                    _ => Statement::Bytes(Vec::new()),
+                };
+                for argument in arguments {
+                    if let (Rule::string_literal, Statement::Bytes(bytes)) =
+                        (argument.as_rule(), &mut statement)
+                    {
+                        match self.charmap.encode_str(&string_from_literal(&argument)) {
+                            Ok(encoded) => {
+                                bytes.extend(encoded.into_iter().map(ShortOperand::Numeric))
+                            }
+                            Err(message) => {
+                                self.diagnostics.push(Diagnostic::error(span, message));
+                                return;
+                            }
+                        }
+                        continue;
+                    }
+                    if argument.as_rule() != Rule::arithmetic {
+                        self.diagnostics.push(Diagnostic::error(
+                            span,
+                            format!("{} expects a list of expressions", name),
+                        ));
+                        return;
+                    }
+                    let pushed = match &mut statement {
+                        Statement::Bytes(bytes) => {
+                            ShortOperand::from_arithmetic_pair(argument, self)
+                                .map(|byte| bytes.push(byte))
+                        }
+                        Statement::Words(words) => {
+                            LongOperand::from_arithmetic_pair(argument, self)
+                                .map(|word| words.push(word))
+                        }
+                        _ => unreachable!(),
+                    };
+                    if let Err(message) = pushed {
+                        self.diagnostics.push(Diagnostic::error(span, message));
+                        return;
+                    }
+                }
+                if let Statement::Bytes(bytes) = &mut statement {
+                    if name == ".asciiz" {
+                        bytes.push(ShortOperand::Numeric(0));
+                    }
+                }
+                self.push_statement(statement);
+            }
+            ".encoding" => {
+                let encoding = match arguments.as_slice() {
+                    [argument] => name_argument(argument),
+                    _ => None,
+                };
+                let Some(encoding) = encoding else {
+                    self.diagnostics.push(Diagnostic::error(
+                        span,
+                        ".encoding expects the name of an encoding",
+                    ));
+                    return;
+                };
+                match Encoding::from_name(&encoding) {
+                    // Switching encodings starts again without any .charmap
+                    // changes.
+                    Some(encoding) => self.charmap = CharacterMap::new(encoding),
+                    None => {
+                        let names: Vec<&str> =
+                            ENCODING_NAMES.iter().map(|(name, _)| *name).collect();
+                        self.diagnostics.push(Diagnostic::error(
+                            span,
+                            format!(
+                                "unknown encoding '{}', expected one of {}",
+                                encoding,
+                                names.join(", ")
+                            ),
+                        ));
+                    }
+                }
+            }
+            // .charmap 'A', $01 remaps a single character. The character is
+            // taken literally rather than through the current map.
+            ".charmap" => {
+                let [character, code] = arguments.as_slice() else {
+                    self.diagnostics.push(Diagnostic::error(
+                        span,
+                        ".charmap expects a character and its code",
+                    ));
+                    return;
+                };
+                let character = match (character.as_rule() == Rule::arithmetic)
+                    .then(|| ArithmeticExpression::from_arithmetic_pair(character.clone()))
+                {
+                    Some(ArithmeticExpression::Character(character)) => Ok(character),
+                    Some(other) => other.evaluate(self).and_then(|value| {
+                        u32::try_from(value)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("${:X} is not a character", value))
+                    }),
+                    None => Err(".charmap expects a character and its code".to_string()),
+                };
+                let code = self.evaluate_argument(&name, code).and_then(|code| {
+                    u8::try_from(code)
+                        .map_err(|_| format!("value ${:X} does not fit in a byte", code))
+                });
+                match (character, code) {
+                    (Ok(character), Ok(code)) => self.charmap.set(character, code),
+                    (Err(message), _) | (_, Err(message)) => {
+                        self.diagnostics.push(Diagnostic::error(span, message))
+                    }
+                }
+            }
+            // .section name[, prg|chr, bank]
+            ".section" => {
+                let bank = match arguments.as_slice() {
+                    [_] => Ok(None),
+                    [_, rom, bank] => {
+                        let rom = match name_argument(rom).map(|rom| rom.to_lowercase()) {
+                            Some(rom) if rom == "prg" => Ok(Rom::Prg),
+                            Some(rom) if rom == "chr" => Ok(Rom::Chr),
+                            _ => Err(".section banks are either prg or chr".to_string()),
+                        };
+                        let bank = self.evaluate_argument(&name, bank).and_then(|bank| {
+                            usize::try_from(bank)
+                                .map_err(|_| format!("bank {} is out of range", bank))
+                        });
+                        rom.and_then(|rom| Ok(Some((rom, bank?))))
+                    }
+                    _ => Err(
+                        ".section expects a name, then optionally prg or chr and a bank"
+                            .to_string(),
+                    ),
+                };
+                let section = arguments.first().and_then(name_argument);
+                match (section, bank) {
+                    (Some(section), Ok(bank)) => self.switch_section(&section, bank, span),
+                    (_, Err(message)) => self.diagnostics.push(Diagnostic::error(span, message)),
+                    (None, _) => self
+                        .diagnostics
+                        .push(Diagnostic::error(span, ".section expects a name")),
+                }
+            }
+            ".ines_prg" | ".ines_chr" | ".ines_mapper" | ".ines_submapper" => {
+                let limit = match name.as_str() {
+                    ".ines_prg" | ".ines_chr" => MAX_BANKS as i64,
+                    ".ines_mapper" => MAX_MAPPER as i64,
+                    _ => MAX_SUBMAPPER as i64,
+                };
+                let value = match arguments.as_slice() {
+                    [argument] => self.evaluate_argument(&name, argument),
+                    _ => Err(format!("{} expects a number", name)),
+                };
+                match value {
+                    Ok(value) if !(0..=limit).contains(&value) => {
+                        self.diagnostics.push(Diagnostic::error(
+                            span,
+                            format!("{} must be between 0 and {}", name, limit),
+                        ))
+                    }
+                    Ok(value) => match name.as_str() {
+                        ".ines_prg" => self.ines.prg_banks = Some(value as usize),
+                        ".ines_chr" => self.ines.chr_banks = Some(value as usize),
+                        ".ines_mapper" => self.ines.mapper = value as u16,
+                        _ => self.ines.submapper = Some(value as u8),
+                    },
+                    Err(message) => self.diagnostics.push(Diagnostic::error(span, message)),
+                }
+            }
+            // .ines_mirror horizontal|vertical|four_screen, or 0 or 1 as in
+            // the header
+            ".ines_mirror" => {
+                let mirroring = match arguments.as_slice() {
+                    [argument] => name_argument(argument)
+                        .and_then(|name| Mirroring::from_name(&name))
+                        .or_else(|| match self.evaluate_argument(&name, argument) {
+                            Ok(0) => Some(Mirroring::Horizontal),
+                            Ok(1) => Some(Mirroring::Vertical),
+                            _ => None,
+                        }),
+                    _ => None,
+                };
+                match mirroring {
+                    Some(mirroring) => self.ines.mirroring = mirroring,
+                    None => self.diagnostics.push(Diagnostic::error(
+                        span,
+                        ".ines_mirror expects horizontal, vertical or four_screen",
+                    )),
+                }
+            }
+            ".org" => {
+                let address = match arguments.as_slice() {
+                    [argument] => self.evaluate_argument(&name, argument),
+                    _ => Err(".org expects an address".to_string()),
+                };
+                match address.and_then(|address| {
+                    u16::try_from(address)
+                        .map_err(|_| format!("address ${:X} is out of range", address))
+                }) {
+                    Ok(address) => self.program.set_origin(address),
+                    Err(message) => self.diagnostics.push(Diagnostic::error(span, message)),
+                }
+            }
+            ".rept" | ".irp" | ".for" => {
+                // The body still has to be collected if the arguments are bad,
+                // it just won't be expanded.
+                let (variable, values) = match self.repetition_values(&name, &arguments) {
+                    Ok(repetition) => repetition,
+                    Err(message) => {
+                        self.diagnostics.push(Diagnostic::error(span, message));
+                        (None, Vec::new())
+                    }
+                };
+                self.repetition = Some(Repetition {
+                    span: span.clone(),
+                    variable,
+                    values,
+                    depth: 0,
+                    body: Vec::new(),
+                });
+            }
+            ".endr" => {
+                self.diagnostics
+                    .push(Diagnostic::error(span, ".endr without matching .rept"));
+            }
+            ".include" => {
+                let Some(file_name) = self.string_argument(&name, &arguments, span) else {
+                    return;
+                };
+                self.include(&file_name, span);
+            }
+            ".incbin" => {
+                if arguments.is_empty()
+                    || arguments.len() > 3
+                    || arguments[0].as_rule() != Rule::string_literal
+                    || arguments[1..]
+                        .iter()
+                        .any(|argument| argument.as_rule() != Rule::arithmetic)
+                {
+                    self.diagnostics.push(Diagnostic::error(
+                        span,
+                        ".incbin expects a file name and an optional offset and length",
+                    ));
+                    return;
+                }
+                let file_name = string_from_literal(&arguments[0]);
+                let mut bounds = Vec::new();
+                for argument in &arguments[1..] {
+                    match ArithmeticExpression::from_arithmetic_pair(argument.clone())
+                        .evaluate(self)
+                    {
+                        Ok(value) if value >= 0 => bounds.push(value as usize),
+                        Ok(value) => {
+                            self.diagnostics.push(Diagnostic::error(
+                                span,
+                                format!(".incbin offset and length can't be negative ({})", value),
+                            ));
+                            return;
+                        }
+                        Err(message) => {
+                            self.diagnostics.push(Diagnostic::error(span, message));
+                            return;
+                        }
+                    }
+                }
+                self.include_binary(
+                    &file_name,
+                    bounds.first().copied(),
+                    bounds.get(1).copied(),
+                    span,
+                );
+            }
+            ".res" => {
+                if arguments.is_empty() || arguments.len() > 2 {
+                    self.diagnostics.push(Diagnostic::error(
+                        span,
+                        ".res expects a size and an optional fill byte",
+                    ));
+                    return;
+                }
+                let reserved = self.storage_size(&name, &arguments[..1]).and_then(|size| {
+                    let fill = match arguments.get(1) {
+                        Some(fill) => self.evaluate_argument(&name, fill)?,
+                        None => 0,
+                    };
+                    if !(-0x80..=0xFF).contains(&fill) {
+                        return Err(format!("fill value ${:X} does not fit in a byte", fill));
+                    }
+                    Ok(vec![fill as u8; size as usize])
+                });
+                match reserved {
+                    Ok(bytes) => self.push_statement(Statement::Binary(bytes)),
+                    Err(message) => self.diagnostics.push(Diagnostic::error(span, message)),
+                }
+            }
+            // Reserves space for an instance of a struct.
+            ".tag" => match self.storage_size(&name, &arguments) {
+                Ok(size) => self.push_statement(Statement::Binary(vec![0; size as usize])),
+                Err(message) => self.diagnostics.push(Diagnostic::error(span, message)),
+            },
+            ".struct" | ".union" | ".enum" => {
+                // The body still has to be read if the name is bad, its
+                // members just won't be defined.
+                let prefix = match arguments.as_slice() {
+                    // An anonymous enum defines its members unqualified.
+                    [] if name == ".enum" => Some(self.scope_prefix().to_string()),
+                    [argument] => self
+                        .type_name(argument, span)
+                        .map(|type_name| format!("{}::", type_name)),
+                    _ => {
+                        self.diagnostics.push(Diagnostic::error(
+                            span,
+                            format!("{} expects a type name", name),
+                        ));
+                        None
+                    }
+                };
+                self.definition = Some(match name.as_str() {
+                    ".enum" => Definition::Enumeration {
+                        span: span.clone(),
+                        prefix,
+                        next: 0,
+                    },
+                    _ => Definition::Layout {
+                        span: span.clone(),
+                        prefix,
+                        levels: vec![Layout {
+                            union: name == ".union",
+                            start: 0,
+                            size: 0,
+                        }],
+                    },
+                });
+            }
+            ".proc" | ".scope" => {
+                let scope_name = match arguments.as_slice() {
+                    [] if name == ".scope" => {
+                        // Anonymous scopes get a name that can't be written in
+                        // source, so nothing outside can refer into them.
+                        self.anonymous_scopes += 1;
+                        Some(format!("@{}", self.anonymous_scopes))
+                    }
+                    [argument] => unqualified_name(argument),
+                    _ => None,
+                };
+                let Some(scope_name) = scope_name else {
+                    self.diagnostics
+                        .push(Diagnostic::error(span, format!("{} expects a name", name)));
+                    return;
+                };
+                let prefix = format!("{}{}::", self.scope_prefix(), scope_name);
+                let procedure = if name == ".proc" {
+                    self.declare_label(scope_name, span);
+                    Some(prefix.trim_end_matches("::").to_string())
+                } else {
+                    None
+                };
+                self.scopes.push(Scope {
+                    span: span.clone(),
+                    directive: if name == ".proc" { ".proc" } else { ".scope" },
+                    prefix,
+                    procedure,
+                });
+            }
+            ".endproc" | ".endscope" => {
+                let Some(scope) = self.scopes.pop() else {
+                    self.diagnostics.push(Diagnostic::error(
+                        span,
+                        format!("{} without matching .{}", name, &name[4..]),
+                    ));
+                    return;
+                };
+                if name[4..] != scope.directive[1..] {
+                    self.diagnostics.push(Diagnostic::error(
+                        span,
+                        format!(
+                            "expected .end{} to close {}",
+                            &scope.directive[1..],
+                            scope.directive
+                        ),
+                    ));
+                }
+                if let Some(procedure) = scope.procedure {
+                    self.program.end_label(&procedure);
+                }
+            }
+            _ if DEFINITION_ENDS.contains(&name.as_str()) => {
+                self.diagnostics.push(Diagnostic::error(
+                    span,
+                    format!("{} without matching .{}", name, &name[4..]),
+                ));
+            }
+            _ if CONDITIONAL_DIRECTIVES.contains(&name.as_str()) => {
+                self.diagnostics.push(Diagnostic::error(
+                    span,
+                    format!("{} must be the first thing on its line", name),
+                ));
+            }
+            _ => {
+                self.diagnostics.push(Diagnostic::error(
+                    span,
+                    format!("unknown directive '{}'", name),
+                ));
+            }
+        }
+    }
+
+    fn evaluate_argument(&self, name: &str, argument: &Pair<Rule>) -> Result<i64, String> {
+        if argument.as_rule() != Rule::arithmetic {
+            return Err(format!("{} expects an expression", name));
+        }
+        ArithmeticExpression::from_arithmetic_pair(argument.clone()).evaluate(self)
+    }
+
+    // How many bytes `.byte [count]`, `.word [count]`, `.res size` or
+    // `.tag Type [count]` take up, whether they're declaring a field or
+    // reserving space.
+    fn storage_size(&self, name: &str, arguments: &[Pair<Rule>]) -> Result<i64, String> {
+        let count = |index: usize| match arguments.get(index) {
+            Some(argument) => self.evaluate_argument(name, argument),
+            None => Ok(1),
+        };
+        let size = match (name, arguments.len()) {
+            (".byte", 0..=1) => count(0)?,
+            (".word", 0..=1) => 2 * count(0)?,
+            (".res", 1) => count(0)?,
+            (".tag", 1..=2) => {
+                let type_name = (arguments[0].as_rule() == Rule::arithmetic)
+                    .then(|| ArithmeticExpression::from_arithmetic_pair(arguments[0].clone()));
+                match type_name {
+                    Some(ArithmeticExpression::Symbol(type_name)) => {
+                        self.size_of(&type_name)? * count(1)?
+                    }
+                    _ => return Err(".tag expects a type name".to_string()),
+                }
+            }
+            (".byte" | ".word" | ".tag", _) => {
+                return Err(format!("{} expects an optional count", name))
+            }
+            (".res", _) => return Err(".res expects a size".to_string()),
+            _ => return Err(format!("{} can't be used to declare a field", name)),
+        };
+        if !(0..=0x10000).contains(&size) {
+            return Err(format!("{} size {} is out of range", name, size));
+        }
+        Ok(size)
+    }
+
+    // Checks the name given to a new struct, union or enum, and qualifies it
+    // with the current scope.
+    fn type_name(&mut self, argument: &Pair<Rule>, span: &SourceSpan) -> Option<String> {
+        let Some(type_name) = unqualified_name(argument) else {
+            self.diagnostics
+                .push(Diagnostic::error(span, "expected a type name"));
+            return None;
+        };
+        let type_name = format!("{}{}", self.scope_prefix(), type_name);
+        if self.is_taken(&type_name) {
+            self.diagnostics.push(Diagnostic::error(
+                span,
+                format!("symbol '{}' is already defined", type_name),
+            ));
+            return None;
+        }
+        Some(type_name)
+    }
+
+    // Struct fields and enum members are constants, with fields also
+    // remembering their size.
+    fn define_member(&mut self, name: String, value: i64, size: Option<i64>, span: &SourceSpan) {
+        if self.is_taken(&name) {
+            self.diagnostics.push(Diagnostic::error(
+                span,
+                format!("symbol '{}' is already defined", name),
+            ));
+            return;
+        }
+        if let Some(size) = size {
+            self.sizes.insert(name.clone(), size);
+        }
+        self.definitions.insert(name.clone(), span.clone());
+        self.constants.insert(name, value);
+    }
+
+    // Closes the innermost level of the struct, union or enum being defined.
+    // A mismatched end directive is reported but still closes it.
+    fn end_definition(&mut self, name: &str, span: &SourceSpan) {
+        let expected = match self.definition.as_mut().unwrap() {
+            Definition::Layout { levels, .. } => levels.last().unwrap().directive(),
+            Definition::Enumeration { .. } => ".enum",
+        };
+        if name[4..] != expected[1..] {
+            self.diagnostics.push(Diagnostic::error(
+                span,
+                format!("expected .end{} to close {}", &expected[1..], expected),
+            ));
+        }
+
+        let Some(Definition::Layout { prefix, levels, .. }) = &mut self.definition else {
+            self.definition = None;
+            return;
+        };
+        let level = levels.pop().unwrap();
+        if let Some(parent) = levels.last_mut() {
+            parent.add(level.size);
+            return;
+        }
+        if let Some(prefix) = prefix.take() {
+            let type_name = prefix.trim_end_matches("::").to_string();
+            self.sizes.insert(type_name, level.size);
+        }
+        self.definition = None;
+    }
+
+    fn field_line(&mut self, line: &SourceLine) {
+        let span = &line.span;
+        let parsed = match Assembler6502Parser::parse(Rule::field_line, &line.text) {
+            Ok(mut pairs) => pairs.next().unwrap().into_inner().next().unwrap(),
+            Err(e) => {
+                self.diagnostics
+                    .push(Diagnostic::error(span, e.variant.message()));
+                return;
+            }
+        };
+        let (field, directive) = match parsed.as_rule() {
+            Rule::field => {
+                let mut inner_pairs = parsed.into_inner();
+                let field = inner_pairs.next().unwrap().as_str().to_string();
+                (Some(field), inner_pairs.next().unwrap())
+            }
+            Rule::directive => (None, parsed),
+            // Blank lines and comments
+            _ => return,
+        };
+        let mut inner_pairs = directive.into_inner();
+        let name = inner_pairs.next().unwrap().as_str().to_lowercase();
+        let arguments: Vec<Pair<Rule>> = inner_pairs.collect();
+
+        if DEFINITION_ENDS.contains(&name.as_str()) {
+            self.end_definition(&name, span);
+            return;
+        }
+        if name == ".struct" || name == ".union" {
+            if field.is_some() || !arguments.is_empty() {
+                self.diagnostics.push(Diagnostic::error(
+                    span,
+                    format!("nested {} can't be named", name),
+                ));
+            }
+            let Some(Definition::Layout { levels, .. }) = &mut self.definition else {
+                unreachable!();
+            };
+            let start = levels.last().unwrap().next_offset();
+            levels.push(Layout {
+                union: name == ".union",
+                start,
+                size: 0,
+            });
+            return;
+        }
+
+        let size = match self.storage_size(&name, &arguments) {
+            Ok(size) => size,
+            Err(message) => {
+                self.diagnostics.push(Diagnostic::error(span, message));
+                return;
+            }
+        };
+        let Some(Definition::Layout { prefix, levels, .. }) = &mut self.definition else {
+            unreachable!();
+        };
+        let level = levels.last_mut().unwrap();
+        let offset = level.next_offset();
+        level.add(size);
+        if let (Some(prefix), Some(field)) = (prefix.clone(), field) {
+            self.define_member(prefix + &field, offset, Some(size), span);
+        }
+    }
+
+    fn enum_line(&mut self, line: &SourceLine) {
+        let span = &line.span;
+        if let Some(name) = directive_name(&line.text) {
+            if DEFINITION_ENDS.contains(&name.as_str()) {
+                self.end_definition(&name, span);
+                return;
+            }
+        }
+        let parsed = match Assembler6502Parser::parse(Rule::enum_line, &line.text) {
+            Ok(mut pairs) => pairs.next().unwrap().into_inner().next().unwrap(),
+            Err(e) => {
+                self.diagnostics
+                    .push(Diagnostic::error(span, e.variant.message()));
+                return;
+            }
+        };
+        if parsed.as_rule() != Rule::enum_member {
+            return;
+        }
+        let mut inner_pairs = parsed.into_inner();
+        let member = inner_pairs.next().unwrap().as_str().to_string();
+        let value = match inner_pairs.next() {
+            Some(value) => match ArithmeticExpression::from_arithmetic_pair(value).evaluate(self) {
+                Ok(value) => Some(value),
+                Err(message) => {
+                    self.diagnostics.push(Diagnostic::error(span, message));
+                    return;
+                }
+            },
+            None => None,
+        };
+        let Some(Definition::Enumeration { prefix, next, .. }) = &mut self.definition else {
+            unreachable!();
+        };
+        let value = value.unwrap_or(*next);
+        *next = value.wrapping_add(1);
+        if let Some(prefix) = prefix.clone() {
+            self.define_member(prefix + &member, value, None, span);
+        }
+    }
+
+    // Works out the loop variable and the value it takes on each time
+    // around for a repetition directive.
+    fn repetition_values(
+        &self,
+        name: &str,
+        arguments: &[Pair<Rule>],
+    ) -> Result<(Option<String>, Vec<ArithmeticExpression>), String> {
+        let variable_name = |argument: &Pair<Rule>| {
+            if argument.as_rule() == Rule::arithmetic {
+                if let ArithmeticExpression::Symbol(variable) =
+                    ArithmeticExpression::from_arithmetic_pair(argument.clone())
+                {
+                    return Ok(variable);
+                }
+            }
+            Err(format!("{} expects a loop variable name", name))
+        };
+
+        let (variable, values) = match name {
+            // .rept count[, variable]
+            ".rept" => {
+                if arguments.is_empty()
+                    || arguments.len() > 2
+                    || arguments[0].as_rule() != Rule::arithmetic
+                {
+                    return Err(".rept expects a count and an optional loop variable".into());
+                }
+                let count = ArithmeticExpression::from_arithmetic_pair(arguments[0].clone())
+                    .evaluate(self)?;
+                if !(0..=MAX_REPETITIONS as i64).contains(&count) {
+                    return Err(format!("repetition count {} is out of range", count));
+                }
+                let variable = match arguments.get(1) {
+                    Some(argument) => Some(variable_name(argument)?),
+                    None => None,
+                };
+                let values = (0..count).map(ArithmeticExpression::Number).collect();
+                (variable, values)
+            }
+            // .irp variable, value, value, ...
+            ".irp" => {
+                let Some(first) = arguments.first() else {
+                    return Err(".irp expects a loop variable and a list of values".into());
+                };
+                let variable = variable_name(first)?;
+                let mut values = Vec::new();
+                for argument in &arguments[1..] {
+                    if argument.as_rule() != Rule::arithmetic {
+                        return Err(".irp values must be expressions".into());
+                    }
+                    values.push(
+                        self.closed_value(ArithmeticExpression::from_arithmetic_pair(
+                            argument.clone(),
+                        ))?,
+                    );
+                }
+                (Some(variable), values)
+            }
+            // .for variable = first to last [step step]
+            ".for" => {
+                if arguments.len() != 1 || arguments[0].as_rule() != Rule::for_range {
+                    return Err(".for expects `variable = first to last`".into());
+                }
+                let mut inner_pairs = arguments[0].clone().into_inner();
+                let variable = inner_pairs.next().unwrap().as_str().to_string();
+                let mut bounds = Vec::new();
+                for bound in inner_pairs {
+                    bounds.push(ArithmeticExpression::from_arithmetic_pair(bound).evaluate(self)?);
+                }
+                let (first, last) = (bounds[0], bounds[1]);
+                let step = bounds.get(2).copied().unwrap_or(1);
+                if step == 0 {
+                    return Err(".for step can't be zero".into());
+                }
+                let mut values = Vec::new();
+                let mut value = first;
+                while (step > 0 && value <= last) || (step < 0 && value >= last) {
+                    if values.len() == MAX_REPETITIONS {
+                        return Err("too many repetitions".into());
+                    }
+                    values.push(ArithmeticExpression::Number(value));
+                    value += step;
+                }
+                (Some(variable), values)
+            }
+            _ => unreachable!(),
+        };
+
+        if values.len() > MAX_REPETITIONS {
+            return Err("too many repetitions".into());
+        }
+        Ok((variable, values))
+    }
+
+    // Loop variables are bound to expressions that don't refer to any other
+    // loop variables, so they mean the same thing however deeply the body
+    // ends up nested.
+    fn closed_value(&self, value: ArithmeticExpression) -> Result<ArithmeticExpression, String> {
+        if let Some((label, offset)) = value.as_label_offset(self) {
+            let label = ArithmeticExpression::Symbol(label);
+            return Ok(match offset {
+                0 => label,
+                _ => ArithmeticExpression::Binary(
+                    BinaryOperator::Add,
+                    Box::new(label),
+                    Box::new(ArithmeticExpression::Number(offset)),
+                ),
+            });
+        }
+        Ok(ArithmeticExpression::Number(value.evaluate(self)?))
+    }
+
+    fn expand(&mut self, repetition: Repetition) {
+        let expanded_from = Rc::new(repetition.span);
+        for value in repetition.values {
+            if let Some(variable) = &repetition.variable {
+                self.loop_variables.push((variable.clone(), value));
+            }
+            for line in &repetition.body {
+                self.assemble_line(&line.expanded_from(&expanded_from));
+            }
+            if repetition.variable.is_some() {
+                self.loop_variables.pop();
+            }
+        }
+    }
+
+    // Looks for an included file next to the file that includes it, then in
+    // each of the include paths.
+    fn find_include(&self, file_name: &str, span: &SourceSpan) -> Option<PathBuf> {
+        let including_directory = Path::new(&*span.file)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        std::iter::once(&including_directory)
+            .chain(self.include_paths.iter())
+            .map(|directory| directory.join(file_name))
+            .find(|candidate| candidate.is_file())
+    }
+
+    fn read_include(&mut self, file_name: &str, span: &SourceSpan) -> Option<(PathBuf, Vec<u8>)> {
+        let Some(path) = self.find_include(file_name, span) else {
+            self.diagnostics.push(Diagnostic::error(
+                span,
+                format!("can't find included file '{}'", file_name),
+            ));
+            return None;
+        };
+        match fs::read(&path) {
+            Ok(contents) => {
+                if !self.dependencies.contains(&path) {
+                    self.dependencies.push(path.clone());
+                }
+                Some((path, contents))
+            }
+            Err(e) => {
+                self.diagnostics.push(Diagnostic::error(
+                    span,
+                    format!("can't read '{}': {}", path.display(), e),
+                ));
+                None
+            }
+        }
+    }
+
+    fn include(&mut self, file_name: &str, span: &SourceSpan) {
+        let Some((path, contents)) = self.read_include(file_name, span) else {
+            return;
+        };
+        let canonical_path = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if self.include_stack.contains(&canonical_path) {
+            let cycle: Vec<String> = self
+                .include_stack
+                .iter()
+                .skip_while(|included| **included != canonical_path)
+                .chain(std::iter::once(&canonical_path))
+                .map(|included| included.display().to_string())
+                .collect();
+            self.diagnostics.push(Diagnostic::error(
+                span,
+                format!("include cycle: {}", cycle.join(" -> ")),
+            ));
+            return;
+        }
+        let Ok(source) = String::from_utf8(contents) else {
+            self.diagnostics.push(Diagnostic::error(
+                span,
+                format!("'{}' is not valid UTF-8", path.display()),
+            ));
+            return;
+        };
+
+        let included_from = Rc::new(span.clone());
+        self.include_stack.push(canonical_path);
+        for line in
+            SourceLine::from_included_source(&path.to_string_lossy(), &source, &included_from)
+        {
+            self.assemble_line(&line);
+        }
+        self.include_stack.pop();
+    }
+
+    fn include_binary(
+        &mut self,
+        file_name: &str,
+        offset: Option<usize>,
+        length: Option<usize>,
+        span: &SourceSpan,
+    ) {
+        let Some((path, contents)) = self.read_include(file_name, span) else {
+            return;
+        };
+        let offset = offset.unwrap_or(0);
+        let length = length.unwrap_or(contents.len().saturating_sub(offset));
+        if offset + length > contents.len() {
+            self.diagnostics.push(Diagnostic::error(
+                span,
+                format!(
+                    "'{}' is {} bytes long, can't read {} bytes from offset {}",
+                    path.display(),
+                    contents.len(),
+                    length,
+                    offset
+                ),
+            ));
+            return;
+        }
+        self.push_statement(Statement::Binary(
+            contents[offset..offset + length].to_vec(),
+        ));
+    }
+
+    fn string_argument(
+        &mut self,
+        name: &str,
+        arguments: &[Pair<Rule>],
+        span: &SourceSpan,
+    ) -> Option<String> {
+        if arguments.len() != 1 || arguments[0].as_rule() != Rule::string_literal {
+            self.diagnostics.push(Diagnostic::error(
+                span,
+                format!("{} expects a single string", name),
+            ));
+            return None;
+        }
+        Some(string_from_literal(&arguments[0]))
+    }
+
+    fn conditional(&mut self, line: &SourceLine, name: &str) {
+        let span = &line.span;
+        match name {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let parent_active = self.is_active();
+                let active = parent_active && self.condition(line, name);
+                self.conditionals.push(Conditional {
+                    span: span.clone(),
+                    parent_active,
+                    taken: active,
+                    active,
+                    seen_else: false,
+                });
+            }
+            ".elseif" => {
+                let Some(conditional) = self.conditionals.last() else {
+                    self.diagnostics
+                        .push(Diagnostic::error(span, ".elseif without matching .if"));
+                    return;
+                };
+                if conditional.seen_else {
+                    self.diagnostics
+                        .push(Diagnostic::error(span, ".elseif after .else"));
+                    return;
+                }
+                // The condition is only parsed if this branch could be taken.
+                let could_take = conditional.parent_active && !conditional.taken;
+                let active = could_take && self.condition(line, name);
+                let conditional = self.conditionals.last_mut().unwrap();
+                conditional.active = active;
+                conditional.taken |= active;
+            }
+            ".else" => {
+                let Some(conditional) = self.conditionals.last_mut() else {
+                    self.diagnostics
+                        .push(Diagnostic::error(span, ".else without matching .if"));
+                    return;
+                };
+                if conditional.seen_else {
+                    self.diagnostics
+                        .push(Diagnostic::error(span, "duplicate .else"));
+                    return;
+                }
+                conditional.active = conditional.parent_active && !conditional.taken;
+                conditional.taken = true;
+                conditional.seen_else = true;
+            }
+            ".endif" => {
+                if self.conditionals.pop().is_none() {
+                    self.diagnostics
+                        .push(Diagnostic::error(span, ".endif without matching .if"));
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Evaluates the condition of an `.if`, `.elseif`, `.ifdef` or `.ifndef`
+    // line. Errors are reported and treated as false.
+    fn condition(&mut self, line: &SourceLine, name: &str) -> bool {
+        let directive = match Assembler6502Parser::parse(Rule::line, &line.text) {
+            Ok(mut pairs) => pairs
+                .next()
+                .unwrap()
+                .into_inner()
+                .next()
+                .unwrap()
+                .into_inner()
+                .next()
+                .unwrap(),
+            Err(e) => {
+                self.diagnostics
+                    .push(Diagnostic::error(&line.span, e.variant.message()));
+                return false;
+            }
+        };
+        let arguments: Vec<Pair<Rule>> = directive.into_inner().skip(1).collect();
+        if arguments.len() != 1 || arguments[0].as_rule() != Rule::arithmetic {
+            self.diagnostics.push(Diagnostic::error(
+                &line.span,
+                format!("{} expects a single expression", name),
+            ));
+            return false;
+        }
+        let condition = ArithmeticExpression::from_arithmetic_pair(arguments[0].clone());
+
+        match name {
+            ".ifdef" | ".ifndef" => {
+                let ArithmeticExpression::Symbol(symbol) = condition else {
+                    self.diagnostics.push(Diagnostic::error(
+                        &line.span,
+                        format!("{} expects a symbol name", name),
+                    ));
+                    return false;
+                };
+                let defined = self.is_defined(&symbol) || self.alias(&symbol).is_some();
+                defined == (name == ".ifdef")
+            }
+            _ => match condition.evaluate(self) {
+                Ok(value) => value != 0,
+                Err(message) => {
+                    self.diagnostics
+                        .push(Diagnostic::error(&line.span, message));
+                    false
+                }
+            },
+        }
+    }
+}
+
+// The fully qualified names a symbol could refer to from inside the scope
+// with the given prefix, innermost first. `::name` only means the global one.
+fn scoped_names<'a>(prefix: &'a str, name: &'a str) -> Box<dyn Iterator<Item = String> + 'a> {
+    if let Some(global) = name.strip_prefix("::") {
+        return Box::new(std::iter::once(global.to_string()));
+    }
+    Box::new(
+        prefix
+            .rmatch_indices("::")
+            .map(move |(index, _)| &prefix[..index + 2])
+            .chain(std::iter::once(""))
+            .map(move |prefix| format!("{}{}", prefix, name)),
+    )
+}
+
+// A directive argument that's a plain symbol like `Player`, not `a::b`.
+// Names of things like encodings and sections can be given bare or as a
+// string.
+fn name_argument(argument: &Pair<Rule>) -> Option<String> {
+    match argument.as_rule() {
+        Rule::string_literal => Some(string_from_literal(argument)),
+        _ => unqualified_name(argument),
+    }
+}
+
+fn unqualified_name(argument: &Pair<Rule>) -> Option<String> {
+    if argument.as_rule() != Rule::arithmetic {
+        return None;
+    }
+    match ArithmeticExpression::from_arithmetic_pair(argument.clone()) {
+        ArithmeticExpression::Symbol(name) if !name.contains("::") => Some(name),
+        _ => None,
+    }
+}
+
+// Pulls the leading directive name off a line without parsing the rest of
+// it, so conditionals can be recognised inside skipped regions.
+fn directive_name(text: &str) -> Option<String> {
+    Assembler6502Parser::parse(Rule::directive_name, text.trim_start())
+        .ok()
+        .map(|mut pairs| pairs.next().unwrap().as_str().to_lowercase())
+}
+
+fn string_from_literal(literal: &Pair<Rule>) -> String {
+    unescape(literal.clone().into_inner().next().unwrap().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::elf::relocatable::Relocatable;
+    use crate::elf::relocatable::Relocation;
+    use crate::elf::relocatable::Symbol;
+    use crate::output::image::Image;
+
+    fn assemble(source: &str) -> (Option<Program>, Vec<Diagnostic>) {
+        let mut assembler = Assembler::new();
+        let program = assembler.assemble(&SourceLine::from_source("test.s", source));
+        (program, assembler.diagnostics().to_vec())
+    }
+
+    #[test]
+    fn test_if_else_chain() {
+        let source = r#"
+MACHINE = 2
+.if MACHINE == 1
+  LDA #$01
+.elseif MACHINE == 2
+  LDA #$02
+.else
+  LDA #$03
+.endif
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let program = program.unwrap();
+        assert_eq!(program.statements.len(), 1);
+        assert_eq!(program.get_raw_section(), vec![0xA9, 0x02]);
+    }
+
+    #[test]
+    fn test_inactive_regions_are_not_parsed() {
+        let source = r#"
+.ifdef APPLE
+  this is %%% not even close to valid
+  .if SOMETHING_UNDEFINED
+  .endif
+.else
+  NOP
+.endif
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(program.unwrap().statements.len(), 1);
+    }
+
+    #[test]
+    fn test_ifndef_and_labels() {
+        let source = r#"
+start:
+  NOP
+.ifndef start
+  BRK
+.endif
+.if defined(start) && !defined(finish)
+  RTS
+.endif
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(program.unwrap().statements.len(), 2);
+    }
+
+    #[test]
+    fn test_error_and_warning_directives() {
+        let (program, diagnostics) = assemble(".warning \"careful\"\nNOP\n");
+        assert!(program.is_some());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].to_string(), "test.s:1: warning: careful");
+
+        let (program, diagnostics) = assemble("NOP\n.error \"unsupported \\\"target\\\"\"\n");
+        assert!(program.is_none());
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "test.s:2: error: unsupported \"target\""
+        );
+    }
+
+    #[test]
+    fn test_assert() {
+        let (program, diagnostics) = assemble("SIZE = 4\n.assert SIZE < 8, \"too big\"\n");
+        assert!(program.is_some(), "{:?}", diagnostics);
+
+        let (program, diagnostics) = assemble("SIZE = 9\n.assert SIZE < 8, \"too big\"\n");
+        assert!(program.is_none());
+        assert_eq!(diagnostics[0].to_string(), "test.s:2: error: too big");
+    }
+
+    #[test]
+    fn test_unbalanced_conditionals() {
+        let (_, diagnostics) = assemble(".if 1\nNOP\n");
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "test.s:1: error: unterminated .if block"
+        );
+
+        let (_, diagnostics) = assemble(".else\n.endif\n");
+        assert_eq!(diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn test_predefined_symbols() {
+        let mut assembler = Assembler::new();
+        assembler.define("PAL", 1);
+        let program = assembler
+            .assemble(&SourceLine::from_source(
+                "test.s",
+                ".if PAL\nLDX #$32\n.else\nLDX #$3C\n.endif\n",
+            ))
+            .unwrap();
+        assert_eq!(program.get_raw_section(), vec![0xA2, 0x32]);
+    }
+
+    #[test]
+    fn test_rept_with_counter() {
+        let source = r#"
+table:
+.rept 4, i
+  .byte i * 3
+.endr
+  .word table
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let program = program.unwrap();
+        assert_eq!(
+            program.get_raw_section(),
+            vec![0x00, 0x03, 0x06, 0x09, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn test_for_with_step_and_nesting() {
+        let source = r#"
+.for row = 0 to 2
+  .for column = 4 to 0 step -4
+    .byte row * 16 + column
+  .endr
+.endr
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(
+            program.unwrap().get_raw_section(),
+            vec![0x04, 0x00, 0x14, 0x10, 0x24, 0x20]
+        );
+    }
+
+    #[test]
+    fn test_irp_over_labels() {
+        let source = r#"
+.irp pointer, source, destination + 1
+  LDA pointer
+.endr
+.irp value, 1, 2
+  LDX #value
+.endr
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let program = program.unwrap();
+        assert_eq!(
+            program.get_raw_section(),
+            vec![0xAD, 0xFF, 0xFF, 0xAD, 0xFF, 0xFF, 0xA2, 0x01, 0xA2, 0x02]
+        );
+        let relocations = program.get_relocations();
+        assert!(matches!(
+            &relocations[0],
+            Relocation::Long(label, 1, 0) if label == "source"
+        ));
+        assert!(matches!(
+            &relocations[1],
+            Relocation::Long(label, 4, 1) if label == "destination"
+        ));
+    }
+
+    #[test]
+    fn test_unrolled_copy_loop() {
+        let source = r#"
+.rept 3, offset
+  LDA $0400 + offset * 40, X
+  STA buffer + offset, X
+.endr
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let program = program.unwrap();
+        assert_eq!(
+            &program.get_raw_section()[..6],
+            &[0xBD, 0x00, 0x04, 0x9D, 0xFF, 0xFF]
+        );
+        assert_eq!(&program.get_raw_section()[6..9], &[0xBD, 0x28, 0x04]);
+        assert!(matches!(
+            &program.get_relocations()[2],
+            Relocation::Long(label, 16, 2) if label == "buffer"
+        ));
+    }
+
+    #[test]
+    fn test_conditionals_inside_repetitions() {
+        let source = r#"
+.rept 4, i
+  .if i % 2
+    .byte $FF
+  .else
+    .byte i
+  .endif
+.endr
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(
+            program.unwrap().get_raw_section(),
+            vec![0x00, 0xFF, 0x02, 0xFF]
+        );
+    }
+
+    #[test]
+    fn test_diagnostics_point_at_expansion() {
+        let (_, diagnostics) = assemble(
+            ".rept 2, i
+  .byte 256 + i
+.endr
+",
+        );
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "test.s:2: error: value $100 does not fit in a byte\ntest.s:1: note: expanded from here"
+        );
+
+        let (_, diagnostics) = assemble(
+            ".rept 2
+NOP
+",
+        );
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "test.s:1: error: unterminated repetition block"
+        );
+
+        let (_, diagnostics) = assemble(".endr\n");
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "test.s:1: error: .endr without matching .rept"
+        );
+    }
+
+    #[test]
+    fn test_struct_layout() {
+        let source = r#"
+.struct Vec2
+  x .byte
+  y .byte
+.endstruct
+.struct Player
+  pos   .tag Vec2
+  name  .res 8
+  hp    .word
+  .union
+    ammo  .byte
+    fuel  .word
+  .endunion
+  flags .byte 2
+.endstruct
+.assert Player::pos == 0, "pos"
+.assert Player::name == 2, "name"
+.assert Player::hp == 10, "hp"
+.assert Player::ammo == 12, "ammo"
+.assert Player::fuel == 12, "fuel"
+.assert Player::flags == 14, "flags"
+.assert sizeof(Player) == 16, "size"
+.assert sizeof(Player::name) == 8, "field size"
+player: .res sizeof(Player)
+other: .tag Player
+  LDA player+Player::hp+1
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let program = program.unwrap();
+        assert_eq!(program.labels["other"], 16);
+        assert!(matches!(
+            program.get_relocations().as_slice(),
+            [Relocation::Long(label, 33, 11)] if label == "player"
+        ));
+    }
+
+    #[test]
+    fn test_struct_field_of_constant_address() {
+        let source = r#"
+.struct Sprite
+  x     .byte
+  y     .byte
+.endstruct
+sprites = $0200
+  LDA sprites+Sprite::y
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(program.unwrap().get_raw_section(), vec![0xAD, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn test_enum_members() {
+        let source = r#"
+.enum Color
+  BLACK
+  WHITE
+  RED = 5
+  CYAN
+.endenum
+.enum
+  IDLE
+  RUNNING
+.endenum
+.assert Color::BLACK == 0 && Color::WHITE == 1, "start"
+.assert Color::RED == 5 && Color::CYAN == 6, "continue"
+.assert RUNNING == 1, "anonymous"
+  LDA #Color::CYAN
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(program.unwrap().get_raw_section(), vec![0xA9, 0x06]);
+    }
+
+    #[test]
+    fn test_bad_definitions() {
+        let (_, diagnostics) = assemble(
+            ".struct Point
+ x .byte
+ x .word
+.endstruct
+",
+        );
+        assert_eq!(
+            diagnostics[0].message,
+            "symbol 'Point::x' is already defined"
+        );
+
+        let (_, diagnostics) = assemble(
+            ".struct Point
+ x .byte
+.endunion
+",
+        );
+        assert_eq!(
+            diagnostics[0].message,
+            "expected .endstruct to close .struct"
+        );
+
+        let (_, diagnostics) = assemble(
+            ".union Either
+ a .byte
+",
+        );
+        assert_eq!(diagnostics[0].message, "unterminated .union block");
+
+        let (_, diagnostics) = assemble(
+            ".endenum
+",
+        );
+        assert_eq!(diagnostics[0].message, ".endenum without matching .enum");
+
+        let (_, diagnostics) = assemble(
+            ".res sizeof(Nothing)
+",
+        );
+        assert_eq!(diagnostics[0].message, "unknown type 'Nothing'");
+    }
+
+    #[test]
+    fn test_scopes() {
+        let source = r#"
+SCREEN = $0400
+.proc clear
+  LDX #0
+loop:
+  STA SCREEN,X
+  INX
+  BNE loop
+  JMP done
+done:
+  RTS
+.endproc
+.proc fill
+  COUNT = 3
+  LDX #COUNT
+loop:
+  DEX
+  BNE loop
+  JSR clear::loop
+  RTS
+.endproc
+.scope
+inner: NOP
+.endscope
+.assert fill::COUNT == 3, "qualified constant"
+.assert !defined(COUNT), "constant stays in its scope"
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let program = program.unwrap();
+        assert_eq!(program.labels["clear"], 0);
+        assert_eq!(program.labels["clear::loop"], 2);
+        assert_eq!(program.labels["clear::done"], 11);
+        assert_eq!(program.labels["fill"], 12);
+        assert_eq!(program.labels["fill::loop"], 14);
+        assert_eq!(program.labels["@1::inner"], 21);
+        assert_eq!(program.sizes["clear"], 12);
+        assert_eq!(program.sizes["fill"], 9);
+
+        // `done` is used before it's declared but still belongs to `clear`.
+        let relocations = program.get_relocations();
+        assert!(relocations.iter().any(|relocation| matches!(
+            relocation,
+            Relocation::Jump(label, 9, 0) if label == "clear::done"
+        )));
+        assert!(relocations.iter().any(|relocation| matches!(
+            relocation,
+            Relocation::Jump(label, 18, 0) if label == "clear::loop"
+        )));
+        // Both branches land on their own `loop`.
+        assert_eq!(program.get_raw_section()[7], 0xFA);
+        assert_eq!(program.get_raw_section()[16], 0xFD);
+    }
+
+    #[test]
+    fn test_nested_scopes_and_shadowing() {
+        let source = r#"
+value = 1
+.scope outer
+  value = 2
+  .scope inner
+    LDA #value
+    LDA #outer::value
+    LDA #::value
+  .endscope
+.endscope
+  LDA #value
+  LDA #outer::value
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(
+            program.unwrap().get_raw_section(),
+            vec![0xA9, 0x02, 0xA9, 0x02, 0xA9, 0x01, 0xA9, 0x01, 0xA9, 0x02]
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_scopes() {
+        let (_, diagnostics) = assemble(".proc main\n  RTS\n");
+        assert_eq!(diagnostics[0].message, "unterminated .proc block");
+
+        let (_, diagnostics) = assemble(".scope\n.endproc\n");
+        assert_eq!(diagnostics[0].message, "expected .endscope to close .scope");
+
+        let (_, diagnostics) = assemble(".endscope\n");
+        assert_eq!(diagnostics[0].message, ".endscope without matching .scope");
+
+        let (_, diagnostics) = assemble(".proc main\n.endproc\n.proc main\n.endproc\n");
+        assert_eq!(diagnostics[0].message, "symbol 'main' is already defined");
+    }
+
+    #[test]
+    fn test_encodings() {
+        let source = r#"
+.ascii "Hi"
+.encoding petscii
+.asciiz "Hi"
+  LDA #'A'
+.encoding "screen"
+.byte "Hi", 'i'+1, 0
+.charmap '{', $5B
+.charmap $7D, $5D
+.byte "{}"
+.encoding apple2
+.byte "{"
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(
+            program.unwrap().get_raw_section(),
+            vec![
+                0x48, 0x69, // ascii
+                0xC8, 0x49, 0x00, // petscii
+                0xA9, 0xC1, // LDA #'A'
+                0x48, 0x09, 0x0A, 0x00, // screen
+                0x5B, 0x5D, // charmap
+                0xFB, // .encoding resets the charmap
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_encodings() {
+        let (_, diagnostics) = assemble(".encoding ebcdic\n");
+        assert_eq!(
+            diagnostics[0].message,
+            "unknown encoding 'ebcdic', expected one of ascii, petscii, screen, atascii, apple2"
+        );
+
+        let (_, diagnostics) = assemble(".encoding petscii\n.byte \"{\"\n");
+        assert_eq!(
+            diagnostics[0].message,
+            "character '{' can't be encoded in petscii"
+        );
+
+        let (_, diagnostics) = assemble(".word \"ab\"\n");
+        assert_eq!(
+            diagnostics[0].message,
+            ".word expects a list of expressions"
+        );
+
+        let (_, diagnostics) = assemble(".charmap 'a', $100\n");
+        assert_eq!(diagnostics[0].message, "value $100 does not fit in a byte");
+    }
+
+    #[test]
+    fn test_origins() {
+        let source = r#"
+  NOP
+.org $C000
+.org $E000
+start:
+  NOP
+.org $FFFC
+  .word start
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty());
+        let program = program.unwrap();
+        // The second .org replaces the first, nothing was placed between them
+        assert_eq!(program.origins, [(1, 0xE000), (2, 0xFFFC)]);
+        assert_eq!(program.address_of(0, 0x0200), 0x0200);
+        assert_eq!(program.address_of(1, 0x0200), 0xE000);
+        assert_eq!(program.address_of(3, 0x0200), 0xFFFD);
+
+        let (_, diagnostics) = assemble(".org $10000\n");
+        assert_eq!(diagnostics[0].message, "address $10000 is out of range");
+        let (_, diagnostics) = assemble(".org\n");
+        assert_eq!(diagnostics[0].message, ".org expects an address");
+    }
+
+    #[test]
+    fn test_sections() {
+        let source = r#"
+  LDA message
+.section data
+message: .byte "hi"
+.section text
+  JMP message
+.section "tiles", chr, 1
+.encoding screen
+  .byte "a"
+.section data
+  .byte "a"
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty());
+        let program = program.unwrap();
+        let sections: Vec<_> = program
+            .sections
+            .iter()
+            .map(|section| {
+                (
+                    section.name.as_str(),
+                    section.start,
+                    section.size,
+                    section.bank,
+                )
+            })
+            .collect();
+        assert_eq!(
+            sections,
+            [
+                ("text", 0, 6, None),
+                ("data", 6, 3, None),
+                ("tiles", 9, 1, Some((Rom::Chr, 1))),
+            ]
+        );
+        assert_eq!(program.labels["message"], 6);
+        // Each section has its own character map
+        assert_eq!(program.get_raw_section()[6..], [b'h', b'i', b'a', 0x01]);
+        // The banked section starts at 0 in its own bank
+        assert_eq!(program.origins, [(9, 0)]);
+    }
+
+    #[test]
+    fn test_bank_bytes() {
+        let source = r#"
+  LDA #^far
+  .bankbyte far, near
+.section code, prg, 3
+far: RTS
+.section other, prg, 0
+near: RTS
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty());
+        let program = program.unwrap();
+        assert_eq!(
+            program.get_relocations()[..3],
+            [
+                Relocation::Bank("far".to_string(), 1, 0),
+                Relocation::Bank("far".to_string(), 2, 0),
+                Relocation::Bank("near".to_string(), 3, 0),
+            ]
+        );
+        let image = Image::from_program(&program, 0x8000).unwrap();
+        assert_eq!(image.segments[0].bytes, [0xA9, 3, 3, 0]);
+
+        let (_, diagnostics) = assemble("far: RTS\n  LDA #^far\n");
+        assert!(diagnostics.is_empty());
+        let (program, _) = assemble("far: RTS\n  LDA #^far\n");
+        assert_eq!(
+            Image::from_program(&program.unwrap(), 0x8000),
+            Err(vec!["'far' isn't in a banked section".to_string()])
+        );
+        for (source, message) in [
+            ("  .bankbyte 5\n", ".bankbyte expects a list of labels"),
+            ("far:\n  BNE ^far\n", "can't branch to the bank of 'far'"),
+            ("far:\n  .word ^far\n", "the bank of 'far' is only a byte"),
+            (
+                "far:\n  JMP ^far\n",
+                "addressing mode is not supported by this instruction",
+            ),
+            ("  LDA #^$1234\n", "'^' only works on a label"),
+        ] {
+            let (_, diagnostics) = assemble(source);
+            assert_eq!(diagnostics[0].message, message, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_address_bytes() {
+        let source = r#"
+  .org $C000
+  LDA #<table
+  LDX #>(table+$10)
+  LDY <table
+  .byte <print, >print
+table: RTS
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty());
+        let program = program.unwrap();
+        assert_eq!(
+            program.get_relocations(),
+            [
+                Relocation::Low("table".to_string(), 1, 0),
+                Relocation::High("table".to_string(), 3, 0x10),
+                Relocation::Low("table".to_string(), 5, 0),
+                Relocation::Low("print".to_string(), 6, 0),
+                Relocation::High("print".to_string(), 7, 0),
+            ]
+        );
+
+        let (program, _) = assemble(
+            "  .org $C0F8
+  LDA #<table
+  LDX #>(table+$10)
+table: RTS
+",
+        );
+        let image = Image::from_program(&program.unwrap(), 0).unwrap();
+        assert_eq!(image.segments[0].bytes, [0xA9, 0xFC, 0xA2, 0xC1, 0x60]);
+
+        for (source, message) in [
+            ("far:\n  BNE <far\n", "can't branch to a byte of 'far'"),
+            ("far:\n  .word >far\n", "a byte of 'far' is only a byte"),
+            (
+                "far:\n  LDA <far,Y\n",
+                "addressing mode is not supported by this instruction",
+            ),
+        ] {
+            let (_, diagnostics) = assemble(source);
+            assert_eq!(diagnostics[0].message, message, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_forward_constants() {
+        let source = r#"
+  .org $0800
+  LDA fwd
+  LDA #fwd
+  JMP fwd2
+  BNE target
+  LDX #big
+fwd = $20
+fwd2 = $1234
+target = $0812
+big = $100
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty());
+        let program = program.unwrap();
+        // Only the branch, and the constant that's too big for a byte, are
+        // left to relocate
+        assert_eq!(
+            program.get_relocations(),
+            [
+                Relocation::Relative("target".to_string(), 9, 0),
+                Relocation::Short("big".to_string(), 11, 0),
+            ]
+        );
+        let symbols = program.get_symbols();
+        assert_eq!(symbols["fwd"], Symbol::ShortValue(0x20));
+        assert_eq!(symbols["fwd2"], Symbol::LongValue(0x1234));
+
+        let (program, _) = assemble(&source.replace("  LDX #big\n", ""));
+        let image = Image::from_program(&program.unwrap(), 0).unwrap();
+        assert_eq!(
+            image.segments[0].bytes,
+            [0xAD, 0x20, 0x00, 0xA9, 0x20, 0x4C, 0x34, 0x12, 0xD0, 0x08]
+        );
+        let (program, _) = assemble(source);
+        assert_eq!(
+            Image::from_program(&program.unwrap(), 0),
+            Err(vec![
+                "value $100 of 'big' does not fit in a byte".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_scoped_constants() {
+        let source = r#"
+.scope sc
+val = 5
+.endscope
+.struct Point
+  x .byte
+  y .word
+.endstruct
+.enum Color
+  BLACK
+  WHITE = $300
+.endenum
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let symbols = program.unwrap().get_symbols();
+        assert_eq!(symbols["sc::val"], Symbol::ShortValue(5));
+        assert_eq!(symbols["Point::x"], Symbol::ShortValue(0));
+        assert_eq!(symbols["Point::y"], Symbol::ShortValue(1));
+        assert_eq!(symbols["Color::BLACK"], Symbol::ShortValue(0));
+        assert_eq!(symbols["Color::WHITE"], Symbol::LongValue(0x300));
+    }
+
+    #[test]
+    fn test_long_branches() {
+        let source = r#"
+loop:
+  JEQ loop
+  JNE done
+  JCS $1234
+  .res 200
+done:
+  RTS
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].message,
+            "'done' is out of range of a branch, so this uses a JMP"
+        );
+        assert_eq!(diagnostics[0].span.as_ref().unwrap().line, 4);
+        let program = program.unwrap();
+        assert_eq!(program.labels["done"], 212);
+        let image = Image::from_program(&program, 0x8000).unwrap();
+        assert_eq!(
+            image.segments[0].bytes[..12],
+            [0xF0, 0xFE, 0xF0, 0x03, 0x4C, 0xD4, 0x80, 0x90, 0x03, 0x4C, 0x34, 0x12]
+        );
+
+        // Making the second one long puts `x` out of reach of the first
+        let source = r#"
+  JEQ x
+  JEQ far
+  .res 125
+x: RTS
+  .res 200
+far: RTS
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert_eq!(diagnostics.len(), 2);
+        let program = program.unwrap();
+        assert_eq!(program.labels["x"], 135);
+        assert_eq!(program.labels["far"], 336);
+
+        // Distances are between addresses, so an `.org` can put a label out
+        // of range however close it is in the section
+        let source = r#"
+  .org $E000
+  JEQ far
+  .org $F000
+far: RTS
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert_eq!(diagnostics.len(), 1);
+        let image = Image::from_program(&program.unwrap(), 0).unwrap();
+        assert_eq!(image.segments[0].bytes, [0xD0, 0x03, 0x4C, 0x00, 0xF0]);
+
+        // Targets outside the section are left long for the linker, with a
+        // marker saying so
+        let (program, diagnostics) = assemble("  JMI elsewhere\n");
+        assert!(diagnostics.is_empty());
+        assert_eq!(
+            program.unwrap().get_relocations(),
+            [
+                Relocation::Relax("elsewhere".to_string(), 0, 0),
+                Relocation::Jump("elsewhere".to_string(), 3, 0),
+            ]
+        );
+        let (_, diagnostics) = assemble("  JEQ #1\n");
+        assert_eq!(
+            diagnostics[0].message,
+            "addressing mode is not supported by this instruction"
+        );
+    }
+
+    #[test]
+    fn test_function_sections() {
+        let source = r#"
+.proc main
+  LDA #0
+  JSR print
+.endproc
+print:
+  LDA #1
+wait:
+  BNE wait
+done:
+exit:
+  RTS
+.section data, prg, 2
+table: .byte 1
+"#;
+        let mut assembler = Assembler::new();
+        assembler.set_function_sections(true);
+        let program = assembler
+            .assemble(&SourceLine::from_source("test.s", source))
+            .unwrap();
+        let sections: Vec<_> = program
+            .sections
+            .iter()
+            .map(|section| (section.name.as_str(), section.size, section.bank))
+            .collect();
+        // main runs on into print, and print into everything after it
+        assert_eq!(
+            sections,
+            [
+                ("text.main", 10, None),
+                ("data.table", 1, Some((Rom::Prg, 2))),
+            ]
+        );
+
+        let source = "first: RTS\nsecond: JMP first\n  .byte 1\nthird: .byte 2\n";
+        let mut assembler = Assembler::new();
+        assembler.set_function_sections(true);
+        let program = assembler
+            .assemble(&SourceLine::from_source("test.s", source))
+            .unwrap();
+        let names: Vec<_> = program
+            .sections
+            .iter()
+            .map(|section| section.name.as_str())
+            .collect();
+        assert_eq!(names, ["text.first", "text.second", "text.third"]);
+        assert_eq!(program.labels["third"], 5);
+    }
+
+    #[test]
+    fn test_bad_sections() {
+        let (_, diagnostics) = assemble(".section\n");
+        assert_eq!(
+            diagnostics[0].message,
+            ".section expects a name, then optionally prg or chr and a bank"
+        );
+        let (_, diagnostics) = assemble(".section code, rom, 1\n");
+        assert_eq!(
+            diagnostics[0].message,
+            ".section banks are either prg or chr"
+        );
+        let (_, diagnostics) = assemble(".section code, prg, 1\n.section code, prg, 2\n");
+        assert_eq!(
+            diagnostics[0].message,
+            "section 'code' was already started in another bank"
+        );
+    }
+
+    #[test]
+    fn test_ines_directives() {
+        let source = r#"
+.ines_prg 2
+.ines_chr 1
+.ines_mapper 1
+.ines_submapper 5
+.ines_mirror vertical
+"#;
+        let (program, diagnostics) = assemble(source);
+        assert!(diagnostics.is_empty());
+        assert_eq!(
+            program.unwrap().ines,
+            InesHeader {
+                prg_banks: Some(2),
+                chr_banks: Some(1),
+                mapper: 1,
+                submapper: Some(5),
+                mirroring: Mirroring::Vertical,
+            }
+        );
+
+        let (_, diagnostics) = assemble(".ines_mapper 4096\n");
+        assert_eq!(
+            diagnostics[0].message,
+            ".ines_mapper must be between 0 and 4095"
+        );
+        let (_, diagnostics) = assemble(".ines_mirror 2\n");
+        assert_eq!(
+            diagnostics[0].message,
+            ".ines_mirror expects horizontal, vertical or four_screen"
+        );
+    }
+}
@@ -2,40 +2,185 @@ use super::expression::AddressValue;
 use super::expression::Expression;
 use super::expression::LongOperand;
 use super::expression::ShortOperand;
+use super::instruction::AddressModeIndexer;
+use super::instruction::InstructionCode;
+use super::instruction::INSTRUCTION_MAP;
 use super::parser::Rule;
 
 use crate::elf::relocatable::Relocatable;
 use crate::elf::relocatable::Relocation;
+use crate::elf::relocatable::Rom;
+use crate::elf::relocatable::Section;
 use crate::elf::relocatable::Symbol;
+use crate::output::ines::InesHeader;
 
 use pest::iterators::Pairs;
 
 use std::collections::HashMap;
-use std::rc::Rc;
 use std::vec::Vec;
 
 #[derive(Debug)]
+pub enum Statement {
+    Instruction(Expression),
+    Bytes(Vec<ShortOperand>),
+    Words(Vec<LongOperand>),
+    Binary(Vec<u8>),
+    Branch(LongBranch),
+}
+
+// `JEQ target` and friends. It's a BEQ if the target's close enough, and a
+// BNE over a JMP to it if it isn't.
+#[derive(Debug)]
+pub struct LongBranch {
+    pub branch: InstructionCode,
+    pub target: LongOperand,
+    pub long: bool,
+}
+
+// The JMP, which is what a long branch has on top of a plain one
+pub const JMP_SIZE: usize = 3;
+const JMP: u8 = 0x4C;
+// Flipping this bit of a branch's opcode gives the opposite branch, BEQ to
+// BNE and so on.
+pub const INVERT_BRANCH: u8 = 0x20;
+
+impl LongBranch {
+    pub fn new(branch: InstructionCode, target: LongOperand) -> LongBranch {
+        LongBranch {
+            branch,
+            target,
+            long: false,
+        }
+    }
+
+    pub fn get_size(&self) -> usize {
+        match self.long {
+            true => 2 + JMP_SIZE,
+            false => 2,
+        }
+    }
+
+    fn opcode(&self) -> u8 {
+        INSTRUCTION_MAP[&(self.branch, AddressModeIndexer::RELATIVE)]
+    }
+
+    fn target_label(&self) -> Option<(&String, i32)> {
+        match &self.target {
+            LongOperand::Numeric(_) => None,
+            LongOperand::Label(label) => Some((label, 0)),
+            LongOperand::Offset(label, offset) => Some((label, *offset)),
+        }
+    }
+}
+
+impl Statement {
+    pub fn get_size(&self) -> usize {
+        match self {
+            Statement::Instruction(expression) => expression.get_size(),
+            Statement::Bytes(bytes) => bytes.len(),
+            Statement::Words(words) => words.len() * 2,
+            Statement::Binary(bytes) => bytes.len(),
+            Statement::Branch(branch) => branch.get_size(),
+        }
+    }
+
+    // Long branches are pushed short, and only made long once it's known
+    // where everything is.
+    fn pushed_size(&self) -> usize {
+        match self {
+            Statement::Branch(_) => 2,
+            statement => statement.get_size(),
+        }
+    }
+
+    // Every label the statement refers to, so references can be renamed
+    // once it's known which scope they belong to.
+    pub fn labels_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Statement::Instruction(expression) => {
+                expression.operand.label_mut().into_iter().collect()
+            }
+            Statement::Bytes(bytes) => bytes
+                .iter_mut()
+                .filter_map(ShortOperand::label_mut)
+                .collect(),
+            Statement::Words(words) => words
+                .iter_mut()
+                .filter_map(LongOperand::label_mut)
+                .collect(),
+            Statement::Binary(_) => Vec::new(),
+            Statement::Branch(branch) => branch.target.label_mut().into_iter().collect(),
+        }
+    }
+
+    // Turns references to constants that were only defined later on into
+    // the numbers they stand for. Anything that doesn't fit is left as it
+    // is, for the relocation to complain about. So are branches, which are
+    // relative to wherever they end up.
+    pub fn resolve_constants(&mut self, constant: impl Fn(&str) -> Option<i64> + Copy) {
+        let short = |operand: &mut ShortOperand| {
+            if let Some(resolved) = operand.resolved(constant) {
+                *operand = resolved;
+            }
+        };
+        let long = |operand: &mut LongOperand| {
+            if let Some(resolved) = operand.resolved(constant) {
+                *operand = resolved;
+            }
+        };
+        match self {
+            Statement::Instruction(expression) => match &mut expression.operand {
+                AddressValue::Accumulator | AddressValue::Implied | AddressValue::Relative(_) => {}
+                AddressValue::Immediate(operand)
+                | AddressValue::ZeroPage(operand)
+                | AddressValue::ZeroPageX(operand)
+                | AddressValue::ZeroPageY(operand)
+                | AddressValue::IndexedIndirect(operand)
+                | AddressValue::IndirectIndexed(operand) => short(operand),
+                AddressValue::Absolute(operand)
+                | AddressValue::AbsoluteIndirect(operand)
+                | AddressValue::AbsoluteX(operand)
+                | AddressValue::AbsoluteY(operand) => long(operand),
+            },
+            Statement::Bytes(bytes) => bytes.iter_mut().for_each(short),
+            Statement::Words(words) => words.iter_mut().for_each(long),
+            Statement::Binary(_) | Statement::Branch(_) => {}
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct Program {
-    pub expressions: Vec<Rc<Expression>>,
-    pub labels: HashMap<String, (usize, Rc<Expression>)>,
+    pub statements: Vec<Statement>,
+    pub labels: HashMap<String, usize>,
+    // Sizes of labels that start a `.proc`
+    pub sizes: HashMap<String, usize>,
+    // Addresses set with `.org`, as (cursor, address) in cursor order
+    pub origins: Vec<(usize, u16)>,
+    pub sections: Vec<Section>,
+    // Set by the `.ines_*` directives
+    pub ines: InesHeader,
+    // Constants defined in the source, exported as plain values
+    pub constants: HashMap<String, i64>,
+    cursor: usize,
 }
 
 impl Program {
+    pub fn new() -> Program {
+        Program::default()
+    }
+
     pub fn from_pairs(pairs: Pairs<Rule>) -> Program {
-        let mut expressions: Vec<Rc<Expression>> = Vec::new();
-        let mut labels: HashMap<String, (usize, Rc<Expression>)> = HashMap::new();
-        let mut cursor: usize = 0;
+        let mut program = Program::new();
 
         for pair in pairs {
             match pair.as_rule() {
                 Rule::expression => {
                     let (new_labels, new_expression) = Expression::from_expression_pair(pair);
-                    let reference_counted_expression = Rc::new(new_expression);
-                    expressions.push(reference_counted_expression.clone());
                     for label in new_labels {
-                        labels.insert(label, (cursor, reference_counted_expression.clone()));
+                        program.define_label(label);
                     }
-                    cursor += reference_counted_expression.get_size();
+                    program.push_statement(Statement::Instruction(new_expression));
                 }
                 Rule::EOI => {
                     break;
@@ -46,18 +191,287 @@ impl Program {
             }
         }
 
-        Program {
-            expressions: expressions,
-            labels: labels,
+        program
+    }
+
+    // Points a label at the end of the program, where the next statement
+    // will go.
+    pub fn define_label(&mut self, label: String) {
+        self.labels.insert(label, self.cursor);
+    }
+
+    // Records the size of whatever starts at a label, from there up to the
+    // end of the program.
+    pub fn end_label(&mut self, label: &str) {
+        if let Some(start) = self.labels.get(label) {
+            self.sizes.insert(label.to_string(), self.cursor - start);
+        }
+    }
+
+    // Places everything from here on at `address`.
+    pub fn set_origin(&mut self, address: u16) {
+        if let Some((cursor, _)) = self.origins.last() {
+            if *cursor == self.cursor {
+                self.origins.pop();
+            }
+        }
+        self.origins.push((self.cursor, address));
+    }
+
+    // Where something at `cursor` ends up once the program is loaded.
+    // Anything before the first `.org` is placed at `origin`.
+    pub fn address_of(&self, cursor: usize, origin: u16) -> u16 {
+        let (start, address) = self
+            .origins
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= cursor)
+            .copied()
+            .unwrap_or((0, origin));
+        address.wrapping_add((cursor - start) as u16)
+    }
+
+    pub fn push_statement(&mut self, statement: Statement) {
+        self.cursor += statement.get_size();
+        self.statements.push(statement);
+    }
+
+    // Puts a separately assembled section on the end of the program. A
+    // section without an `.org` at its start carries on from wherever the
+    // one before it finished.
+    //
+    // Banked sections have an address space of their own, and start at 0
+    // unless they say otherwise.
+    pub fn append_section(&mut self, name: &str, bank: Option<(Rom, usize)>, section: Program) {
+        let start = self.cursor;
+        if bank.is_some()
+            && section
+                .origins
+                .first()
+                .is_none_or(|(cursor, _)| *cursor != 0)
+        {
+            self.origins.push((start, 0));
+        }
+        self.labels.extend(
+            section
+                .labels
+                .into_iter()
+                .map(|(label, cursor)| (label, start + cursor)),
+        );
+        self.sizes.extend(section.sizes);
+        self.origins.extend(
+            section
+                .origins
+                .into_iter()
+                .map(|(cursor, address)| (start + cursor, address)),
+        );
+        self.statements.extend(section.statements);
+        self.cursor += section.cursor;
+        self.sections.push(Section {
+            name: name.to_string(),
+            start,
+            size: section.cursor,
+            bank,
+        });
+    }
+
+    // Picks between the short and long forms of the long branches. Ones to
+    // a label in the program start out short, and get made long if the label
+    // is out of range. That can push other labels out of range too, so it
+    // goes round until nothing else needs to change. Ones to anywhere else
+    // are long, and are left for the linker to shrink if it can.
+    //
+    // Everything was placed with the branches short, so everything after
+    // one that's made long moves along. Returns where each of those was, for
+    // `moved_cursor`.
+    pub fn relax_branches(&mut self) -> Vec<usize> {
+        let mut grown = Vec::new();
+        let mut cursor = 0;
+        for statement in &mut self.statements {
+            if let Statement::Branch(branch) = statement {
+                branch.long = branch
+                    .target_label()
+                    .is_none_or(|(label, _)| !self.labels.contains_key(label));
+                if branch.long {
+                    grown.push(cursor);
+                }
+            }
+            cursor += statement.pushed_size();
+        }
+
+        loop {
+            let mut cursor = 0;
+            let mut changed = false;
+            for statement in &mut self.statements {
+                let start = cursor;
+                cursor += statement.pushed_size();
+                let Statement::Branch(branch) = statement else {
+                    continue;
+                };
+                if branch.long {
+                    continue;
+                }
+                let (label, addend) = branch.target_label().unwrap();
+                let target = relaxed_address(&self.origins, &grown, self.labels[label]);
+                let offset =
+                    target + addend as i64 - (relaxed_address(&self.origins, &grown, start) + 2);
+                if !(-0x80..=0x7F).contains(&offset) {
+                    branch.long = true;
+                    changed = true;
+                    let index = grown.partition_point(|grown| *grown < start);
+                    grown.insert(index, start);
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        for (label, size) in self.sizes.iter_mut() {
+            let start = self.labels[label];
+            *size = moved_cursor(&grown, start + *size) - moved_cursor(&grown, start);
+        }
+        for cursor in self.labels.values_mut() {
+            *cursor = moved_cursor(&grown, *cursor);
+        }
+        for (cursor, _) in self.origins.iter_mut() {
+            *cursor = moved_cursor(&grown, *cursor);
+        }
+        self.cursor = moved_cursor(&grown, self.cursor);
+        grown
+    }
+
+    // The section something at `cursor` is in.
+    pub fn section_of(&self, cursor: usize) -> Option<&Section> {
+        self.sections
+            .iter()
+            .find(|section| (section.start..section.start + section.size).contains(&cursor))
+    }
+
+    pub fn get_cursor(&self) -> usize {
+        self.cursor
+    }
+}
+
+// The address of something at `cursor`, from before any branches were made
+// long, once the ones at `grown` have been. It's relative to wherever the
+// program starts, which is all a branch needs.
+fn relaxed_address(origins: &[(usize, u16)], grown: &[usize], cursor: usize) -> i64 {
+    let (start, address) = origins
+        .iter()
+        .rev()
+        .find(|(start, _)| *start <= cursor)
+        .copied()
+        .unwrap_or((0, 0));
+    address as i64 + (moved_cursor(grown, cursor) - moved_cursor(grown, start)) as i64
+}
+
+// Where something at `cursor` ends up once the branches at `grown` have
+// been made long, from `Program::relax_branches`.
+pub fn moved_cursor(grown: &[usize], cursor: usize) -> usize {
+    cursor + JMP_SIZE * grown.partition_point(|grown| *grown < cursor)
+}
+
+fn push_short(acc: &mut Vec<u8>, operand: &ShortOperand) {
+    match operand {
+        ShortOperand::Numeric(value) => acc.push(*value),
+        ShortOperand::Label(_)
+        | ShortOperand::Offset(_, _)
+        | ShortOperand::Bank(_)
+        | ShortOperand::Low(_, _)
+        | ShortOperand::High(_, _) => acc.push(0xFF),
+    }
+}
+
+fn push_long(acc: &mut Vec<u8>, operand: &LongOperand) {
+    match operand {
+        LongOperand::Numeric(value) => {
+            acc.push((value & 0xFF) as u8);
+            acc.push((value >> 8) as u8);
+        }
+        LongOperand::Label(_) | LongOperand::Offset(_, _) => {
+            acc.push(0xFF);
+            acc.push(0xFF);
+        }
+    }
+}
+
+fn short_relocation(operand: &ShortOperand, location: usize) -> Option<Relocation> {
+    match operand {
+        ShortOperand::Numeric(_) => None,
+        ShortOperand::Label(label) => Some(Relocation::Short(label.clone(), location as u32, 0)),
+        ShortOperand::Offset(label, offset) => {
+            Some(Relocation::Short(label.clone(), location as u32, *offset))
+        }
+        ShortOperand::Bank(label) => Some(Relocation::Bank(label.clone(), location as u32, 0)),
+        ShortOperand::Low(label, offset) => {
+            Some(Relocation::Low(label.clone(), location as u32, *offset))
+        }
+        ShortOperand::High(label, offset) => {
+            Some(Relocation::High(label.clone(), location as u32, *offset))
+        }
+    }
+}
+
+fn long_relocation(operand: &LongOperand, location: usize) -> Option<Relocation> {
+    match operand {
+        LongOperand::Numeric(_) => None,
+        LongOperand::Label(label) => Some(Relocation::Long(label.clone(), location as u32, 0)),
+        LongOperand::Offset(label, offset) => {
+            Some(Relocation::Long(label.clone(), location as u32, *offset))
+        }
+    }
+}
+
+fn jump_relocation(operand: &LongOperand, location: usize) -> Option<Relocation> {
+    match operand {
+        LongOperand::Numeric(_) => None,
+        LongOperand::Label(label) => Some(Relocation::Jump(label.clone(), location as u32, 0)),
+        LongOperand::Offset(label, offset) => {
+            Some(Relocation::Jump(label.clone(), location as u32, *offset))
         }
     }
 }
 
 impl Relocatable for Program {
     fn get_raw_section(&self) -> Vec<u8> {
-        self.expressions
+        self.statements
             .iter()
-            .fold((Vec::<u8>::new(), 0), |(mut acc, cursor), expression| {
+            .fold((Vec::<u8>::new(), 0), |(mut acc, cursor), statement| {
+                let expression = match statement {
+                    Statement::Instruction(expression) => expression,
+                    Statement::Bytes(bytes) => {
+                        bytes.iter().for_each(|byte| push_short(&mut acc, byte));
+                        return (acc, cursor + statement.get_size());
+                    }
+                    Statement::Words(words) => {
+                        words.iter().for_each(|word| push_long(&mut acc, word));
+                        return (acc, cursor + statement.get_size());
+                    }
+                    Statement::Binary(bytes) => {
+                        acc.extend(bytes);
+                        return (acc, cursor + statement.get_size());
+                    }
+                    // The long form branches the other way over the JMP
+                    Statement::Branch(branch) if branch.long => {
+                        acc.extend([branch.opcode() ^ INVERT_BRANCH, JMP_SIZE as u8, JMP]);
+                        push_long(&mut acc, &branch.target);
+                        return (acc, cursor + statement.get_size());
+                    }
+                    Statement::Branch(branch) => {
+                        acc.push(branch.opcode());
+                        let (label, addend) = branch.target_label().unwrap();
+                        match self.labels.get(label) {
+                            Some(target) => {
+                                let offset = *target as i64 + addend as i64 - (cursor as i64 + 2);
+                                acc.push(offset as u8);
+                            }
+                            None => acc.push(0xFF),
+                        }
+                        return (acc, cursor + statement.get_size());
+                    }
+                };
+
                 let code: u8 = *expression.get_code();
                 acc.push(code);
                 match expression.operand {
@@ -66,39 +480,30 @@ impl Relocatable for Program {
                     | AddressValue::ZeroPageX(ref op)
                     | AddressValue::ZeroPageY(ref op)
                     | AddressValue::IndexedIndirect(ref op)
-                    | AddressValue::IndirectIndexed(ref op) => match op {
-                        ShortOperand::Numeric(value) => {
-                            acc.push(*value);
-                        }
-                        ShortOperand::Label(_) => {
-                            acc.push(0xFF);
-                        }
-                    },
+                    | AddressValue::IndirectIndexed(ref op) => push_short(&mut acc, op),
 
                     AddressValue::Absolute(ref long_op)
                     | AddressValue::AbsoluteX(ref long_op)
                     | AddressValue::AbsoluteY(ref long_op)
-                    | AddressValue::AbsoluteIndirect(ref long_op) => match long_op {
-                        LongOperand::Numeric(value) => {
-                            acc.push((value & 0xFF) as u8);
-                            acc.push((value >> 8) as u8);
-                        }
-                        LongOperand::Label(_) => {
-                            acc.push(0xFF);
-                            acc.push(0xFF);
-                        }
-                    },
+                    | AddressValue::AbsoluteIndirect(ref long_op) => push_long(&mut acc, long_op),
 
                     AddressValue::Relative(ref short_op) => match short_op {
                         ShortOperand::Numeric(value) => {
                             acc.push(*value);
                         }
-                        ShortOperand::Label(label) => {
+                        ShortOperand::Bank(_)
+                        | ShortOperand::Low(_, _)
+                        | ShortOperand::High(_, _) => unreachable!(),
+                        ShortOperand::Label(label) | ShortOperand::Offset(label, _) => {
                             // If the label is in the symbol table, calculate the offset
-                            // to the currect cursor and insert that value as a u8.
+                            // from the end of the branch and insert that value as a u8.
                             // Otherwise, insert 0xFF.
-                            if let Some((label_cursor, _)) = self.labels.get(label) {
-                                let offset = *label_cursor as i16 - cursor as i16;
+                            let addend = match short_op {
+                                ShortOperand::Offset(_, offset) => *offset as i16,
+                                _ => 0,
+                            };
+                            if let Some(label_cursor) = self.labels.get(label) {
+                                let offset = *label_cursor as i16 + addend - (cursor as i16 + 2);
                                 acc.push(offset as u8);
                             } else {
                                 acc.push(0xFF);
@@ -108,43 +513,98 @@ impl Relocatable for Program {
 
                     AddressValue::Accumulator | AddressValue::Implied => {}
                 }
-                (acc, cursor + expression.operand.get_size())
+                (acc, cursor + statement.get_size())
             })
             .0
     }
 
     fn get_relocations(&self) -> Vec<Relocation> {
-        self.expressions
+        self.statements
             .iter()
             .fold(
                 (Vec::<Relocation>::new(), 0),
-                |(mut acc, cursor), expression| {
+                |(mut acc, cursor), statement| {
+                    let new_cursor = cursor + statement.get_size();
+                    let expression = match statement {
+                        Statement::Instruction(expression) => expression,
+                        Statement::Bytes(bytes) => {
+                            acc.extend(bytes.iter().enumerate().filter_map(|(index, byte)| {
+                                short_relocation(byte, cursor + index)
+                            }));
+                            return (acc, new_cursor);
+                        }
+                        Statement::Words(words) => {
+                            acc.extend(words.iter().enumerate().filter_map(|(index, word)| {
+                                long_relocation(word, cursor + index * 2)
+                            }));
+                            return (acc, new_cursor);
+                        }
+                        Statement::Binary(_) => {
+                            return (acc, new_cursor);
+                        }
+                        Statement::Branch(branch) => {
+                            if let Some((label, addend)) = branch.target_label() {
+                                let label = label.clone();
+                                match branch.long {
+                                    true => acc.extend([
+                                        Relocation::Relax(label.clone(), cursor as u32, addend),
+                                        Relocation::Jump(label, cursor as u32 + 3, addend),
+                                    ]),
+                                    false => acc.push(Relocation::Relative(
+                                        label,
+                                        cursor as u32 + 1,
+                                        addend,
+                                    )),
+                                }
+                            }
+                            return (acc, new_cursor);
+                        }
+                    };
+
                     let current_relocation = cursor + 1;
-                    let new_cursor = cursor + expression.operand.get_size();
                     match expression.operand {
-                        AddressValue::Immediate(ShortOperand::Label(ref label))
-                        | AddressValue::ZeroPage(ShortOperand::Label(ref label))
-                        | AddressValue::ZeroPageX(ShortOperand::Label(ref label))
-                        | AddressValue::ZeroPageY(ShortOperand::Label(ref label))
-                        | AddressValue::IndexedIndirect(ShortOperand::Label(ref label))
-                        | AddressValue::IndirectIndexed(ShortOperand::Label(ref label)) => {
-                            acc.push(Relocation::Short(label.clone(), current_relocation as u16));
+                        AddressValue::Immediate(ref op)
+                        | AddressValue::ZeroPage(ref op)
+                        | AddressValue::ZeroPageX(ref op)
+                        | AddressValue::ZeroPageY(ref op)
+                        | AddressValue::IndexedIndirect(ref op)
+                        | AddressValue::IndirectIndexed(ref op) => {
+                            acc.extend(short_relocation(op, current_relocation));
                         }
-                        AddressValue::Absolute(LongOperand::Label(ref label))
-                        | AddressValue::AbsoluteX(LongOperand::Label(ref label))
-                        | AddressValue::AbsoluteY(LongOperand::Label(ref label)) => {
-                            acc.push(Relocation::Long(label.clone(), current_relocation as u16));
+                        AddressValue::Absolute(ref long_op) if expression.is_jump() => {
+                            acc.extend(jump_relocation(long_op, current_relocation));
+                        }
+                        AddressValue::Absolute(ref long_op)
+                        | AddressValue::AbsoluteX(ref long_op)
+                        | AddressValue::AbsoluteY(ref long_op) => {
+                            acc.extend(long_relocation(long_op, current_relocation));
                         }
                         AddressValue::Relative(ShortOperand::Label(ref label)) => {
                             acc.push(Relocation::Relative(
                                 label.clone(),
-                                current_relocation as u16,
+                                current_relocation as u32,
+                                0,
+                            ));
+                        }
+                        AddressValue::Relative(ShortOperand::Offset(ref label, offset)) => {
+                            acc.push(Relocation::Relative(
+                                label.clone(),
+                                current_relocation as u32,
+                                offset,
                             ));
                         }
                         AddressValue::AbsoluteIndirect(LongOperand::Label(ref label)) => {
                             acc.push(Relocation::Absolute(
                                 label.clone(),
-                                current_relocation as u16,
+                                current_relocation as u32,
+                                0,
+                            ));
+                        }
+                        AddressValue::AbsoluteIndirect(LongOperand::Offset(ref label, offset)) => {
+                            acc.push(Relocation::Absolute(
+                                label.clone(),
+                                current_relocation as u32,
+                                offset,
                             ));
                         }
                         _ => {}
@@ -155,10 +615,41 @@ impl Relocatable for Program {
             .0
     }
 
+    // Programs put together without the assembler have everything in the
+    // one section.
+    fn get_sections(&self) -> Vec<Section> {
+        if self.sections.is_empty() {
+            return vec![Section {
+                name: "text".to_string(),
+                start: 0,
+                size: self.cursor,
+                bank: None,
+            }];
+        }
+        self.sections.clone()
+    }
+
     fn get_symbols(&self) -> HashMap<String, Symbol> {
-        self.labels
+        let mut symbols: HashMap<String, Symbol> = self
+            .labels
             .iter()
-            .map(|(label, (cursor, _))| (label.clone(), Symbol::Location(*cursor)))
-            .collect()
+            .map(|(label, cursor)| {
+                let symbol = match self.sizes.get(label) {
+                    Some(size) => Symbol::Procedure(*cursor, *size),
+                    None => Symbol::Location(*cursor),
+                };
+                (label.clone(), symbol)
+            })
+            .collect();
+        // Ones that don't fit in an address can't be written out
+        for (name, value) in &self.constants {
+            let symbol = match *value {
+                0..=0xFF => Symbol::ShortValue(*value as u8),
+                -0x8000..=0xFFFF => Symbol::LongValue(*value as u16),
+                _ => continue,
+            };
+            symbols.insert(name.clone(), symbol);
+        }
+        symbols
     }
 }
@@ -0,0 +1,70 @@
+use super::source::SourceSpan;
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Note,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<SourceSpan>,
+}
+
+impl Diagnostic {
+    pub fn error(span: &SourceSpan, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            span: Some(span.clone()),
+        }
+    }
+
+    pub fn warning(span: &SourceSpan, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            message: message.into(),
+            span: Some(span.clone()),
+        }
+    }
+
+    pub fn note(span: &SourceSpan, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Note,
+            message: message.into(),
+            span: Some(span.clone()),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match &self.span {
+            Some(span) => {
+                write!(f, "{}: {}: {}", span, severity, self.message)?;
+                for expansion in span.expansions() {
+                    write!(f, "\n{}: note: expanded from here", expansion)?;
+                }
+                for include in span.include_stack() {
+                    write!(f, "\n{}: note: included from here", include)?;
+                }
+                Ok(())
+            }
+            None => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
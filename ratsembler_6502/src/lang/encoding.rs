@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fmt;
+
+// Character sets of the machines we assemble for. Strings and character
+// literals are written in the source as Unicode and translated on the way
+// into the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Ascii,
+    // Commodore PETSCII, as printed by the KERNAL CHROUT routine
+    Petscii,
+    // C64 screen codes, for writing straight into screen memory
+    Screen,
+    // Atari 8-bit ATASCII
+    Atascii,
+    // Apple II text, which is ASCII with the high bit set
+    AppleII,
+}
+
+pub const ENCODING_NAMES: &[(&str, Encoding)] = &[
+    ("ascii", Encoding::Ascii),
+    ("petscii", Encoding::Petscii),
+    ("screen", Encoding::Screen),
+    ("atascii", Encoding::Atascii),
+    ("apple2", Encoding::AppleII),
+];
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        ENCODING_NAMES
+            .iter()
+            .find(|(encoding_name, _)| name.eq_ignore_ascii_case(encoding_name))
+            .map(|(_, encoding)| *encoding)
+    }
+
+    pub fn encode(self, character: char) -> Option<u8> {
+        let code = character as u32;
+        match self {
+            Encoding::Ascii => (code < 0x80).then_some(code as u8),
+            // Lower case is the normal unshifted character set, and upper
+            // case is shifted, which matches how text looks in the
+            // upper/lower case character set.
+            Encoding::Petscii => match character {
+                '\n' => Some(0x0D),
+                ' '..='@' | '[' | ']' => Some(code as u8),
+                'a'..='z' => Some(code as u8 - 0x20),
+                'A'..='Z' => Some(code as u8 + 0x80),
+                '£' => Some(0x5C),
+                '↑' => Some(0x5E),
+                '←' => Some(0x5F),
+                _ => None,
+            },
+            Encoding::Screen => match Encoding::Petscii.encode(character)? {
+                petscii @ 0x20..=0x3F => Some(petscii),
+                petscii @ 0x40..=0x5F => Some(petscii - 0x40),
+                petscii @ 0xC0..=0xDF => Some(petscii - 0x80),
+                _ => None,
+            },
+            Encoding::Atascii => match character {
+                '\n' => Some(0x9B),
+                ' '..='_' | 'a'..='z' | '|' => Some(code as u8),
+                _ => None,
+            },
+            Encoding::AppleII => match character {
+                '\n' => Some(0x8D),
+                ' '..='~' => Some(code as u8 | 0x80),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, _) = ENCODING_NAMES
+            .iter()
+            .find(|(_, encoding)| encoding == self)
+            .unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+// An encoding plus any characters remapped with `.charmap`.
+#[derive(Debug, Clone)]
+pub struct CharacterMap {
+    encoding: Encoding,
+    overrides: HashMap<char, u8>,
+}
+
+impl Default for CharacterMap {
+    fn default() -> Self {
+        CharacterMap::new(Encoding::Ascii)
+    }
+}
+
+impl CharacterMap {
+    pub fn new(encoding: Encoding) -> CharacterMap {
+        CharacterMap {
+            encoding,
+            overrides: HashMap::new(),
+        }
+    }
+
+    pub fn set(&mut self, character: char, code: u8) {
+        self.overrides.insert(character, code);
+    }
+
+    pub fn encode(&self, character: char) -> Result<u8, String> {
+        self.overrides
+            .get(&character)
+            .copied()
+            .or_else(|| self.encoding.encode(character))
+            .ok_or_else(|| {
+                format!(
+                    "character {:?} can't be encoded in {}",
+                    character, self.encoding
+                )
+            })
+    }
+
+    pub fn encode_str(&self, string: &str) -> Result<Vec<u8>, String> {
+        string
+            .chars()
+            .map(|character| self.encode(character))
+            .collect()
+    }
+}
+
+// Expands the escapes allowed in string and character literals.
+pub fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut characters = text.chars();
+    while let Some(character) = characters.next() {
+        if character != '\\' {
+            result.push(character);
+            continue;
+        }
+        match characters.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('0') => result.push('\0'),
+            Some(escaped) => result.push(escaped),
+            None => {}
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_encodings() {
+        let encode =
+            |encoding: Encoding, text: &str| CharacterMap::new(encoding).encode_str(text).unwrap();
+        assert_eq!(encode(Encoding::Ascii, "Hi!\n"), b"Hi!\n");
+        assert_eq!(
+            encode(Encoding::Petscii, "Hi 1\n"),
+            [0xC8, 0x49, 0x20, 0x31, 0x0D]
+        );
+        assert_eq!(
+            encode(Encoding::Screen, "@Hi 1"),
+            [0x00, 0x48, 0x09, 0x20, 0x31]
+        );
+        assert_eq!(encode(Encoding::Atascii, "Hi|\n"), [0x48, 0x69, 0x7C, 0x9B]);
+        assert_eq!(encode(Encoding::AppleII, "Hi\n"), [0xC8, 0xE9, 0x8D]);
+    }
+
+    #[test]
+    fn test_unencodable_characters() {
+        assert_eq!(
+            CharacterMap::new(Encoding::Petscii).encode('{'),
+            Err("character '{' can't be encoded in petscii".to_string())
+        );
+        assert!(CharacterMap::new(Encoding::Ascii).encode('é').is_err());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut map = CharacterMap::new(Encoding::Screen);
+        map.set('{', 0x5B);
+        map.set('A', 0x01);
+        assert_eq!(
+            map.encode_str("{A}"),
+            Err("character '}' can't be encoded in screen".to_string())
+        );
+        assert_eq!(map.encode_str("{A"), Ok(vec![0x5B, 0x01]));
+    }
+
+    #[test]
+    fn test_names_and_escapes() {
+        assert_eq!(Encoding::from_name("PETSCII"), Some(Encoding::Petscii));
+        assert_eq!(Encoding::from_name("ebcdic"), None);
+        assert_eq!(unescape(r#"a\n\"\\\x"#), "a\n\"\\x");
+    }
+}
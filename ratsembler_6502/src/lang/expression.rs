@@ -1,24 +1,35 @@
+use super::arithmetic::ArithmeticExpression;
+use super::arithmetic::SymbolLookup;
+use super::arithmetic::UnaryOperator;
 use super::instruction::AddressModeIndexer;
 use super::instruction::InstructionCode;
 use super::instruction::INSTRUCTION_MAP;
 use super::instruction::INSTRUCTION_STR_MAP;
+use super::instruction::LONG_BRANCHES;
 
 use pest::iterators::Pair;
-use pest::iterators::Pairs;
 
 use super::parser::Rule;
-use pest::Parser;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ShortOperand {
     Numeric(u8),
     Label(String),
+    // A label plus a constant, e.g. `table+2`
+    Offset(String, i32),
+    // The bank a label is in, `^far`
+    Bank(String),
+    // The low and high bytes of a label's address plus a constant, `<table`
+    // and `>(table+2)`
+    Low(String, i32),
+    High(String, i32),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum LongOperand {
     Numeric(u16),
     Label(String),
+    Offset(String, i32),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -47,13 +58,123 @@ pub struct Expression {
     pub operand: AddressValue,
 }
 
+// Used when parsing outside of the assembler, where every symbol is
+// treated as a label.
+struct NoSymbols;
+
+impl SymbolLookup for NoSymbols {
+    fn lookup(&self, name: &str) -> Result<i64, String> {
+        Err(format!("undefined symbol '{}'", name))
+    }
+
+    fn is_defined(&self, _name: &str) -> bool {
+        false
+    }
+}
+
+// What an operand expression turned out to be once its symbols were looked
+// up. Hex literals written with more than two digits are always treated as
+// absolute addresses, so `LDA $0010` stays a three byte instruction.
+enum OperandValue {
+    Numeric(i64, bool),
+    Label(String, i64),
+    Bank(String),
+    Low(String, i64),
+    High(String, i64),
+}
+
+impl OperandValue {
+    fn from_arithmetic_pair(
+        arithmetic: Pair<Rule>,
+        symbols: &dyn SymbolLookup,
+    ) -> Result<OperandValue, String> {
+        let long_literal = {
+            let mut inner_pairs = arithmetic.clone().into_inner();
+            match (inner_pairs.next(), inner_pairs.next()) {
+                (Some(literal), None) => {
+                    literal.as_rule() == Rule::hex_number && literal.as_str().len() > 3
+                }
+                _ => false,
+            }
+        };
+        let expression = ArithmeticExpression::from_arithmetic_pair(arithmetic);
+        if let Some((label, offset)) = expression.as_label_offset(symbols) {
+            return Ok(OperandValue::Label(label, offset));
+        }
+        if let Some(label) = expression.as_bank_label(symbols) {
+            return Ok(OperandValue::Bank(label));
+        }
+        match expression.as_byte_label(symbols) {
+            Some((UnaryOperator::LowByte, label, offset)) => {
+                return Ok(OperandValue::Low(label, offset))
+            }
+            Some((_, label, offset)) => return Ok(OperandValue::High(label, offset)),
+            None => {}
+        }
+        Ok(OperandValue::Numeric(
+            expression.evaluate(symbols)?,
+            long_literal,
+        ))
+    }
+
+    fn is_short(&self) -> bool {
+        match self {
+            OperandValue::Numeric(value, long_literal) => {
+                !long_literal && (0..=0xFF).contains(value)
+            }
+            OperandValue::Label(_, _) => false,
+            OperandValue::Bank(_) | OperandValue::Low(_, _) | OperandValue::High(_, _) => true,
+        }
+    }
+
+    fn to_short(&self) -> Result<ShortOperand, String> {
+        match self {
+            OperandValue::Numeric(value, _) => {
+                if !(-0x80..=0xFF).contains(value) {
+                    return Err(format!("value ${:X} does not fit in a byte", value));
+                }
+                Ok(ShortOperand::Numeric(*value as u8))
+            }
+            OperandValue::Label(label, 0) => Ok(ShortOperand::Label(label.clone())),
+            OperandValue::Label(label, offset) => {
+                Ok(ShortOperand::Offset(label.clone(), *offset as i32))
+            }
+            OperandValue::Bank(label) => Ok(ShortOperand::Bank(label.clone())),
+            OperandValue::Low(label, offset) => {
+                Ok(ShortOperand::Low(label.clone(), *offset as i32))
+            }
+            OperandValue::High(label, offset) => {
+                Ok(ShortOperand::High(label.clone(), *offset as i32))
+            }
+        }
+    }
+
+    fn to_long(&self) -> Result<LongOperand, String> {
+        match self {
+            OperandValue::Numeric(value, _) => {
+                if !(-0x8000..=0xFFFF).contains(value) {
+                    return Err(format!("value ${:X} does not fit in a word", value));
+                }
+                Ok(LongOperand::Numeric(*value as u16))
+            }
+            OperandValue::Label(label, 0) => Ok(LongOperand::Label(label.clone())),
+            OperandValue::Label(label, offset) => {
+                Ok(LongOperand::Offset(label.clone(), *offset as i32))
+            }
+            OperandValue::Bank(label) => Err(format!("the bank of '{}' is only a byte", label)),
+            OperandValue::Low(label, _) | OperandValue::High(label, _) => {
+                Err(format!("a byte of '{}' is only a byte", label))
+            }
+        }
+    }
+}
+
 impl AddressValue {
     pub fn to_indexer(&self) -> AddressModeIndexer {
         match self {
             AddressValue::Accumulator => AddressModeIndexer::ACCUMULATOR,
             AddressValue::Implied => AddressModeIndexer::IMPLIED,
-            AddressValue::Immediate(ShortOperand::Numeric(_)) => AddressModeIndexer::IMMEDIATE,
-            AddressValue::Immediate(ShortOperand::Label(_)) => AddressModeIndexer::IMMEDIATE,
+            AddressValue::Immediate(_) => AddressModeIndexer::IMMEDIATE,
             AddressValue::Absolute(_) => AddressModeIndexer::ABSOLUTE,
             AddressValue::ZeroPage(_) => AddressModeIndexer::ZERO_PAGE,
             AddressValue::Relative(_) => AddressModeIndexer::RELATIVE,
@@ -86,63 +207,153 @@ impl AddressValue {
     }
 
     pub fn from_indexed_addresser(addresser: Pair<Rule>) -> AddressValue {
+        AddressValue::from_indexed_addresser_with_symbols(addresser, &NoSymbols).unwrap()
+    }
+
+    pub fn from_indexed_addresser_with_symbols(
+        addresser: Pair<Rule>,
+        symbols: &dyn SymbolLookup,
+    ) -> Result<AddressValue, String> {
         let mut inner_pairs = addresser.into_inner();
-        let address: Pair<Rule> = inner_pairs.next().unwrap();
-        match address.as_rule() {
-            Rule::short_literal => match inner_pairs.next().unwrap().as_str() {
-                ",X" => AddressValue::ZeroPageX(ShortOperand::Numeric(
-                    u8::from_str_radix(&address.as_str()[1..], 16).unwrap(),
-                )),
-                ",Y" => AddressValue::ZeroPageY(ShortOperand::Numeric(
-                    u8::from_str_radix(&address.as_str()[1..], 16).unwrap(),
-                )),
-                _ => {
-                    unreachable!()
-                }
-            },
-            Rule::label => match inner_pairs.next().unwrap().as_str() {
-                ",X" => AddressValue::ZeroPageX(ShortOperand::Label(address.as_str().into())),
-                ",Y" => AddressValue::ZeroPageY(ShortOperand::Label(address.as_str().into())),
-                _ => {
-                    unreachable!()
-                }
-            },
-            Rule::long_literal => match inner_pairs.next().unwrap().as_str() {
-                ",X" => AddressValue::AbsoluteX(LongOperand::Numeric(
-                    u16::from_str_radix(&address.as_str()[1..], 16).unwrap(),
-                )),
-                ",Y" => AddressValue::AbsoluteY(LongOperand::Numeric(
-                    u16::from_str_radix(&address.as_str()[1..], 16).unwrap(),
-                )),
-                _ => {
-                    unreachable!()
-                }
-            },
+        let address = OperandValue::from_arithmetic_pair(inner_pairs.next().unwrap(), symbols)?;
+        let short = address.is_short();
+        match (inner_pairs.next().unwrap().as_rule(), short) {
+            (Rule::x_indexed_addresser, true) => Ok(AddressValue::ZeroPageX(address.to_short()?)),
+            (Rule::y_indexed_addresser, true) => Ok(AddressValue::ZeroPageY(address.to_short()?)),
+            (Rule::x_indexed_addresser, false) => Ok(AddressValue::AbsoluteX(address.to_long()?)),
+            (Rule::y_indexed_addresser, false) => Ok(AddressValue::AbsoluteY(address.to_long()?)),
             _ => {
                 unreachable!()
             }
         }
     }
+
+    pub fn label_mut(&mut self) -> Option<&mut String> {
+        match self {
+            AddressValue::Accumulator | AddressValue::Implied => None,
+            AddressValue::Immediate(operand)
+            | AddressValue::ZeroPage(operand)
+            | AddressValue::Relative(operand)
+            | AddressValue::ZeroPageX(operand)
+            | AddressValue::ZeroPageY(operand)
+            | AddressValue::IndexedIndirect(operand)
+            | AddressValue::IndirectIndexed(operand) => operand.label_mut(),
+            AddressValue::Absolute(operand)
+            | AddressValue::AbsoluteIndirect(operand)
+            | AddressValue::AbsoluteX(operand)
+            | AddressValue::AbsoluteY(operand) => operand.label_mut(),
+        }
+    }
+
+    // Zero page forms that the instruction doesn't have fall back to the
+    // absolute form, e.g. `LDA $10,Y` can only be encoded as `LDA $0010,Y`.
+    fn widen(self) -> AddressValue {
+        let widen_operand = |operand: ShortOperand| match operand {
+            ShortOperand::Numeric(value) => LongOperand::Numeric(value as u16),
+            ShortOperand::Label(label) => LongOperand::Label(label),
+            ShortOperand::Offset(label, offset) => LongOperand::Offset(label, offset),
+            ShortOperand::Bank(_) | ShortOperand::Low(_, _) | ShortOperand::High(_, _) => {
+                unreachable!()
+            }
+        };
+        // A bank number or a byte of an address is never an address itself,
+        // so it's left for `has_code` to turn down.
+        if let AddressValue::ZeroPage(operand)
+        | AddressValue::ZeroPageX(operand)
+        | AddressValue::ZeroPageY(operand) = &self
+        {
+            if !matches!(
+                operand,
+                ShortOperand::Numeric(_) | ShortOperand::Label(_) | ShortOperand::Offset(_, _)
+            ) {
+                return self;
+            }
+        }
+        match self {
+            AddressValue::ZeroPage(operand) => AddressValue::Absolute(widen_operand(operand)),
+            AddressValue::ZeroPageX(operand) => AddressValue::AbsoluteX(widen_operand(operand)),
+            AddressValue::ZeroPageY(operand) => AddressValue::AbsoluteY(widen_operand(operand)),
+            other => other,
+        }
+    }
+}
+
+impl ShortOperand {
+    // The label the operand refers to, if any.
+    pub fn label_mut(&mut self) -> Option<&mut String> {
+        match self {
+            ShortOperand::Numeric(_) => None,
+            ShortOperand::Label(label)
+            | ShortOperand::Offset(label, _)
+            | ShortOperand::Bank(label)
+            | ShortOperand::Low(label, _)
+            | ShortOperand::High(label, _) => Some(label),
+        }
+    }
+
+    // The number the operand comes to if its label turned out to be a
+    // constant, and it fits. Bank numbers are only ever for labels.
+    pub fn resolved(&self, constant: impl Fn(&str) -> Option<i64>) -> Option<ShortOperand> {
+        let value = match self {
+            ShortOperand::Numeric(_) | ShortOperand::Bank(_) => return None,
+            ShortOperand::Label(label) => constant(label)?,
+            ShortOperand::Offset(label, offset) => constant(label)? + *offset as i64,
+            ShortOperand::Low(label, offset) => (constant(label)? + *offset as i64) & 0xFF,
+            ShortOperand::High(label, offset) => (constant(label)? + *offset as i64) >> 8 & 0xFF,
+        };
+        (-0x80..=0xFF)
+            .contains(&value)
+            .then_some(ShortOperand::Numeric(value as u8))
+    }
+
+    pub fn from_arithmetic_pair(
+        arithmetic: Pair<Rule>,
+        symbols: &dyn SymbolLookup,
+    ) -> Result<ShortOperand, String> {
+        OperandValue::from_arithmetic_pair(arithmetic, symbols)?.to_short()
+    }
 }
 
 impl LongOperand {
+    pub fn label_mut(&mut self) -> Option<&mut String> {
+        match self {
+            LongOperand::Numeric(_) => None,
+            LongOperand::Label(label) | LongOperand::Offset(label, _) => Some(label),
+        }
+    }
+
+    pub fn resolved(&self, constant: impl Fn(&str) -> Option<i64>) -> Option<LongOperand> {
+        let value = match self {
+            LongOperand::Numeric(_) => return None,
+            LongOperand::Label(label) => constant(label)?,
+            LongOperand::Offset(label, offset) => constant(label)? + *offset as i64,
+        };
+        (-0x8000..=0xFFFF)
+            .contains(&value)
+            .then_some(LongOperand::Numeric(value as u16))
+    }
+
+    pub fn from_arithmetic_pair(
+        arithmetic: Pair<Rule>,
+        symbols: &dyn SymbolLookup,
+    ) -> Result<LongOperand, String> {
+        OperandValue::from_arithmetic_pair(arithmetic, symbols)?.to_long()
+    }
+
     pub fn from_indirect_addresser(addresser: Pair<super::parser::Rule>) -> LongOperand {
         assert_eq!(addresser.as_rule(), super::parser::Rule::indirect_addresser);
-        let mut inner_pairs = addresser.into_inner();
-        let address: Pair<Rule> = inner_pairs.next().unwrap();
-        match address.as_rule() {
-            Rule::label => LongOperand::Label(address.as_str().into()),
-            Rule::long_literal => {
-                LongOperand::Numeric(u16::from_str_radix(&address.as_str()[1..], 16).unwrap())
-            }
-            _ => {
-                unreachable!()
-            }
-        }
+        LongOperand::from_arithmetic_pair(addresser.into_inner().next().unwrap(), &NoSymbols)
+            .unwrap()
     }
 }
 
 impl Expression {
+    // Not every instruction supports every addressing mode the grammar can
+    // produce, e.g. `LDX $10,X`.
+    pub fn has_code(&self) -> bool {
+        INSTRUCTION_MAP.contains_key(&(self.operator, self.operand.to_indexer()))
+    }
+
     pub fn get_code(&self) -> &u8 {
         INSTRUCTION_MAP
             .get(&(self.operator, self.operand.to_indexer()))
@@ -153,11 +364,43 @@ impl Expression {
         self.operand.get_size()
     }
 
+    // The branch a long branch like JEQ stands for, and where it's to.
+    pub fn long_branch(&self) -> Option<(InstructionCode, LongOperand)> {
+        let (_, branch) = LONG_BRANCHES
+            .iter()
+            .find(|(long_branch, _)| *long_branch == self.operator)?;
+        match &self.operand {
+            AddressValue::Absolute(target) => Some((*branch, target.clone())),
+            _ => None,
+        }
+    }
+
+    // Whether the next instruction can run after this one without a jump
+    // to it.
+    pub fn falls_through(&self) -> bool {
+        !matches!(
+            self.operator,
+            InstructionCode::JMP | InstructionCode::RTS | InstructionCode::RTI
+        )
+    }
+
+    // Whether this is a JSR or JMP to an absolute address.
+    pub fn is_jump(&self) -> bool {
+        matches!(self.operator, InstructionCode::JSR | InstructionCode::JMP)
+            && matches!(self.operand, AddressValue::Absolute(_))
+    }
+
     pub fn from_expression_pair(
         expression: Pair<super::parser::Rule>,
     ) -> (Vec<String>, Expression) {
+        Expression::from_expression_pair_with_symbols(expression, &NoSymbols).unwrap()
+    }
+
+    pub fn from_expression_pair_with_symbols(
+        expression: Pair<super::parser::Rule>,
+        symbols: &dyn SymbolLookup,
+    ) -> Result<(Vec<String>, Expression), String> {
         assert_eq!(expression.as_rule(), super::parser::Rule::expression);
-        println!("parsing expression {:?}", expression);
 
         let mut labels: Vec<String> = Vec::new();
         // We're going to assume that we actually have an Expression here.
@@ -179,68 +422,66 @@ impl Expression {
         let operand: AddressValue = {
             if let Some(address_value) = inner_pairs.next() {
                 match address_value.as_rule() {
-                    Rule::indirect_addresser => AddressValue::AbsoluteIndirect(
-                        LongOperand::from_indirect_addresser(address_value),
-                    ),
+                    Rule::indirect_addresser => {
+                        AddressValue::AbsoluteIndirect(LongOperand::from_arithmetic_pair(
+                            address_value.into_inner().next().unwrap(),
+                            symbols,
+                        )?)
+                    }
                     Rule::immediate_addresser => {
-                        let mut inner_pairs = address_value.into_inner();
-                        // print the tokens that come through here
-                        println!("{:?}", inner_pairs);
-                        AddressValue::Immediate(ShortOperand::Numeric(
-                            u8::from_str_radix(&inner_pairs.next().unwrap().as_str()[1..], 16)
-                                .unwrap(),
-                        ))
+                        AddressValue::Immediate(ShortOperand::from_arithmetic_pair(
+                            address_value.into_inner().next().unwrap(),
+                            symbols,
+                        )?)
+                    }
+                    Rule::indexed_addresser => {
+                        AddressValue::from_indexed_addresser_with_symbols(address_value, symbols)?
                     }
-                    Rule::indexed_addresser => AddressValue::from_indexed_addresser(address_value),
                     Rule::indexed_indirect_addresser => {
-                        let mut inner_pairs = address_value.into_inner();
-                        let address: Pair<Rule> = inner_pairs.next().unwrap();
-                        assert_eq!(address.as_rule(), Rule::short_literal);
-                        AddressValue::IndexedIndirect(ShortOperand::Numeric(
-                            u8::from_str_radix(&address.as_str()[1..], 16).unwrap(),
-                        ))
+                        AddressValue::IndexedIndirect(ShortOperand::from_arithmetic_pair(
+                            address_value.into_inner().next().unwrap(),
+                            symbols,
+                        )?)
                     }
                     Rule::indirect_indexed_addresser => {
-                        let mut inner_pairs: Pairs<Rule> = address_value.into_inner();
-                        let address: Pair<Rule> = inner_pairs.next().unwrap();
-                        assert_eq!(address.as_rule(), Rule::short_literal);
-                        AddressValue::IndirectIndexed(ShortOperand::Numeric(
-                            u8::from_str_radix(&address.as_str()[1..], 16).unwrap(),
-                        ))
+                        AddressValue::IndirectIndexed(ShortOperand::from_arithmetic_pair(
+                            address_value.into_inner().next().unwrap(),
+                            symbols,
+                        )?)
+                    }
+                    Rule::accumulator_addresser => AddressValue::Accumulator,
+                    Rule::arithmetic => {
+                        let address = OperandValue::from_arithmetic_pair(address_value, symbols)?;
+                        match operation {
+                            InstructionCode::BCC
+                            | InstructionCode::BCS
+                            | InstructionCode::BEQ
+                            | InstructionCode::BMI
+                            | InstructionCode::BNE
+                            | InstructionCode::BPL
+                            | InstructionCode::BVC
+                            | InstructionCode::BVS => match address {
+                                OperandValue::Bank(label) => {
+                                    return Err(format!("can't branch to the bank of '{}'", label))
+                                }
+                                OperandValue::Low(label, _) | OperandValue::High(label, _) => {
+                                    return Err(format!("can't branch to a byte of '{}'", label))
+                                }
+                                _ => AddressValue::Relative(address.to_short()?),
+                            },
+                            // Even to the zero page, since it might be a JMP
+                            InstructionCode::JCC
+                            | InstructionCode::JCS
+                            | InstructionCode::JEQ
+                            | InstructionCode::JMI
+                            | InstructionCode::JNE
+                            | InstructionCode::JPL
+                            | InstructionCode::JVC
+                            | InstructionCode::JVS => AddressValue::Absolute(address.to_long()?),
+                            _ if address.is_short() => AddressValue::ZeroPage(address.to_short()?),
+                            _ => AddressValue::Absolute(address.to_long()?),
+                        }
                     }
-                    Rule::short_literal => match operation {
-                        InstructionCode::BCC
-                        | InstructionCode::BCS
-                        | InstructionCode::BEQ
-                        | InstructionCode::BMI
-                        | InstructionCode::BNE
-                        | InstructionCode::BPL
-                        | InstructionCode::BVC
-                        | InstructionCode::BVS => AddressValue::Relative(ShortOperand::Numeric(
-                            u8::from_str_radix(&address_value.as_str()[1..], 16).unwrap(),
-                        )),
-                        _ => AddressValue::ZeroPage(ShortOperand::Numeric(
-                            u8::from_str_radix(&address_value.as_str()[1..], 16).unwrap(),
-                        )),
-                    },
-                    Rule::long_literal => AddressValue::Absolute(LongOperand::Numeric(
-                        u16::from_str_radix(&address_value.as_str()[1..], 16).unwrap(),
-                    )),
-                    Rule::label => match operation {
-                        InstructionCode::BCC
-                        | InstructionCode::BCS
-                        | InstructionCode::BEQ
-                        | InstructionCode::BMI
-                        | InstructionCode::BNE
-                        | InstructionCode::BPL
-                        | InstructionCode::BVC
-                        | InstructionCode::BVS => AddressValue::Relative(ShortOperand::Label(
-                            address_value.as_str().into(),
-                        )),
-                        _ => AddressValue::Absolute(LongOperand::Label(
-                            address_value.as_str().into(),
-                        )),
-                    },
                     _ => {
                         unreachable!()
                     }
@@ -256,13 +497,15 @@ impl Expression {
             }
         };
 
-        (
-            labels,
-            Expression {
-                operator: operation,
-                operand: operand,
-            },
-        )
+        let mut expression = Expression {
+            operator: operation,
+            operand,
+        };
+        if !expression.has_code() {
+            expression.operand = expression.operand.widen();
+        }
+
+        Ok((labels, expression))
     }
 }
 
@@ -271,6 +514,8 @@ mod tests {
     use super::super::parser::Assembler6502Parser;
     use super::*;
 
+    use pest::Parser;
+
     #[test]
     fn test_to_indexer() {
         assert_eq!(
@@ -433,4 +678,50 @@ mod tests {
             AddressValue::ZeroPageX(ShortOperand::Numeric(0x10))
         );
     }
+
+    fn parse_expression(input: &str) -> Expression {
+        let pairs = Assembler6502Parser::parse(Rule::expression, input).unwrap();
+        Expression::from_expression_pair(pairs.into_iter().next().unwrap()).1
+    }
+
+    #[test]
+    fn test_operand_widths() {
+        assert_eq!(
+            parse_expression("LDA $0010").operand,
+            AddressValue::Absolute(LongOperand::Numeric(0x10))
+        );
+        assert_eq!(
+            parse_expression("LDA 16").operand,
+            AddressValue::ZeroPage(ShortOperand::Numeric(0x10))
+        );
+        assert_eq!(
+            parse_expression("LDA $10, y").operand,
+            AddressValue::AbsoluteY(LongOperand::Numeric(0x10))
+        );
+        assert_eq!(
+            parse_expression("LDX $10,Y").operand,
+            AddressValue::ZeroPageY(ShortOperand::Numeric(0x10))
+        );
+        assert_eq!(parse_expression("ASL A").operand, AddressValue::Accumulator);
+    }
+
+    #[test]
+    fn test_operand_expressions() {
+        assert_eq!(
+            parse_expression("LDA #<$1234").operand,
+            AddressValue::Immediate(ShortOperand::Numeric(0x34))
+        );
+        assert_eq!(
+            parse_expression("LDA (pointer + 2),Y").operand,
+            AddressValue::IndirectIndexed(ShortOperand::Offset("pointer".into(), 2))
+        );
+        assert_eq!(
+            parse_expression("JMP (vector)").operand,
+            AddressValue::AbsoluteIndirect(LongOperand::Label("vector".into()))
+        );
+        assert_eq!(
+            parse_expression("STA $0200 + 2 * 3").operand,
+            AddressValue::Absolute(LongOperand::Numeric(0x206))
+        );
+    }
 }
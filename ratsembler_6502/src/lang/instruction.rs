@@ -35,6 +35,10 @@ pub enum InstructionCode {
     LSR,
     ROL,
     ROR,
+    TAX,
+    TAY,
+    TXA,
+    TYA,
     TSX,
     TXS,
     PHA,
@@ -53,8 +57,20 @@ pub enum InstructionCode {
     SEI,
     NOP,
     BRK,
+    // Long branches, which aren't real instructions. They're a branch if the
+    // target's close enough and a branch over a JMP if it isn't.
+    JCC,
+    JCS,
+    JEQ,
+    JNE,
+    JMI,
+    JPL,
+    JVS,
+    JVC,
 }
 
+// Variant names mirror the keys used in byte_codes.yaml.
+#[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum AddressModeIndexer {
     ABSOLUTE,
@@ -207,6 +223,10 @@ pub static INSTRUCTION_SET: &[(InstructionCode, AddressModeIndexer, u8)] = &[
     (InstructionCode::ROR, AddressModeIndexer::ACCUMULATOR, 0x6A),
     (InstructionCode::ROR, AddressModeIndexer::ZERO_PAGE, 0x66),
     (InstructionCode::ROR, AddressModeIndexer::ZP_X, 0x76),
+    (InstructionCode::TAX, AddressModeIndexer::IMPLIED, 0xAA),
+    (InstructionCode::TAY, AddressModeIndexer::IMPLIED, 0xA8),
+    (InstructionCode::TXA, AddressModeIndexer::IMPLIED, 0x8A),
+    (InstructionCode::TYA, AddressModeIndexer::IMPLIED, 0x98),
     (InstructionCode::TSX, AddressModeIndexer::IMPLIED, 0xBA),
     (InstructionCode::TXS, AddressModeIndexer::IMPLIED, 0x9A),
     (InstructionCode::PHA, AddressModeIndexer::IMPLIED, 0x48),
@@ -237,6 +257,17 @@ lazy_static! {
     };
 }
 
+lazy_static! {
+    // The other way round, for the disassembler
+    pub static ref OPCODE_MAP: HashMap<u8, (InstructionCode, AddressModeIndexer)> = {
+        let mut map = HashMap::new();
+        for (code, mode, opcode) in INSTRUCTION_SET.iter() {
+            map.insert(*opcode, (*code, *mode));
+        }
+        map
+    };
+}
+
 pub static INSTRUCTION_STRINGS: &[(InstructionCode, &str)] = &[
     (InstructionCode::LDA, "LDA"),
     (InstructionCode::LDX, "LDX"),
@@ -272,6 +303,10 @@ pub static INSTRUCTION_STRINGS: &[(InstructionCode, &str)] = &[
     (InstructionCode::LSR, "LSR"),
     (InstructionCode::ROL, "ROL"),
     (InstructionCode::ROR, "ROR"),
+    (InstructionCode::TAX, "TAX"),
+    (InstructionCode::TAY, "TAY"),
+    (InstructionCode::TXA, "TXA"),
+    (InstructionCode::TYA, "TYA"),
     (InstructionCode::TSX, "TSX"),
     (InstructionCode::TXS, "TXS"),
     (InstructionCode::PHA, "PHA"),
@@ -290,6 +325,26 @@ pub static INSTRUCTION_STRINGS: &[(InstructionCode, &str)] = &[
     (InstructionCode::SEI, "SEI"),
     (InstructionCode::NOP, "NOP"),
     (InstructionCode::BRK, "BRK"),
+    (InstructionCode::JCC, "JCC"),
+    (InstructionCode::JCS, "JCS"),
+    (InstructionCode::JEQ, "JEQ"),
+    (InstructionCode::JNE, "JNE"),
+    (InstructionCode::JMI, "JMI"),
+    (InstructionCode::JPL, "JPL"),
+    (InstructionCode::JVS, "JVS"),
+    (InstructionCode::JVC, "JVC"),
+];
+
+// Each long branch and the branch it stands for.
+pub static LONG_BRANCHES: &[(InstructionCode, InstructionCode)] = &[
+    (InstructionCode::JCC, InstructionCode::BCC),
+    (InstructionCode::JCS, InstructionCode::BCS),
+    (InstructionCode::JEQ, InstructionCode::BEQ),
+    (InstructionCode::JNE, InstructionCode::BNE),
+    (InstructionCode::JMI, InstructionCode::BMI),
+    (InstructionCode::JPL, InstructionCode::BPL),
+    (InstructionCode::JVS, InstructionCode::BVS),
+    (InstructionCode::JVC, InstructionCode::BVC),
 ];
 
 lazy_static! {
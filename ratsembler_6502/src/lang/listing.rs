@@ -0,0 +1,303 @@
+use super::ast::Program;
+use super::source::SourceSpan;
+
+use crate::elf::relocatable::Relocatable;
+use crate::elf::relocatable::Relocation;
+use crate::output::image::relocate;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+const BYTES_PER_ROW: usize = 8;
+
+// One line of source as it went through the assembler. Lines in skipped
+// conditional regions, or collected into a repetition body, aren't
+// `assembled`; the expanded copies of a body show up as lines of their own.
+#[derive(Debug, Clone)]
+pub struct ListingLine {
+    pub span: SourceSpan,
+    pub text: String,
+    pub assembled: bool,
+    // Relative to the start of the section until assembly is finished
+    pub address: usize,
+    pub size: usize,
+    // Index into the program's sections
+    pub section: usize,
+    // Constants the line used. Labels are found from the relocations.
+    pub references: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+    pub definitions: HashMap<String, SourceSpan>,
+    pub constants: HashMap<String, i64>,
+}
+
+fn format_value(value: i64) -> String {
+    if value < 0 {
+        format!("-{:04X}", -value)
+    } else {
+        format!("{:04X}", value)
+    }
+}
+
+impl Listing {
+    // Writes every line with the address and bytes it assembled to, then
+    // a cross-reference of where each symbol was defined and used. Addresses
+    // are where things get loaded, with anything before the first `.org` at
+    // `origin`. When `resolve` is set, references to the program's own labels
+    // are filled in the way a flat image has them.
+    // Bytes that still need relocating are shown as `rr`.
+    pub fn write(
+        &self,
+        out: &mut impl Write,
+        program: &Program,
+        origin: u16,
+        resolve: bool,
+    ) -> io::Result<()> {
+        let raw_section = match resolve {
+            true => relocate(program, origin).0,
+            false => program.get_raw_section(),
+        };
+        let relocations = program.get_relocations();
+        let mut relocated = HashSet::new();
+        for relocation in &relocations {
+            let offset = relocation.offset() as usize;
+            let local = program.labels.contains_key(relocation.symbol())
+                || program.constants.contains_key(relocation.symbol());
+            match relocation {
+                // Branches to labels in this program are already resolved.
+                Relocation::Relative(..) if local => {}
+                _ if resolve && local => {}
+                _ => relocated.extend(offset..offset + relocation.size()),
+            }
+        }
+
+        writeln!(out, " Line    Addr  Bytes                    Source")?;
+        writeln!(out)?;
+        let mut references: BTreeMap<&str, Vec<&SourceSpan>> = BTreeMap::new();
+        for line in &self.lines {
+            let depth = line.span.include_stack().len();
+            let marker = format!(
+                "{}{}",
+                if depth > 0 {
+                    depth.to_string()
+                } else {
+                    " ".to_string()
+                },
+                if line.span.expanded_from.is_some() {
+                    "+"
+                } else {
+                    " "
+                }
+            );
+            let bytes: Vec<String> = (line.address..line.address + line.size)
+                .map(|offset| match relocated.contains(&offset) {
+                    true => "rr".to_string(),
+                    false => format!("{:02X}", raw_section[offset]),
+                })
+                .collect();
+            let mut rows = bytes.chunks(BYTES_PER_ROW);
+            let address = match line.assembled {
+                true => format!("{:04X}", program.address_of(line.address, origin)),
+                false => String::new(),
+            };
+            let row = format!(
+                "{:>5} {} {:<4}  {:<24} {}",
+                line.span.line,
+                marker,
+                address,
+                rows.next().unwrap_or_default().join(" "),
+                line.text
+            );
+            writeln!(out, "{}", row.trim_end())?;
+            for (row, bytes) in rows.enumerate() {
+                writeln!(
+                    out,
+                    "{:>5} {} {:04X}  {}",
+                    "",
+                    marker,
+                    program.address_of(line.address + (row + 1) * BYTES_PER_ROW, origin),
+                    bytes.join(" ")
+                )?;
+            }
+
+            for name in &line.references {
+                references.entry(name).or_default().push(&line.span);
+            }
+            for relocation in &relocations {
+                let offset = relocation.offset() as usize;
+                if (line.address..line.address + line.size).contains(&offset) {
+                    references
+                        .entry(relocation.symbol())
+                        .or_default()
+                        .push(&line.span);
+                }
+            }
+        }
+
+        let mut names: Vec<&str> = self.definitions.keys().map(String::as_str).collect();
+        names.extend(references.keys());
+        names.sort();
+        names.dedup();
+
+        writeln!(out)?;
+        writeln!(out, "Symbols")?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:<24} {:<6} {:<20} Referenced",
+            "Name", "Value", "Defined"
+        )?;
+        for name in names {
+            let value = match (program.labels.get(name), self.constants.get(name)) {
+                (Some(location), _) => format!("{:04X}", program.address_of(*location, origin)),
+                (_, Some(value)) => format_value(*value),
+                _ => "????".to_string(),
+            };
+            let defined = match self.definitions.get(name) {
+                Some(span) => span.to_string(),
+                // Constants defined on the command line
+                None if self.constants.contains_key(name) => "(predefined)".to_string(),
+                None => "(external)".to_string(),
+            };
+            let mut referenced: Vec<String> = references
+                .get(name)
+                .into_iter()
+                .flatten()
+                .map(|span| span.to_string())
+                .collect();
+            referenced.dedup();
+            let row = format!(
+                "{:<24} {:<6} {:<20} {}",
+                name,
+                value,
+                defined,
+                referenced.join(", ")
+            );
+            writeln!(out, "{}", row.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::assembler::Assembler;
+    use crate::lang::source::SourceLine;
+
+    #[test]
+    fn test_listing() {
+        let source = r#"COUNT = 2
+start:
+  LDX #COUNT
+  JSR print
+.rept 2
+  DEX
+.endr
+  BNE start
+.if 0
+  nonsense
+.endif
+text: .byte "0123456789"
+"#;
+        let mut assembler = Assembler::new();
+        let program = assembler
+            .assemble(&SourceLine::from_source("test.s", source))
+            .unwrap();
+        let mut listing = Vec::new();
+        assembler
+            .listing()
+            .write(&mut listing, &program, 0, false)
+            .unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(
+            lines[2..],
+            [
+                "    1    0000                           COUNT = 2",
+                "    2    0000                           start:",
+                "    3    0000  A2 02                      LDX #COUNT",
+                "    4    0002  20 rr rr                   JSR print",
+                "    5    0005                           .rept 2",
+                "    6                                     DEX",
+                "    7                                   .endr",
+                "    6  + 0005  CA                         DEX",
+                "    6  + 0006  CA                         DEX",
+                "    8    0007  D0 F7                      BNE start",
+                "    9                                   .if 0",
+                "   10                                     nonsense",
+                "   11                                   .endif",
+                "   12    0009  30 31 32 33 34 35 36 37  text: .byte \"0123456789\"",
+                "         0011  38 39",
+                "",
+                "Symbols",
+                "",
+                "Name                     Value  Defined              Referenced",
+                "COUNT                    0002   test.s:1             test.s:3",
+                "print                    ????   (external)           test.s:4",
+                "start                    0000   test.s:2             test.s:8",
+                "text                     0009   test.s:12",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_org_listing() {
+        let source = r#"  .org $C000
+start:
+  JMP start
+  LDA var
+  JSR print
+.section data
+  .org $0200
+var: .byte 1
+"#;
+        let write = |resolve: bool| {
+            let mut assembler = Assembler::new();
+            let program = assembler
+                .assemble(&SourceLine::from_source("test.s", source))
+                .unwrap();
+            let mut listing = Vec::new();
+            assembler
+                .listing()
+                .write(&mut listing, &program, 0x0800, resolve)
+                .unwrap();
+            String::from_utf8(listing).unwrap()
+        };
+
+        let listing = write(true);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            lines[2..10],
+            [
+                "    1    C000                             .org $C000",
+                "    2    C000                           start:",
+                "    3    C000  4C 00 C0                   JMP start",
+                "    4    C003  AD 00 02                   LDA var",
+                "    5    C006  20 rr rr                   JSR print",
+                "    6    0200                           .section data",
+                "    7    0200                             .org $0200",
+                "    8    0200  01                       var: .byte 1",
+            ]
+        );
+        assert!(
+            listing.contains("\nstart                    C000 "),
+            "{}",
+            listing
+        );
+        assert!(
+            listing.contains("\nvar                      0200 "),
+            "{}",
+            listing
+        );
+
+        // Left for the linker in an object
+        let listing = write(false);
+        assert!(listing.contains("    3    C000  4C rr rr"), "{}", listing);
+    }
+}
@@ -0,0 +1,37 @@
+// Numbers given outside of the source, on the command line and in linker
+// configs and hint files, in any of the ways people tend to write them:
+// `$C000`, `0xC000`, `%1010` or plain decimal.
+pub fn parse_number(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix('$') {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = text.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix('%') {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+// A number that has to fit in the address space.
+pub fn parse_address(text: &str) -> Option<u16> {
+    parse_number(text).and_then(|value| u16::try_from(value).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("$C000"), Some(0xC000));
+        assert_eq!(parse_number("0xc000"), Some(0xC000));
+        assert_eq!(parse_number("%101"), Some(5));
+        assert_eq!(parse_number("-12"), Some(-12));
+        assert_eq!(parse_number("$"), None);
+        assert_eq!(parse_number("start"), None);
+        assert_eq!(parse_address("$FFFF"), Some(0xFFFF));
+        assert_eq!(parse_address("$10000"), None);
+        assert_eq!(parse_address("-1"), None);
+    }
+}
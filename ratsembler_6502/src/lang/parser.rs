@@ -1,4 +1,3 @@
-use pest::Parser;
 use pest_derive::Parser;
 
 #[derive(Parser)]
@@ -7,10 +6,10 @@ pub struct Assembler6502Parser;
 
 #[cfg(test)]
 mod tests {
-    use crate::lang::ast::Program;
-
     use super::*;
 
+    use pest::Parser;
+
     #[test]
     fn test_parse_valid_instruction() {
         let parse_result = Assembler6502Parser::parse(Rule::instruction, "LDA");
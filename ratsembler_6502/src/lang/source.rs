@@ -0,0 +1,92 @@
+use std::fmt;
+use std::rc::Rc;
+
+// Where a line of source came from. Kept cheap to clone since every
+// diagnostic and every assembled line carries one around.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub file: Rc<str>,
+    pub line: usize,
+    // Lines produced by `.rept` and friends point back at the directive
+    // that expanded them.
+    pub expanded_from: Option<Rc<SourceSpan>>,
+    // Lines read by `.include` point back at the directive that read them.
+    pub included_from: Option<Rc<SourceSpan>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub span: SourceSpan,
+    pub text: String,
+}
+
+impl fmt::Display for SourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+impl SourceSpan {
+    // Every directive this span was expanded by, innermost first.
+    pub fn expansions(&self) -> Vec<Rc<SourceSpan>> {
+        let mut expansions = Vec::new();
+        let mut current = self.expanded_from.clone();
+        while let Some(span) = current {
+            current = span.expanded_from.clone();
+            expansions.push(span);
+        }
+        expansions
+    }
+
+    // Every `.include` that led to this span, innermost first.
+    pub fn include_stack(&self) -> Vec<Rc<SourceSpan>> {
+        let mut includes = Vec::new();
+        let mut current = self.included_from.clone();
+        while let Some(span) = current {
+            current = span.included_from.clone();
+            includes.push(span);
+        }
+        includes
+    }
+}
+
+impl SourceLine {
+    pub fn from_source(file_name: &str, source: &str) -> Vec<SourceLine> {
+        let file: Rc<str> = file_name.into();
+        source
+            .lines()
+            .enumerate()
+            .map(|(index, text)| SourceLine {
+                span: SourceSpan {
+                    file: file.clone(),
+                    line: index + 1,
+                    expanded_from: None,
+                    included_from: None,
+                },
+                text: text.to_string(),
+            })
+            .collect()
+    }
+
+    pub fn from_included_source(
+        file_name: &str,
+        source: &str,
+        included_from: &Rc<SourceSpan>,
+    ) -> Vec<SourceLine> {
+        let mut lines = SourceLine::from_source(file_name, source);
+        for line in &mut lines {
+            line.span.included_from = Some(included_from.clone());
+        }
+        lines
+    }
+
+    pub fn expanded_from(&self, span: &Rc<SourceSpan>) -> SourceLine {
+        SourceLine {
+            span: SourceSpan {
+                expanded_from: Some(span.clone()),
+                ..self.span.clone()
+            },
+            text: self.text.clone(),
+        }
+    }
+}
@@ -1,5 +1,10 @@
+pub mod disasm;
 pub mod elf;
+pub mod emu;
 pub mod lang;
+pub mod linker;
+pub mod o65;
+pub mod output;
 
 #[macro_use]
 extern crate lazy_static;
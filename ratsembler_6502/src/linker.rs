@@ -0,0 +1,1078 @@
+pub mod archive;
+pub mod config;
+pub mod gc;
+pub mod map;
+pub mod relax;
+pub mod trampolines;
+
+#[cfg(test)]
+pub mod test_util;
+
+use config::LinkerConfig;
+use config::MemoryRegion;
+use config::OUTPUT_FILE;
+use gc::Removed;
+use trampolines::jump_kind;
+
+use crate::elf::reader::read_relocatable;
+use crate::elf::relocatable::base_section_name;
+use crate::elf::relocatable::is_local_symbol;
+use crate::elf::relocatable::section_index;
+use crate::elf::relocatable::Object;
+use crate::elf::relocatable::Relocation;
+use crate::elf::relocatable::Rom;
+use crate::elf::relocatable::Symbol;
+use crate::o65::reader::read_o65;
+use crate::o65::writer::MAGIC;
+use crate::output::image::Image;
+use crate::output::image::Segment;
+use crate::output::labels::Label;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// An object going into the link, with the name it's reported by.
+pub struct Input {
+    pub name: String,
+    pub object: Object,
+}
+
+// Reads either one of our ELF objects or an o65 file from another
+// toolchain, whichever it looks like.
+pub fn read_input(name: &str, bytes: &[u8]) -> Result<Input, String> {
+    let object = match bytes.starts_with(&MAGIC) {
+        true => read_o65(bytes),
+        false => read_relocatable(bytes),
+    };
+    object
+        .map(|object| Input {
+            name: name.to_string(),
+            object,
+        })
+        .map_err(|error| format!("{}: {}", name, error))
+}
+
+// Every input section with the same name, one after another at their final
+// address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputSection {
+    pub name: String,
+    // Where it runs, which everything that refers to it uses
+    pub address: u16,
+    // Where it's stored in the output, if it gets copied somewhere else to
+    // run. Otherwise the same as `address`.
+    pub load_address: u16,
+    // The memory region it's stored in, when there's a config
+    pub region: Option<usize>,
+    pub bank: Option<(Rom, usize)>,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkedSymbol {
+    pub value: u16,
+    // Non-zero for `.proc`s
+    pub size: usize,
+    // The output section it's in, or None for plain values
+    pub section: Option<usize>,
+}
+
+// Part of an output section that came from one input object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Piece {
+    pub object: String,
+    // The section's name in the object, which can be a function section
+    pub input_section: String,
+    pub section: usize,
+    // From the start of the output section
+    pub offset: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Linked {
+    pub sections: Vec<OutputSection>,
+    // From the config, if there was one
+    pub memory: Vec<MemoryRegion>,
+    // Only the globals, locals stay with the object they came from
+    pub symbols: BTreeMap<String, LinkedSymbol>,
+    // What went into each output section, in order
+    pub pieces: Vec<Piece>,
+    // The object each global came from. Ones the config defines aren't in
+    // here.
+    pub defined_in: BTreeMap<String, String>,
+    // What --gc-sections threw away before linking, for the map
+    pub removed: Vec<Removed>,
+}
+
+impl Linked {
+    // The image for the output file.
+    pub fn image(&self) -> Image {
+        self.images()
+            .into_iter()
+            .find(|(file, _)| file == OUTPUT_FILE)
+            .map(|(_, image)| image)
+            .unwrap_or_default()
+    }
+
+    // An image for each file the memory regions go in. Without a config
+    // everything goes in the output file.
+    pub fn images(&self) -> Vec<(String, Image)> {
+        let segment = |section: &OutputSection| Segment {
+            address: section.load_address,
+            bytes: section.bytes.clone(),
+            bank: section.bank,
+        };
+        if self.memory.is_empty() {
+            let segments = self
+                .sections
+                .iter()
+                .filter(|section| !section.bytes.is_empty())
+                .map(segment)
+                .collect();
+            let image = Image {
+                segments,
+                entry: None,
+            };
+            return vec![(OUTPUT_FILE.to_string(), image)];
+        }
+
+        let mut images: Vec<(String, Image)> = Vec::new();
+        for (index, region) in self.memory.iter().enumerate() {
+            let Some(file) = &region.file else {
+                continue;
+            };
+            // Banks only need telling apart when more than one of them goes
+            // in the same file. A bank on its own is just a flat image.
+            let shared = self
+                .memory
+                .iter()
+                .filter(|other| other.bank.is_some() && other.file.as_ref() == Some(file))
+                .count()
+                > 1;
+            let bank = |bank: Option<(Rom, usize)>| match region.bank {
+                Some(_) if !shared => None,
+                _ => bank,
+            };
+            let sections = self
+                .sections
+                .iter()
+                .filter(|section| section.region == Some(index) && !section.bytes.is_empty());
+            let segments: Vec<Segment> = match region.fill {
+                true => {
+                    let mut bytes = vec![region.fill_value; region.size];
+                    for section in sections {
+                        let start = (section.load_address - region.start) as usize;
+                        bytes[start..start + section.bytes.len()].copy_from_slice(&section.bytes);
+                    }
+                    vec![Segment {
+                        address: region.start,
+                        bytes,
+                        bank: bank(region.bank.map(|bank| (Rom::Prg, bank))),
+                    }]
+                }
+                false => sections
+                    .map(|section| Segment {
+                        bank: bank(section.bank),
+                        ..segment(section)
+                    })
+                    .collect(),
+            };
+            match images.iter_mut().find(|(name, _)| name == file) {
+                Some((_, image)) => image.segments.extend(segments),
+                None => images.push((
+                    file.clone(),
+                    Image {
+                        segments,
+                        entry: None,
+                    },
+                )),
+            }
+        }
+        images
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).map(|symbol| symbol.value)
+    }
+
+    // The global labels in address order, for label files.
+    pub fn labels(&self) -> Vec<Label> {
+        let mut labels: Vec<Label> = self
+            .symbols
+            .iter()
+            .filter_map(|(name, symbol)| {
+                let section = &self.sections[symbol.section?];
+                Some((name.clone(), symbol.value, running_bank(section)))
+            })
+            .collect();
+        labels.sort_by(|(a_name, a, _), (b_name, b, _)| (a, a_name).cmp(&(b, b_name)));
+        labels
+    }
+}
+
+// Where an input section ended up: the output section, and how far into
+// it.
+type Placement = (usize, usize);
+
+// How the merged sections are laid out in memory.
+pub enum Layout {
+    // One after another in the order their names first turn up, starting
+    // at the address given. Banked sections start from 0 within their bank
+    // instead.
+    Origin(u16),
+    Config(LinkerConfig),
+}
+
+// Links objects together. Sections with the same name are merged in the
+// order the objects are given, then placed according to `layout`. Function
+// sections go in with the section they were split from.
+pub fn link(inputs: &[Input], layout: &Layout) -> Result<Linked, Vec<String>> {
+    let mut errors = Vec::new();
+    let (mut linked, placements) = lay_out(inputs, layout, &mut errors);
+
+    // Where something at `offset` in an object's raw section is now, as the
+    // output section and its address.
+    let locate = |index: usize, offset: usize| {
+        let sections = &inputs[index].object.sections;
+        section_index(sections, offset).map(|section| {
+            let (output, start) = placements[index][section];
+            let address =
+                linked.sections[output].address as usize + start + offset - sections[section].start;
+            (output, address as u16)
+        })
+    };
+
+    let mut locals: Vec<HashMap<&str, LinkedSymbol>> = Vec::new();
+    let mut defined_in: HashMap<&str, usize> = HashMap::new();
+    for (index, input) in inputs.iter().enumerate() {
+        let mut symbols: Vec<_> = input.object.symbols.iter().collect();
+        symbols.sort_by_key(|(name, _)| name.as_str());
+        let mut object_locals = HashMap::new();
+        for (name, symbol) in symbols {
+            let linked_symbol = match symbol {
+                Symbol::ShortValue(value) => LinkedSymbol {
+                    value: *value as u16,
+                    size: 0,
+                    section: None,
+                },
+                Symbol::LongValue(value) => LinkedSymbol {
+                    value: *value,
+                    size: 0,
+                    section: None,
+                },
+                Symbol::Location(location) | Symbol::Procedure(location, _) => {
+                    let Some((section, value)) = locate(index, *location) else {
+                        errors.push(format!("{}: '{}' isn't in any section", input.name, name));
+                        continue;
+                    };
+                    let size = match symbol {
+                        Symbol::Procedure(_, size) => *size,
+                        _ => 0,
+                    };
+                    LinkedSymbol {
+                        value,
+                        size,
+                        section: Some(section),
+                    }
+                }
+            };
+            if is_local_symbol(name) {
+                object_locals.insert(name.as_str(), linked_symbol);
+                continue;
+            }
+            if !defined_in.contains_key(name.as_str()) && linked.symbols.contains_key(name) {
+                errors.push(format!(
+                    "duplicate symbol '{}' in {} and the linker config",
+                    name, input.name
+                ));
+                continue;
+            }
+            // Objects that share an include file of constants all define
+            // them, which is fine as long as they agree
+            if let Some(existing) = linked.symbols.get(name) {
+                if defined_in.contains_key(name.as_str())
+                    && existing.section.is_none()
+                    && *existing == linked_symbol
+                {
+                    continue;
+                }
+            }
+            if let Some(other) = defined_in.insert(name, index) {
+                errors.push(format!(
+                    "duplicate symbol '{}' in {} and {}",
+                    name, inputs[other].name, input.name
+                ));
+                continue;
+            }
+            linked.symbols.insert(name.clone(), linked_symbol);
+            linked.defined_in.insert(name.clone(), input.name.clone());
+        }
+        locals.push(object_locals);
+    }
+
+    let mut patches = Vec::new();
+    for (index, input) in inputs.iter().enumerate() {
+        let mut undefined = BTreeSet::new();
+        for relocation in &input.object.relocations {
+            let offset = relocation.offset() as usize;
+            let sections = &input.object.sections;
+            let Some(section) = sections
+                .iter()
+                .position(|section| (section.start..section.end()).contains(&offset))
+            else {
+                errors.push(format!(
+                    "{}: relocation for '{}' at offset {} isn't in any section",
+                    input.name,
+                    relocation.symbol(),
+                    offset
+                ));
+                continue;
+            };
+            let name = relocation.symbol();
+            let Some(symbol) = locals[index].get(name).or_else(|| linked.symbols.get(name)) else {
+                undefined.insert(name);
+                continue;
+            };
+            let (output, start) = placements[index][section];
+            let position = start + offset - sections[section].start;
+            let place = linked.sections[output].address as i64 + position as i64;
+            let target = symbol.section.map(|section| &linked.sections[section]);
+            if let Some(jump) = jump_kind(&input.object.raw_section, relocation) {
+                let from = &linked.sections[output];
+                if let (Some(from_bank), Some(to)) = (running_bank(from), target) {
+                    match running_bank(to) {
+                        Some(to_bank) if to_bank != from_bank => errors.push(format!(
+                            "{}: {} to '{}' in bank {} from bank {} needs a trampoline",
+                            input.name, jump, name, to_bank.1, from_bank.1
+                        )),
+                        _ => {}
+                    }
+                }
+            }
+            let value = match relocation {
+                Relocation::Bank(..) => match target.and_then(|section| section.bank) {
+                    Some((_, bank)) => bank as i64,
+                    None => {
+                        errors.push(format!(
+                            "{}: '{}' isn't in a banked section",
+                            input.name, name
+                        ));
+                        continue;
+                    }
+                },
+                _ => symbol.value as i64,
+            };
+            match relocation.resolve(value, place) {
+                Ok(bytes) => patches.push((output, position, bytes)),
+                Err(message) => errors.push(format!("{}: {}", input.name, message)),
+            }
+        }
+        for name in undefined {
+            errors.push(format!("{}: undefined symbol '{}'", input.name, name));
+        }
+    }
+    for (output, position, bytes) in patches {
+        linked.sections[output].bytes[position..position + bytes.len()].copy_from_slice(&bytes);
+    }
+
+    if errors.is_empty() {
+        for (_, image) in linked.images() {
+            errors.extend(Image::layout_errors(&image.segments));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(linked)
+}
+
+// Merges the sections and places them, without resolving anything. Where
+// things end up is still worked out when they don't fit, so passes that
+// only need to know what's where can carry on past the errors.
+pub fn lay_out(
+    inputs: &[Input],
+    layout: &Layout,
+    errors: &mut Vec<String>,
+) -> (Linked, Vec<Vec<Placement>>) {
+    let mut linked = Linked::default();
+
+    // Which input section went where, and which object each output section
+    // first came from so bank mismatches can name both.
+    let mut placements: Vec<Vec<Placement>> = Vec::new();
+    let mut first_input: Vec<usize> = Vec::new();
+    for (index, input) in inputs.iter().enumerate() {
+        let object = &input.object;
+        let mut placed = Vec::new();
+        for section in &object.sections {
+            let name = base_section_name(&section.name);
+            let output = match linked
+                .sections
+                .iter()
+                .position(|output| output.name == name)
+            {
+                Some(output) => output,
+                None => {
+                    linked.sections.push(OutputSection {
+                        name: name.to_string(),
+                        address: 0,
+                        load_address: 0,
+                        region: None,
+                        bank: section.bank,
+                        bytes: Vec::new(),
+                    });
+                    first_input.push(index);
+                    linked.sections.len() - 1
+                }
+            };
+            let merged = &mut linked.sections[output];
+            if merged.bank != section.bank {
+                errors.push(format!(
+                    "section '{}' is in a different bank in {} than in {}",
+                    name, input.name, inputs[first_input[output]].name
+                ));
+            }
+            placed.push((output, merged.bytes.len()));
+            linked.pieces.push(Piece {
+                object: input.name.clone(),
+                input_section: section.name.clone(),
+                section: output,
+                offset: merged.bytes.len(),
+                size: section.size,
+            });
+            merged
+                .bytes
+                .extend(&object.raw_section[section.start..section.end()]);
+        }
+        placements.push(placed);
+    }
+
+    match layout {
+        Layout::Origin(origin) => place_from(&mut linked, *origin, errors),
+        Layout::Config(config) => place_by_config(&mut linked, config, errors),
+    }
+    (linked, placements)
+}
+
+// The bank a section is in while it runs, which is only the one it's
+// stored in if it runs where it's stored.
+fn running_bank(section: &OutputSection) -> Option<(Rom, usize)> {
+    match section.address == section.load_address {
+        true => section.bank,
+        false => None,
+    }
+}
+
+fn place_from(linked: &mut Linked, origin: u16, errors: &mut Vec<String>) {
+    let mut cursors: HashMap<Option<(Rom, usize)>, usize> = HashMap::new();
+    for section in &mut linked.sections {
+        let cursor = cursors.entry(section.bank).or_insert(match section.bank {
+            Some(_) => 0,
+            None => origin as usize,
+        });
+        section.address = *cursor as u16;
+        section.load_address = section.address;
+        *cursor += section.bytes.len();
+        if *cursor > 0x10000 {
+            errors.push(format!(
+                "section '{}' at ${:04X} runs past the end of memory",
+                section.name, section.address
+            ));
+        }
+    }
+}
+
+// Places each section in the memory region it runs from, in the order the
+// config lists them, and where it's loaded if that's somewhere else.
+fn place_by_config(linked: &mut Linked, config: &LinkerConfig, errors: &mut Vec<String>) {
+    linked.memory = config.memory.clone();
+    for section in &linked.sections {
+        if config.rule(&section.name).is_none() && !section.bytes.is_empty() {
+            errors.push(format!(
+                "section '{}' isn't in the linker config's SEGMENTS",
+                section.name
+            ));
+        }
+    }
+
+    let region_index = |name: &str| {
+        config
+            .memory
+            .iter()
+            .position(|region| region.name == name)
+            .unwrap()
+    };
+    let mut cursors: Vec<usize> = config
+        .memory
+        .iter()
+        .map(|region| region.start as usize)
+        .collect();
+    for rule in &config.segments {
+        let Some(section) = linked
+            .sections
+            .iter_mut()
+            .find(|section| section.name == rule.name)
+        else {
+            if !rule.optional {
+                errors.push(format!(
+                    "segment '{}' isn't in any of the objects",
+                    rule.name
+                ));
+            }
+            continue;
+        };
+        let size = section.bytes.len();
+        let mut place = |region: usize, start: Option<u16>, align: usize| {
+            let memory = &config.memory[region];
+            let mut address = cursors[region];
+            if let Some(start) = start {
+                if (start as usize) < address || start as usize > memory.end() {
+                    errors.push(format!(
+                        "segment '{}' can't start at ${:04X}, memory region '{}' is ${:04X}-${:04X} and used up to ${:04X}",
+                        rule.name,
+                        start,
+                        memory.name,
+                        memory.start,
+                        memory.end() - 1,
+                        address
+                    ));
+                }
+                address = start as usize;
+            }
+            address = address.next_multiple_of(align);
+            cursors[region] = address + size;
+            if cursors[region] > memory.end() {
+                errors.push(format!(
+                    "segment '{}' overflows memory region '{}' by {} bytes",
+                    rule.name,
+                    memory.name,
+                    cursors[region] - memory.end()
+                ));
+            }
+            address as u16
+        };
+        let load = region_index(&rule.load);
+        let run = region_index(rule.run_region());
+        section.address = place(run, rule.start, rule.align);
+        section.load_address = match run == load {
+            true => section.address,
+            false => place(load, None, 1),
+        };
+        section.region = Some(load);
+        if let Some(bank) = config.memory[load].bank {
+            match section.bank {
+                Some((_, other)) if other != bank => errors.push(format!(
+                    "section '{}' is in bank {} but memory region '{}' is bank {}",
+                    rule.name, other, config.memory[load].name, bank
+                )),
+                Some(_) => {}
+                None => section.bank = Some((Rom::Prg, bank)),
+            }
+        }
+
+        if rule.define {
+            let name = rule.name.to_uppercase();
+            for (suffix, value) in [
+                ("LOAD", section.load_address),
+                ("RUN", section.address),
+                ("SIZE", size as u16),
+            ] {
+                linked.symbols.insert(
+                    format!("__{}_{}__", name, suffix),
+                    LinkedSymbol {
+                        value,
+                        size: 0,
+                        section: None,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::elf::reader::ElfFile;
+    use crate::elf::writer::*;
+    use crate::linker::test_util::input;
+    use crate::o65::writer::write_o65;
+
+    #[test]
+    fn test_link() {
+        let main = input(
+            "main.o",
+            r#"
+start:
+  JSR print
+  LDA message,X
+  BNE start
+.scope
+done: RTS
+.endscope
+.section data
+message: .byte 1
+"#,
+        );
+        let print = input(
+            "print.o",
+            r#"
+.proc print
+  BEQ skip
+  STA count
+skip: RTS
+.endproc
+.scope
+done: RTS
+.endscope
+.section data
+count: .word print
+"#,
+        );
+        let linked = link(&[main, print], &Layout::Origin(0x0800)).unwrap();
+
+        assert_eq!(
+            linked.sections,
+            [
+                OutputSection {
+                    name: "text".to_string(),
+                    address: 0x0800,
+                    load_address: 0x0800,
+                    region: None,
+                    bank: None,
+                    bytes: vec![
+                        0x20, 0x09, 0x08, 0xBD, 0x10, 0x08, 0xD0, 0xF8, 0x60, // main.o
+                        0xF0, 0x03, 0x8D, 0x11, 0x08, 0x60, 0x60, // print.o
+                    ],
+                },
+                OutputSection {
+                    name: "data".to_string(),
+                    address: 0x0810,
+                    load_address: 0x0810,
+                    region: None,
+                    bank: None,
+                    bytes: vec![0x01, 0x09, 0x08],
+                },
+            ]
+        );
+        assert_eq!(linked.address_of("print"), Some(0x0809));
+        assert_eq!(linked.symbols["print"].size, 6);
+        assert_eq!(linked.address_of("count"), Some(0x0811));
+        // Each object keeps its own `done`
+        assert_eq!(linked.address_of("@1::done"), None);
+        assert_eq!(
+            linked.labels()[..2],
+            [
+                ("start".to_string(), 0x0800, None),
+                ("print".to_string(), 0x0809, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_banks_and_o65() {
+        let bank = input(
+            "bank.o",
+            ".section fixed\nreset: JMP reset\n.section chr, chr, 0\n.byte 1, 2\n",
+        );
+        let mut o65 = Vec::new();
+        write_o65(&mut o65, &input("lib.o", "helper: JMP reset\n").object).unwrap();
+        let helper = read_input("lib.o65", &o65).unwrap();
+        let linked = link(&[bank, helper], &Layout::Origin(0xC000)).unwrap();
+
+        // bank.o starts with an empty text section, so text still comes
+        // first.
+        let image = linked.image();
+        let segments: Vec<_> = image
+            .segments
+            .iter()
+            .map(|segment| (segment.address, segment.bank, segment.bytes.clone()))
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                (0xC000, None, vec![0x4C, 0x03, 0xC0]),
+                (0xC003, None, vec![0x4C, 0x03, 0xC0]),
+                (0x0000, Some((Rom::Chr, 0)), vec![0x01, 0x02]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_address_bytes() {
+        let main = input(
+            "main.o",
+            "  LDA #<print\n  LDX #>print\n  LDY #>(message+$FF)\n.section data\nmessage: .byte <message, >message\n",
+        );
+        let mut o65 = Vec::new();
+        write_o65(
+            &mut o65,
+            &input("print.o", "print: LDA #<message\n  RTS\n").object,
+        )
+        .unwrap();
+        let print = read_input("print.o65", &o65).unwrap();
+        let linked = link(&[main, print], &Layout::Origin(0x12F0)).unwrap();
+
+        assert_eq!(
+            linked.sections[0].bytes,
+            [0xA9, 0xF6, 0xA2, 0x12, 0xA0, 0x13, 0xA9, 0xF9, 0x60]
+        );
+        assert_eq!(linked.sections[1].bytes, [0xF9, 0x12]);
+    }
+
+    #[test]
+    fn test_constants() {
+        let main = input(
+            "main.o",
+            "  LDA #value\n  STA PUTCHAR\n  JMP far\nPUTCHAR = $F001\n",
+        );
+        let lib = input("lib.o", "PUTCHAR = $F001\nvalue = 7\nfar = $1234\n");
+        let linked = link(&[main, lib], &Layout::Origin(0x0800)).unwrap();
+        assert_eq!(
+            linked.sections[0].bytes,
+            [0xA9, 0x07, 0x8D, 0x01, 0xF0, 0x4C, 0x34, 0x12]
+        );
+
+        let other = input("other.o", "PUTCHAR = $FFD2\n");
+        let lib = input("lib.o", "PUTCHAR = $F001\n");
+        assert_eq!(
+            link(&[lib, other], &Layout::Origin(0x0800)).unwrap_err(),
+            ["duplicate symbol 'PUTCHAR' in lib.o and other.o"]
+        );
+
+        // Scoped ones and struct fields go by their full name
+        let main = input("main.o", "  LDA #sc::val\n  LDX #Point::y\n");
+        let lib = input(
+            "lib.o",
+            ".scope sc\nval = 7\n.endscope\n.struct Point\n  x .byte\n  y .byte\n.endstruct\n",
+        );
+        let linked = link(&[main, lib], &Layout::Origin(0x0800)).unwrap();
+        assert_eq!(linked.sections[0].bytes, [0xA9, 0x07, 0xA2, 0x01]);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let errors = |inputs: &[Input]| link(inputs, &Layout::Origin(0x0200)).unwrap_err();
+
+        assert_eq!(
+            errors(&[input("a.o", "start: RTS\n"), input("b.o", "start: RTS\n")]),
+            ["duplicate symbol 'start' in a.o and b.o"]
+        );
+        assert_eq!(
+            errors(&[input("a.o", "  JSR print\n  JMP print\n  JMP exit\n")]),
+            [
+                "a.o: undefined symbol 'exit'",
+                "a.o: undefined symbol 'print'"
+            ]
+        );
+        // The object has to leave room for a page zero address.
+        assert_eq!(
+            errors(&[
+                input("a.o", "  LDA #table\n"),
+                input("b.o", ".section data\ntable: .byte 0\n")
+            ]),
+            ["a.o: value $202 of 'table' does not fit in a byte"]
+        );
+        assert_eq!(
+            errors(&[
+                input("a.o", ".section tiles, chr, 0\n.byte 0\n"),
+                input("b.o", ".section tiles, chr, 1\n.byte 0\n")
+            ]),
+            ["section 'tiles' is in a different bank in b.o than in a.o"]
+        );
+        assert_eq!(
+            link(&[input("a.o", ".res $200\n")], &Layout::Origin(0xFF00)).unwrap_err(),
+            ["section 'text' at $FF00 runs past the end of memory"]
+        );
+    }
+
+    #[test]
+    fn test_executable() {
+        let linked = link(
+            &[input(
+                "a.o",
+                "start: JMP start\n.section data\nvalue: .byte 0\n",
+            )],
+            &Layout::Origin(0x1000),
+        )
+        .unwrap();
+        let mut file = Vec::new();
+        write_executable(&mut file, &linked, Some(0x1000)).unwrap();
+
+        let elf = ElfFile::parse(&file).unwrap();
+        assert_eq!(elf.header.e_type, ET_EXEC);
+        assert_eq!(elf.header.e_entry, 0x1000);
+        let loads: Vec<_> = elf
+            .program_headers
+            .iter()
+            .map(|header| {
+                (
+                    header.p_type,
+                    header.p_vaddr,
+                    header.p_filesz,
+                    header.p_flags,
+                )
+            })
+            .collect();
+        assert_eq!(
+            loads,
+            [
+                (PT_LOAD, 0x1000, 3, PF_R | PF_X),
+                (PT_LOAD, 0x1003, 1, PF_R | PF_W)
+            ]
+        );
+        let header = &elf.program_headers[0];
+        let offset = header.p_offset as usize;
+        assert_eq!(file[offset..offset + 3], [0x4C, 0x00, 0x10]);
+        let symbols: Vec<_> = elf
+            .symbols()
+            .unwrap()
+            .iter()
+            .map(|symbol| (symbol.name.clone(), symbol.symbol.st_value))
+            .collect();
+        assert_eq!(
+            symbols[1..],
+            [("start".to_string(), 0x1000), ("value".to_string(), 0x1003)]
+        );
+
+        let image = elf.to_image().unwrap();
+        assert_eq!(image.entry, Some(0x1000));
+        assert_eq!(
+            image.segments,
+            [
+                Segment {
+                    address: 0x1000,
+                    bytes: vec![0x4C, 0x00, 0x10],
+                    bank: None
+                },
+                Segment {
+                    address: 0x1003,
+                    bytes: vec![0x00],
+                    bank: None
+                }
+            ]
+        );
+    }
+
+    fn config(text: &str) -> Layout {
+        Layout::Config(config::parse_config(text).unwrap())
+    }
+
+    #[test]
+    fn test_config_layout() {
+        let layout = config(
+            r#"
+MEMORY {
+    RAM: start = $0200, size = $0600, file = "";
+    ROM: start = $F000, size = $1000, fill = yes, fillval = $FF;
+}
+SEGMENTS {
+    text:    load = ROM;
+    data:    load = ROM, run = RAM, define = yes;
+    bss:     load = RAM, align = $100;
+    vectors: load = ROM, start = $FFFA;
+    zp:      load = RAM, optional = yes;
+}
+"#,
+        );
+        let source = r#"
+reset:
+  LDA __DATA_LOAD__
+  STA counter
+  JMP reset
+.section bss
+  .res 2
+.section data
+counter: .byte 7
+.section vectors
+  .word reset, reset, reset
+"#;
+        let linked = link(&[input("a.o", source)], &layout).unwrap();
+
+        let placed: Vec<_> = linked
+            .sections
+            .iter()
+            .map(|section| (section.name.as_str(), section.address, section.load_address))
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                ("text", 0xF000, 0xF000),
+                ("bss", 0x0300, 0x0300),
+                ("data", 0x0200, 0xF009),
+                ("vectors", 0xFFFA, 0xFFFA),
+            ]
+        );
+        assert_eq!(linked.address_of("__DATA_LOAD__"), Some(0xF009));
+        assert_eq!(linked.address_of("__DATA_RUN__"), Some(0x0200));
+        assert_eq!(linked.address_of("__DATA_SIZE__"), Some(1));
+        assert_eq!(linked.address_of("counter"), Some(0x0200));
+
+        // Only ROM is written, as one filled block.
+        let images = linked.images();
+        assert_eq!(images.len(), 1);
+        let image = linked.image();
+        assert_eq!(image.segments.len(), 1);
+        let rom = &image.segments[0].bytes;
+        assert_eq!(rom.len(), 0x1000);
+        assert_eq!(
+            rom[..10],
+            [0xAD, 0x09, 0xF0, 0x8D, 0x00, 0x02, 0x4C, 0x00, 0xF0, 0x07]
+        );
+        assert_eq!(rom[10], 0xFF);
+        assert_eq!(rom[0xFFA..], [0x00, 0xF0, 0x00, 0xF0, 0x00, 0xF0]);
+
+        // The executable keeps where data is loaded apart from where it runs
+        let mut file = Vec::new();
+        write_executable(&mut file, &linked, None).unwrap();
+        let elf = ElfFile::parse(&file).unwrap();
+        let loads: Vec<_> = elf
+            .program_headers
+            .iter()
+            .map(|header| (header.p_vaddr, header.p_paddr))
+            .collect();
+        assert!(loads.contains(&(0x0200, 0xF009)), "{:X?}", loads);
+        assert!(loads.contains(&(0xF000, 0xF000)), "{:X?}", loads);
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let layout = config(
+            r#"
+MEMORY {
+    ZP:  start = $0080, size = $0004;
+    ROM: start = $F000, size = $0004;
+}
+SEGMENTS {
+    zp:   load = ZP;
+    text: load = ROM;
+    late: load = ROM, start = $F002;
+    data: load = ROM;
+}
+"#,
+        );
+        let source = ".section zp
+  .res 6
+.section text
+  NOP
+  NOP
+  NOP
+.section late
+  NOP
+.section other
+  NOP
+";
+        assert_eq!(
+            link(&[input("a.o", source)], &layout).unwrap_err(),
+            [
+                "section 'other' isn't in the linker config's SEGMENTS",
+                "segment 'zp' overflows memory region 'ZP' by 2 bytes",
+                "segment 'late' can't start at $F002, memory region 'ROM' is $F000-$F003 and used up to $F003",
+                "segment 'data' isn't in any of the objects",
+            ]
+        );
+
+        let layout = config(
+            "MEMORY { RAM: start = $0200, size = $100; }\nSEGMENTS { text: load = RAM, define = yes; }\n",
+        );
+        // The assembler won't define a name like that, but another toolchain
+        // might.
+        let mut clash = input("a.o", "RTS\n");
+        clash
+            .object
+            .symbols
+            .insert("__TEXT_RUN__".to_string(), Symbol::Location(0));
+        assert_eq!(
+            link(&[clash], &layout).unwrap_err(),
+            ["duplicate symbol '__TEXT_RUN__' in a.o and the linker config"]
+        );
+    }
+    #[test]
+    fn test_banked_regions() {
+        let layout = config(
+            r#"
+MEMORY {
+    FIXED: start = $C000, size = $4000;
+    BANK0: start = $8000, size = $4000, bank = 0;
+    BANK1: start = $8000, size = $4000, bank = 1;
+}
+SEGMENTS {
+    text: load = FIXED;
+    one:  load = BANK0, optional = yes;
+    two:  load = BANK1, optional = yes;
+}
+"#,
+        );
+        let source = r#"
+main:
+  LDA #^far
+  JSR far
+  RTS
+tables:
+  .bankbyte near, far
+.section one
+near:
+  LDA far
+  RTS
+.section two
+far:
+  RTS
+"#;
+        let linked = link(&[input("a.o", source)], &layout).unwrap();
+        let placed: Vec<_> = linked
+            .sections
+            .iter()
+            .map(|section| (section.name.as_str(), section.address, section.bank))
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                ("text", 0xC000, None),
+                ("one", 0x8000, Some((Rom::Prg, 0))),
+                ("two", 0x8000, Some((Rom::Prg, 1))),
+            ]
+        );
+        assert_eq!(
+            linked.sections[0].bytes,
+            [0xA9, 0x01, 0x20, 0x00, 0x80, 0x60, 0x00, 0x01]
+        );
+        let banks: Vec<_> = linked
+            .image()
+            .segments
+            .iter()
+            .map(|segment| segment.bank)
+            .collect();
+        assert_eq!(banks, [None, Some((Rom::Prg, 0)), Some((Rom::Prg, 1))]);
+
+        // Jumping straight into another bank switches out the code doing it
+        let source = ".section one\nnear: JSR far\n  JMP far\n.section two\nfar: RTS\n";
+        assert_eq!(
+            link(&[input("a.o", source)], &layout).unwrap_err(),
+            [
+                "a.o: JSR to 'far' in bank 1 from bank 0 needs a trampoline",
+                "a.o: JMP to 'far' in bank 1 from bank 0 needs a trampoline",
+            ]
+        );
+        // But a pointer that happens to come after a $20 isn't a JSR
+        let source = ".section one
+msg: .byte \" \"\n  .word far\n.section two\nfar: RTS\n";
+        let linked = link(&[input("a.o", source)], &layout).unwrap();
+        let one = linked.sections.iter().find(|section| section.name == "one");
+        assert_eq!(one.unwrap().bytes, [0x20, 0x00, 0x80]);
+        assert_eq!(
+            link(&[input("a.o", "LDA #^main\nmain: RTS\n")], &layout).unwrap_err(),
+            ["a.o: 'main' isn't in a banked section"]
+        );
+        assert_eq!(
+            link(&[input("a.o", ".section two, prg, 3\nRTS\n")], &layout).unwrap_err(),
+            ["section 'two' is in bank 3 but memory region 'BANK1' is bank 1"]
+        );
+
+        // A bank on its own in a file is written as a plain image
+        let layout = config(
+            "MEMORY { BANK: start = $8000, size = $100, bank = 4; }\nSEGMENTS { text: load = BANK; }\n",
+        );
+        let linked = link(&[input("a.o", "RTS\n")], &layout).unwrap();
+        assert_eq!(linked.image().segments[0].bank, None);
+    }
+}
@@ -0,0 +1,355 @@
+use super::read_input;
+use super::Input;
+
+use crate::elf::relocatable::is_local_symbol;
+
+use std::collections::{BTreeSet, HashSet};
+use std::io::{self, Write};
+
+pub const ARCHIVE_MAGIC: [u8; 8] = *b"!<arch>\n";
+
+const HEADER_SIZE: usize = 60;
+// Names longer than this go in the `//` member instead of the header
+const SHORT_NAME: usize = 15;
+
+// A file stored in an archive, usually one of our objects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Archive {
+    pub members: Vec<Member>,
+    // Each global symbol and the member that defines it
+    pub symbols: Vec<(String, usize)>,
+}
+
+impl Archive {
+    pub fn member_defining(&self, symbol: &str) -> Option<usize> {
+        self.symbols
+            .iter()
+            .find(|(name, _)| name == symbol)
+            .map(|(_, member)| *member)
+    }
+}
+
+// The globals each member defines, in member order, for the symbol index.
+pub fn index_symbols(members: &[Member]) -> Result<Vec<(String, usize)>, String> {
+    let mut symbols = Vec::new();
+    for (index, member) in members.iter().enumerate() {
+        let input = read_input(&member.name, &member.bytes)?;
+        let mut names: Vec<&String> = input
+            .object
+            .symbols
+            .keys()
+            .filter(|name| !is_local_symbol(name))
+            .collect();
+        names.sort();
+        symbols.extend(names.into_iter().map(|name| (name.clone(), index)));
+    }
+    Ok(symbols)
+}
+
+fn write_header(out: &mut impl Write, name: &str, size: usize) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`",
+        name, 0, 0, 0, 644, size
+    )
+}
+
+fn write_member(out: &mut impl Write, name: &str, bytes: &[u8]) -> io::Result<()> {
+    write_header(out, name, bytes.len())?;
+    out.write_all(bytes)?;
+    if bytes.len() % 2 == 1 {
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+// Writes a System V/GNU style archive, the kind `ar rcs` makes, with a
+// symbol index up front so the linker can find members without reading
+// them all. Dates and owners are left as zero so builds are reproducible.
+pub fn write_archive(out: &mut impl Write, members: &[Member]) -> io::Result<()> {
+    let symbols = index_symbols(members)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+
+    let mut long_names = Vec::new();
+    let names: Vec<String> = members
+        .iter()
+        .map(|member| match member.name.len() > SHORT_NAME {
+            true => {
+                let name = format!("/{}", long_names.len());
+                long_names.extend(member.name.as_bytes());
+                long_names.extend(b"/\n");
+                name
+            }
+            false => format!("{}/", member.name),
+        })
+        .collect();
+
+    let padded = |size: usize| size + size % 2;
+    let index_size = 4
+        + 4 * symbols.len()
+        + symbols
+            .iter()
+            .map(|(name, _)| name.len() + 1)
+            .sum::<usize>();
+    let mut offset = ARCHIVE_MAGIC.len() + HEADER_SIZE + padded(index_size);
+    if !long_names.is_empty() {
+        offset += HEADER_SIZE + padded(long_names.len());
+    }
+    let mut offsets = Vec::new();
+    for member in members {
+        offsets.push(offset as u32);
+        offset += HEADER_SIZE + padded(member.bytes.len());
+    }
+
+    let mut index = Vec::with_capacity(index_size);
+    index.extend((symbols.len() as u32).to_be_bytes());
+    for (_, member) in &symbols {
+        index.extend(offsets[*member].to_be_bytes());
+    }
+    for (name, _) in &symbols {
+        index.extend(name.as_bytes());
+        index.push(0);
+    }
+
+    out.write_all(&ARCHIVE_MAGIC)?;
+    write_member(out, "/", &index)?;
+    if !long_names.is_empty() {
+        write_member(out, "//", &long_names)?;
+    }
+    for (member, name) in members.iter().zip(&names) {
+        write_member(out, name, &member.bytes)?;
+    }
+    Ok(())
+}
+
+fn field(header: &[u8], start: usize, size: usize) -> Result<&str, String> {
+    std::str::from_utf8(&header[start..start + size])
+        .map(str::trim_end)
+        .map_err(|_| "bad archive member header".to_string())
+}
+
+// Reads an archive written by us or by `ar`. If it doesn't have a symbol
+// index one is made by reading the members.
+pub fn read_archive(bytes: &[u8]) -> Result<Archive, String> {
+    if !bytes.starts_with(&ARCHIVE_MAGIC) {
+        return Err("not an archive".to_string());
+    }
+    let mut index: Option<&[u8]> = None;
+    let mut long_names: &[u8] = &[];
+    let mut offsets = Vec::new();
+    let mut archive = Archive::default();
+    let mut offset = ARCHIVE_MAGIC.len();
+    while offset < bytes.len() {
+        let header = bytes
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or("archive member header runs past the end of the file")?;
+        if &header[58..60] != b"`\n" {
+            return Err(format!("bad archive member header at offset {}", offset));
+        }
+        let name = field(header, 0, 16)?;
+        let size: usize = field(header, 48, 10)?
+            .parse()
+            .map_err(|_| format!("bad archive member size at offset {}", offset))?;
+        let start = offset + HEADER_SIZE;
+        let contents = bytes
+            .get(start..start + size)
+            .ok_or("archive member runs past the end of the file")?;
+        match name {
+            "/" => index = Some(contents),
+            "//" => long_names = contents,
+            _ => {
+                let name = match name.strip_prefix('/') {
+                    Some(position) => {
+                        let position: usize = position
+                            .parse()
+                            .map_err(|_| format!("bad archive member name '{}'", name))?;
+                        let rest = long_names
+                            .get(position..)
+                            .ok_or(format!("bad archive member name '{}'", name))?;
+                        let end = rest
+                            .iter()
+                            .position(|&byte| byte == b'\n')
+                            .unwrap_or(rest.len());
+                        String::from_utf8_lossy(&rest[..end])
+                            .trim_end_matches('/')
+                            .to_string()
+                    }
+                    None => name.trim_end_matches('/').to_string(),
+                };
+                offsets.push(offset);
+                archive.members.push(Member {
+                    name,
+                    bytes: contents.to_vec(),
+                });
+            }
+        }
+        offset = start + size + size % 2;
+    }
+
+    archive.symbols = match index {
+        Some(index) => {
+            let word = |at: usize| {
+                index
+                    .get(at..at + 4)
+                    .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+                    .ok_or("archive symbol index is truncated")
+            };
+            let count = word(0)?;
+            let mut names = index
+                .get(4 + 4 * count..)
+                .ok_or("archive symbol index is truncated")?
+                .split(|&byte| byte == 0);
+            let mut symbols = Vec::new();
+            for entry in 0..count {
+                let offset = word(4 + 4 * entry)?;
+                let member = offsets
+                    .iter()
+                    .position(|&start| start == offset)
+                    .ok_or(format!("archive symbol index points at offset {}", offset))?;
+                let name = names.next().ok_or("archive symbol index is truncated")?;
+                symbols.push((String::from_utf8_lossy(name).to_string(), member));
+            }
+            symbols
+        }
+        None => index_symbols(&archive.members)?,
+    };
+    Ok(archive)
+}
+
+// Adds the members of the archives that define something the inputs use
+// but don't define themselves, then any that those need in turn, until
+// nothing else gets pulled in. Archives are searched in the order given, so
+// the first one to define a symbol wins.
+pub fn pull_members(inputs: &mut Vec<Input>, archives: &[(String, Archive)]) -> Result<(), String> {
+    let mut pulled: HashSet<(usize, usize)> = HashSet::new();
+    loop {
+        let defined: HashSet<&str> = inputs
+            .iter()
+            .flat_map(|input| input.object.symbols.keys())
+            .map(String::as_str)
+            .collect();
+        let undefined: BTreeSet<&str> = inputs
+            .iter()
+            .flat_map(|input| &input.object.relocations)
+            .map(|relocation| relocation.symbol())
+            .filter(|name| !is_local_symbol(name) && !defined.contains(name))
+            .collect();
+
+        let mut wanted = BTreeSet::new();
+        for name in undefined {
+            let found = archives
+                .iter()
+                .enumerate()
+                .find_map(|(index, (_, archive))| {
+                    archive.member_defining(name).map(|member| (index, member))
+                });
+            if let Some(found) = found {
+                if !pulled.contains(&found) {
+                    wanted.insert(found);
+                }
+            }
+        }
+        if wanted.is_empty() {
+            return Ok(());
+        }
+        for (index, member) in wanted {
+            let (name, archive) = &archives[index];
+            let member_name = format!("{}({})", name, archive.members[member].name);
+            inputs.push(read_input(&member_name, &archive.members[member].bytes)?);
+            pulled.insert((index, member));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::elf::writer::write_relocatable;
+    use crate::lang::assembler::Assembler;
+    use crate::lang::source::SourceLine;
+
+    fn member(name: &str, source: &str) -> Member {
+        let program = Assembler::new()
+            .assemble(&SourceLine::from_source(name, source))
+            .unwrap();
+        let mut bytes = Vec::new();
+        write_relocatable(&mut bytes, &program).unwrap();
+        Member {
+            name: name.to_string(),
+            bytes,
+        }
+    }
+
+    fn library() -> Vec<Member> {
+        vec![
+            member("sin.o", "sin: LDA table\n  RTS\n"),
+            member("cos.o", "cos: JMP sin\n"),
+            member("lookup_table_data.o", "table: .byte 1, 2, 3\n"),
+        ]
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let members = library();
+        let mut bytes = Vec::new();
+        write_archive(&mut bytes, &members).unwrap();
+        assert!(bytes.starts_with(b"!<arch>\n/               0           0     0     644     "));
+
+        let archive = read_archive(&bytes).unwrap();
+        assert_eq!(archive.members, members);
+        assert_eq!(
+            archive.symbols,
+            [
+                ("sin".to_string(), 0),
+                ("cos".to_string(), 1),
+                ("table".to_string(), 2),
+            ]
+        );
+        assert_eq!(archive.member_defining("table"), Some(2));
+        assert_eq!(archive.member_defining("tan"), None);
+    }
+
+    #[test]
+    fn test_pull_members() {
+        let mut bytes = Vec::new();
+        write_archive(&mut bytes, &library()).unwrap();
+        let archives = [("libmath.a".to_string(), read_archive(&bytes).unwrap())];
+
+        let main = member("main.o", "main: JSR sin\n");
+        let mut inputs = vec![read_input("main.o", &main.bytes).unwrap()];
+        pull_members(&mut inputs, &archives).unwrap();
+        let names: Vec<&str> = inputs.iter().map(|input| input.name.as_str()).collect();
+        // `table` only gets pulled in because `sin` needs it, `cos` not at all
+        assert_eq!(
+            names,
+            [
+                "main.o",
+                "libmath.a(sin.o)",
+                "libmath.a(lookup_table_data.o)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_archives() {
+        assert_eq!(read_archive(b"!<arch\n"), Err("not an archive".to_string()));
+        let mut bytes = Vec::new();
+        write_archive(&mut bytes, &library()).unwrap();
+        assert_eq!(
+            read_archive(&bytes[..bytes.len() - 4]),
+            Err("archive member runs past the end of the file".to_string())
+        );
+        let members = [Member {
+            name: "notes.txt".to_string(),
+            bytes: b"hello".to_vec(),
+        }];
+        assert!(write_archive(&mut Vec::new(), &members).is_err());
+    }
+}
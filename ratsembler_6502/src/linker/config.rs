@@ -0,0 +1,584 @@
+// Linker configuration, a subset of what ld65 takes:
+//
+//     MEMORY {
+//         ZP:  start = $0000, size = $0100, file = "";
+//         RAM: start = $0200, size = $7E00, file = "";
+//         ROM: start = $8000, size = $8000, fill = yes, fillval = $FF;
+//     }
+//     SEGMENTS {
+//         zp:      load = ZP;
+//         text:    load = ROM;
+//         data:    load = ROM, run = RAM, define = yes;
+//         bss:     load = RAM, align = $100;
+//         vectors: load = ROM, start = $FFFA;
+//     }
+//
+// Sections are placed in the order they're listed, each in the memory it
+// runs from. Ones with a separate run address are stored where they're
+// loaded, for the program to copy into place itself.
+//
+// Cartridges that switch banks in and out give each bank a region of its
+// own, all at the same addresses:
+//
+//         BANK0: start = $8000, size = $4000, bank = 0;
+//         BANK1: start = $8000, size = $4000, bank = 1;
+
+use crate::lang::number::parse_number;
+
+// The output file given on the command line, the default for `file`
+pub const OUTPUT_FILE: &str = "%O";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub start: u16,
+    pub size: usize,
+    // Pad the whole region out in the output, rather than only writing the
+    // parts that are used
+    pub fill: bool,
+    pub fill_value: u8,
+    // Where the region is written, or None if it's only there to be run
+    // from, like RAM
+    pub file: Option<String>,
+    // For switchable banks that share an address window with other
+    // regions. What `^label` gives for anything in the region.
+    pub bank: Option<usize>,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> usize {
+        self.start as usize + self.size
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentRule {
+    pub name: String,
+    pub load: String,
+    pub run: Option<String>,
+    pub align: usize,
+    pub start: Option<u16>,
+    // Define __NAME_LOAD__, __NAME_RUN__ and __NAME_SIZE__
+    pub define: bool,
+    // Don't complain if no object has this section
+    pub optional: bool,
+    // Never throw it away with --gc-sections, even if nothing refers to it
+    pub keep: bool,
+}
+
+impl SegmentRule {
+    pub fn run_region(&self) -> &str {
+        self.run.as_deref().unwrap_or(&self.load)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkerConfig {
+    pub memory: Vec<MemoryRegion>,
+    pub segments: Vec<SegmentRule>,
+}
+
+impl LinkerConfig {
+    pub fn region(&self, name: &str) -> Option<&MemoryRegion> {
+        self.memory.iter().find(|region| region.name == name)
+    }
+
+    pub fn rule(&self, section: &str) -> Option<&SegmentRule> {
+        self.segments.iter().find(|rule| rule.name == section)
+    }
+
+    // The bank a section is stored in, if it's in a banked region.
+    pub fn bank(&self, section: &str) -> Option<usize> {
+        let rule = self.rule(section)?;
+        self.region(&rule.load)?.bank
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Number(i64),
+    Text(String),
+    Punctuation(char),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Name(name) => format!("'{}'", name),
+            Token::Number(number) => format!("{}", number),
+            Token::Text(text) => format!("\"{}\"", text),
+            Token::Punctuation(character) => format!("'{}'", character),
+        }
+    }
+}
+
+// Splits the config into tokens, each with the line it's on.
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = line.split('#').next().unwrap_or_default();
+        let mut characters = line.char_indices().peekable();
+        while let Some((start, character)) = characters.next() {
+            let token = match character {
+                ' ' | '\t' | '\r' => continue,
+                '{' | '}' | ':' | '=' | ',' | ';' => Token::Punctuation(character),
+                '"' => {
+                    let mut text = String::new();
+                    loop {
+                        match characters.next() {
+                            Some((_, '"')) => break,
+                            Some((_, character)) => text.push(character),
+                            None => return Err(format!("line {}: unterminated string", number)),
+                        }
+                    }
+                    Token::Text(text)
+                }
+                _ => {
+                    let mut end = start + character.len_utf8();
+                    while let Some((index, character)) = characters.peek() {
+                        if !(character.is_alphanumeric() || *character == '_') {
+                            break;
+                        }
+                        end = index + character.len_utf8();
+                        characters.next();
+                    }
+                    let word = &line[start..end];
+                    if word == OUTPUT_FILE {
+                        Token::Text(word.to_string())
+                    } else if let Some(value) = parse_number(word) {
+                        Token::Number(value)
+                    } else if character.is_alphabetic() || character == '_' {
+                        Token::Name(word.to_string())
+                    } else {
+                        return Err(format!("line {}: unexpected '{}'", number, word));
+                    }
+                }
+            };
+            tokens.push((number, token));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        match self.tokens.get(self.position) {
+            Some((line, _)) => *line,
+            None => self.tokens.last().map_or(1, |(line, _)| *line),
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line(), message))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn next(&mut self, expected: &str) -> Result<Token, String> {
+        match self.tokens.get(self.position) {
+            Some((_, token)) => {
+                self.position += 1;
+                Ok(token.clone())
+            }
+            None => self.error(format!("expected {}, but the config ends", expected)),
+        }
+    }
+
+    fn punctuation(&mut self, expected: char) -> Result<(), String> {
+        match self.next(&format!("'{}'", expected))? {
+            Token::Punctuation(character) if character == expected => Ok(()),
+            other => {
+                self.position -= 1;
+                self.error(format!(
+                    "expected '{}' but found {}",
+                    expected,
+                    other.describe()
+                ))
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next("a name")? {
+            Token::Name(name) => Ok(name),
+            other => {
+                self.position -= 1;
+                self.error(format!("expected a name but found {}", other.describe()))
+            }
+        }
+    }
+
+    // A block of `name: attribute = value, ...;` entries, given to `entry`
+    // one at a time with their attributes.
+    fn block(
+        &mut self,
+        mut entry: impl FnMut(usize, String, Vec<(String, Token)>) -> Result<(), String>,
+    ) -> Result<(), String> {
+        self.punctuation('{')?;
+        while self.peek() != Some(&Token::Punctuation('}')) {
+            let line = self.line();
+            let name = self.name()?;
+            self.punctuation(':')?;
+            let mut attributes = Vec::new();
+            loop {
+                let attribute = self.name()?;
+                self.punctuation('=')?;
+                let value = self.next("a value")?;
+                attributes.push((attribute.to_lowercase(), value));
+                match self.next("',' or ';'")? {
+                    Token::Punctuation(',') => continue,
+                    Token::Punctuation(';') => break,
+                    other => {
+                        self.position -= 1;
+                        return self.error(format!(
+                            "expected ',' or ';' but found {}",
+                            other.describe()
+                        ));
+                    }
+                }
+            }
+            entry(line, name, attributes)?;
+        }
+        self.punctuation('}')
+    }
+}
+
+fn line_error<T>(line: usize, message: String) -> Result<T, String> {
+    Err(format!("line {}: {}", line, message))
+}
+
+fn number_value(line: usize, attribute: &str, value: &Token, max: i64) -> Result<i64, String> {
+    match value {
+        Token::Number(number) if (0..=max).contains(number) => Ok(*number),
+        Token::Number(number) => line_error(
+            line,
+            format!(
+                "{} must be between 0 and ${:X}, not ${:X}",
+                attribute, max, number
+            ),
+        ),
+        other => line_error(
+            line,
+            format!("{} should be a number, not {}", attribute, other.describe()),
+        ),
+    }
+}
+
+fn yes_no(line: usize, attribute: &str, value: &Token) -> Result<bool, String> {
+    match value {
+        Token::Name(name) if name == "yes" => Ok(true),
+        Token::Name(name) if name == "no" => Ok(false),
+        other => line_error(
+            line,
+            format!(
+                "{} should be yes or no, not {}",
+                attribute,
+                other.describe()
+            ),
+        ),
+    }
+}
+
+fn region_name(line: usize, attribute: &str, value: &Token) -> Result<String, String> {
+    match value {
+        Token::Name(name) => Ok(name.clone()),
+        other => line_error(
+            line,
+            format!(
+                "{} should be a memory region, not {}",
+                attribute,
+                other.describe()
+            ),
+        ),
+    }
+}
+
+fn memory_region(
+    line: usize,
+    name: String,
+    attributes: Vec<(String, Token)>,
+) -> Result<MemoryRegion, String> {
+    let mut start = None;
+    let mut size = None;
+    let mut region = MemoryRegion {
+        name,
+        start: 0,
+        size: 0,
+        fill: false,
+        fill_value: 0,
+        file: Some(OUTPUT_FILE.to_string()),
+        bank: None,
+    };
+    for (attribute, value) in attributes {
+        match attribute.as_str() {
+            "start" => start = Some(number_value(line, "start", &value, 0xFFFF)? as u16),
+            "size" => size = Some(number_value(line, "size", &value, 0x10000)? as usize),
+            "fill" => region.fill = yes_no(line, "fill", &value)?,
+            "fillval" => region.fill_value = number_value(line, "fillval", &value, 0xFF)? as u8,
+            "bank" => region.bank = Some(number_value(line, "bank", &value, 0xFF)? as usize),
+            "file" => {
+                region.file = match value {
+                    Token::Text(file) if file.is_empty() => None,
+                    Token::Text(file) => Some(file),
+                    other => {
+                        return line_error(
+                            line,
+                            format!("file should be a string or %O, not {}", other.describe()),
+                        )
+                    }
+                }
+            }
+            // ld65 configs always say, but it doesn't change anything here.
+            "type" => match value {
+                Token::Name(kind) if kind == "ro" || kind == "rw" => {}
+                other => {
+                    return line_error(
+                        line,
+                        format!("type should be ro or rw, not {}", other.describe()),
+                    )
+                }
+            },
+            _ => {
+                return line_error(
+                    line,
+                    format!("memory region '{}' can't have {}", region.name, attribute),
+                )
+            }
+        }
+    }
+    let (Some(start), Some(size)) = (start, size) else {
+        return line_error(
+            line,
+            format!("memory region '{}' needs a start and a size", region.name),
+        );
+    };
+    region.start = start;
+    region.size = size;
+    if region.end() > 0x10000 {
+        return line_error(
+            line,
+            format!(
+                "memory region '{}' runs past the end of memory",
+                region.name
+            ),
+        );
+    }
+    Ok(region)
+}
+
+fn segment_rule(
+    line: usize,
+    name: String,
+    attributes: Vec<(String, Token)>,
+) -> Result<SegmentRule, String> {
+    let mut load = None;
+    let mut rule = SegmentRule {
+        name,
+        load: String::new(),
+        run: None,
+        align: 1,
+        start: None,
+        define: false,
+        optional: false,
+        keep: false,
+    };
+    for (attribute, value) in attributes {
+        match attribute.as_str() {
+            "load" => load = Some(region_name(line, "load", &value)?),
+            "run" => rule.run = Some(region_name(line, "run", &value)?),
+            "align" => {
+                let align = number_value(line, "align", &value, 0x10000)? as usize;
+                if !align.is_power_of_two() {
+                    return line_error(
+                        line,
+                        format!("align should be a power of two, not {}", align),
+                    );
+                }
+                rule.align = align;
+            }
+            "start" => rule.start = Some(number_value(line, "start", &value, 0xFFFF)? as u16),
+            "define" => rule.define = yes_no(line, "define", &value)?,
+            "optional" => rule.optional = yes_no(line, "optional", &value)?,
+            "keep" => rule.keep = yes_no(line, "keep", &value)?,
+            // ld65's segment types are all the same to us.
+            "type" => {}
+            _ => {
+                return line_error(
+                    line,
+                    format!("segment '{}' can't have {}", rule.name, attribute),
+                )
+            }
+        }
+    }
+    let Some(load) = load else {
+        return line_error(line, format!("segment '{}' needs a load region", rule.name));
+    };
+    rule.load = load;
+    Ok(rule)
+}
+
+pub fn parse_config(text: &str) -> Result<LinkerConfig, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        position: 0,
+    };
+    let mut config = LinkerConfig::default();
+    while parser.peek().is_some() {
+        let block = parser.name()?;
+        match block.to_uppercase().as_str() {
+            "MEMORY" => parser.block(|line, name, attributes| {
+                if config.region(&name).is_some() {
+                    return line_error(line, format!("memory region '{}' is defined twice", name));
+                }
+                config.memory.push(memory_region(line, name, attributes)?);
+                Ok(())
+            })?,
+            "SEGMENTS" => parser.block(|line, name, attributes| {
+                if config.rule(&name).is_some() {
+                    return line_error(line, format!("segment '{}' is listed twice", name));
+                }
+                config.segments.push(segment_rule(line, name, attributes)?);
+                Ok(())
+            })?,
+            _ => {
+                parser.position -= 1;
+                return parser.error(format!("expected MEMORY or SEGMENTS, not '{}'", block));
+            }
+        }
+    }
+
+    for rule in &config.segments {
+        for region in [Some(&rule.load), rule.run.as_ref()].into_iter().flatten() {
+            if config.region(region).is_none() {
+                return Err(format!(
+                    "segment '{}' is in memory region '{}', which isn't defined",
+                    rule.name, region
+                ));
+            }
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config(
+            r#"
+# comments run to the end of the line
+MEMORY {
+    ZP:  start = $0000, size = $100, type = rw, file = "";
+    ROM: start = $E000, size = 8192, fill = yes, fillval = %11101010, bank = 2;
+    CHR: start = 0, size = 0x2000, file = "tiles.chr";
+}
+SEGMENTS {
+    zp:   load = ZP, optional = yes;
+    data: load = ROM, run = ZP, define = yes, align = 16, keep = yes;
+    vectors: load = ROM, start = $FFFA, type = ro;
+}
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.memory,
+            [
+                MemoryRegion {
+                    name: "ZP".to_string(),
+                    start: 0,
+                    size: 0x100,
+                    fill: false,
+                    fill_value: 0,
+                    file: None,
+                    bank: None,
+                },
+                MemoryRegion {
+                    name: "ROM".to_string(),
+                    start: 0xE000,
+                    size: 0x2000,
+                    fill: true,
+                    fill_value: 0xEA,
+                    file: Some(OUTPUT_FILE.to_string()),
+                    bank: Some(2),
+                },
+                MemoryRegion {
+                    name: "CHR".to_string(),
+                    start: 0,
+                    size: 0x2000,
+                    fill: false,
+                    fill_value: 0,
+                    file: Some("tiles.chr".to_string()),
+                    bank: None,
+                },
+            ]
+        );
+        assert!(config.rule("zp").unwrap().optional);
+        assert_eq!(
+            config.rule("data"),
+            Some(&SegmentRule {
+                name: "data".to_string(),
+                load: "ROM".to_string(),
+                run: Some("ZP".to_string()),
+                align: 16,
+                start: None,
+                define: true,
+                optional: false,
+                keep: true,
+            })
+        );
+        assert_eq!(config.rule("data").unwrap().run_region(), "ZP");
+        assert_eq!(config.bank("data"), Some(2));
+        assert_eq!(config.bank("zp"), None);
+        assert_eq!(config.rule("vectors").unwrap().start, Some(0xFFFA));
+    }
+
+    #[test]
+    fn test_bad_configs() {
+        let error = |text: &str| parse_config(text).unwrap_err();
+
+        assert_eq!(
+            error("MEMORY {\n  ROM: start = $8000;\n}\n"),
+            "line 2: memory region 'ROM' needs a start and a size"
+        );
+        assert_eq!(
+            error("MEMORY {\n  ROM: start = $8000, size = $8001;\n}\n"),
+            "line 2: memory region 'ROM' runs past the end of memory"
+        );
+        assert_eq!(
+            error("MEMORY {\n  ROM: start = $8000, size = $100, colour = red;\n}\n"),
+            "line 2: memory region 'ROM' can't have colour"
+        );
+        assert_eq!(
+            error("MEMORY {\n  ROM: start = $8000 size = $100;\n}\n"),
+            "line 2: expected ',' or ';' but found 'size'"
+        );
+        assert_eq!(
+            error("SEGMENTS {\n  text: load = ROM, align = 3;\n}\n"),
+            "line 2: align should be a power of two, not 3"
+        );
+        assert_eq!(
+            error("SEGMENTS {\n  text: load = ROM;\n}\n"),
+            "segment 'text' is in memory region 'ROM', which isn't defined"
+        );
+        assert_eq!(
+            error("FILES {\n}\n"),
+            "line 1: expected MEMORY or SEGMENTS, not 'FILES'"
+        );
+        assert_eq!(
+            error("MEMORY {\n  ROM: start = $8000, size = $100;\n"),
+            "line 2: expected a name, but the config ends"
+        );
+    }
+}
@@ -0,0 +1,462 @@
+use super::lay_out;
+use super::Input;
+use super::Layout;
+use super::Linked;
+
+use crate::elf::relocatable::base_section_name;
+use crate::elf::relocatable::is_local_symbol;
+use crate::elf::relocatable::section_index;
+use crate::elf::relocatable::Object;
+use crate::elf::relocatable::Symbol;
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+// Sections that are always kept, since the CPU gets into the program
+// through them rather than through anything that refers to them.
+const VECTORS: &str = "vectors";
+// The same goes for whatever ends up over the NMI, reset and IRQ vectors,
+// whatever section it's in.
+const VECTOR_ADDRESSES: RangeInclusive<usize> = 0xFFFA..=0xFFFF;
+
+// Somewhere the program gets entered from, like the entry point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Root {
+    Symbol(String),
+    Address(u16),
+}
+
+// An input section thrown away because nothing used it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Removed {
+    pub object: String,
+    pub section: String,
+    pub size: usize,
+}
+
+// The input section a symbol is in, if it's in one rather than being a
+// plain value.
+fn symbol_section(object: &Object, symbol: &Symbol) -> Option<usize> {
+    match symbol {
+        Symbol::Location(location) | Symbol::Procedure(location, _) => {
+            section_index(&object.sections, *location)
+        }
+        Symbol::ShortValue(_) | Symbol::LongValue(_) => None,
+    }
+}
+
+// The input sections laid out over any of `addresses`, as the index of the
+// input and of the section in it. The layout can be one that doesn't fit,
+// where a section runs on over the ones after it, so each section only
+// goes up to wherever the next one starts.
+fn sections_at(
+    inputs: &[Input],
+    linked: &Linked,
+    addresses: &RangeInclusive<usize>,
+) -> Vec<(usize, usize)> {
+    let ends: Vec<usize> = linked
+        .sections
+        .iter()
+        .map(|section| {
+            linked
+                .sections
+                .iter()
+                .filter(|other| other.bank == section.bank && other.address > section.address)
+                .filter(|other| !other.bytes.is_empty())
+                .map(|other| other.address as usize)
+                .min()
+                .unwrap_or(0x10000)
+        })
+        .collect();
+    linked
+        .pieces
+        .iter()
+        .filter(|piece| {
+            let start = linked.sections[piece.section].address as usize + piece.offset;
+            let end = (start + piece.size).min(ends[piece.section]);
+            start <= *addresses.end() && *addresses.start() < end
+        })
+        .filter_map(|piece| {
+            let index = inputs.iter().position(|input| input.name == piece.object)?;
+            let section = inputs[index]
+                .object
+                .sections
+                .iter()
+                .position(|section| section.name == piece.input_section)?;
+            Some((index, section))
+        })
+        .collect()
+}
+
+// Throws away every input section that can't be reached from the roots,
+// the vectors or the sections in `keep`, by following relocations. Works
+// best on objects assembled with function sections, where each routine is
+// a section of its own. Roots given as addresses, and the vectors, are
+// found by laying everything out first, even if it doesn't fit yet. With
+// nothing to start from, everything would go, so that's an error.
+pub fn gc_sections(
+    inputs: &mut [Input],
+    layout: &Layout,
+    roots: &[Root],
+    keep: &[String],
+) -> Result<Vec<Removed>, String> {
+    let mut globals: HashMap<&str, (usize, usize)> = HashMap::new();
+    for (index, input) in inputs.iter().enumerate() {
+        for (name, symbol) in &input.object.symbols {
+            if is_local_symbol(name) {
+                continue;
+            }
+            if let Some(section) = symbol_section(&input.object, symbol) {
+                globals.entry(name.as_str()).or_insert((index, section));
+            }
+        }
+    }
+
+    let mut live: Vec<Vec<bool>> = inputs
+        .iter()
+        .map(|input| vec![false; input.object.sections.len()])
+        .collect();
+    let mut pending: Vec<(usize, usize)> = roots
+        .iter()
+        .filter_map(|root| match root {
+            Root::Symbol(name) => globals.get(name.as_str()).copied(),
+            Root::Address(_) => None,
+        })
+        .collect();
+    // Anything wrong with the layout gets reported by the real link, which
+    // includes the program not fitting until this has thrown things away
+    let (linked, _) = lay_out(inputs, layout, &mut Vec::new());
+    pending.extend(sections_at(inputs, &linked, &VECTOR_ADDRESSES));
+    for root in roots {
+        let Root::Address(address) = root else {
+            continue;
+        };
+        let address = *address as usize;
+        let found = sections_at(inputs, &linked, &(address..=address));
+        if found.is_empty() {
+            return Err(format!("nothing to keep at ${:04X}", address));
+        }
+        pending.extend(found);
+    }
+    for (index, input) in inputs.iter().enumerate() {
+        for (section, input_section) in input.object.sections.iter().enumerate() {
+            let name = base_section_name(&input_section.name);
+            if name == VECTORS || keep.iter().any(|keep| keep == name) {
+                pending.push((index, section));
+            }
+        }
+    }
+    if pending.is_empty() {
+        return Err(
+            "nothing to start from: no entry point, init routine, vectors or kept sections"
+                .to_string(),
+        );
+    }
+
+    while let Some((index, section)) = pending.pop() {
+        if live[index][section] {
+            continue;
+        }
+        live[index][section] = true;
+        let object = &inputs[index].object;
+        let range = object.sections[section].start..object.sections[section].end();
+        for relocation in &object.relocations {
+            if !range.contains(&(relocation.offset() as usize)) {
+                continue;
+            }
+            let name = relocation.symbol();
+            let target = match is_local_symbol(name) {
+                true => object
+                    .symbols
+                    .get(name)
+                    .and_then(|symbol| symbol_section(object, symbol))
+                    .map(|section| (index, section)),
+                false => globals.get(name).copied(),
+            };
+            pending.extend(target);
+        }
+    }
+
+    let mut removed = Vec::new();
+    for (input, live) in inputs.iter_mut().zip(live) {
+        for (section, _) in live.iter().enumerate().filter(|(_, live)| !**live) {
+            let section = &input.object.sections[section];
+            if section.size > 0 {
+                removed.push(Removed {
+                    object: input.name.clone(),
+                    section: section.name.clone(),
+                    size: section.size,
+                });
+            }
+        }
+        strip_sections(&mut input.object, &live);
+    }
+    Ok(removed)
+}
+
+// Drops the sections that aren't live, along with their symbols and
+// relocations, and closes up the gaps they leave in the raw section.
+fn strip_sections(object: &mut Object, live: &[bool]) {
+    // Where each old section starts in the new raw section
+    let mut moved = Vec::new();
+    let mut raw_section = Vec::new();
+    let mut sections = Vec::new();
+    for (section, live) in object.sections.iter().zip(live) {
+        moved.push(raw_section.len());
+        if *live {
+            raw_section.extend(&object.raw_section[section.start..section.end()]);
+            let mut section = section.clone();
+            section.start = moved[moved.len() - 1];
+            sections.push(section);
+        }
+    }
+    let relocate = |offset: usize| {
+        section_index(&object.sections, offset)
+            .filter(|section| live[*section])
+            .map(|section| moved[section] + offset - object.sections[section].start)
+    };
+
+    let mut symbols = HashMap::new();
+    for (name, symbol) in &object.symbols {
+        let symbol = match symbol {
+            Symbol::Location(location) => match relocate(*location) {
+                Some(location) => Symbol::Location(location),
+                None => continue,
+            },
+            Symbol::Procedure(location, size) => match relocate(*location) {
+                Some(location) => Symbol::Procedure(location, *size),
+                None => continue,
+            },
+            value => value.clone(),
+        };
+        symbols.insert(name.clone(), symbol);
+    }
+    let relocations = object
+        .relocations
+        .iter()
+        .filter_map(|relocation| {
+            relocate(relocation.offset() as usize).map(|offset| relocation.moved_to(offset as u32))
+        })
+        .collect();
+
+    object.raw_section = raw_section;
+    object.sections = sections;
+    object.symbols = symbols;
+    object.relocations = relocations;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::linker::config::parse_config;
+    use crate::linker::link;
+    use crate::linker::test_util::input_with_function_sections;
+
+    #[test]
+    fn test_gc_sections() {
+        let main = input_with_function_sections(
+            "main.o",
+            r#"
+.proc main
+  JSR used
+loop:
+  BNE loop
+  RTS
+.endproc
+.proc unused
+  JSR used
+  RTS
+.endproc
+.section vectors
+  .word 0, main, 0
+"#,
+        );
+        let lib = input_with_function_sections(
+            "lib.o",
+            r#"
+.proc used
+  LDA table
+  RTS
+.endproc
+.proc helper
+  RTS
+.endproc
+.section data
+table: .byte 1, 2
+spare: .byte 3
+"#,
+        );
+        let mut inputs = [main, lib];
+        let layout = Layout::Origin(0x8000);
+        let removed = gc_sections(
+            &mut inputs,
+            &layout,
+            &[Root::Symbol("main".to_string())],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            removed,
+            [
+                Removed {
+                    object: "main.o".to_string(),
+                    section: "text.unused".to_string(),
+                    size: 4,
+                },
+                Removed {
+                    object: "lib.o".to_string(),
+                    section: "text.helper".to_string(),
+                    size: 1,
+                },
+                Removed {
+                    object: "lib.o".to_string(),
+                    section: "data.spare".to_string(),
+                    size: 1,
+                },
+            ]
+        );
+
+        let linked = link(&inputs, &layout).unwrap();
+        assert_eq!(
+            linked.sections[0].bytes,
+            [0x20, 0x06, 0x80, 0xD0, 0xFE, 0x60, 0xAD, 0x10, 0x80, 0x60]
+        );
+        assert_eq!(linked.sections[1].name, "vectors");
+        assert_eq!(linked.sections[1].bytes, [0, 0, 0x00, 0x80, 0, 0]);
+        assert_eq!(linked.sections[2].bytes, [1, 2]);
+        assert_eq!(linked.address_of("unused"), None);
+    }
+
+    #[test]
+    fn test_keep() {
+        let mut inputs = [input_with_function_sections(
+            "main.o",
+            "main: RTS\n.section data\nbuild: .byte 7\n",
+        )];
+        let layout = Layout::Origin(0x8000);
+        let removed = gc_sections(&mut inputs, &layout, &[], &["data".to_string()]).unwrap();
+        assert_eq!(
+            removed,
+            [Removed {
+                object: "main.o".to_string(),
+                section: "text.main".to_string(),
+                size: 1,
+            }]
+        );
+        assert_eq!(inputs[0].object.raw_section, [7]);
+        assert_eq!(inputs[0].object.symbols["build"], Symbol::Location(0));
+    }
+
+    #[test]
+    fn test_address_roots() {
+        let source = r#"
+.proc main
+  RTS
+.endproc
+.proc nmi
+  RTI
+.endproc
+.proc unused
+  RTS
+.endproc
+.section handlers
+  .word nmi, main, nmi
+"#;
+        let layout = Layout::Config(
+            parse_config(
+                r#"
+MEMORY {
+    ROM: start = $E000, size = $2000;
+}
+SEGMENTS {
+    text:     load = ROM;
+    handlers: load = ROM, start = $FFFA;
+}
+"#,
+            )
+            .unwrap(),
+        );
+        // The entry point given as an address, and the vectors found by
+        // where they are rather than what they're called
+        let mut inputs = [input_with_function_sections("main.o", source)];
+        let removed = gc_sections(&mut inputs, &layout, &[Root::Address(0xE000)], &[]).unwrap();
+        assert_eq!(
+            removed,
+            [Removed {
+                object: "main.o".to_string(),
+                section: "text.unused".to_string(),
+                size: 1,
+            }]
+        );
+
+        let mut inputs = [input_with_function_sections("main.o", source)];
+        assert_eq!(
+            gc_sections(&mut inputs, &layout, &[Root::Address(0xD000)], &[]),
+            Err("nothing to keep at $D000".to_string())
+        );
+
+        // Without a config nothing is at the vectors, so there'd be nothing
+        // left at all
+        let mut inputs = [input_with_function_sections("main.o", source)];
+        assert_eq!(
+            gc_sections(&mut inputs, &Layout::Origin(0x8000), &[], &[]),
+            Err(
+                "nothing to start from: no entry point, init routine, vectors or kept sections"
+                    .to_string()
+            )
+        );
+        assert_eq!(inputs[0].object.raw_section.len(), 9);
+    }
+
+    #[test]
+    fn test_gc_makes_it_fit() {
+        let source = r#"
+.proc reset
+  JMP reset
+.endproc
+.proc dead
+  .res $100
+.endproc
+.section vecs
+  .word reset, reset, reset
+"#;
+        let layout = Layout::Config(
+            parse_config(
+                r#"
+MEMORY {
+    ROM: start = $FF00, size = $100;
+}
+SEGMENTS {
+    text: load = ROM;
+    vecs: load = ROM, start = $FFFA;
+}
+"#,
+            )
+            .unwrap(),
+        );
+        let inputs = [input_with_function_sections("main.o", source)];
+        assert!(link(&inputs, &layout).is_err());
+
+        // The roots still have to be found while it's too big to link
+        let dead = [Removed {
+            object: "main.o".to_string(),
+            section: "text.dead".to_string(),
+            size: 0x100,
+        }];
+        for roots in [
+            vec![],
+            vec![Root::Address(0xFF00)],
+            vec![Root::Symbol("reset".to_string())],
+        ] {
+            let mut inputs = [input_with_function_sections("main.o", source)];
+            assert_eq!(
+                gc_sections(&mut inputs, &layout, &roots, &[]),
+                Ok(dead.to_vec())
+            );
+            let linked = link(&inputs, &layout).unwrap();
+            assert_eq!(linked.sections[0].bytes, [0x4C, 0x00, 0xFF]);
+            assert_eq!(linked.sections[1].address, 0xFFFA);
+        }
+    }
+}
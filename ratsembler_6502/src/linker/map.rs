@@ -0,0 +1,150 @@
+use super::Linked;
+
+use std::io::{self, Write};
+
+// How many bytes of a region are taken, counting both the sections stored
+// in it and the ones copied into it to run.
+fn region_used(linked: &Linked, index: usize) -> usize {
+    let region = &linked.memory[index];
+    linked
+        .sections
+        .iter()
+        .filter(|section| {
+            let runs_here =
+                (region.start as usize..region.end()).contains(&(section.address as usize));
+            section.region == Some(index) || (runs_here && section.address != section.load_address)
+        })
+        .map(|section| section.bytes.len())
+        .sum()
+}
+
+// Writes a map of where everything ended up: the memory regions and how
+// full they are, each output section with the objects that went into it,
+// then the global symbols.
+pub fn write_map(out: &mut impl Write, linked: &Linked) -> io::Result<()> {
+    if !linked.memory.is_empty() {
+        writeln!(out, "Memory regions:")?;
+        writeln!(out, "  Name             Start  End    Size   Used   Free")?;
+        for (index, region) in linked.memory.iter().enumerate() {
+            let used = region_used(linked, index);
+            writeln!(
+                out,
+                "  {:<16} ${:04X}  ${:04X}  ${:04X}  ${:04X}  ${:04X}",
+                region.name,
+                region.start,
+                region.end() - 1,
+                region.size,
+                used,
+                region.size.saturating_sub(used),
+            )?;
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out, "Sections:")?;
+    writeln!(out, "  Name             Run    Load   Size   Bank")?;
+    for (index, section) in linked.sections.iter().enumerate() {
+        let bank = match section.bank {
+            Some((rom, bank)) => format!("{:?} {}", rom, bank),
+            None => String::new(),
+        };
+        writeln!(
+            out,
+            "  {:<16} ${:04X}  ${:04X}  ${:04X}  {}",
+            section.name,
+            section.address,
+            section.load_address,
+            section.bytes.len(),
+            bank,
+        )?;
+        for piece in linked.pieces.iter().filter(|piece| piece.section == index) {
+            writeln!(
+                out,
+                "    {:<14} {:<16} ${:04X}  ${:04X}",
+                piece.object,
+                piece.input_section,
+                section.address as usize + piece.offset,
+                piece.size,
+            )?;
+        }
+    }
+
+    if !linked.removed.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "Removed sections:")?;
+        for removed in &linked.removed {
+            writeln!(
+                out,
+                "  {:<16} {:<14} ${:04X}",
+                removed.section, removed.object, removed.size
+            )?;
+        }
+    }
+
+    writeln!(out)?;
+    writeln!(out, "Symbols:")?;
+    writeln!(
+        out,
+        "  Name                     Value  Size   Section          File"
+    )?;
+    for (name, symbol) in &linked.symbols {
+        let section = symbol
+            .section
+            .map_or("", |section| linked.sections[section].name.as_str());
+        let file = linked
+            .defined_in
+            .get(name)
+            .map_or("(linker config)", |file| file.as_str());
+        writeln!(
+            out,
+            "  {:<24} ${:04X}  {:<5}  {:<16} {}",
+            name, symbol.value, symbol.size, section, file,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::linker::config::parse_config;
+    use crate::linker::link;
+    use crate::linker::test_util::input;
+    use crate::linker::Layout;
+
+    #[test]
+    fn test_map() {
+        let config = parse_config(
+            r#"
+MEMORY {
+    RAM: start = $0200, size = $0100, file = "";
+    ROM: start = $F000, size = $1000;
+}
+SEGMENTS {
+    text: load = ROM;
+    data: load = ROM, run = RAM, define = yes;
+}
+"#,
+        )
+        .unwrap();
+        let main = input("main.o", "main: JSR print\n.section data\ncount: .byte 0\n");
+        let print = input("print.o", ".proc print\n  RTS\n.endproc\n");
+        let linked = link(&[main, print], &Layout::Config(config)).unwrap();
+        let mut map = Vec::new();
+        write_map(&mut map, &linked).unwrap();
+        let map = String::from_utf8(map).unwrap();
+
+        assert!(map.contains("  RAM              $0200  $02FF  $0100  $0001  $00FF\n"));
+        assert!(map.contains("  ROM              $F000  $FFFF  $1000  $0005  $0FFB\n"));
+        assert!(map.contains(
+            "  text             $F000  $F000  $0004  \n    main.o         text             $F000  $0003\n    print.o        text             $F003  $0001\n"
+        ));
+        assert!(map.contains("  data             $0200  $F004  $0001  \n"));
+        assert!(map.contains("  print                    $F003  1      text             print.o\n"));
+        assert!(map.contains("  count                    $0200  0      data             main.o\n"));
+        assert!(map.contains(
+            "  __DATA_RUN__             $0200  0                       (linker config)\n"
+        ));
+    }
+}
@@ -0,0 +1,185 @@
+use super::link;
+use super::Input;
+use super::Layout;
+use super::Linked;
+
+use crate::elf::relocatable::is_local_symbol;
+use crate::elf::relocatable::section_index;
+use crate::elf::relocatable::Object;
+use crate::elf::relocatable::Relocation;
+use crate::elf::relocatable::Symbol;
+use crate::lang::ast::INVERT_BRANCH;
+use crate::lang::ast::JMP_SIZE;
+
+// A long branch that still needs its JMP once everything's been placed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FarBranch {
+    pub object: String,
+    pub target: String,
+    pub address: u16,
+}
+
+// The output section and address something in one of the inputs ended up
+// at.
+fn locate(linked: &Linked, input: &Input, offset: usize) -> Option<(usize, u16)> {
+    let section = &input.object.sections[section_index(&input.object.sections, offset)?];
+    let piece = linked
+        .pieces
+        .iter()
+        .find(|piece| piece.object == input.name && piece.input_section == section.name)?;
+    let address =
+        linked.sections[piece.section].address as usize + piece.offset + offset - section.start;
+    Some((piece.section, address as u16))
+}
+
+fn locate_symbol(linked: &Linked, input: &Input, name: &str) -> Option<(usize, u16)> {
+    match is_local_symbol(name) {
+        true => match input.object.symbols.get(name)? {
+            Symbol::Location(location) | Symbol::Procedure(location, _) => {
+                locate(linked, input, *location)
+            }
+            Symbol::ShortValue(_) | Symbol::LongValue(_) => None,
+        },
+        false => {
+            let symbol = linked.symbols.get(name)?;
+            Some((symbol.section?, symbol.value))
+        }
+    }
+}
+
+// Turns long branches from the assembler back into plain ones where the
+// target's close enough, now that it's known where everything goes.
+// Shrinking a branch only ever brings things in the same output section
+// closer together, so only branches within one are shrunk, and it goes
+// round until there's nothing left that can be. Returns the branches that
+// still need their JMP.
+pub fn relax_branches(inputs: &mut [Input], layout: &Layout) -> Vec<FarBranch> {
+    loop {
+        // Anything wrong gets reported by the real link
+        let Ok(linked) = link(inputs, layout) else {
+            return Vec::new();
+        };
+        let mut far = Vec::new();
+        let mut shrink = Vec::new();
+        for (index, input) in inputs.iter().enumerate() {
+            for relocation in &input.object.relocations {
+                let Relocation::Relax(name, offset, addend) = relocation else {
+                    continue;
+                };
+                let (Some((section, address)), Some((target_section, target))) = (
+                    locate(&linked, input, *offset as usize),
+                    locate_symbol(&linked, input, name),
+                ) else {
+                    continue;
+                };
+                let mut distance = target as i64 + *addend as i64 - (address as i64 + 2);
+                // The JMP won't be in between any more
+                if target > address {
+                    distance -= JMP_SIZE as i64;
+                }
+                match section == target_section && (-0x80..=0x7F).contains(&distance) {
+                    true => shrink.push((index, *offset as usize)),
+                    false => far.push(FarBranch {
+                        object: input.name.clone(),
+                        target: name.clone(),
+                        address,
+                    }),
+                }
+            }
+        }
+        if shrink.is_empty() {
+            return far;
+        }
+        // From the end, so the ones still to do don't move
+        shrink.sort();
+        for (index, offset) in shrink.into_iter().rev() {
+            shrink_branch(&mut inputs[index].object, offset);
+        }
+    }
+}
+
+// Takes the JMP out of the long branch at `offset` and turns the branch
+// over it back round, closing up the gap.
+fn shrink_branch(object: &mut Object, offset: usize) {
+    let jump = offset + 2..offset + 2 + JMP_SIZE;
+    object.raw_section[offset] ^= INVERT_BRANCH;
+    object.raw_section[offset + 1] = 0xFF;
+    object.raw_section.drain(jump.clone());
+    let moved = |position: usize| match position >= jump.end {
+        true => position - JMP_SIZE,
+        false => position,
+    };
+
+    object.relocations = object
+        .relocations
+        .iter()
+        .filter_map(|relocation| {
+            let position = relocation.offset() as usize;
+            match relocation {
+                Relocation::Relax(name, _, addend) if position == offset => Some(
+                    Relocation::Relative(name.clone(), offset as u32 + 1, *addend),
+                ),
+                _ if jump.contains(&position) => None,
+                _ => Some(relocation.moved_to(moved(position) as u32)),
+            }
+        })
+        .collect();
+    for section in &mut object.sections {
+        if section.start > offset {
+            section.start -= JMP_SIZE;
+        } else if offset < section.end() {
+            section.size -= JMP_SIZE;
+        }
+    }
+    for symbol in object.symbols.values_mut() {
+        match symbol {
+            Symbol::Location(location) => *location = moved(*location),
+            Symbol::Procedure(location, size) => {
+                let end = moved(*location + *size);
+                *location = moved(*location);
+                *size = end - *location;
+            }
+            Symbol::ShortValue(_) | Symbol::LongValue(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::linker::test_util::input;
+
+    #[test]
+    fn test_relax_branches() {
+        let main = input(
+            "main.o",
+            "main:\n  JEQ helper\n  JNE far\n  JCC main\ndone: RTS\n",
+        );
+        let lib = input("lib.o", "helper: RTS\n  .res 200\nfar: RTS\n");
+        let mut inputs = [main, lib];
+        let layout = Layout::Origin(0x8000);
+        assert_eq!(inputs[0].object.raw_section.len(), 13);
+
+        let far = relax_branches(&mut inputs, &layout);
+        assert_eq!(
+            far,
+            [FarBranch {
+                object: "main.o".to_string(),
+                target: "far".to_string(),
+                address: 0x8002,
+            }]
+        );
+        let linked = link(&inputs, &layout).unwrap();
+        assert_eq!(
+            linked.sections[0].bytes[..10],
+            [0xF0, 0x08, 0xF0, 0x03, 0x4C, 0xD3, 0x80, 0x90, 0xF7, 0x60]
+        );
+        assert_eq!(linked.address_of("done"), Some(0x8009));
+        assert_eq!(linked.address_of("helper"), Some(0x800A));
+
+        // Nothing to do the second time round
+        assert_eq!(relax_branches(&mut inputs, &layout), far);
+        assert_eq!(inputs[0].object.raw_section.len(), 10);
+    }
+}
@@ -0,0 +1,29 @@
+// Objects for the tests of the linker and its passes, assembled and read
+// back in the way `link` would.
+use super::read_input;
+use super::Input;
+
+use crate::elf::writer::write_relocatable;
+use crate::lang::assembler::Assembler;
+use crate::lang::source::SourceLine;
+
+pub fn input(name: &str, source: &str) -> Input {
+    assemble(name, source, false)
+}
+
+// With each routine in a section of its own, for the passes that throw
+// sections away.
+pub fn input_with_function_sections(name: &str, source: &str) -> Input {
+    assemble(name, source, true)
+}
+
+fn assemble(name: &str, source: &str, function_sections: bool) -> Input {
+    let mut assembler = Assembler::new();
+    assembler.set_function_sections(function_sections);
+    let program = assembler
+        .assemble(&SourceLine::from_source(name, source))
+        .unwrap();
+    let mut object = Vec::new();
+    write_relocatable(&mut object, &program).unwrap();
+    read_input(name, &object).unwrap()
+}
@@ -0,0 +1,199 @@
+use super::Input;
+use super::Layout;
+
+use crate::elf::relocatable::base_section_name;
+use crate::elf::relocatable::is_local_symbol;
+use crate::elf::relocatable::section_index;
+use crate::elf::relocatable::Object;
+use crate::elf::relocatable::Relocation;
+use crate::elf::relocatable::Section;
+use crate::elf::relocatable::Symbol;
+
+use std::collections::{BTreeSet, HashMap};
+
+// The section the trampolines go in. It has to be somewhere that's always
+// mapped in, so with a linker config it needs a segment of its own.
+pub const TRAMPOLINES: &str = "trampolines";
+
+const JSR: u8 = 0x20;
+const JMP: u8 = 0x4C;
+const RTS: u8 = 0x60;
+const TRAMPOLINE_SIZE: usize = 7;
+
+// Which instruction a Jump relocation is the operand of, for messages.
+pub fn jump_kind(raw_section: &[u8], relocation: &Relocation) -> Option<&'static str> {
+    let Relocation::Jump(..) = relocation else {
+        return None;
+    };
+    let opcode = (relocation.offset() as usize).checked_sub(1)?;
+    match raw_section.get(opcode) {
+        Some(&JMP) => Some("JMP"),
+        _ => Some("JSR"),
+    }
+}
+
+fn section_bank(section: &Section, layout: &Layout) -> Option<usize> {
+    match (section.bank, layout) {
+        (Some((_, bank)), _) => Some(bank),
+        (None, Layout::Config(config)) => config.bank(base_section_name(&section.name)),
+        (None, Layout::Origin(_)) => None,
+    }
+}
+
+fn symbol_bank(object: &Object, symbol: &Symbol, layout: &Layout) -> Option<usize> {
+    match symbol {
+        Symbol::Location(location) | Symbol::Procedure(location, _) => {
+            let section = section_index(&object.sections, *location)?;
+            section_bank(&object.sections[section], layout)
+        }
+        Symbol::ShortValue(_) | Symbol::LongValue(_) => None,
+    }
+}
+
+// Sends every JSR and JMP into another bank through a trampoline instead,
+// and adds the trampolines as an object of their own. Each one is
+//
+//     JSR routine
+//     .bankbyte target
+//     .word target
+//     RTS
+//
+// so `routine` has to pull its return address, switch to the bank after
+// it, call the address after that, switch back and return past the three
+// bytes. Returns the targets that needed one.
+pub fn add_trampolines(inputs: &mut Vec<Input>, layout: &Layout, routine: &str) -> Vec<String> {
+    let mut banks: HashMap<String, usize> = HashMap::new();
+    for input in inputs.iter() {
+        for (name, symbol) in &input.object.symbols {
+            if is_local_symbol(name) {
+                continue;
+            }
+            if let Some(bank) = symbol_bank(&input.object, symbol, layout) {
+                banks.entry(name.clone()).or_insert(bank);
+            }
+        }
+    }
+
+    let mut targets = BTreeSet::new();
+    for input in inputs.iter_mut() {
+        let object = &mut input.object;
+        for relocation in object.relocations.iter_mut() {
+            let name = relocation.symbol();
+            if jump_kind(&object.raw_section, relocation).is_none()
+                || relocation.addend() != 0
+                || is_local_symbol(name)
+            {
+                continue;
+            }
+            let Some(&to) = banks.get(name) else {
+                continue;
+            };
+            let from = section_index(&object.sections, relocation.offset() as usize)
+                .and_then(|section| section_bank(&object.sections[section], layout));
+            if from.is_some_and(|from| from != to) {
+                targets.insert(name.to_string());
+                *relocation =
+                    Relocation::Jump(format!("__trampoline_{}", name), relocation.offset(), 0);
+            }
+        }
+    }
+    if targets.is_empty() {
+        return Vec::new();
+    }
+
+    let mut object = Object::default();
+    for target in &targets {
+        let start = object.raw_section.len();
+        object
+            .raw_section
+            .extend([JSR, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, RTS]);
+        object.relocations.extend([
+            Relocation::Jump(routine.to_string(), start as u32 + 1, 0),
+            Relocation::Bank(target.clone(), start as u32 + 3, 0),
+            Relocation::Long(target.clone(), start as u32 + 4, 0),
+        ]);
+        object.symbols.insert(
+            format!("__trampoline_{}", target),
+            Symbol::Procedure(start, TRAMPOLINE_SIZE),
+        );
+    }
+    object.sections.push(Section {
+        name: TRAMPOLINES.to_string(),
+        start: 0,
+        size: object.raw_section.len(),
+        bank: None,
+    });
+    inputs.push(Input {
+        name: TRAMPOLINES.to_string(),
+        object,
+    });
+    targets.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::elf::relocatable::Rom;
+    use crate::linker::config::parse_config;
+    use crate::linker::link;
+    use crate::linker::test_util::input;
+
+    #[test]
+    fn test_trampolines() {
+        let layout = Layout::Config(
+            parse_config(
+                r#"
+MEMORY {
+    FIXED: start = $C000, size = $4000;
+    BANK0: start = $8000, size = $4000, bank = 0;
+    BANK1: start = $8000, size = $4000, bank = 1;
+}
+SEGMENTS {
+    text:        load = FIXED;
+    trampolines: load = FIXED;
+    one:         load = BANK0;
+    two:         load = BANK1;
+}
+"#,
+            )
+            .unwrap(),
+        );
+        let main = input("main.o", "main: JSR near\nfarcall: RTS\n");
+        let one = input(
+            "one.o",
+            ".section one\nnear: JSR far\n  JSR near\n  JMP far\n  .byte \" \"\n  .word far\n",
+        );
+        let two = input("two.o", ".section two\nfar: RTS\n");
+        let mut inputs = vec![main, one, two];
+        assert_eq!(add_trampolines(&mut inputs, &layout, "farcall"), ["far"]);
+        assert_eq!(inputs[3].name, "trampolines");
+
+        let linked = link(&inputs, &layout).unwrap();
+        let section = |name: &str| {
+            linked
+                .sections
+                .iter()
+                .find(|section| section.name == name)
+                .unwrap()
+        };
+        let trampolines = section("trampolines");
+        assert_eq!((trampolines.address, trampolines.bank), (0xC004, None));
+        assert_eq!(
+            trampolines.bytes,
+            [0x20, 0x03, 0xC0, 0x01, 0x00, 0x80, 0x60]
+        );
+        assert_eq!(section("one").bank, Some((Rom::Prg, 0)));
+        // Calls within the bank and from the fixed code stay as they were
+        assert_eq!(
+            section("one").bytes,
+            [0x20, 0x04, 0xC0, 0x20, 0x00, 0x80, 0x4C, 0x04, 0xC0, 0x20, 0x00, 0x80]
+        );
+        assert_eq!(section("text").bytes, [0x20, 0x00, 0x80, 0x60]);
+        assert_eq!(linked.address_of("__trampoline_far"), Some(0xC004));
+
+        let mut inputs = vec![input("main.o", "main: JSR far\n")];
+        assert!(add_trampolines(&mut inputs, &layout, "farcall").is_empty());
+        assert_eq!(inputs.len(), 1);
+    }
+}
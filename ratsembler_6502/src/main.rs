@@ -1,32 +1,788 @@
-use std::fs::read_to_string;
-use std::io::{self};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
 
+use ratsembler_6502::lang::assembler::Assembler;
 use ratsembler_6502::lang::ast::Program;
-use ratsembler_6502::lang::parser::Assembler6502Parser;
-use ratsembler_6502::lang::parser::Rule;
+use ratsembler_6502::lang::number::parse_address;
+use ratsembler_6502::lang::number::parse_number;
 
+use ratsembler_6502::disasm::hints::parse_hints;
+use ratsembler_6502::disasm::hints::Hint;
+use ratsembler_6502::disasm::trace::trace;
+use ratsembler_6502::disasm::write_disassembly;
+use ratsembler_6502::disasm::Disassembly;
+use ratsembler_6502::disasm::Kind;
+use ratsembler_6502::elf::dump::write_dump;
+use ratsembler_6502::elf::reader::ElfFile;
 use ratsembler_6502::elf::relocatable::Relocatable;
+use ratsembler_6502::elf::writer::write_executable;
+use ratsembler_6502::elf::writer::write_relocatable;
+use ratsembler_6502::emu::machine::Machine;
+use ratsembler_6502::emu::machine::Stop;
+use ratsembler_6502::emu::Cpu;
+use ratsembler_6502::emu::Variant;
+use ratsembler_6502::emu::RESET_VECTOR;
+use ratsembler_6502::linker::archive::pull_members;
+use ratsembler_6502::linker::archive::read_archive;
+use ratsembler_6502::linker::archive::write_archive;
+use ratsembler_6502::linker::archive::Member;
+use ratsembler_6502::linker::archive::ARCHIVE_MAGIC;
+use ratsembler_6502::linker::config::parse_config;
+use ratsembler_6502::linker::config::OUTPUT_FILE;
+use ratsembler_6502::linker::gc::gc_sections;
+use ratsembler_6502::linker::gc::Root;
+use ratsembler_6502::linker::link;
+use ratsembler_6502::linker::map::write_map;
+use ratsembler_6502::linker::read_input;
+use ratsembler_6502::linker::relax::relax_branches;
+use ratsembler_6502::linker::trampolines::add_trampolines;
+use ratsembler_6502::linker::Layout;
+use ratsembler_6502::o65::reader::read_o65;
+use ratsembler_6502::o65::writer::write_o65;
+use ratsembler_6502::o65::writer::MAGIC;
+use ratsembler_6502::output::format::output_format;
+use ratsembler_6502::output::format::FormatOptions;
+use ratsembler_6502::output::format::FORMAT_NAMES;
+use ratsembler_6502::output::image::Image;
+use ratsembler_6502::output::image::Segment;
+use ratsembler_6502::output::labels::resolve_labels;
+use ratsembler_6502::output::labels::write_labels;
+use ratsembler_6502::output::labels::LabelFormat;
 
-use pest::Parser;
+fn usage() -> ! {
+    eprintln!(
+        "usage: ratsembler_6502 [-D NAME[=VALUE]]... [-I DIR]... [-MD] [-o OUTPUT] [-l LISTING]"
+    );
+    eprintln!("                       [--function-sections]");
+    eprintln!("                       [-f elf|o65|bin|ihex|srec|prg|xex|apple|bbc|nes] [--fill BYTE] [--size BYTES]");
+    eprintln!("                       [--record-length BYTES] [--origin ADDRESS]");
+    eprintln!("                       [--entry ADDRESS|LABEL] [--init ADDRESS|LABEL]");
+    eprintln!("                       [--labels|--vice-labels|--mesen-labels|--fceux-labels FILE]... FILE");
+    eprintln!(
+        "       ratsembler_6502 link [-o OUTPUT] [-C CONFIG] [-m MAP] [-f elf|bin|ihex|srec|prg|xex|apple|bbc|nes]"
+    );
+    eprintln!("                       [--fill BYTE] [--size BYTES] [--record-length BYTES] [--origin ADDRESS]");
+    eprintln!("                       [--entry ADDRESS|LABEL] [--init ADDRESS|LABEL]");
+    eprintln!(
+        "                       [--labels|--vice-labels|--mesen-labels|--fceux-labels FILE]..."
+    );
+    eprintln!("                       [--gc-sections] [--trampolines ROUTINE]");
+    eprintln!("                       [--whole-archive|--no-whole-archive]... OBJECT|ARCHIVE...");
+    eprintln!("       ratsembler_6502 archive ARCHIVE OBJECT...");
+    eprintln!("       ratsembler_6502 archive -t ARCHIVE");
+    eprintln!("       ratsembler_6502 dump OBJECT");
+    eprintln!(
+        "       ratsembler_6502 disasm [--origin ADDRESS] [--data START-END]... [--hints FILE]"
+    );
+    eprintln!("                       [--trace] [--entry ADDRESS]... [-o OUTPUT] FILE");
+    eprintln!(
+        "       ratsembler_6502 run [-D NAME[=VALUE]]... [-I DIR]... [--origin ADDRESS] [--binary]"
+    );
+    eprintln!("                       [--reset ADDRESS|LABEL] [--cmos] [--cycles CYCLES] FILE");
+    exit(2);
+}
+
+// Options that take a value accept it either attached (`-Iinc`) or as the
+// next argument (`-I inc`).
+fn option_value(attached: &str, args: &mut impl Iterator<Item = String>) -> String {
+    match attached {
+        "" => args.next().unwrap_or_else(|| usage()),
+        _ => attached.to_string(),
+    }
+}
+
+fn label_format(flag: &str) -> Option<LabelFormat> {
+    match flag {
+        "--labels" => Some(LabelFormat::Plain),
+        "--vice-labels" => Some(LabelFormat::Vice),
+        "--mesen-labels" => Some(LabelFormat::Mesen),
+        "--fceux-labels" => Some(LabelFormat::Fceux),
+        _ => None,
+    }
+}
+
+// -DNAME on its own defines NAME as 1, like a C preprocessor.
+fn define(assembler: &mut Assembler, definition: String) {
+    let (name, value) = match definition.split_once('=') {
+        Some((name, value)) => (name.to_string(), parse_number(value)),
+        None => (definition, Some(1)),
+    };
+    match value {
+        Some(value) => assembler.define(&name, value),
+        None => usage(),
+    }
+}
 
 fn main() -> io::Result<()> {
-    // read_to_string a file thats argument 1 on the command line
-    println!("Parsiing file: {}", std::env::args().nth(1).unwrap());
-    let unparsed = read_to_string(std::env::args().nth(1).unwrap())?;
-    let parsed = Assembler6502Parser::parse(Rule::program, &unparsed);
-    match parsed {
-        Ok(mut pairs) => {
-            let program_pairs = pairs.next().unwrap();
-            let program = Program::from_pairs(program_pairs.into_inner());
-            println!("{:?}", program);
-            println!("{:x?}", program.get_raw_section());
-            println!("{:?}", program.get_relocations());
-            println!("{:?}", program.get_symbols());
+    let mut assembler = Assembler::new();
+    let mut file_name: Option<String> = None;
+    let mut write_dependencies = false;
+    let mut output: Option<PathBuf> = None;
+    let mut listing: Option<PathBuf> = None;
+    let mut label_files: Vec<(LabelFormat, PathBuf)> = Vec::new();
+    // Object files are written straight from the program, everything else
+    // from the resolved image.
+    let mut format = "elf".to_string();
+    let mut options = FormatOptions::default();
+    let mut entry: Option<String> = None;
+    let mut init: Option<String> = None;
+    // Where anything before the first `.org` will be loaded.
+    let mut origin: u16 = 0;
+
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if_eq("link").is_some() {
+        return link_objects(args);
+    }
+    if args.next_if_eq("archive").is_some() {
+        return archive(args);
+    }
+    if args.next_if_eq("dump").is_some() {
+        let path = args.next().unwrap_or_else(|| usage());
+        if args.next().is_some() {
+            usage();
+        }
+        return dump(Path::new(&path));
+    }
+    if args.next_if_eq("disasm").is_some() {
+        return disassemble(args);
+    }
+    if args.next_if_eq("run").is_some() {
+        return run(args);
+    }
+    while let Some(arg) = args.next() {
+        if arg == "-MD" {
+            write_dependencies = true;
+        } else if arg == "--function-sections" {
+            assembler.set_function_sections(true);
+        } else if let Some(format) = label_format(&arg) {
+            let path = args.next().unwrap_or_else(|| usage());
+            label_files.push((format, path.into()));
+        } else if arg == "--origin" {
+            origin = origin_option(&mut args);
+        } else if format_option(&arg, &mut args, &mut options) {
+        } else if arg == "--entry" {
+            entry = Some(args.next().unwrap_or_else(|| usage()));
+        } else if arg == "--init" {
+            init = Some(args.next().unwrap_or_else(|| usage()));
+        } else if let Some(name) = arg.strip_prefix("-f") {
+            format = option_value(name, &mut args);
+            if format != "elf" && format != "o65" && !FORMAT_NAMES.contains(&format.as_str()) {
+                usage();
+            }
+        } else if let Some(definition) = arg.strip_prefix("-D") {
+            define(&mut assembler, option_value(definition, &mut args));
+        } else if let Some(path) = arg.strip_prefix("-l") {
+            listing = Some(option_value(path, &mut args).into());
+        } else if let Some(path) = arg.strip_prefix("-o") {
+            output = Some(option_value(path, &mut args).into());
+        } else if let Some(path) = arg.strip_prefix("-I") {
+            assembler.add_include_path(option_value(path, &mut args));
+        } else if file_name.is_none() {
+            file_name = Some(arg);
+        } else {
+            usage();
+        }
+    }
+    let file_name = file_name.unwrap_or_else(|| usage());
+
+    let program = assembler.assemble_file(Path::new(&file_name))?;
+    for diagnostic in assembler.diagnostics() {
+        eprintln!("{}", diagnostic);
+    }
+
+    let Some(program) = program else {
+        exit(1);
+    };
+
+    let input = Path::new(&file_name);
+    let output = if format == "elf" {
+        let output = output.unwrap_or_else(|| input.with_extension("o"));
+        let mut object = Vec::new();
+        write_relocatable(&mut object, &program)?;
+        fs::write(&output, object)?;
+        output
+    } else if format == "o65" {
+        let output = output.unwrap_or_else(|| input.with_extension("o65"));
+        let mut object = Vec::new();
+        write_o65(&mut object, &program).unwrap_or_else(|error| {
+            eprintln!("error: {}", error);
+            exit(1);
+        });
+        fs::write(&output, object)?;
+        output
+    } else {
+        let mut image = Image::from_program(&program, origin).unwrap_or_else(|errors| {
+            for error in errors {
+                eprintln!("error: {}", error);
+            }
+            exit(1);
+        });
+        let address = |kind: &str, text: &str| {
+            resolve_address(&program, text, origin).unwrap_or_else(|| {
+                eprintln!("error: {} '{}' is not a label or an address", kind, text);
+                exit(1);
+            })
+        };
+        image.entry = entry.map(|entry| address("entry point", &entry));
+        options.init = init.map(|init| address("init routine", &init));
+        options.ines = program.ines.clone();
+
+        let format = output_format(&format, &options).unwrap_or_else(|| usage());
+        let output = output.unwrap_or_else(|| input.with_extension(format.extension()));
+        let contents = format.write(&image).unwrap_or_else(|error| {
+            eprintln!("error: {}", error);
+            exit(1);
+        });
+        fs::write(&output, contents)?;
+        if let Some((path, contents)) = format.sidecar(&image, &output) {
+            fs::write(path, contents)?;
+        }
+        output
+    };
+
+    let labels = resolve_labels(&program.get_symbols(), |location| {
+        let bank = program
+            .section_of(location)
+            .and_then(|section| section.bank);
+        (program.address_of(location, origin), bank)
+    });
+    // Mesen labels are offsets into PRG ROM, which depend on how big it is
+    let prg_banks = match Image::from_program(&program, origin) {
+        Ok(image) => program.ines.bank_counts(&image).0,
+        Err(_) => 1,
+    };
+    for (format, path) in label_files {
+        let mut contents = Vec::new();
+        write_labels(&mut contents, format, &labels, prg_banks)?;
+        fs::write(path, contents)?;
+    }
+
+    if let Some(listing) = listing {
+        let mut contents = Vec::new();
+        // Flat images have every reference to the program's own labels
+        // filled in, objects leave them to the linker.
+        let resolve = format != "elf" && format != "o65";
+        assembler
+            .listing()
+            .write(&mut contents, &program, origin, resolve)?;
+        fs::write(listing, contents)?;
+    }
+
+    if write_dependencies {
+        write_dependency_file(
+            &input.with_extension("d"),
+            &output,
+            assembler.dependencies(),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn origin_option(args: &mut impl Iterator<Item = String>) -> u16 {
+    args.next()
+        .and_then(|value| parse_address(&value))
+        .unwrap_or_else(|| usage())
+}
+
+// Handles the options for the flat output formats, returning false for
+// anything else.
+fn format_option(
+    arg: &str,
+    args: &mut impl Iterator<Item = String>,
+    options: &mut FormatOptions,
+) -> bool {
+    let mut number = || args.next().and_then(|value| parse_number(&value));
+    match arg {
+        "--fill" => {
+            options.fill = number()
+                .and_then(|value| u8::try_from(value).ok())
+                .unwrap_or_else(|| usage());
+        }
+        "--size" => {
+            options.size = number()
+                .and_then(|value| usize::try_from(value).ok())
+                .or_else(|| usage());
+        }
+        "--record-length" => {
+            options.record_length = number()
+                .and_then(|value| usize::try_from(value).ok())
+                .filter(|length| (1..=0xFC).contains(length))
+                .or_else(|| usage());
+        }
+        _ => return false,
+    }
+    true
+}
+
+// Links objects into an executable ELF file or one of the flat formats.
+fn link_objects(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    // Each input and whether it's an archive to take every member of
+    let mut paths: Vec<(String, bool)> = Vec::new();
+    let mut whole_archive = false;
+    let mut output: Option<PathBuf> = None;
+    let mut label_files: Vec<(LabelFormat, PathBuf)> = Vec::new();
+    let mut format = "elf".to_string();
+    let mut options = FormatOptions::default();
+    let mut entry: Option<String> = None;
+    let mut init: Option<String> = None;
+    let mut origin: u16 = 0;
+    let mut config: Option<PathBuf> = None;
+    let mut map: Option<PathBuf> = None;
+    let mut gc = false;
+    let mut trampolines: Option<String> = None;
+    while let Some(arg) = args.next() {
+        if let Some(format) = label_format(&arg) {
+            let path = args.next().unwrap_or_else(|| usage());
+            label_files.push((format, path.into()));
+        } else if arg == "--origin" {
+            origin = origin_option(&mut args);
+        } else if format_option(&arg, &mut args, &mut options) {
+        } else if arg == "--entry" {
+            entry = Some(args.next().unwrap_or_else(|| usage()));
+        } else if arg == "--init" {
+            init = Some(args.next().unwrap_or_else(|| usage()));
+        } else if arg == "--gc-sections" {
+            gc = true;
+        } else if arg == "--trampolines" {
+            trampolines = Some(args.next().unwrap_or_else(|| usage()));
+        } else if arg == "--whole-archive" {
+            whole_archive = true;
+        } else if arg == "--no-whole-archive" {
+            whole_archive = false;
+        } else if let Some(name) = arg.strip_prefix("-f") {
+            format = option_value(name, &mut args);
+            if format != "elf" && !FORMAT_NAMES.contains(&format.as_str()) {
+                usage();
+            }
+        } else if let Some(path) = arg.strip_prefix("-o") {
+            output = Some(option_value(path, &mut args).into());
+        } else if let Some(path) = arg.strip_prefix("-C") {
+            config = Some(option_value(path, &mut args).into());
+        } else if let Some(path) = arg.strip_prefix("-m") {
+            map = Some(option_value(path, &mut args).into());
+        } else if arg.starts_with('-') {
+            usage();
+        } else {
+            paths.push((arg, whole_archive));
+        }
+    }
+    if paths.is_empty() {
+        usage();
+    }
+
+    let fail = |error: String| -> ! {
+        eprintln!("error: {}", error);
+        exit(1);
+    };
+    // Archive members only get linked in if something needs them, unless
+    // they're under --whole-archive.
+    let mut inputs = Vec::new();
+    let mut archives = Vec::new();
+    for (path, whole) in &paths {
+        let bytes = fs::read(path)?;
+        if !bytes.starts_with(&ARCHIVE_MAGIC) {
+            inputs.push(read_input(path, &bytes).unwrap_or_else(|error| fail(error)));
+            continue;
+        }
+        let archive =
+            read_archive(&bytes).unwrap_or_else(|error| fail(format!("{}: {}", path, error)));
+        match whole {
+            true => {
+                for member in &archive.members {
+                    let name = format!("{}({})", path, member.name);
+                    inputs
+                        .push(read_input(&name, &member.bytes).unwrap_or_else(|error| fail(error)));
+                }
+            }
+            false => archives.push((path.clone(), archive)),
+        }
+    }
+    pull_members(&mut inputs, &archives).unwrap_or_else(|error| fail(error));
+    // Everything is placed from --origin unless there's a config.
+    let layout = match config {
+        Some(path) => {
+            let text = fs::read_to_string(&path)?;
+            Layout::Config(parse_config(&text).unwrap_or_else(|error| {
+                eprintln!("error: {}: {}", path.display(), error);
+                exit(1);
+            }))
+        }
+        None => Layout::Origin(origin),
+    };
+    // Everything that can't be reached from the entry point, the init
+    // routine, the vectors or a section the config says to keep goes.
+    let mut removed = Vec::new();
+    if gc {
+        // Numbers are kept by whatever gets laid out there
+        let roots: Vec<Root> = entry
+            .iter()
+            .chain(&init)
+            .map(|root| match parse_address(root) {
+                Some(address) => Root::Address(address),
+                None => Root::Symbol(root.clone()),
+            })
+            .collect();
+        let keep: Vec<String> = match &layout {
+            Layout::Config(config) => config
+                .segments
+                .iter()
+                .filter(|rule| rule.keep)
+                .map(|rule| rule.name.clone())
+                .collect(),
+            Layout::Origin(_) => Vec::new(),
+        };
+        removed = gc_sections(&mut inputs, &layout, &roots, &keep).unwrap_or_else(|error| {
+            eprintln!("error: --gc-sections: {}", error);
+            exit(1);
+        });
+        for section in &removed {
+            eprintln!(
+                "removing unused section '{}' in {} ({} bytes)",
+                section.section, section.object, section.size
+            );
+        }
+    }
+    // Calls into other banks go through the bank switching routine given.
+    if let Some(routine) = &trampolines {
+        add_trampolines(&mut inputs, &layout, routine);
+    }
+    // Long branches that turn out to be close enough are made plain ones
+    for branch in relax_branches(&mut inputs, &layout) {
+        eprintln!(
+            "{}: branch to '{}' at ${:04X} is out of range, so it uses a JMP",
+            branch.object, branch.target, branch.address
+        );
+    }
+    let mut linked = link(&inputs, &layout).unwrap_or_else(|errors| {
+        for error in errors {
+            eprintln!("error: {}", error);
+        }
+        exit(1);
+    });
+    linked.removed = removed;
+    let address = |kind: &str, text: &str| {
+        linked
+            .address_of(text)
+            .or_else(|| parse_address(text))
+            .unwrap_or_else(|| {
+                eprintln!("error: {} '{}' is not a symbol or an address", kind, text);
+                exit(1);
+            })
+    };
+    let entry = entry.map(|entry| address("entry point", &entry));
+
+    if format == "elf" {
+        let mut contents = Vec::new();
+        write_executable(&mut contents, &linked, entry)?;
+        fs::write(output.unwrap_or_else(|| "a.out".into()), contents)?;
+    } else {
+        options.init = init.map(|init| address("init routine", &init));
+        let format = output_format(&format, &options).unwrap_or_else(|| usage());
+        let output = output.unwrap_or_else(|| Path::new("a").with_extension(format.extension()));
+        // Memory regions can go in files of their own, written in the same
+        // format as the output.
+        for (file, mut image) in linked.images() {
+            let path = match file == OUTPUT_FILE {
+                true => output.clone(),
+                false => PathBuf::from(file),
+            };
+            image.entry = entry;
+            let contents = format.write(&image).unwrap_or_else(|error| {
+                eprintln!("error: {}: {}", path.display(), error);
+                exit(1);
+            });
+            fs::write(&path, contents)?;
+            if let Some((path, contents)) = format.sidecar(&image, &path) {
+                fs::write(path, contents)?;
+            }
+        }
+    }
+
+    let labels = linked.labels();
+    let (prg_banks, _) = options.ines.bank_counts(&linked.image());
+    for (format, path) in label_files {
+        let mut contents = Vec::new();
+        write_labels(&mut contents, format, &labels, prg_banks)?;
+        fs::write(path, contents)?;
+    }
+    if let Some(path) = map {
+        let mut contents = Vec::new();
+        write_map(&mut contents, &linked)?;
+        fs::write(path, contents)?;
+    }
+    Ok(())
+}
+
+// Makes an archive out of objects, or lists what's in one.
+fn archive(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let list = match args.next() {
+        Some(arg) if arg == "-t" => true,
+        Some(path) => {
+            let mut members = Vec::new();
+            for object in args {
+                let name = Path::new(&object)
+                    .file_name()
+                    .map_or(object.clone(), |name| name.to_string_lossy().to_string());
+                members.push(Member {
+                    name,
+                    bytes: fs::read(&object)?,
+                });
+            }
+            if members.is_empty() {
+                usage();
+            }
+            let mut contents = Vec::new();
+            if let Err(error) = write_archive(&mut contents, &members) {
+                eprintln!("error: {}: {}", path, error);
+                exit(1);
+            }
+            return fs::write(path, contents);
+        }
+        None => usage(),
+    };
+    let (Some(path), None, true) = (args.next(), args.next(), list) else {
+        usage();
+    };
+    let archive = read_archive(&fs::read(&path)?).unwrap_or_else(|error| {
+        eprintln!("error: {}: {}", path, error);
+        exit(1);
+    });
+    let mut stdout = io::stdout();
+    for (index, member) in archive.members.iter().enumerate() {
+        writeln!(stdout, "{}", member.name)?;
+        for (symbol, _) in archive
+            .symbols
+            .iter()
+            .filter(|(_, member)| *member == index)
+        {
+            writeln!(stdout, "    {}", symbol)?;
+        }
+    }
+    Ok(())
+}
+
+// Prints what's in an object file written by the assembler.
+fn dump(path: &Path) -> io::Result<()> {
+    let bytes = fs::read(path)?;
+    let file = ElfFile::parse(&bytes).unwrap_or_else(|error| {
+        eprintln!("error: {}: {}", path.display(), error);
+        exit(1);
+    });
+    let mut contents = Vec::new();
+    write_dump(&mut contents, &file)?;
+    io::stdout().write_all(&contents)
+}
+
+// Turns an object, executable or flat binary back into source. Binaries are
+// loaded at `--origin`, and `--data` marks a range of addresses in them as
+// data rather than code, as does a hint file. With `--trace`, or any entry
+// points, only the code that can be reached is disassembled as code.
+fn disassemble(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut origin = 0;
+    let mut hints = Vec::new();
+    let mut entries = Vec::new();
+    let mut tracing = false;
+    let mut output: Option<PathBuf> = None;
+    let mut path: Option<String> = None;
+    while let Some(arg) = args.next() {
+        if arg == "--origin" {
+            origin = origin_option(&mut args);
+        } else if arg == "--data" {
+            let range = args.next().unwrap_or_else(|| usage());
+            let (start, end) = range.split_once('-').unwrap_or_else(|| usage());
+            let address = |text: &str| parse_address(text).unwrap_or_else(|| usage());
+            hints.push(Hint::Range(address(start)..=address(end), Kind::Data));
+        } else if arg == "--hints" {
+            let file = args.next().unwrap_or_else(|| usage());
+            let parsed = parse_hints(&fs::read_to_string(&file)?).unwrap_or_else(|error| {
+                eprintln!("error: {}: {}", file, error);
+                exit(1);
+            });
+            hints.extend(parsed);
+        } else if arg == "--entry" {
+            entries.push(origin_option(&mut args));
+        } else if arg == "--trace" {
+            tracing = true;
+        } else if let Some(file) = arg.strip_prefix("-o") {
+            output = Some(option_value(file, &mut args).into());
+        } else if path.is_none() {
+            path = Some(arg);
+        } else {
+            usage();
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let bytes = fs::read(&path)?;
+    let disassembly = if bytes.starts_with(b"\x7FELF") {
+        ElfFile::parse(&bytes).and_then(|file| Disassembly::from_elf(&file))
+    } else if bytes.starts_with(&MAGIC) {
+        read_o65(&bytes).map(|object| Disassembly::from_object(&object))
+    } else {
+        Ok(Disassembly::from_binary(&bytes, origin))
+    };
+    let mut disassembly = disassembly.unwrap_or_else(|error| {
+        eprintln!("error: {}: {}", path, error);
+        exit(1);
+    });
+    for hint in hints {
+        match hint {
+            Hint::Range(range, kind) => disassembly.mark(&range, kind),
+            Hint::Entry(address) => entries.push(address),
+        }
+    }
+    if tracing || !entries.is_empty() {
+        trace(&mut disassembly, &entries);
+    }
+    let mut source = Vec::new();
+    write_disassembly(&mut source, &disassembly)?;
+    match output {
+        Some(output) => fs::write(output, source),
+        None => io::stdout().write_all(&source),
+    }
+}
+
+// Assembles a source, or loads an executable or flat binary, and runs it
+// with stdin and stdout wired up to the machine's I/O addresses. The exit
+// code is whatever the program writes to EXIT. The reset vector is
+// `--reset` if it's given, or left alone if the program sets it, or
+// otherwise the entry point or the start of the program.
+fn run(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut assembler = Assembler::new();
+    let mut origin: u16 = 0;
+    let mut binary = false;
+    let mut reset: Option<String> = None;
+    let mut variant = Variant::Nmos;
+    let mut cycles: Option<u64> = None;
+    let mut path: Option<String> = None;
+    while let Some(arg) = args.next() {
+        if arg == "--origin" {
+            origin = origin_option(&mut args);
+        } else if arg == "--binary" {
+            binary = true;
+        } else if arg == "--reset" {
+            reset = Some(args.next().unwrap_or_else(|| usage()));
+        } else if arg == "--cmos" {
+            variant = Variant::Cmos;
+        } else if arg == "--cycles" {
+            cycles = Some(
+                args.next()
+                    .and_then(|value| parse_number(&value))
+                    .and_then(|value| u64::try_from(value).ok())
+                    .unwrap_or_else(|| usage()),
+            );
+        } else if let Some(definition) = arg.strip_prefix("-D") {
+            define(&mut assembler, option_value(definition, &mut args));
+        } else if let Some(directory) = arg.strip_prefix("-I") {
+            assembler.add_include_path(option_value(directory, &mut args));
+        } else if path.is_none() {
+            path = Some(arg);
+        } else {
+            usage();
         }
-        Err(e) => {
-            eprintln!("Error parsing file: {}", e);
+    }
+    let path = path.unwrap_or_else(|| usage());
+    let fail = |error: String| -> ! {
+        eprintln!("error: {}: {}", path, error);
+        exit(1);
+    };
+
+    let bytes = fs::read(&path)?;
+    let (image, reset) = if bytes.starts_with(b"\x7FELF") {
+        let file = ElfFile::parse(&bytes).unwrap_or_else(|error| fail(error));
+        let image = file.to_image().unwrap_or_else(|error| fail(error));
+        let symbols = file.symbols().unwrap_or_else(|error| fail(error));
+        let reset = reset.map(|reset| {
+            symbols
+                .iter()
+                .find(|symbol| symbol.name == reset)
+                .map(|symbol| symbol.symbol.st_value as u16)
+                .or_else(|| parse_address(&reset))
+                .unwrap_or_else(|| fail(format!("'{}' is not a symbol or an address", reset)))
+        });
+        (image, reset)
+    } else if binary {
+        let mut image = Image::default();
+        image.segments.push(Segment {
+            address: origin,
+            bytes,
+            bank: None,
+        });
+        let reset = reset.map(|reset| parse_address(&reset).unwrap_or_else(|| usage()));
+        (image, reset)
+    } else {
+        let program = assembler.assemble_file(Path::new(&path))?;
+        for diagnostic in assembler.diagnostics() {
+            eprintln!("{}", diagnostic);
         }
+        let Some(program) = program else {
+            exit(1);
+        };
+        let image = Image::from_program(&program, origin).unwrap_or_else(|errors| {
+            for error in errors {
+                eprintln!("error: {}", error);
+            }
+            exit(1);
+        });
+        let reset = reset.map(|reset| {
+            resolve_address(&program, &reset, origin)
+                .unwrap_or_else(|| fail(format!("'{}' is not a label or an address", reset)))
+        });
+        (image, reset)
+    };
+
+    let mut machine = Machine::new(io::stdin().lock(), io::stdout().lock());
+    machine.load(&image).unwrap_or_else(|error| fail(error));
+    let has_vector = image.segments.iter().any(|segment| {
+        segment.address as usize <= RESET_VECTOR as usize
+            && segment.end() >= RESET_VECTOR as usize + 2
+    });
+    let start = image
+        .segments
+        .first()
+        .map_or(origin, |segment| segment.address);
+    match (reset, has_vector) {
+        (Some(reset), _) => machine.set_reset_vector(reset),
+        (None, true) => {}
+        (None, false) => machine.set_reset_vector(image.entry.unwrap_or(start)),
+    }
+    let mut cpu = Cpu::new(variant);
+    cpu.reset(&mut machine);
+    match machine.run(&mut cpu, cycles) {
+        Stop::Exit(code) => exit(code as i32),
+        Stop::Stuck(address) => fail(format!(
+            "stuck at ${:04X} after {} cycles",
+            address, cpu.cycles
+        )),
+        Stop::OutOfCycles => fail(format!(
+            "still running at ${:04X} after {} cycles",
+            cpu.pc, cpu.cycles
+        )),
+        Stop::Error(error) => fail(error),
     }
+}
 
-    return Ok(());
+// Addresses like the entry point can be given as a number or as a label in
+// the program.
+fn resolve_address(program: &Program, text: &str, origin: u16) -> Option<u16> {
+    if let Some(location) = program.labels.get(text) {
+        return Some(program.address_of(*location, origin));
+    }
+    parse_address(text)
+}
+
+// Writes a Makefile fragment so that `target` is rebuilt whenever any file
+// that went into it changes.
+fn write_dependency_file(path: &Path, target: &Path, dependencies: &[PathBuf]) -> io::Result<()> {
+    let escape = |path: &Path| path.to_string_lossy().replace(' ', "\\ ");
+    let mut contents = format!("{}:", escape(target));
+    for dependency in dependencies {
+        contents.push_str(" \\\n  ");
+        contents.push_str(&escape(dependency));
+    }
+    contents.push('\n');
+    fs::write(path, contents)
 }
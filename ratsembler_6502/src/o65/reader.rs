@@ -0,0 +1,405 @@
+use super::writer::MAGIC;
+use super::writer::MODE_32BIT;
+use super::writer::MODE_65816;
+use super::writer::MODE_PAGED;
+use super::writer::RELOCATION_HIGH;
+use super::writer::RELOCATION_LOW;
+use super::writer::RELOCATION_WORD;
+use super::writer::SEGMENT_ABSOLUTE;
+use super::writer::SEGMENT_NAMES;
+use super::writer::SEGMENT_UNDEFINED;
+
+use crate::elf::relocatable::Object;
+use crate::elf::relocatable::Relocation;
+use crate::elf::relocatable::Section;
+use crate::elf::relocatable::Symbol;
+
+use std::collections::HashMap;
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    // Sizes and addresses are 32 bits wide rather than 16
+    wide: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + count)
+            .ok_or_else(|| format!("o65 file ends early, at byte {}", self.bytes.len()))?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    // A size or address, whichever width the file uses.
+    fn word(&mut self) -> Result<usize, String> {
+        if self.wide {
+            let bytes = self.take(4)?;
+            Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+        } else {
+            Ok(self.u16()? as usize)
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.bytes[self.position..]
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or("o65 file ends in the middle of a name")?;
+        let name = String::from_utf8_lossy(self.take(length)?).into_owned();
+        self.position += 1;
+        Ok(name)
+    }
+}
+
+// A segment from the header, and where it went in the raw section.
+struct Segment {
+    id: u8,
+    name: &'static str,
+    base: usize,
+    size: usize,
+    start: usize,
+}
+
+impl Segment {
+    // The symbol segment relative relocations are made against.
+    fn symbol(&self) -> String {
+        format!("@{}", self.name)
+    }
+}
+
+fn segment(segments: &[Segment], id: u8) -> Result<&Segment, String> {
+    segments
+        .iter()
+        .find(|segment| segment.id == id)
+        .ok_or_else(|| format!("o65 file refers to unknown segment {}", id))
+}
+
+// Reads one of the two relocation tables. Segment relative relocations are
+// turned into ones against a symbol at the start of the segment's section,
+// with the value that was in the file less the segment's base as the addend.
+fn read_relocations(
+    reader: &mut Reader,
+    raw_section: &[u8],
+    segments: &[Segment],
+    location: &Segment,
+    undefined: &[String],
+    paged: bool,
+) -> Result<Vec<Relocation>, String> {
+    let mut relocations = Vec::new();
+    let mut position = location.base as i64 - 1;
+    loop {
+        match reader.byte()? {
+            0 => return Ok(relocations),
+            255 => {
+                position += 254;
+                continue;
+            }
+            distance => position += distance as i64,
+        }
+        let address = position - location.base as i64;
+        if !(0..location.size as i64).contains(&address) {
+            return Err(format!(
+                "o65 relocation at ${:X} is outside the {} segment",
+                position, location.name
+            ));
+        }
+        let offset = location.start + address as usize;
+
+        let kind = reader.byte()?;
+        let (symbol, base) = match kind & 0x1F {
+            SEGMENT_UNDEFINED => {
+                let index = reader.word()?;
+                let name = undefined
+                    .get(index)
+                    .ok_or_else(|| format!("o65 file has no undefined reference {}", index))?;
+                (name.clone(), 0)
+            }
+            SEGMENT_ABSOLUTE => {
+                return Err("o65 file relocates against the absolute segment".to_string())
+            }
+            id => {
+                let segment = segment(segments, id)?;
+                (segment.symbol(), segment.base)
+            }
+        };
+        let stored = |size: usize| -> Result<i64, String> {
+            raw_section
+                .get(offset..offset + size)
+                .map(|bytes| match bytes {
+                    [low, high] => u16::from_le_bytes([*low, *high]) as i64,
+                    _ => bytes[0] as i64,
+                })
+                .ok_or_else(|| format!("o65 relocation at ${:X} runs off its segment", position))
+        };
+        let addend = |value: i64| (value - base as i64) as i32;
+        relocations.push(match kind & 0xE0 {
+            RELOCATION_WORD => Relocation::Long(symbol, offset as u32, addend(stored(2)?)),
+            RELOCATION_LOW => Relocation::Low(symbol, offset as u32, addend(stored(1)?) & 0xFF),
+            RELOCATION_HIGH => {
+                // Paged files are relocated a page at a time, so there's no
+                // low byte to carry from.
+                let low = if paged { 0 } else { reader.byte()? as i64 };
+                let value = stored(1)? << 8 | low;
+                Relocation::High(symbol, offset as u32, addend(value))
+            }
+            other => {
+                return Err(format!(
+                    "o65 relocation type ${:02X} isn't supported",
+                    other
+                ))
+            }
+        });
+    }
+}
+
+// Reads an o65 object file into the shape the assembler produces. Each
+// segment with something in it becomes a section of the same name, with
+// bss and zero page reservations filled with zeroes.
+pub fn read_o65(bytes: &[u8]) -> Result<Object, String> {
+    if !bytes.starts_with(&MAGIC) {
+        return Err("not an o65 file".to_string());
+    }
+    let mut reader = Reader {
+        bytes,
+        position: MAGIC.len(),
+        wide: false,
+    };
+    let version = reader.byte()?;
+    if version != 0 {
+        return Err(format!("o65 version {} isn't supported", version));
+    }
+    let mode = reader.u16()?;
+    if mode & MODE_65816 != 0 {
+        return Err("65816 o65 files aren't supported".to_string());
+    }
+    reader.wide = mode & MODE_32BIT != 0;
+    let paged = mode & MODE_PAGED != 0;
+
+    let mut segments = Vec::new();
+    for (id, name) in SEGMENT_NAMES {
+        let base = reader.word()?;
+        let size = reader.word()?;
+        segments.push(Segment {
+            id,
+            name,
+            base,
+            size,
+            start: 0,
+        });
+    }
+    // Stack size, which only matters to a loader
+    reader.word()?;
+
+    // Header options are a length that counts itself, then the contents.
+    loop {
+        match reader.byte()? {
+            0 => break,
+            1 => return Err("o65 header option has no type".to_string()),
+            length => reader.take(length as usize - 1).map(|_| ())?,
+        }
+    }
+
+    let mut object = Object::default();
+    for segment in &mut segments {
+        segment.start = object.raw_section.len();
+        match segment.name {
+            "text" | "data" => object.raw_section.extend(reader.take(segment.size)?),
+            _ => object.raw_section.resize(segment.start + segment.size, 0),
+        }
+        if segment.size > 0 {
+            object.sections.push(Section {
+                name: segment.name.to_string(),
+                start: segment.start,
+                size: segment.size,
+                bank: None,
+            });
+        }
+    }
+
+    let undefined = (0..reader.word()?)
+        .map(|_| reader.string())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for id in [segments[0].id, segments[1].id] {
+        let location = segment(&segments, id)?;
+        let relocations = read_relocations(
+            &mut reader,
+            &object.raw_section,
+            &segments,
+            location,
+            &undefined,
+            paged,
+        )?;
+        object.relocations.extend(relocations);
+    }
+
+    // Segments something is relocated against get a symbol at their start.
+    let mut symbols = HashMap::new();
+    for segment in &segments {
+        let name = segment.symbol();
+        if object
+            .relocations
+            .iter()
+            .any(|relocation| relocation.symbol() == name)
+        {
+            symbols.insert(name, Symbol::Location(segment.start));
+        }
+    }
+
+    for _ in 0..reader.word()? {
+        let name = reader.string()?;
+        let id = reader.byte()?;
+        let value = reader.word()?;
+        let symbol = match id {
+            SEGMENT_ABSOLUTE => Symbol::LongValue(value as u16),
+            id => {
+                let segment = segment(&segments, id)?;
+                let address = value.checked_sub(segment.base).ok_or_else(|| {
+                    format!(
+                        "'{}' at ${:X} is before the start of the {} segment",
+                        name, value, segment.name
+                    )
+                })?;
+                Symbol::Location(segment.start + address)
+            }
+        };
+        symbols.insert(name, symbol);
+    }
+    object.symbols = symbols;
+
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lang::assembler::Assembler;
+    use crate::lang::source::SourceLine;
+    use crate::o65::writer::write_o65;
+
+    // Roughly what xa makes of a small program assembled at $1000
+    fn xa_file() -> Vec<u8> {
+        let mut file = vec![
+            0x01, 0x00, b'o', b'6', b'5', 0x00, 0x00, 0x00, // header
+            0x00, 0x10, 0x07, 0x00, // text
+            0x00, 0x20, 0x02, 0x00, // data
+            0x00, 0x30, 0x04, 0x00, // bss
+            0x80, 0x00, 0x02, 0x00, // zero page
+            0x00, 0x00, // stack
+            0x06, 0x00, b'a', b'.', b's', 0x00, 0x00, // file name option
+            0xA9, 0x12, 0xA2, 0x10, 0x20, 0x00, 0x00, // text
+            0x05, 0x10, // data
+        ];
+        file.extend(b"\x01\x00print\0");
+        file.extend([0x02, 0x22, 0x02, 0x42, 0x12, 0x02, 0x80, 0x00, 0x00, 0x00]);
+        file.extend([0x01, 0x82, 0x00]);
+        file.extend(b"\x03\x00main\0\x02\x00\x10chrout\0\x01\xD2\xFFbuffer\0\x04\x02\x30");
+        file
+    }
+
+    #[test]
+    fn test_read_o65() {
+        let object = read_o65(&xa_file()).unwrap();
+
+        let mut raw_section = vec![0xA9, 0x12, 0xA2, 0x10, 0x20, 0x00, 0x00, 0x05, 0x10];
+        raw_section.extend([0; 6]);
+        assert_eq!(object.raw_section, raw_section);
+        let sections: Vec<_> = object
+            .sections
+            .iter()
+            .map(|section| (section.name.as_str(), section.start, section.size))
+            .collect();
+        assert_eq!(
+            sections,
+            [
+                ("text", 0, 7),
+                ("data", 7, 2),
+                ("bss", 9, 4),
+                ("zeropage", 13, 2)
+            ]
+        );
+        assert_eq!(
+            object.relocations,
+            [
+                Relocation::Low("@text".to_string(), 1, 0x12),
+                Relocation::High("@text".to_string(), 3, 0x12),
+                Relocation::Long("print".to_string(), 5, 0),
+                Relocation::Long("@text".to_string(), 7, 5),
+            ]
+        );
+        assert_eq!(
+            object.symbols,
+            HashMap::from([
+                ("@text".to_string(), Symbol::Location(0)),
+                ("main".to_string(), Symbol::Location(0)),
+                ("chrout".to_string(), Symbol::LongValue(0xFFD2)),
+                ("buffer".to_string(), Symbol::Location(11)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_o65_round_trip() {
+        let mut file = Vec::new();
+        write_o65(&mut file, &read_o65(&xa_file()).unwrap()).unwrap();
+        // Segments all start at 0 once written back out, so only the bytes
+        // that get relocated change.
+        let object = read_o65(&file).unwrap();
+        let original = read_o65(&xa_file()).unwrap();
+        assert_eq!(object.raw_section[3], 0x00);
+        assert_eq!(object.raw_section[8], 0x00);
+        assert_eq!(object.relocations, original.relocations);
+        assert_eq!(object.symbols["buffer"], Symbol::Location(11));
+        assert_eq!(object.sections, original.sections);
+
+        let mut again = Vec::new();
+        write_o65(&mut again, &object).unwrap();
+        assert_eq!(again, file);
+    }
+
+    #[test]
+    fn test_address_bytes_round_trip() {
+        let source =
+            "start: LDA #<table\n  LDX #>(table+1)\n  .byte <print, >(print+$100)\ntable: RTS\n";
+        let program = Assembler::new()
+            .assemble(&SourceLine::from_source("a.s", source))
+            .unwrap();
+        let mut file = Vec::new();
+        write_o65(&mut file, &program).unwrap();
+        let object = read_o65(&file).unwrap();
+        // Labels in the file come back as offsets into their segment
+        assert_eq!(
+            object.relocations,
+            [
+                Relocation::Low("@text".to_string(), 1, 6),
+                Relocation::High("@text".to_string(), 3, 7),
+                Relocation::Low("print".to_string(), 4, 0),
+                Relocation::High("print".to_string(), 5, 0x100),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_o65() {
+        assert!(read_o65(b"\x7FELF").unwrap_err().contains("not an o65"));
+        let mut file = xa_file();
+        file[7] = 0x80;
+        assert!(read_o65(&file).unwrap_err().contains("65816"));
+        assert!(read_o65(&xa_file()[..40])
+            .unwrap_err()
+            .contains("ends early"));
+    }
+}
@@ -0,0 +1,435 @@
+use crate::elf::relocatable::is_local_symbol;
+use crate::elf::relocatable::section_index;
+use crate::elf::relocatable::Relocatable;
+use crate::elf::relocatable::Relocation;
+use crate::elf::relocatable::Section;
+use crate::elf::relocatable::Symbol;
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+// Non-C64 marker, then "o65"
+pub const MAGIC: [u8; 5] = [0x01, 0x00, b'o', b'6', b'5'];
+
+pub const MODE_65816: u16 = 0x8000;
+pub const MODE_PAGED: u16 = 0x4000;
+pub const MODE_32BIT: u16 = 0x2000;
+pub const MODE_OBJECT: u16 = 0x1000;
+
+pub const SEGMENT_UNDEFINED: u8 = 0;
+pub const SEGMENT_ABSOLUTE: u8 = 1;
+pub const SEGMENT_TEXT: u8 = 2;
+pub const SEGMENT_DATA: u8 = 3;
+pub const SEGMENT_BSS: u8 = 4;
+pub const SEGMENT_ZEROPAGE: u8 = 5;
+
+pub const RELOCATION_WORD: u8 = 0x80;
+pub const RELOCATION_HIGH: u8 = 0x40;
+pub const RELOCATION_LOW: u8 = 0x20;
+pub const RELOCATION_SEGADR: u8 = 0xC0;
+pub const RELOCATION_SEG: u8 = 0xA0;
+
+pub const OPTION_ASSEMBLER: u8 = 2;
+
+// The sections o65 has room for, by the segment they go in.
+pub const SEGMENT_NAMES: [(u8, &str); 4] = [
+    (SEGMENT_TEXT, "text"),
+    (SEGMENT_DATA, "data"),
+    (SEGMENT_BSS, "bss"),
+    (SEGMENT_ZEROPAGE, "zeropage"),
+];
+
+pub fn segment_id(section: &str) -> Option<u8> {
+    match section.to_lowercase().as_str() {
+        "text" | "code" => Some(SEGMENT_TEXT),
+        "data" | "rodata" => Some(SEGMENT_DATA),
+        "bss" => Some(SEGMENT_BSS),
+        "zeropage" | "zp" => Some(SEGMENT_ZEROPAGE),
+        _ => None,
+    }
+}
+
+fn segment_name(id: u8) -> &'static str {
+    SEGMENT_NAMES
+        .iter()
+        .find(|(segment, _)| *segment == id)
+        .map_or("absolute", |(_, name)| name)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend(value.to_le_bytes());
+}
+
+// Where each segment's section is. Every segment starts at 0, so offsets
+// into a section are also addresses in its segment.
+struct Segments<'a> {
+    sections: &'a [Section],
+    by_id: HashMap<u8, &'a Section>,
+}
+
+impl Segments<'_> {
+    fn new(sections: &[Section]) -> io::Result<Segments<'_>> {
+        let mut by_id = HashMap::new();
+        for section in sections.iter().filter(|section| section.size > 0) {
+            let Some(id) = segment_id(&section.name) else {
+                return Err(invalid(format!(
+                    "o65 only has text, data, bss and zeropage segments, so can't hold section '{}'",
+                    section.name
+                )));
+            };
+            if section.bank.is_some() {
+                return Err(invalid(format!(
+                    "section '{}' is banked, which o65 can't represent",
+                    section.name
+                )));
+            }
+            if by_id.insert(id, section).is_some() {
+                return Err(invalid(format!(
+                    "more than one section goes in the o65 {} segment",
+                    segment_name(id)
+                )));
+            }
+        }
+        Ok(Segments { sections, by_id })
+    }
+
+    fn len(&self, id: u8) -> usize {
+        self.by_id.get(&id).map_or(0, |section| section.size)
+    }
+
+    fn bytes<'b>(&self, id: u8, raw_section: &'b [u8]) -> &'b [u8] {
+        match self.by_id.get(&id) {
+            Some(section) => &raw_section[section.start..section.end()],
+            None => &[],
+        }
+    }
+
+    // The segment something at an offset into the raw section is in, and
+    // the address in that segment.
+    fn locate(&self, offset: usize) -> io::Result<(u8, usize)> {
+        let section = section_index(self.sections, offset).map(|index| &self.sections[index]);
+        match section.map(|section| (segment_id(&section.name), section)) {
+            Some((Some(id), section)) => Ok((id, offset - section.start)),
+            _ => Err(invalid(format!(
+                "offset {} isn't in a section o65 can hold",
+                offset
+            ))),
+        }
+    }
+}
+
+// The offset bytes in front of a relocation entry. They count on from the
+// last entry, starting just before the segment, and 255 skips ahead by 254
+// without an entry.
+fn push_position(table: &mut Vec<u8>, last: &mut i64, position: usize) {
+    let mut distance = position as i64 - *last;
+    while distance > 254 {
+        table.push(255);
+        distance -= 254;
+    }
+    table.push(distance as u8);
+    *last = position as i64;
+}
+
+// Writes an o65 object file, with each section in the segment of the same
+// name. Relocations against labels become segment relative entries, and
+// against anything else entries for the undefined reference list. Every
+// global symbol is exported.
+pub fn write_o65(out: &mut impl Write, object: &dyn Relocatable) -> io::Result<()> {
+    let mut raw_section = object.get_raw_section();
+    let sections = object.get_sections();
+    let symbols = object.get_symbols();
+    let mut relocations = object.get_relocations();
+    relocations.sort_by_key(|relocation| relocation.offset());
+    let segments = Segments::new(&sections)?;
+
+    // bss and zero page segments only have a size in the file.
+    for id in [SEGMENT_BSS, SEGMENT_ZEROPAGE] {
+        let Some(section) = segments.by_id.get(&id) else {
+            continue;
+        };
+        let relocated = relocations.iter().any(|relocation| {
+            (section.start..section.end()).contains(&(relocation.offset() as usize))
+        });
+        if relocated
+            || segments
+                .bytes(id, &raw_section)
+                .iter()
+                .any(|byte| *byte != 0)
+        {
+            return Err(invalid(format!(
+                "section '{}' can only reserve space, as it goes in the o65 {} segment",
+                section.name,
+                segment_name(id)
+            )));
+        }
+    }
+
+    let mut undefined: Vec<&str> = Vec::new();
+    let mut tables: HashMap<u8, (Vec<u8>, i64)> = HashMap::from([
+        (SEGMENT_TEXT, (Vec::new(), -1)),
+        (SEGMENT_DATA, (Vec::new(), -1)),
+    ]);
+    for relocation in &relocations {
+        let offset = relocation.offset() as usize;
+        let (location_id, position) = segments.locate(offset)?;
+        let name = relocation.symbol();
+        let (target_id, value) = match symbols.get(name) {
+            Some(Symbol::Location(location) | Symbol::Procedure(location, _)) => {
+                let (id, address) = segments.locate(*location)?;
+                (id, address as i64 + relocation.addend() as i64)
+            }
+            Some(Symbol::ShortValue(value)) => {
+                (SEGMENT_ABSOLUTE, *value as i64 + relocation.addend() as i64)
+            }
+            Some(Symbol::LongValue(value)) => {
+                (SEGMENT_ABSOLUTE, *value as i64 + relocation.addend() as i64)
+            }
+            None => {
+                let index = match undefined.iter().position(|undefined| *undefined == name) {
+                    Some(index) => index,
+                    None => {
+                        undefined.push(name);
+                        undefined.len() - 1
+                    }
+                };
+                (SEGMENT_UNDEFINED, index as i64)
+            }
+        };
+        // What goes in the bytes being relocated. For undefined references
+        // that's just the addend.
+        let stored = match target_id {
+            SEGMENT_UNDEFINED => relocation.addend() as i64,
+            _ => value,
+        };
+
+        let kind = match relocation {
+            // Branches within a segment stay the same wherever it's loaded.
+            Relocation::Relative(..) if target_id == location_id => {
+                let distance = stored - (position as i64 + 1);
+                if !(-0x80..=0x7F).contains(&distance) {
+                    return Err(invalid(format!(
+                        "branch to '{}' is out of range ({} bytes)",
+                        name, distance
+                    )));
+                }
+                raw_section[offset] = distance as u8;
+                continue;
+            }
+            Relocation::Relative(..) => {
+                return Err(invalid(format!(
+                    "o65 can't relocate the branch to '{}' in another segment",
+                    name
+                )))
+            }
+            // Long branches just stay long
+            Relocation::Relax(..) => continue,
+            Relocation::Bank(..) => {
+                return Err(invalid(format!(
+                    "o65 has no way to refer to the bank of '{}'",
+                    name
+                )))
+            }
+            // o65 can't tell jumps apart, so they come back as plain words
+            Relocation::Absolute(..) | Relocation::Long(..) | Relocation::Jump(..) => {
+                raw_section[offset..offset + 2].copy_from_slice(&(stored as u16).to_le_bytes());
+                RELOCATION_WORD
+            }
+            Relocation::Short(..) | Relocation::Low(..) => {
+                raw_section[offset] = stored as u8;
+                RELOCATION_LOW
+            }
+            Relocation::High(..) => {
+                raw_section[offset] = (stored >> 8) as u8;
+                RELOCATION_HIGH
+            }
+        };
+        if target_id == SEGMENT_ABSOLUTE {
+            continue;
+        }
+        let Some((table, last)) = tables.get_mut(&location_id) else {
+            return Err(invalid(format!(
+                "o65 can't relocate anything in the {} segment",
+                segment_name(location_id)
+            )));
+        };
+        push_position(table, last, position);
+        table.push(kind | target_id);
+        if target_id == SEGMENT_UNDEFINED {
+            push_u16(table, value as u16);
+        }
+        // The loader needs the low byte to carry into the high byte.
+        if kind == RELOCATION_HIGH {
+            table.push(stored as u8);
+        }
+    }
+
+    let mut file = MAGIC.to_vec();
+    file.push(0);
+    push_u16(&mut file, MODE_OBJECT);
+    for id in [SEGMENT_TEXT, SEGMENT_DATA, SEGMENT_BSS, SEGMENT_ZEROPAGE] {
+        push_u16(&mut file, 0);
+        push_u16(&mut file, segments.len(id) as u16);
+    }
+    // Stack size
+    push_u16(&mut file, 0);
+
+    let assembler = b"ratsembler_6502\0";
+    file.push(2 + assembler.len() as u8);
+    file.push(OPTION_ASSEMBLER);
+    file.extend(assembler);
+    file.push(0);
+
+    file.extend(segments.bytes(SEGMENT_TEXT, &raw_section));
+    file.extend(segments.bytes(SEGMENT_DATA, &raw_section));
+
+    push_u16(&mut file, undefined.len() as u16);
+    for name in &undefined {
+        file.extend(name.as_bytes());
+        file.push(0);
+    }
+
+    for id in [SEGMENT_TEXT, SEGMENT_DATA] {
+        file.extend(&tables[&id].0);
+        file.push(0);
+    }
+
+    let mut exported: Vec<_> = symbols
+        .iter()
+        .filter(|(name, _)| !is_local_symbol(name))
+        .collect();
+    exported.sort_by_key(|(name, _)| name.as_str());
+    push_u16(&mut file, exported.len() as u16);
+    for (name, symbol) in exported {
+        let (id, value) = match symbol {
+            Symbol::Location(location) | Symbol::Procedure(location, _) => {
+                let (id, address) = segments.locate(*location)?;
+                (id, address as u16)
+            }
+            Symbol::ShortValue(value) => (SEGMENT_ABSOLUTE, *value as u16),
+            Symbol::LongValue(value) => (SEGMENT_ABSOLUTE, *value),
+        };
+        file.extend(name.as_bytes());
+        file.push(0);
+        file.push(id);
+        push_u16(&mut file, value);
+    }
+
+    out.write_all(&file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::elf::relocatable::Object;
+    use crate::elf::relocatable::Rom;
+
+    fn test_object() -> Object {
+        Object {
+            raw_section: vec![0xAD, 0xFF, 0xFF, 0xA9, 0xFF, 0x60, 0xFF, 0xFF, 0, 0],
+            relocations: vec![
+                Relocation::Long("start".to_string(), 6, 2),
+                Relocation::Long("table".to_string(), 1, 0),
+                Relocation::Low("putchar".to_string(), 4, 0),
+            ],
+            symbols: HashMap::from([
+                ("start".to_string(), Symbol::Location(0)),
+                ("table".to_string(), Symbol::Location(6)),
+                ("scope@1@done".to_string(), Symbol::Location(5)),
+                ("screen".to_string(), Symbol::LongValue(0x0400)),
+            ]),
+            sections: vec![
+                Section {
+                    name: "text".to_string(),
+                    start: 0,
+                    size: 6,
+                    bank: None,
+                },
+                Section {
+                    name: "rodata".to_string(),
+                    start: 6,
+                    size: 2,
+                    bank: None,
+                },
+                Section {
+                    name: "bss".to_string(),
+                    start: 8,
+                    size: 2,
+                    bank: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_write_o65() {
+        let mut file = Vec::new();
+        write_o65(&mut file, &test_object()).unwrap();
+
+        let mut expected = vec![
+            0x01,
+            0x00,
+            b'o',
+            b'6',
+            b'5',
+            0x00,
+            0x00,
+            0x10, // header
+            0x00,
+            0x00,
+            0x06,
+            0x00, // text
+            0x00,
+            0x00,
+            0x02,
+            0x00, // data
+            0x00,
+            0x00,
+            0x02,
+            0x00, // bss
+            0x00,
+            0x00,
+            0x00,
+            0x00, // zero page
+            0x00,
+            0x00, // stack
+            0x12,
+            OPTION_ASSEMBLER,
+        ];
+        expected.extend(b"ratsembler_6502\0\0");
+        expected.extend([0xAD, 0x00, 0x00, 0xA9, 0x00, 0x60, 0x02, 0x00]);
+        expected.extend(b"\x01\x00putchar\0");
+        expected.extend([0x02, 0x83, 0x03, 0x20, 0x00, 0x00, 0x00]);
+        expected.extend([0x01, 0x82, 0x00]);
+        expected.extend(b"\x03\x00screen\0\x01\x00\x04start\0\x02\x00\x00table\0\x03\x00\x00");
+        assert_eq!(file, expected);
+    }
+
+    #[test]
+    fn test_o65_errors() {
+        let error = |object: Object| write_o65(&mut Vec::new(), &object).unwrap_err().to_string();
+
+        let mut object = test_object();
+        object.sections[1].name = "vectors".to_string();
+        assert!(error(object).contains("can't hold section 'vectors'"));
+
+        let mut object = test_object();
+        object.sections[0].bank = Some((Rom::Prg, 1));
+        assert!(error(object).contains("is banked"));
+
+        let mut object = test_object();
+        object.raw_section[9] = 1;
+        assert!(error(object).contains("can only reserve space"));
+
+        let mut object = test_object();
+        object
+            .relocations
+            .push(Relocation::Relative("table".to_string(), 4, 0));
+        assert!(error(object).contains("in another segment"));
+    }
+}
@@ -0,0 +1,8 @@
+pub mod binary;
+pub mod format;
+pub mod hex;
+pub mod image;
+pub mod ines;
+pub mod labels;
+pub mod platform;
+pub mod srec;
@@ -0,0 +1,92 @@
+use super::format::OutputFormat;
+use super::image::Image;
+
+// The image as flat bytes starting at its lowest address, with any gaps
+// between segments filled in. With a size the output is padded out to
+// exactly that many bytes, e.g. to fill a ROM.
+pub fn to_binary(image: &Image, fill: u8, size: Option<usize>) -> Result<Vec<u8>, String> {
+    image.check_unbanked()?;
+    let start = image.start() as usize;
+    let length = image.end().saturating_sub(start);
+    if let Some(size) = size {
+        if length > size {
+            return Err(format!(
+                "output is {} bytes, which doesn't fit in {} bytes",
+                length, size
+            ));
+        }
+    }
+
+    let mut binary = vec![fill; size.unwrap_or(length)];
+    for segment in &image.segments {
+        let offset = segment.address as usize - start;
+        binary[offset..offset + segment.bytes.len()].copy_from_slice(&segment.bytes);
+    }
+    Ok(binary)
+}
+
+// The flat bytes on their own, e.g. for burning to a ROM.
+pub struct Raw {
+    pub fill: u8,
+    pub size: Option<usize>,
+}
+
+impl OutputFormat for Raw {
+    fn extension(&self) -> &'static str {
+        "bin"
+    }
+
+    fn write(&self, image: &Image) -> Result<Vec<u8>, String> {
+        to_binary(image, self.fill, self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::output::image::Segment;
+
+    fn image() -> Image {
+        Image {
+            segments: vec![
+                Segment {
+                    address: 0x8000,
+                    bytes: vec![1, 2],
+                    bank: None,
+                },
+                Segment {
+                    address: 0x8004,
+                    bytes: vec![3],
+                    bank: None,
+                },
+            ],
+            entry: None,
+        }
+    }
+
+    #[test]
+    fn test_gaps_are_filled() {
+        assert_eq!(
+            to_binary(&image(), 0xEA, None),
+            Ok(vec![1, 2, 0xEA, 0xEA, 3])
+        );
+    }
+
+    #[test]
+    fn test_fixed_size() {
+        assert_eq!(
+            to_binary(&image(), 0xFF, Some(8)),
+            Ok(vec![1, 2, 0xFF, 0xFF, 3, 0xFF, 0xFF, 0xFF])
+        );
+        assert_eq!(
+            to_binary(&image(), 0xFF, Some(4)),
+            Err("output is 5 bytes, which doesn't fit in 4 bytes".to_string())
+        );
+    }
+
+    #[test]
+    fn test_empty_image() {
+        assert_eq!(to_binary(&Image::default(), 0, Some(2)), Ok(vec![0, 0]));
+    }
+}
@@ -0,0 +1,62 @@
+use super::binary::Raw;
+use super::hex::IntelHex;
+use super::image::Image;
+use super::ines::{Ines, InesHeader};
+use super::platform::{AppleDos, BbcMicro, Prg, Xex};
+use super::srec::Srec;
+
+use std::path::{Path, PathBuf};
+
+// A way of writing out a resolved image, from a plain ROM dump to a file one
+// particular machine knows how to load.
+pub trait OutputFormat {
+    // Used for the output file when it isn't given a name.
+    fn extension(&self) -> &'static str;
+
+    fn write(&self, image: &Image) -> Result<Vec<u8>, String>;
+
+    // Another file that has to go along with the output, like the .inf files
+    // BBC Micro emulators look for next to a binary.
+    fn sidecar(&self, _image: &Image, _output: &Path) -> Option<(PathBuf, Vec<u8>)> {
+        None
+    }
+}
+
+pub const FORMAT_NAMES: [&str; 8] = ["bin", "ihex", "srec", "prg", "xex", "apple", "bbc", "nes"];
+
+// Everything the formats can be configured with. Each one only looks at the
+// options that make sense for it.
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    pub fill: u8,
+    pub size: Option<usize>,
+    pub record_length: Option<usize>,
+    pub init: Option<u16>,
+    pub ines: InesHeader,
+}
+
+pub fn output_format(name: &str, options: &FormatOptions) -> Option<Box<dyn OutputFormat>> {
+    Some(match name {
+        "bin" => Box::new(Raw {
+            fill: options.fill,
+            size: options.size,
+        }),
+        "ihex" => Box::new(IntelHex {
+            record_length: options
+                .record_length
+                .unwrap_or(IntelHex::DEFAULT_RECORD_LENGTH),
+        }),
+        "srec" => Box::new(Srec {
+            record_length: options.record_length.unwrap_or(Srec::DEFAULT_RECORD_LENGTH),
+        }),
+        "prg" => Box::new(Prg { fill: options.fill }),
+        "xex" => Box::new(Xex { init: options.init }),
+        "apple" => Box::new(AppleDos { fill: options.fill }),
+        "bbc" => Box::new(BbcMicro { fill: options.fill }),
+        "nes" => Box::new(Ines {
+            header: options.ines.clone(),
+            fill: options.fill,
+        }),
+        _ => return None,
+    })
+}
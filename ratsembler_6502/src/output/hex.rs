@@ -0,0 +1,122 @@
+use super::format::OutputFormat;
+use super::image::Image;
+
+use std::io::{self, Write};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+fn write_record(out: &mut impl Write, address: u16, kind: u8, data: &[u8]) -> io::Result<()> {
+    let mut record = vec![data.len() as u8];
+    record.extend(address.to_be_bytes());
+    record.push(kind);
+    record.extend(data);
+    let checksum = record
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg();
+    record.push(checksum);
+
+    write!(out, ":")?;
+    for byte in record {
+        write!(out, "{:02X}", byte)?;
+    }
+    writeln!(out)
+}
+
+// Writes the image as Intel HEX, with at most `record_length` data bytes a
+// record. The whole of 6502 memory fits in the 16 bit record addresses, so
+// there are never any extended address records. The entry point goes in a
+// start linear address record.
+pub fn write_intel_hex(
+    out: &mut impl Write,
+    image: &Image,
+    record_length: usize,
+) -> io::Result<()> {
+    assert!((1..=0xFF).contains(&record_length));
+    for segment in &image.segments {
+        for (index, data) in segment.bytes.chunks(record_length).enumerate() {
+            let address = segment.address as usize + index * record_length;
+            write_record(out, address as u16, DATA, data)?;
+        }
+    }
+    if let Some(entry) = image.entry {
+        write_record(out, 0, START_LINEAR_ADDRESS, &(entry as u32).to_be_bytes())?;
+    }
+    write_record(out, 0, END_OF_FILE, &[])
+}
+
+pub struct IntelHex {
+    pub record_length: usize,
+}
+
+impl IntelHex {
+    pub const DEFAULT_RECORD_LENGTH: usize = 16;
+}
+
+impl OutputFormat for IntelHex {
+    fn extension(&self) -> &'static str {
+        "hex"
+    }
+
+    fn write(&self, image: &Image) -> Result<Vec<u8>, String> {
+        image.check_unbanked()?;
+        let mut out = Vec::new();
+        write_intel_hex(&mut out, image, self.record_length).map_err(|error| error.to_string())?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::output::image::Segment;
+
+    fn written(image: &Image, record_length: usize) -> String {
+        let mut out = Vec::new();
+        write_intel_hex(&mut out, image, record_length).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_records() {
+        let image = Image {
+            segments: vec![
+                Segment {
+                    address: 0xC000,
+                    bytes: vec![0xA9, 0x01, 0x4C, 0x00, 0xC0],
+                    bank: None,
+                },
+                Segment {
+                    address: 0xFFFC,
+                    bytes: vec![0x00, 0xC0],
+                    bank: None,
+                },
+            ],
+            entry: Some(0xC000),
+        };
+        assert_eq!(
+            written(&image, 4),
+            ":04C00000A9014C0046\n\
+             :01C00400C07B\n\
+             :02FFFC0000C043\n\
+             :040000050000C00037\n\
+             :00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn test_without_entry() {
+        let image = Image {
+            segments: vec![Segment {
+                address: 0x0100,
+                bytes: vec![0xEA],
+                bank: None,
+            }],
+            entry: None,
+        };
+        assert_eq!(written(&image, 16), ":01010000EA14\n:00000001FF\n");
+    }
+}
@@ -0,0 +1,252 @@
+use crate::elf::relocatable::Relocatable;
+use crate::elf::relocatable::Relocation;
+use crate::elf::relocatable::Rom;
+use crate::lang::ast::Program;
+
+use std::collections::BTreeSet;
+
+// A run of bytes loaded at a fixed address. Banked segments are in their
+// own address space, shared with the other segments in the same bank.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub bank: Option<(Rom, usize)>,
+}
+
+impl Segment {
+    // One past the last address, which can be $10000.
+    pub fn end(&self) -> usize {
+        self.address as usize + self.bytes.len()
+    }
+}
+
+// A program laid out at its final addresses with every relocation applied.
+// This is what all of the flat output formats are written from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    // In the order they were assembled, which matters to formats like XEX
+    // where the loader acts on segments as they come in. They never overlap.
+    pub segments: Vec<Segment>,
+    pub entry: Option<u16>,
+}
+
+impl Image {
+    // Places the program using its `.org`s, with anything before the first
+    // one at `origin`. Every relocation has to be against a label in the
+    // program, there's nothing left to link against.
+    pub fn from_program(program: &Program, origin: u16) -> Result<Image, Vec<String>> {
+        let (raw_section, mut errors) = relocate(program, origin);
+
+        // A new segment starts wherever the address jumps or the section
+        // changes.
+        let mut starts: Vec<usize> = program.origins.iter().map(|(cursor, _)| *cursor).collect();
+        starts.extend(program.sections.iter().map(|section| section.start));
+        starts.push(0);
+        starts.sort();
+        starts.dedup();
+        let mut segments = Vec::new();
+        for (index, start) in starts.iter().enumerate() {
+            let end = starts.get(index + 1).copied().unwrap_or(raw_section.len());
+            if end == *start {
+                continue;
+            }
+            segments.push(Segment {
+                address: program.address_of(*start, origin),
+                bytes: raw_section[*start..end].to_vec(),
+                bank: program.section_of(*start).and_then(|section| section.bank),
+            });
+        }
+        errors.extend(Image::layout_errors(&segments));
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Image {
+            segments,
+            entry: None,
+        })
+    }
+
+    // Everything wrong with where the segments have ended up: running off
+    // the end of memory, or landing on top of each other in the same bank.
+    pub fn layout_errors(segments: &[Segment]) -> Vec<String> {
+        let mut errors = Vec::new();
+        for segment in segments {
+            if segment.end() > 0x10000 {
+                errors.push(format!(
+                    "code at ${:04X} runs past the end of memory",
+                    segment.address
+                ));
+            }
+        }
+        let mut sorted: Vec<&Segment> = segments.iter().collect();
+        sorted.sort_by_key(|segment| {
+            (
+                segment.bank.map(|(rom, bank)| (rom as usize, bank)),
+                segment.address,
+            )
+        });
+        for pair in sorted.windows(2) {
+            if pair[0].bank == pair[1].bank && pair[0].end() > pair[1].address as usize {
+                errors.push(format!(
+                    "code at ${:04X}-${:04X} overlaps code at ${:04X}",
+                    pair[0].address,
+                    pair[0].end() - 1,
+                    pair[1].address
+                ));
+            }
+        }
+        errors
+    }
+
+    // Formats with a single address space can't hold banked segments.
+    pub fn check_unbanked(&self) -> Result<(), String> {
+        match self.segments.iter().any(|segment| segment.bank.is_some()) {
+            true => Err("banked sections can only be written to a cartridge format".to_string()),
+            false => Ok(()),
+        }
+    }
+
+    // The lowest address with anything in it.
+    pub fn start(&self) -> u16 {
+        self.segments
+            .iter()
+            .map(|segment| segment.address)
+            .min()
+            .unwrap_or(0)
+    }
+
+    // One past the highest address with anything in it.
+    pub fn end(&self) -> usize {
+        self.segments.iter().map(Segment::end).max().unwrap_or(0)
+    }
+}
+
+// The program's bytes with every relocation applied, using the addresses
+// from `Program::address_of`. Relocations against anything the program
+// doesn't define are errors.
+pub fn relocate(program: &Program, origin: u16) -> (Vec<u8>, Vec<String>) {
+    let mut raw_section = program.get_raw_section();
+    let mut errors = Vec::new();
+    let mut undefined = BTreeSet::new();
+    for relocation in program.get_relocations() {
+        let offset = relocation.offset() as usize;
+        let symbol = relocation.symbol();
+        let value = match (&relocation, program.labels.get(symbol)) {
+            (Relocation::Bank(..), Some(cursor)) => {
+                match program.section_of(*cursor).and_then(|section| section.bank) {
+                    Some((_, bank)) => bank as i64,
+                    None => {
+                        errors.push(format!("'{}' isn't in a banked section", symbol));
+                        continue;
+                    }
+                }
+            }
+            (_, Some(cursor)) => program.address_of(*cursor, origin) as i64,
+            // Constants that were used before they were assigned, where
+            // they didn't fit or are the target of a branch
+            (Relocation::Bank(..), None) if program.constants.contains_key(symbol) => {
+                errors.push(format!("'{}' isn't in a banked section", symbol));
+                continue;
+            }
+            (_, None) => match program.constants.get(symbol) {
+                Some(value) => *value,
+                None => {
+                    undefined.insert(symbol.to_string());
+                    continue;
+                }
+            },
+        };
+        let place = program.address_of(offset, origin) as i64;
+        match relocation.resolve(value, place) {
+            Ok(bytes) => raw_section[offset..offset + bytes.len()].copy_from_slice(&bytes),
+            Err(message) => errors.push(message),
+        }
+    }
+    for symbol in undefined {
+        errors.push(format!(
+            "undefined symbol '{}' still needs a relocation",
+            symbol
+        ));
+    }
+    (raw_section, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lang::assembler::Assembler;
+    use crate::lang::source::SourceLine;
+
+    fn image(source: &str) -> Result<Image, Vec<String>> {
+        let program = Assembler::new()
+            .assemble(&SourceLine::from_source("test.s", source))
+            .unwrap();
+        Image::from_program(&program, 0x0200)
+    }
+
+    #[test]
+    fn test_segments_and_relocations() {
+        let source = r#"
+  JMP start
+.org $C000
+start:
+  LDA table,X
+  BNE start
+.org $C100
+table: .byte 1, 2
+  .word start
+"#;
+        let image = image(source).unwrap();
+        assert_eq!(
+            image.segments,
+            [
+                Segment {
+                    address: 0x0200,
+                    bytes: vec![0x4C, 0x00, 0xC0],
+                    bank: None,
+                },
+                Segment {
+                    address: 0xC000,
+                    bytes: vec![0xBD, 0x00, 0xC1, 0xD0, 0xFB],
+                    bank: None,
+                },
+                Segment {
+                    address: 0xC100,
+                    bytes: vec![0x01, 0x02, 0x00, 0xC0],
+                    bank: None,
+                },
+            ]
+        );
+        assert_eq!(image.start(), 0x0200);
+        assert_eq!(image.end(), 0xC104);
+    }
+
+    #[test]
+    fn test_unresolvable_images() {
+        assert_eq!(
+            image("  JSR print\n  JMP print\n"),
+            Err(vec![
+                "undefined symbol 'print' still needs a relocation".to_string()
+            ])
+        );
+        assert_eq!(
+            image(".org $10\n  LDA #value\n.org $300\nvalue: RTS\n"),
+            Err(vec![
+                "value $300 of 'value' does not fit in a byte".to_string()
+            ])
+        );
+        assert_eq!(
+            image(".org $1000\nhere: .res $10\n.org $1008\n  BNE here\n"),
+            Err(vec![
+                "code at $1000-$100F overlaps code at $1008".to_string()
+            ])
+        );
+        assert_eq!(
+            image(".org $FFFF\n  NOP\n  NOP\n"),
+            Err(vec!["code at $FFFF runs past the end of memory".to_string()])
+        );
+    }
+}
@@ -0,0 +1,408 @@
+use super::format::OutputFormat;
+use super::image::Image;
+
+use crate::elf::relocatable::Rom;
+
+pub const PRG_BANK_SIZE: usize = 0x4000;
+pub const CHR_BANK_SIZE: usize = 0x2000;
+
+// The largest counts that fit in a NES 2.0 header without the exponent form
+pub const MAX_BANKS: usize = 0xEFF;
+pub const MAX_MAPPER: u16 = 0xFFF;
+pub const MAX_SUBMAPPER: u8 = 0xF;
+
+const VECTORS: u16 = 0xFFFA;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mirroring {
+    #[default]
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+impl Mirroring {
+    pub fn from_name(name: &str) -> Option<Mirroring> {
+        match name.to_lowercase().as_str() {
+            "horizontal" | "h" => Some(Mirroring::Horizontal),
+            "vertical" | "v" => Some(Mirroring::Vertical),
+            "four_screen" | "four" => Some(Mirroring::FourScreen),
+            _ => None,
+        }
+    }
+}
+
+// What goes in the header besides the ROM sizes. Bank counts are in 16K
+// units for PRG and 8K for CHR, and are worked out from the banks that are
+// used when they aren't given.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InesHeader {
+    pub prg_banks: Option<usize>,
+    pub chr_banks: Option<usize>,
+    pub mapper: u16,
+    // Only NES 2.0 headers have a submapper, so giving one asks for NES 2.0
+    pub submapper: Option<u8>,
+    pub mirroring: Mirroring,
+}
+
+impl InesHeader {
+    // The plain iNES header covers most things, NES 2.0 is only used when
+    // something doesn't fit in it.
+    pub fn is_nes2(&self, prg_banks: usize, chr_banks: usize) -> bool {
+        self.submapper.is_some() || self.mapper > 0xFF || prg_banks > 0xFF || chr_banks > 0xFF
+    }
+
+    pub fn to_bytes(&self, prg_banks: usize, chr_banks: usize) -> [u8; 16] {
+        let mut header = [0; 16];
+        header[..4].copy_from_slice(b"NES\x1A");
+        header[4] = prg_banks as u8;
+        header[5] = chr_banks as u8;
+        header[6] = ((self.mapper & 0x0F) << 4) as u8
+            | match self.mirroring {
+                Mirroring::Horizontal => 0x00,
+                Mirroring::Vertical => 0x01,
+                Mirroring::FourScreen => 0x08,
+            };
+        header[7] = (self.mapper & 0xF0) as u8;
+        if self.is_nes2(prg_banks, chr_banks) {
+            header[7] |= 0x08;
+            header[8] = (self.mapper >> 8) as u8 | self.submapper.unwrap_or(0) << 4;
+            header[9] = (prg_banks >> 8) as u8 | ((chr_banks >> 8) << 4) as u8;
+            // Without any CHR ROM there's 8K of CHR RAM, as 64 << 7
+            if chr_banks == 0 {
+                header[11] = 0x07;
+            }
+        }
+        header
+    }
+
+    // The PRG and CHR bank counts for the image, where they aren't given.
+    pub fn bank_counts(&self, image: &Image) -> (usize, usize) {
+        let highest = |rom| {
+            image
+                .segments
+                .iter()
+                .filter_map(|segment| match segment.bank {
+                    Some((bank_rom, bank)) if bank_rom == rom => Some(bank + 1),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0)
+        };
+        // Unbanked code needs another bank after the banked ones, or two if
+        // it goes down into $8000-$BFFF.
+        let unbanked: Vec<_> = image
+            .segments
+            .iter()
+            .filter(|segment| segment.bank.is_none() && segment.end() > 0x8000)
+            .collect();
+        let fixed = match unbanked.iter().map(|segment| segment.address).min() {
+            Some(address) if address < 0xC000 => 2,
+            Some(_) => 1,
+            None => 0,
+        };
+        let prg_banks = self
+            .prg_banks
+            .unwrap_or_else(|| (highest(Rom::Prg) + fixed).max(1));
+        let chr_banks = self.chr_banks.unwrap_or_else(|| highest(Rom::Chr));
+        (prg_banks, chr_banks)
+    }
+}
+
+// Where code at `address` goes in PRG ROM. Banked code is at its address
+// within the bank, and everything else from $8000 up is in the last 32K,
+// or the one bank that's mirrored at $8000 and $C000.
+pub fn prg_offset(address: u16, bank: Option<usize>, prg_banks: usize) -> usize {
+    let address = address as usize;
+    match (bank, prg_banks) {
+        (Some(bank), _) => bank * PRG_BANK_SIZE + address % PRG_BANK_SIZE,
+        (None, 1) => address % PRG_BANK_SIZE,
+        (None, _) => (prg_banks - 2) * PRG_BANK_SIZE + address - 0x8000,
+    }
+}
+
+// An iNES ROM for NES emulators and flash carts: the header, then the PRG
+// banks, then the CHR banks.
+//
+// Banked sections go in the bank they name, at their address within the
+// bank. Everything else from $8000 up is in the last 32K of PRG, and
+// anything lower is RAM and left out.
+pub struct Ines {
+    pub header: InesHeader,
+    pub fill: u8,
+}
+
+// The bytes of one of the ROMs, with which of them have been written.
+struct RomImage {
+    rom: Rom,
+    bank_size: usize,
+    bytes: Vec<u8>,
+    written: Vec<bool>,
+}
+
+impl RomImage {
+    fn new(rom: Rom, banks: usize, fill: u8) -> RomImage {
+        let bank_size = match rom {
+            Rom::Prg => PRG_BANK_SIZE,
+            Rom::Chr => CHR_BANK_SIZE,
+        };
+        RomImage {
+            rom,
+            bank_size,
+            bytes: vec![fill; banks * bank_size],
+            written: vec![false; banks * bank_size],
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self.rom {
+            Rom::Prg => "PRG",
+            Rom::Chr => "CHR",
+        }
+    }
+
+    fn banks(&self) -> usize {
+        self.bytes.len() / self.bank_size
+    }
+
+    fn write(&mut self, offset: usize, address: u16, bytes: &[u8]) -> Result<(), String> {
+        let bank = offset / self.bank_size;
+        if offset + bytes.len() > self.bytes.len() {
+            return Err(format!(
+                "code at ${:04X} is past the end of {} ROM, which has {} banks",
+                address,
+                self.name(),
+                self.banks()
+            ));
+        }
+        if self.written[offset..offset + bytes.len()].contains(&true) {
+            return Err(format!(
+                "code at ${:04X} overlaps other code in {} bank {}",
+                address,
+                self.name(),
+                bank
+            ));
+        }
+        self.bytes[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.written[offset..offset + bytes.len()].fill(true);
+        Ok(())
+    }
+}
+
+impl Ines {
+    // Every PRG bank that's ever mapped in at the top of memory has to have
+    // the vectors in it. That's the last bank, which most mappers start up
+    // with there, and any bank with code assembled for $C000 and up.
+    fn check_vectors(&self, prg: &RomImage, upper_banks: &[usize]) -> Result<(), String> {
+        let mut banks = upper_banks.to_vec();
+        banks.push(prg.banks() - 1);
+        banks.sort();
+        banks.dedup();
+        for bank in banks {
+            let vectors = bank * PRG_BANK_SIZE + (VECTORS as usize & (PRG_BANK_SIZE - 1));
+            if prg.written[vectors..vectors + 6].contains(&false) {
+                return Err(format!(
+                    "PRG bank {} is mapped at $C000 but has no vectors at ${:04X}",
+                    bank, VECTORS
+                ));
+            }
+            let reset = u16::from_le_bytes([prg.bytes[vectors + 2], prg.bytes[vectors + 3]]);
+            if reset < 0x8000 {
+                return Err(format!(
+                    "reset vector in PRG bank {} points at ${:04X}, outside of PRG ROM",
+                    bank, reset
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl OutputFormat for Ines {
+    fn extension(&self) -> &'static str {
+        "nes"
+    }
+
+    fn write(&self, image: &Image) -> Result<Vec<u8>, String> {
+        let (prg_banks, chr_banks) = self.header.bank_counts(image);
+        let mut prg = RomImage::new(Rom::Prg, prg_banks, self.fill);
+        let mut chr = RomImage::new(Rom::Chr, chr_banks, self.fill);
+        let mut upper_banks = Vec::new();
+
+        for segment in &image.segments {
+            match segment.bank {
+                Some((rom, bank)) => {
+                    let rom = match rom {
+                        Rom::Prg => &mut prg,
+                        Rom::Chr => &mut chr,
+                    };
+                    let offset = segment.address as usize % rom.bank_size;
+                    if offset + segment.bytes.len() > rom.bank_size {
+                        return Err(format!(
+                            "code at ${:04X} runs past the end of {} bank {}",
+                            segment.address,
+                            rom.name(),
+                            bank
+                        ));
+                    }
+                    if bank >= rom.banks() {
+                        return Err(format!(
+                            "{} bank {} is past the end of {} ROM, which has {} banks",
+                            rom.name(),
+                            bank,
+                            rom.name(),
+                            rom.banks()
+                        ));
+                    }
+                    rom.write(
+                        bank * rom.bank_size + offset,
+                        segment.address,
+                        &segment.bytes,
+                    )?;
+                    if rom.rom == Rom::Prg && segment.end() > 0xC000 {
+                        upper_banks.push(bank);
+                    }
+                }
+                None if segment.end() <= 0x8000 => {}
+                None => {
+                    // RAM below $8000 isn't part of the ROM
+                    let skip = 0x8000_usize.saturating_sub(segment.address as usize);
+                    let address = segment.address + skip as u16;
+                    let offset = prg_offset(address, None, prg_banks);
+                    prg.write(offset, address, &segment.bytes[skip..])?;
+                }
+            }
+        }
+        self.check_vectors(&prg, &upper_banks)?;
+
+        let mut rom = self.header.to_bytes(prg_banks, chr_banks).to_vec();
+        rom.extend(prg.bytes);
+        rom.extend(chr.bytes);
+        Ok(rom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lang::assembler::Assembler;
+    use crate::lang::source::SourceLine;
+
+    fn rom(source: &str) -> Result<Vec<u8>, String> {
+        let mut assembler = Assembler::new();
+        let program = assembler
+            .assemble(&SourceLine::from_source("test.s", source))
+            .unwrap_or_else(|| panic!("{:?}", assembler.diagnostics()));
+        let image = Image::from_program(&program, 0).unwrap();
+        Ines {
+            header: program.ines.clone(),
+            fill: 0xFF,
+        }
+        .write(&image)
+    }
+
+    #[test]
+    fn test_headers() {
+        let header = InesHeader {
+            prg_banks: None,
+            chr_banks: None,
+            mapper: 4,
+            submapper: None,
+            mirroring: Mirroring::Vertical,
+        };
+        assert_eq!(
+            header.to_bytes(8, 16),
+            [b'N', b'E', b'S', 0x1A, 8, 16, 0x41, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        let header = InesHeader {
+            mapper: 0x123,
+            submapper: Some(5),
+            ..InesHeader::default()
+        };
+        assert_eq!(
+            header.to_bytes(0x102, 0),
+            [b'N', b'E', b'S', 0x1A, 2, 0, 0x30, 0x28, 0x51, 0x01, 0, 0x07, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_nrom() {
+        let source = r#"
+.ines_mirror vertical
+.org $C000
+reset: JMP reset
+nmi: RTI
+.org $FFFA
+  .word nmi, reset, nmi
+.section tiles, chr, 0
+  .byte $3C, $42
+"#;
+        let rom = rom(source).unwrap();
+        assert_eq!(rom.len(), 16 + PRG_BANK_SIZE + CHR_BANK_SIZE);
+        assert_eq!(rom[4..7], [1, 1, 0x01]);
+        assert_eq!(rom[16..20], [0x4C, 0x00, 0xC0, 0x40]);
+        assert_eq!(rom[16 + 0x3FFA..16 + 0x4000], [3, 0xC0, 0, 0xC0, 3, 0xC0]);
+        assert_eq!(rom[16 + 0x4000..16 + 0x4003], [0x3C, 0x42, 0xFF]);
+    }
+
+    #[test]
+    fn test_banks() {
+        let source = r#"
+.ines_prg 4
+.ines_mapper 2
+.section bank1, prg, 1
+.org $8000
+  .byte 1
+.section bank2, prg, 2
+.org $8000
+  .byte 2
+.section fixed
+.org $C000
+reset: JMP reset
+.org $FFFA
+  .word reset, reset, reset
+"#;
+        let rom = rom(source).unwrap();
+        assert_eq!(rom.len(), 16 + 4 * PRG_BANK_SIZE);
+        assert_eq!(rom[6], 0x20);
+        assert_eq!(rom[16], 0xFF);
+        assert_eq!(rom[16 + PRG_BANK_SIZE], 1);
+        assert_eq!(rom[16 + 2 * PRG_BANK_SIZE], 2);
+        assert_eq!(rom[16 + 3 * PRG_BANK_SIZE], 0x4C);
+    }
+
+    #[test]
+    fn test_bad_roms() {
+        assert_eq!(
+            rom(".org $C000\nreset: JMP reset\n"),
+            Err("PRG bank 0 is mapped at $C000 but has no vectors at $FFFA".to_string())
+        );
+        assert_eq!(
+            rom(".org $FFFA\n  .word 0, $0200, 0\n"),
+            Err("reset vector in PRG bank 0 points at $0200, outside of PRG ROM".to_string())
+        );
+        assert_eq!(
+            rom(".ines_prg 2\n.section high, prg, 2\n.org $8000\nRTS\n"),
+            Err("PRG bank 2 is past the end of PRG ROM, which has 2 banks".to_string())
+        );
+        assert_eq!(
+            rom(".section big, chr, 0\n.res $2001\n"),
+            Err("code at $0000 runs past the end of CHR bank 0".to_string())
+        );
+        // Unbanked code goes in the last bank, which is already in use
+        let source = r#"
+.ines_prg 2
+.org $C000
+  .word 1
+.section one, prg, 1
+.org $C001
+  .word 1
+"#;
+        assert_eq!(
+            rom(source),
+            Err("code at $C001 overlaps other code in PRG bank 1".to_string())
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use crate::elf::relocatable::Rom;
+use crate::elf::relocatable::Symbol;
+use crate::output::ines::prg_offset;
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+// Label file formats understood by emulator debuggers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelFormat {
+    // VICE monitor command file, `al C:c000 .main`
+    Vice,
+    // Mesen label file, `P:0000:main`
+    Mesen,
+    // FCEUX name list, `$C000#main#`
+    Fceux,
+    // `main = $C000`
+    Plain,
+}
+
+// A label at its final address, with the bank it's in if it's banked.
+pub type Label = (String, u16, Option<(Rom, usize)>);
+
+// Every label in the program at its final address, in address order. Labels
+// are offsets into the section, `place` turns one into where it's loaded
+// and which bank that's in.
+pub fn resolve_labels(
+    symbols: &HashMap<String, Symbol>,
+    place: impl Fn(usize) -> (u16, Option<(Rom, usize)>),
+) -> Vec<Label> {
+    let mut labels: Vec<Label> = symbols
+        .iter()
+        .filter_map(|(name, symbol)| match symbol {
+            Symbol::Location(location) | Symbol::Procedure(location, _) => {
+                let (address, bank) = place(*location);
+                Some((name.clone(), address, bank))
+            }
+            Symbol::ShortValue(_) | Symbol::LongValue(_) => None,
+        })
+        .collect();
+    labels.sort_by(|(a_name, a, _), (b_name, b, _)| (a, a_name).cmp(&(b, b_name)));
+    labels
+}
+
+// Debuggers only take plain identifiers, so scoped names like `main::loop`
+// become `main__loop`.
+fn identifier(name: &str) -> String {
+    name.chars()
+        .map(|character| match character {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => character,
+            _ => '_',
+        })
+        .collect()
+}
+
+// Mesen wants to know which memory a label is in, and for PRG ROM the
+// offset into the ROM rather than the CPU address. That's wherever the iNES
+// writer puts it in a ROM with `prg_banks` banks. CHR ROM isn't somewhere
+// the CPU can see, so there's no label for it.
+fn mesen_location(
+    address: u16,
+    bank: Option<(Rom, usize)>,
+    prg_banks: usize,
+) -> Option<(char, usize)> {
+    match (address, bank) {
+        (_, Some((Rom::Chr, _))) => None,
+        (0x0000..=0x1FFF, _) => Some(('R', address as usize & 0x07FF)),
+        (0x2000..=0x5FFF, _) => Some(('G', address as usize)),
+        (0x6000..=0x7FFF, _) => Some(('S', address as usize - 0x6000)),
+        (_, bank) => Some((
+            'P',
+            prg_offset(address, bank.map(|(_, bank)| bank), prg_banks),
+        )),
+    }
+}
+
+pub fn write_labels(
+    out: &mut impl Write,
+    format: LabelFormat,
+    labels: &[Label],
+    prg_banks: usize,
+) -> io::Result<()> {
+    for (name, address, bank) in labels {
+        match format {
+            LabelFormat::Vice => writeln!(out, "al C:{:04x} .{}", address, identifier(name))?,
+            LabelFormat::Mesen => {
+                if let Some((memory, offset)) = mesen_location(*address, *bank, prg_banks) {
+                    writeln!(out, "{}:{:04X}:{}", memory, offset, identifier(name))?
+                }
+            }
+            LabelFormat::Fceux => writeln!(out, "${:04X}#{}#", address, identifier(name))?,
+            LabelFormat::Plain => writeln!(out, "{} = ${:04X}", name, address)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels() -> Vec<Label> {
+        let symbols = HashMap::from([
+            ("main".to_string(), Symbol::Procedure(0, 4)),
+            ("main::loop".to_string(), Symbol::Location(2)),
+            ("@1::data".to_string(), Symbol::Location(0x10)),
+            ("zero".to_string(), Symbol::ShortValue(0)),
+        ]);
+        resolve_labels(&symbols, |location| (0xC000 + location as u16, None))
+    }
+
+    fn written(format: LabelFormat) -> String {
+        let mut out = Vec::new();
+        // A 16K NROM, mirrored at $8000 and $C000
+        write_labels(&mut out, format, &labels(), 1).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_labels() {
+        assert_eq!(
+            labels(),
+            [
+                ("main".to_string(), 0xC000, None),
+                ("main::loop".to_string(), 0xC002, None),
+                ("@1::data".to_string(), 0xC010, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(
+            written(LabelFormat::Vice),
+            "al C:c000 .main\nal C:c002 .main__loop\nal C:c010 ._1__data\n"
+        );
+        assert_eq!(
+            written(LabelFormat::Mesen),
+            "P:0000:main\nP:0002:main__loop\nP:0010:_1__data\n"
+        );
+        assert_eq!(
+            written(LabelFormat::Fceux),
+            "$C000#main#\n$C002#main__loop#\n$C010#_1__data#\n"
+        );
+        assert_eq!(
+            written(LabelFormat::Plain),
+            "main = $C000\nmain::loop = $C002\n@1::data = $C010\n"
+        );
+    }
+
+    #[test]
+    fn test_mesen_memory_types() {
+        assert_eq!(mesen_location(0x0812, None, 2), Some(('R', 0x0012)));
+        assert_eq!(mesen_location(0x2002, None, 2), Some(('G', 0x2002)));
+        assert_eq!(mesen_location(0x6010, None, 2), Some(('S', 0x0010)));
+        assert_eq!(mesen_location(0xC000, None, 2), Some(('P', 0x4000)));
+        // The fixed banks come after the switched ones
+        assert_eq!(mesen_location(0xC000, None, 8), Some(('P', 0x1C000)));
+        assert_eq!(
+            mesen_location(0x8010, Some((Rom::Prg, 2)), 8),
+            Some(('P', 0x8010))
+        );
+        assert_eq!(mesen_location(0x0010, Some((Rom::Chr, 1)), 8), None);
+    }
+}
@@ -0,0 +1,187 @@
+use super::binary::to_binary;
+use super::format::OutputFormat;
+use super::image::Image;
+
+use std::path::{Path, PathBuf};
+
+// Atari OS vectors the XEX loader jumps through
+const RUNAD: u16 = 0x02E0;
+const INITAD: u16 = 0x02E2;
+
+// DFS file names are at most seven characters.
+const BBC_NAME_LENGTH: usize = 7;
+
+// Commodore .prg: the load address then the bytes to load there.
+pub struct Prg {
+    pub fill: u8,
+}
+
+impl OutputFormat for Prg {
+    fn extension(&self) -> &'static str {
+        "prg"
+    }
+
+    fn write(&self, image: &Image) -> Result<Vec<u8>, String> {
+        let mut prg = image.start().to_le_bytes().to_vec();
+        prg.extend(to_binary(image, self.fill, None)?);
+        Ok(prg)
+    }
+}
+
+// Atari .xex: every segment with its first and last address, in the order
+// they were assembled, then INITAD and RUNAD segments if there's an init
+// routine or an entry point.
+pub struct Xex {
+    pub init: Option<u16>,
+}
+
+fn push_xex_segment(xex: &mut Vec<u8>, address: u16, bytes: &[u8]) {
+    let last = address as usize + bytes.len() - 1;
+    xex.extend(address.to_le_bytes());
+    xex.extend((last as u16).to_le_bytes());
+    xex.extend(bytes);
+}
+
+impl OutputFormat for Xex {
+    fn extension(&self) -> &'static str {
+        "xex"
+    }
+
+    fn write(&self, image: &Image) -> Result<Vec<u8>, String> {
+        image.check_unbanked()?;
+        let mut xex = vec![0xFF, 0xFF];
+        for segment in &image.segments {
+            push_xex_segment(&mut xex, segment.address, &segment.bytes);
+        }
+        if let Some(init) = self.init {
+            push_xex_segment(&mut xex, INITAD, &init.to_le_bytes());
+        }
+        if let Some(entry) = image.entry {
+            push_xex_segment(&mut xex, RUNAD, &entry.to_le_bytes());
+        }
+        Ok(xex)
+    }
+}
+
+// Apple II DOS 3.3 binary (B) file: the load address and length, then the
+// bytes. `BRUN` starts it at the load address.
+pub struct AppleDos {
+    pub fill: u8,
+}
+
+impl OutputFormat for AppleDos {
+    fn extension(&self) -> &'static str {
+        "bin"
+    }
+
+    fn write(&self, image: &Image) -> Result<Vec<u8>, String> {
+        let bytes = to_binary(image, self.fill, None)?;
+        let length = u16::try_from(bytes.len())
+            .map_err(|_| format!("{} bytes is too long for a DOS 3.3 file", bytes.len()))?;
+        let mut file = image.start().to_le_bytes().to_vec();
+        file.extend(length.to_le_bytes());
+        file.extend(bytes);
+        Ok(file)
+    }
+}
+
+// BBC Micro: the plain bytes, with the load and execution addresses in a
+// .inf file alongside for emulators and disc image tools.
+pub struct BbcMicro {
+    pub fill: u8,
+}
+
+impl OutputFormat for BbcMicro {
+    fn extension(&self) -> &'static str {
+        ""
+    }
+
+    fn write(&self, image: &Image) -> Result<Vec<u8>, String> {
+        to_binary(image, self.fill, None)
+    }
+
+    fn sidecar(&self, image: &Image, output: &Path) -> Option<(PathBuf, Vec<u8>)> {
+        let name: String = output
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .chars()
+            .take(BBC_NAME_LENGTH)
+            .collect();
+        let load = image.start();
+        let exec = image.entry.unwrap_or(load);
+        let length = image.end() - load as usize;
+        // The high FF bytes load into the host rather than a second processor
+        let inf = format!("$.{} FF{:04X} FF{:04X} {:06X}\n", name, load, exec, length);
+        let mut path = output.as_os_str().to_owned();
+        path.push(".inf");
+        Some((path.into(), inf.into_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::output::image::Segment;
+
+    fn image() -> Image {
+        Image {
+            segments: vec![
+                Segment {
+                    address: 0x0810,
+                    bytes: vec![0xA9, 0x00],
+                    bank: None,
+                },
+                Segment {
+                    address: 0x0801,
+                    bytes: vec![0x0B, 0x08],
+                    bank: None,
+                },
+            ],
+            entry: Some(0x0810),
+        }
+    }
+
+    #[test]
+    fn test_prg() {
+        let prg = Prg { fill: 0 }.write(&image()).unwrap();
+        assert_eq!(prg[..4], [0x01, 0x08, 0x0B, 0x08]);
+        assert_eq!(prg.len(), 2 + 0x11);
+        assert_eq!(prg[prg.len() - 2..], [0xA9, 0x00]);
+    }
+
+    #[test]
+    fn test_xex() {
+        let xex = Xex { init: Some(0x0900) }.write(&image()).unwrap();
+        assert_eq!(
+            xex,
+            [
+                0xFF, 0xFF, // header
+                0x10, 0x08, 0x11, 0x08, 0xA9, 0x00, // in assembly order
+                0x01, 0x08, 0x02, 0x08, 0x0B, 0x08, //
+                0xE2, 0x02, 0xE3, 0x02, 0x00, 0x09, // INITAD
+                0xE0, 0x02, 0xE1, 0x02, 0x10, 0x08, // RUNAD
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apple_dos() {
+        let file = AppleDos { fill: 0 }.write(&image()).unwrap();
+        assert_eq!(file[..6], [0x01, 0x08, 0x11, 0x00, 0x0B, 0x08]);
+        assert_eq!(file.len(), 4 + 0x11);
+    }
+
+    #[test]
+    fn test_bbc_inf() {
+        let (path, inf) = BbcMicro { fill: 0 }
+            .sidecar(&image(), Path::new("out/LONGNAME"))
+            .unwrap();
+        assert_eq!(path, Path::new("out/LONGNAME.inf"));
+        assert_eq!(
+            String::from_utf8(inf).unwrap(),
+            "$.LONGNAM FF0801 FF0810 000011\n"
+        );
+    }
+}
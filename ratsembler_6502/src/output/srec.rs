@@ -0,0 +1,99 @@
+use super::format::OutputFormat;
+use super::image::Image;
+
+use std::io::{self, Write};
+
+fn write_record(out: &mut impl Write, kind: u8, address: u16, data: &[u8]) -> io::Result<()> {
+    // The count covers the address and checksum as well as the data
+    let mut record = vec![(data.len() + 3) as u8];
+    record.extend(address.to_be_bytes());
+    record.extend(data);
+    let checksum = !record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(checksum);
+
+    write!(out, "S{}", kind)?;
+    for byte in record {
+        write!(out, "{:02X}", byte)?;
+    }
+    writeln!(out)
+}
+
+// Writes the image as Motorola S-records: an empty S0 header, S1 data
+// records with at most `record_length` bytes each, an S5 count of the data
+// records, and an S9 with the entry point (or 0 without one).
+pub fn write_srec(out: &mut impl Write, image: &Image, record_length: usize) -> io::Result<()> {
+    assert!((1..=0xFC).contains(&record_length));
+    write_record(out, 0, 0, &[])?;
+    let mut count = 0usize;
+    for segment in &image.segments {
+        for (index, data) in segment.bytes.chunks(record_length).enumerate() {
+            let address = segment.address as usize + index * record_length;
+            write_record(out, 1, address as u16, data)?;
+            count += 1;
+        }
+    }
+    // S6 with a 24 bit count is much less widely supported, so just leave
+    // the count out when there are too many records.
+    if let Ok(count) = u16::try_from(count) {
+        write_record(out, 5, count, &[])?;
+    }
+    write_record(out, 9, image.entry.unwrap_or(0), &[])
+}
+
+pub struct Srec {
+    pub record_length: usize,
+}
+
+impl Srec {
+    pub const DEFAULT_RECORD_LENGTH: usize = 16;
+}
+
+impl OutputFormat for Srec {
+    fn extension(&self) -> &'static str {
+        "srec"
+    }
+
+    fn write(&self, image: &Image) -> Result<Vec<u8>, String> {
+        image.check_unbanked()?;
+        let mut out = Vec::new();
+        write_srec(&mut out, image, self.record_length).map_err(|error| error.to_string())?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::output::image::Segment;
+
+    #[test]
+    fn test_records() {
+        let image = Image {
+            segments: vec![
+                Segment {
+                    address: 0xC000,
+                    bytes: vec![0xA9, 0x01, 0x4C, 0x00, 0xC0],
+                    bank: None,
+                },
+                Segment {
+                    address: 0xFFFC,
+                    bytes: vec![0x00, 0xC0],
+                    bank: None,
+                },
+            ],
+            entry: Some(0xC000),
+        };
+        let mut out = Vec::new();
+        write_srec(&mut out, &image, 4).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "S0030000FC\n\
+             S107C000A9014C0042\n\
+             S104C004C077\n\
+             S105FFFC00C03F\n\
+             S5030003F9\n\
+             S903C0003C\n"
+        );
+    }
+}
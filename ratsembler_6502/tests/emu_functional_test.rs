@@ -0,0 +1,71 @@
+use std::fs;
+use std::path::PathBuf;
+
+use ratsembler_6502::emu::Bus;
+use ratsembler_6502::emu::Cpu;
+use ratsembler_6502::emu::Ram;
+use ratsembler_6502::emu::Variant;
+
+// Klaus Dormann's 6502 functional test, from
+// https://github.com/Klaus2m5/6502_65C02_functional_tests. The binary isn't
+// in the repo, so these only run with `cargo test -- --ignored`, and then
+// look for it in FUNCTIONAL_TEST or at tests/data/6502_functional_test.bin.
+// It's the prebuilt image, which loads at $0000, starts at $0400 and ends
+// up in a `JMP *` at $3469 if everything passed, or somewhere else if it
+// didn't. Set FUNCTIONAL_TEST_SUCCESS if it's been reassembled and that's
+// moved.
+fn functional_test() -> (Vec<u8>, u16) {
+    let path = std::env::var_os("FUNCTIONAL_TEST")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/6502_functional_test.bin")
+        });
+    let bytes =
+        fs::read(&path).unwrap_or_else(|error| panic!("can't read {}: {}", path.display(), error));
+    let success = std::env::var("FUNCTIONAL_TEST_SUCCESS")
+        .ok()
+        .map(|address| u16::from_str_radix(address.trim_start_matches('$'), 16).unwrap())
+        .unwrap_or(0x3469);
+    (bytes, success)
+}
+
+fn run_functional_test(variant: Variant) {
+    let (bytes, success) = functional_test();
+    let mut ram = Ram::new();
+    ram.load(0x0000, &bytes);
+    let mut cpu = Cpu::new(variant);
+    cpu.reset(&mut ram);
+    cpu.pc = 0x0400;
+    // It takes about 30 million instructions
+    for _ in 0..100_000_000u64 {
+        let pc = cpu.pc;
+        if let Err(error) = cpu.step(&mut ram) {
+            panic!("{}, after test {:02X}", error, ram.read(0x0200));
+        }
+        if cpu.pc == pc {
+            assert_eq!(
+                pc,
+                success,
+                "trapped at ${:04X} in test {:02X}",
+                pc,
+                ram.read(0x0200)
+            );
+            return;
+        }
+    }
+    panic!("still running at ${:04X}", cpu.pc);
+}
+
+#[test]
+#[ignore = "needs 6502_functional_test.bin"]
+fn test_nmos_functional() {
+    run_functional_test(Variant::Nmos);
+}
+
+// The functional test only uses the NMOS instructions, so the 65C02 has to
+// pass it too.
+#[test]
+#[ignore = "needs 6502_functional_test.bin"]
+fn test_cmos_functional() {
+    run_functional_test(Variant::Cmos);
+}
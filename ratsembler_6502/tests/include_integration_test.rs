@@ -0,0 +1,173 @@
+use std::fs;
+use std::path::PathBuf;
+
+use ratsembler_6502::elf::relocatable::Relocatable;
+use ratsembler_6502::lang::assembler::Assembler;
+use ratsembler_6502::output::format::OutputFormat;
+use ratsembler_6502::output::image::Image;
+use ratsembler_6502::output::ines::Ines;
+
+// Each test gets its own scratch directory so they can run in parallel.
+fn scratch_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!(
+        "ratsembler_include_{}_{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(directory.join("lib")).unwrap();
+    directory
+}
+
+#[test]
+fn test_include_search_paths() {
+    let directory = scratch_directory("search");
+    fs::write(
+        directory.join("lib/defs.s"),
+        "BORDER = $D020\n.include \"colours.s\"\n",
+    )
+    .unwrap();
+    fs::write(directory.join("lib/colours.s"), "BLACK = 0\n").unwrap();
+    fs::write(
+        directory.join("main.s"),
+        ".include \"defs.s\"\n  LDA #BLACK\n  STA BORDER\n",
+    )
+    .unwrap();
+
+    let mut assembler = Assembler::new();
+    assembler.add_include_path(directory.join("lib"));
+    let program = assembler
+        .assemble_file(&directory.join("main.s"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        program.get_raw_section(),
+        vec![0xA9, 0x00, 0x8D, 0x20, 0xD0]
+    );
+    assert_eq!(
+        assembler.dependencies(),
+        &[
+            directory.join("main.s"),
+            directory.join("lib/defs.s"),
+            directory.join("lib/colours.s"),
+        ]
+    );
+}
+
+#[test]
+fn test_incbin_offset_and_length() {
+    let directory = scratch_directory("incbin");
+    fs::write(directory.join("sprite.bin"), [1u8, 2, 3, 4, 5, 6]).unwrap();
+    fs::write(
+        directory.join("main.s"),
+        "sprite:\n.incbin \"sprite.bin\", 2, 3\n.incbin \"sprite.bin\", 4\nend:\n.word end\n",
+    )
+    .unwrap();
+
+    let mut assembler = Assembler::new();
+    let program = assembler
+        .assemble_file(&directory.join("main.s"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(program.get_raw_section(), vec![3, 4, 5, 5, 6, 0xFF, 0xFF]);
+    assert_eq!(program.labels["end"], 5);
+
+    fs::write(directory.join("main.s"), ".incbin \"sprite.bin\", 4, 3\n").unwrap();
+    let mut assembler = Assembler::new();
+    assert!(assembler
+        .assemble_file(&directory.join("main.s"))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_include_cycle() {
+    let directory = scratch_directory("cycle");
+    fs::write(directory.join("a.s"), "NOP\n.include \"b.s\"\n").unwrap();
+    fs::write(directory.join("b.s"), ".include \"a.s\"\n").unwrap();
+
+    let mut assembler = Assembler::new();
+    assert!(assembler
+        .assemble_file(&directory.join("a.s"))
+        .unwrap()
+        .is_none());
+    let message = assembler.diagnostics()[0].to_string();
+    assert!(message.contains("include cycle"), "{}", message);
+    assert!(
+        message.ends_with("a.s:2: note: included from here"),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn test_diagnostics_name_the_include_stack() {
+    let directory = scratch_directory("stack");
+    fs::write(directory.join("lib/inner.s"), "NOP\n  LDA #$123\n").unwrap();
+    fs::write(directory.join("lib/outer.s"), ".include \"inner.s\"\n").unwrap();
+    fs::write(directory.join("main.s"), "\n.include \"lib/outer.s\"\n").unwrap();
+
+    let mut assembler = Assembler::new();
+    assert!(assembler
+        .assemble_file(&directory.join("main.s"))
+        .unwrap()
+        .is_none());
+    let lines: Vec<String> = assembler.diagnostics()[0]
+        .to_string()
+        .lines()
+        .map(String::from)
+        .collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].ends_with("lib/inner.s:2: error: value $123 does not fit in a byte"));
+    assert!(lines[1].ends_with("lib/outer.s:1: note: included from here"));
+    assert!(lines[2].ends_with("main.s:2: note: included from here"));
+}
+
+#[test]
+fn test_missing_include() {
+    let directory = scratch_directory("missing");
+    fs::write(directory.join("main.s"), ".include \"nowhere.s\"\n").unwrap();
+
+    let mut assembler = Assembler::new();
+    assert!(assembler
+        .assemble_file(&directory.join("main.s"))
+        .unwrap()
+        .is_none());
+    assert!(assembler.diagnostics()[0]
+        .to_string()
+        .ends_with("main.s:1: error: can't find included file 'nowhere.s'"));
+}
+
+#[test]
+fn test_incbin_chr_banks() {
+    let directory = scratch_directory("chr");
+    let tiles: Vec<u8> = (0..=255).cycle().take(0x2000).collect();
+    fs::write(directory.join("tiles.chr"), &tiles).unwrap();
+    fs::write(
+        directory.join("main.s"),
+        r#".ines_chr 1
+.org $C000
+reset: JMP reset
+.org $FFFA
+  .word reset, reset, reset
+.section tiles, chr, 0
+.incbin "tiles.chr"
+"#,
+    )
+    .unwrap();
+
+    let program = Assembler::new()
+        .assemble_file(&directory.join("main.s"))
+        .unwrap()
+        .unwrap();
+    let image = Image::from_program(&program, 0).unwrap();
+    let rom = Ines {
+        header: program.ines.clone(),
+        fill: 0,
+    }
+    .write(&image)
+    .unwrap();
+    assert_eq!(rom[..6], [b'N', b'E', b'S', 0x1A, 1, 1]);
+    assert_eq!(rom[16..19], [0x4C, 0x00, 0xC0]);
+    assert_eq!(rom[16 + 0x4000..], tiles[..]);
+}
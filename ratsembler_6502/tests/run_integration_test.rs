@@ -0,0 +1,135 @@
+use std::fs;
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+
+#[test]
+fn test_run_output() {
+    let directory = std::env::temp_dir().join(format!("ratsembler_run_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    let source = directory.join("echo.s");
+    fs::write(
+        &source,
+        r#"
+PUTCHAR = $F001
+EXIT = $F002
+GETCHAR = $F004
+.org $0800
+loop:
+  LDA GETCHAR
+  BEQ done
+  STA PUTCHAR
+  JMP loop
+done:
+  LDA #5
+  STA EXIT
+"#,
+    )
+    .unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ratsembler_6502"))
+        .arg("run")
+        .arg(&source)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"just this\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    // Nothing but what the program wrote goes to stdout
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "just this\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    assert_eq!(output.status.code(), Some(5));
+}
+
+#[test]
+fn test_run_copied_data() {
+    let directory = std::env::temp_dir().join(format!("ratsembler_copy_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(
+        directory.join("m.s"),
+        r#"
+PUTCHAR = $F001
+EXIT = $F002
+reset:
+  LDX #0
+copy:
+  LDA __DATA_LOAD__,X
+  STA __DATA_RUN__,X
+  INX
+  CPX #<__DATA_SIZE__
+  BNE copy
+  LDX #0
+print:
+  LDA message,X
+  BEQ done
+  STA PUTCHAR
+  INX
+  BNE print
+done:
+  LDA #0
+  STA EXIT
+.section data
+message: .byte "ok", 10, 0
+"#,
+    )
+    .unwrap();
+    // Data is stored in ROM after the code, and copied to RAM to run
+    fs::write(
+        directory.join("m.cfg"),
+        r#"
+MEMORY {
+    RAM: start = $0200, size = $0600, file = "";
+    ROM: start = $E000, size = $1000;
+}
+SEGMENTS {
+    text: load = ROM;
+    data: load = ROM, run = RAM, define = yes;
+}
+"#,
+    )
+    .unwrap();
+
+    let ratsembler = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_ratsembler_6502"))
+            .args(args)
+            .current_dir(&directory)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    };
+    // Assembling doesn't print anything unless something's wrong
+    let output = ratsembler(&["-o", "m.o", "m.s"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    for format in ["elf", "bin"] {
+        let output = format!("m.{}", format);
+        ratsembler(&[
+            "link", "-C", "m.cfg", "-f", format, "--entry", "reset", "-o", &output, "m.o",
+        ]);
+        let mut run = vec!["run", &output];
+        if format == "bin" {
+            run.extend(["--binary", "--origin", "$E000"]);
+        }
+        let output = ratsembler(&run);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "ok\n",
+            "{}",
+            format
+        );
+    }
+}